@@ -93,6 +93,8 @@ pub struct Downloader<C> {
     pub connector: C,
     pub destination_dir: PathBuf,
     pub config: TransferConfig,
+    /// Node data disk quota, if enforced by host.
+    pub data_quota_bytes: Option<u64>,
 }
 
 #[async_trait]
@@ -149,11 +151,17 @@ impl<C: BabelEngineConnector + Clone + Send + Sync + 'static> Runner for Downloa
 }
 
 impl<C: BabelEngineConnector + Clone + Send + Sync + 'static> Downloader<C> {
-    pub fn new(connector: C, destination_dir: PathBuf, config: TransferConfig) -> Self {
+    pub fn new(
+        connector: C,
+        destination_dir: PathBuf,
+        config: TransferConfig,
+        data_quota_bytes: Option<u64>,
+    ) -> Self {
         Self {
             connector,
             destination_dir,
             config,
+            data_quota_bytes,
         }
     }
 
@@ -188,12 +196,29 @@ impl<C: BabelEngineConnector + Clone + Send + Sync + 'static> Downloader<C> {
             bv_utils::system::available_disk_space_by_path(&self.destination_dir)?;
 
         let required_space = required_disk_space(metadata, downloaded_chunks)?;
+        if let Some(quota) = self.data_quota_bytes {
+            if metadata.total_size > quota {
+                bail!(
+                    "Can't download {} bytes of data, it exceeds node data disk quota of {} bytes",
+                    metadata.total_size,
+                    quota
+                )
+            }
+        }
         if required_space > available_space {
-            bail!(
-                "Can't download {} bytes of data while only {} available",
-                required_space,
-                available_space
-            )
+            match self.data_quota_bytes {
+                Some(quota) => bail!(
+                    "Can't download {} bytes of data while only {} available within node data disk quota of {} bytes",
+                    required_space,
+                    available_space,
+                    quota
+                ),
+                None => bail!(
+                    "Can't download {} bytes of data while only {} available",
+                    required_space,
+                    available_space
+                ),
+            }
         }
         Ok(())
     }
@@ -738,6 +763,13 @@ mod tests {
     impl TestEnv {
         fn download_job(
             &self,
+        ) -> ArchiveJobRunner<SysTimer, Downloader<utils::tests::DummyConnector>> {
+            self.download_job_with_quota(None)
+        }
+
+        fn download_job_with_quota(
+            &self,
+            data_quota_bytes: Option<u64>,
         ) -> ArchiveJobRunner<SysTimer, Downloader<utils::tests::DummyConnector>> {
             ArchiveJobRunner::new(
                 SysTimer,
@@ -759,6 +791,7 @@ mod tests {
                         progress_file_path: self.download_progress_path.clone(),
                         compression: None,
                     },
+                    data_quota_bytes,
                 },
             )
         }
@@ -1527,6 +1560,36 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_disk_quota_exceeded() -> Result<()> {
+        let test_env = setup_test_env().await?;
+
+        let mut mock = MockBabelEngine::new();
+        mock.expect_get_download_metadata()
+            .once()
+            .returning(move |_| {
+                Ok(Response::new(DownloadMetadata {
+                    total_size: 2048,
+                    compression: None,
+                    chunks: 1,
+                    data_version: 0,
+                }))
+            });
+        let server = test_env.start_server(mock).await;
+        assert_eq!(
+            JobStatus::Finished {
+                exit_code: Some(-1),
+                message: "job 'name' failed with: Can't download 2048 bytes of data, it exceeds node data disk quota of 1024 bytes".to_string(),
+            },
+            test_env
+                .download_job_with_quota(Some(1024))
+                .run(RunFlag::default(), "name", &test_env.tmp_dir)
+                .await
+        );
+        server.assert().await;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_writer_error() -> Result<()> {
         let mut test_env = setup_test_env().await?;
@@ -137,6 +137,7 @@ pub async fn run_job(
                             max_connections.unwrap_or(DEFAULT_MAX_DOWNLOAD_CONNECTIONS),
                             max_runners.unwrap_or(DEFAULT_MAX_RUNNERS),
                         )?,
+                        babel_config.node_env.data_quota_bytes,
                     ),
                 )
                 .run(run, &job_name, &jobs::JOBS_DIR)
@@ -2,7 +2,7 @@ use crate::plugin_config::PluginConfig;
 use eyre::{anyhow, ensure, Result};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fmt,
     path::{Path, PathBuf},
     time::{Duration, SystemTime},
@@ -65,6 +65,22 @@ pub trait Engine {
     /// Load plugin data from persistent storage.
     fn load_data(&self) -> Result<String>;
 
+    /// Get value stored under `key` in plugin key-value storage.
+    fn kv_get(&self, key: &str) -> Result<Option<String>>;
+
+    /// Store `value` under `key` in plugin key-value storage.
+    fn kv_set(&self, key: &str, value: &str) -> Result<()>;
+
+    /// Delete `key` from plugin key-value storage. Returns `true` if key existed.
+    fn kv_delete(&self, key: &str) -> Result<bool>;
+
+    /// List all plugin key-value storage entries with keys starting with given `prefix`.
+    fn kv_list(&self, prefix: &str) -> Result<BTreeMap<String, String>>;
+
+    /// Atomically store `new` value under `key`, but only if current value is equal to `expected`
+    /// (`None` means that key shall not exist). Returns `true` if value has been swapped.
+    fn kv_compare_and_swap(&self, key: &str, expected: Option<String>, new: &str) -> Result<bool>;
+
     /// Save plugin config to persistent storage.
     fn save_config(&self, value: &PluginConfig) -> Result<()>;
 
@@ -130,6 +146,9 @@ pub struct NodeEnv {
     pub data_mount_point: PathBuf,
     /// Absolute path to directory where protocol data are stored.
     pub protocol_data_path: PathBuf,
+    /// Data drive quota in bytes, if enforced by host.
+    #[serde(default)]
+    pub data_quota_bytes: Option<u64>,
 }
 
 /// Structure describing where decompressed data shall be written to and how many bytes.
@@ -1,5 +1,6 @@
-use eyre::Result;
+use eyre::{bail, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 /// Interface to be implemented by babel plugin.
 /// Babel plugin adds support for some protocol type.
@@ -39,9 +40,34 @@ pub trait Plugin {
     /// Returns protocol status.
     fn protocol_status(&self) -> Result<ProtocolStatus>;
 
+    /// Returns custom, protocol specific metrics (e.g. peers count or sync lag),
+    /// as a map of named gauges and counters.
+    fn metrics(&self) -> Result<CustomMetrics>;
+
+    /// Hook called before node is stopped.
+    fn pre_stop(&self) -> Result<()>;
+
+    /// Hook called before node is upgraded, while it is still running with current image.
+    fn pre_upgrade(&self) -> Result<()>;
+
+    /// Hook called after node is upgraded and started with new image.
+    fn post_upgrade(&self) -> Result<()>;
+
+    /// Hook called when node properties are changed on running node. Gets map of changed
+    /// properties as input. If implemented, it is called instead of `init`.
+    fn on_properties_changed(&self, changed: &HashMap<String, String>) -> Result<()>;
+
+    /// Hook called before node is deleted.
+    fn pre_delete(&self) -> Result<()>;
+
     /// Call custom protocol method by `name`, that gets String param as input and returns String as well.
     /// It is recommended to use Json string for more complex input/output.
     fn call_custom_method(&self, name: &str, param: &str) -> Result<String>;
+
+    /// Evaluate arbitrary script in the plugin context and return its result as String.
+    /// In `read_only` mode, engine functions that modify node state (e.g. starting jobs
+    /// or saving data) are not allowed.
+    fn evaluate(&self, script: &str, read_only: bool) -> Result<String>;
 }
 
 /// Describe the node's protocol related status. These states are used to describe the
@@ -59,3 +85,29 @@ pub enum NodeHealth {
     Neutral,
     Unhealthy,
 }
+
+/// Custom protocol metrics, indexed by metric name.
+pub type CustomMetrics = HashMap<String, CustomMetric>;
+
+/// Check that custom metric name consists of `[a-z0-9_.]` characters only, so it can be safely
+/// used as part of exported metric name.
+pub fn check_metric_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| matches!(c, 'a'..='z' | '0'..='9' | '_' | '.'))
+    {
+        bail!("invalid metric name '{name}', only [a-z0-9_.] characters are allowed");
+    }
+    Ok(())
+}
+
+/// Single custom protocol metric value.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum CustomMetric {
+    /// Value that can arbitrarily go up and down (e.g. peers count).
+    Gauge(f64),
+    /// Monotonically increasing value (e.g. number of processed transactions).
+    Counter(u64),
+}
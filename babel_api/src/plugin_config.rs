@@ -40,6 +40,8 @@ pub struct PluginConfig {
     pub post_upload: Option<Vec<Job>>,
     /// List of tasks to be scheduled on init.
     pub scheduled: Option<Vec<Task>>,
+    /// Port on which node serves RPC. It is exposed by BV RPC proxy (if enabled) as `https://<dns_name>`.
+    pub rpc_port: Option<u16>,
 }
 
 impl PluginConfig {
@@ -3,8 +3,11 @@ use crate::plugin_config::{
     UPLOADING_STATE_NAME,
 };
 use crate::{
-    engine::{Engine, HttpResponse, JobConfig, JobStatus, JrpcRequest, RestRequest, ShResponse},
-    plugin::{NodeHealth, Plugin, ProtocolStatus},
+    engine::{
+        Engine, HttpResponse, JobConfig, JobInfo, JobStatus, JobsInfo, JrpcRequest, NodeEnv,
+        RestRequest, ShResponse,
+    },
+    plugin::{check_metric_name, CustomMetrics, NodeHealth, Plugin, ProtocolStatus},
     plugin_config::{
         self, Actions, ConfigFile, Job, PluginConfig, Service, DOWNLOAD_JOB_NAME, UPLOAD_JOB_NAME,
     },
@@ -14,23 +17,40 @@ use rhai::module_resolvers::FileModuleResolver;
 use rhai::{
     self,
     serde::{from_dynamic, to_dynamic},
-    Dynamic, FnPtr, Map, AST,
+    Dynamic, FnPtr, Map, Module, Scope, AST,
 };
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
-use std::{path::Path, sync::Arc, time::Duration};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
 use tracing::Level;
 
 pub const PLUGIN_CONFIG_FN_NAME: &str = "plugin_config";
 const INIT_FN_NAME: &str = "init";
 const PROTOCOL_STATUS_FN_NAME: &str = "protocol_status";
+const METRICS_FN_NAME: &str = "metrics";
+pub const PRE_STOP_FN_NAME: &str = "pre_stop";
+pub const PRE_UPGRADE_FN_NAME: &str = "pre_upgrade";
+pub const POST_UPGRADE_FN_NAME: &str = "post_upgrade";
+pub const ON_PROPERTIES_CHANGED_FN_NAME: &str = "on_properties_changed";
+pub const PRE_DELETE_FN_NAME: &str = "pre_delete";
+/// Max time plugin hook may run, then script is terminated.
+pub const HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often (in Rhai operations) hook deadline is checked.
+const HOOK_DEADLINE_CHECK_OPS: u64 = 1024;
 
 #[derive(Debug)]
 pub struct RhaiPlugin<E> {
     pub(crate) bare: BarePlugin<E>,
     rhai_engine: rhai::Engine,
+    hook_timeout: Duration,
+    /// Deadline of currently running hook, checked by Rhai engine `on_progress` callback.
+    hook_deadline: Arc<Mutex<Option<Instant>>>,
 }
 
 impl<E: Engine + Sync + Send + 'static> Clone for RhaiPlugin<E> {
@@ -43,6 +63,8 @@ impl<E: Engine + Sync + Send + 'static> Clone for RhaiPlugin<E> {
         let mut clone = Self {
             bare: self.bare.clone(),
             rhai_engine,
+            hook_timeout: self.hook_timeout,
+            hook_deadline: Default::default(),
         };
         clone.register_defaults();
         clone
@@ -117,6 +139,8 @@ impl<E: Engine + Sync + Send + 'static> RhaiPlugin<E> {
                 plugin_config,
             },
             rhai_engine,
+            hook_timeout: HOOK_TIMEOUT,
+            hook_deadline: Default::default(),
         };
         plugin.register_defaults();
         Ok(plugin)
@@ -251,6 +275,43 @@ impl<E: Engine + Sync + Send + 'static> RhaiPlugin<E> {
             into_rhai_result(babel_engine.load_data())
         });
         let babel_engine = engine.clone();
+        rhai_engine.register_fn("kv_get", move |key: &str| {
+            into_rhai_result(babel_engine.kv_get(key)).map(|value| match value {
+                Some(value) => Dynamic::from(value),
+                None => Dynamic::UNIT,
+            })
+        });
+        let babel_engine = engine.clone();
+        rhai_engine.register_fn("kv_set", move |key: &str, value: &str| {
+            into_rhai_result(babel_engine.kv_set(key, value))
+        });
+        let babel_engine = engine.clone();
+        rhai_engine.register_fn("kv_delete", move |key: &str| {
+            into_rhai_result(babel_engine.kv_delete(key))
+        });
+        let babel_engine = engine.clone();
+        rhai_engine.register_fn("kv_list", move |prefix: &str| {
+            to_dynamic(into_rhai_result(babel_engine.kv_list(prefix))?)
+        });
+        let babel_engine = engine.clone();
+        rhai_engine.register_fn(
+            "kv_compare_and_swap",
+            move |key: &str, expected: &str, new: &str| {
+                into_rhai_result(babel_engine.kv_compare_and_swap(
+                    key,
+                    Some(expected.to_string()),
+                    new,
+                ))
+            },
+        );
+        let babel_engine = engine.clone();
+        rhai_engine.register_fn(
+            "kv_compare_and_swap",
+            move |key: &str, _expected: (), new: &str| {
+                into_rhai_result(babel_engine.kv_compare_and_swap(key, None, new))
+            },
+        );
+        let babel_engine = engine.clone();
         rhai_engine.register_fn("put_secret", move |name: &str, value: Vec<u8>| {
             into_rhai_result(babel_engine.put_secret(name, value))
         });
@@ -345,6 +406,16 @@ impl<E: Engine + Sync + Send + 'static> RhaiPlugin<E> {
     }
 
     fn register_defaults(&mut self) {
+        let hook_deadline = self.hook_deadline.clone();
+        self.rhai_engine.on_progress(move |ops| {
+            if ops % HOOK_DEADLINE_CHECK_OPS != 0 {
+                return None;
+            }
+            let deadline = *hook_deadline.lock().unwrap_or_else(|err| err.into_inner());
+            deadline
+                .filter(|deadline| Instant::now() >= *deadline)
+                .map(|_| Dynamic::from("hook timed out"))
+        });
         let bare = self.bare.clone();
         self.rhai_engine.register_fn("default_init", move || {
             into_rhai_result(bare.default_init())
@@ -365,6 +436,71 @@ impl<E: Engine + Sync + Send + 'static> RhaiPlugin<E> {
             .with_context(|| format!("Rhai function '{name}' returned error"))
     }
 
+    /// Call plugin hook. Hook is terminated by Rhai engine if it runs longer than `hook_timeout`,
+    /// so it doesn't keep running in background, after caller gave up.
+    fn call_hook<P: rhai::FuncArgs>(&self, name: &str, args: P) -> Result<()> {
+        let deadline = Instant::now() + self.hook_timeout;
+        *self
+            .hook_deadline
+            .lock()
+            .unwrap_or_else(|err| err.into_inner()) = Some(deadline);
+        let res = self.call_fn::<_, Dynamic>(name, args);
+        *self
+            .hook_deadline
+            .lock()
+            .unwrap_or_else(|err| err.into_inner()) = None;
+        match res {
+            Err(_) if Instant::now() >= deadline => bail!("plugin '{name}' hook timed out"),
+            res => res.map(|_| ()),
+        }
+    }
+
+    /// Evaluate script with plugin functions and constants available. Plugin functions
+    /// are provided as global module, so plugin top-level statements are not executed again.
+    fn eval(mut self, script: &str, plugin_module: Module) -> Result<String> {
+        let script_ast = self
+            .rhai_engine
+            .compile(script)
+            .with_context(|| "Rhai syntax error")?;
+        let mut scope = Scope::new();
+        for (name, value) in plugin_module.iter_var() {
+            scope.push_constant_dynamic(name.to_string(), value.clone());
+        }
+        self.rhai_engine
+            .register_global_module(plugin_module.into());
+        let result = self
+            .rhai_engine
+            .eval_ast_with_scope::<Dynamic>(&mut scope, &script_ast)
+            .with_context(|| "Rhai script returned error")?;
+        Ok(result.to_string())
+    }
+
+    /// Create plugin copy that use the same script, but can't modify node state.
+    fn read_only(&self) -> RhaiPlugin<ReadOnlyEngine<E>> {
+        self.copy_with(Arc::new(ReadOnlyEngine(self.bare.babel_engine.clone())))
+    }
+
+    /// Create plugin copy that use the same script, with separate Rhai engine.
+    fn copy_with<F: Engine + Sync + Send + 'static>(&self, babel_engine: Arc<F>) -> RhaiPlugin<F> {
+        let mut rhai_engine = RhaiPlugin::<F>::new_rhai_engine(babel_engine.clone());
+        if let Some(plugin_dir) = &self.bare.plugin_path {
+            rhai_engine.set_module_resolver(FileModuleResolver::new_with_path(plugin_dir));
+        }
+        let mut plugin = RhaiPlugin {
+            bare: BarePlugin {
+                plugin_path: self.bare.plugin_path.clone(),
+                babel_engine,
+                ast: self.bare.ast.clone(),
+                plugin_config: self.bare.plugin_config.clone(),
+            },
+            rhai_engine,
+            hook_timeout: self.hook_timeout,
+            hook_deadline: Default::default(),
+        };
+        plugin.register_defaults();
+        plugin
+    }
+
     fn get_config<T: DeserializeOwned>(&self, config_fn_name: &str) -> Result<Option<T>> {
         let dynamic = if self
             .bare
@@ -670,6 +806,37 @@ impl<E: Engine + Sync + Send + 'static> Plugin for RhaiPlugin<E> {
         }
     }
 
+    fn metrics(&self) -> Result<CustomMetrics> {
+        // go through json, so integers returned by script are accepted as gauge values
+        let value: serde_json::Value =
+            from_dynamic(&self.call_fn::<_, Dynamic>(METRICS_FN_NAME, ())?)?;
+        let metrics: CustomMetrics = serde_json::from_value(value)?;
+        for name in metrics.keys() {
+            check_metric_name(name)?;
+        }
+        Ok(metrics)
+    }
+
+    fn pre_stop(&self) -> Result<()> {
+        self.call_hook(PRE_STOP_FN_NAME, ())
+    }
+
+    fn pre_upgrade(&self) -> Result<()> {
+        self.call_hook(PRE_UPGRADE_FN_NAME, ())
+    }
+
+    fn post_upgrade(&self) -> Result<()> {
+        self.call_hook(POST_UPGRADE_FN_NAME, ())
+    }
+
+    fn on_properties_changed(&self, changed: &HashMap<String, String>) -> Result<()> {
+        self.call_hook(ON_PROPERTIES_CHANGED_FN_NAME, (to_dynamic(changed)?,))
+    }
+
+    fn pre_delete(&self) -> Result<()> {
+        self.call_hook(PRE_DELETE_FN_NAME, ())
+    }
+
     fn call_custom_method(&self, name: &str, param: &str) -> Result<String> {
         if self
             .bare
@@ -690,6 +857,175 @@ impl<E: Engine + Sync + Send + 'static> Plugin for RhaiPlugin<E> {
             bail!("no matching method '{name}' found")
         }
     }
+
+    fn evaluate(&self, script: &str, read_only: bool) -> Result<String> {
+        // evaluate plugin top-level statements (e.g. constants and imports) only once, and always
+        // in read-only mode, so script can't trigger any plugin side effects
+        let read_only_plugin = self.read_only();
+        let plugin_module =
+            Module::eval_ast_as_module(Scope::new(), &read_only_plugin.rhai_engine, &self.bare.ast)
+                .with_context(|| "Rhai plugin returned error")?;
+        if read_only {
+            read_only_plugin.eval(script, plugin_module)
+        } else {
+            self.copy_with(self.bare.babel_engine.clone())
+                .eval(script, plugin_module)
+        }
+    }
+}
+
+/// Engine wrapper that reject all calls that may modify node state.
+#[derive(Debug)]
+struct ReadOnlyEngine<E>(Arc<E>);
+
+impl<E: Engine> ReadOnlyEngine<E> {
+    fn read_only_error<T>(&self, name: &str) -> Result<T> {
+        bail!("'{name}' is not allowed in read-only mode")
+    }
+}
+
+impl<E: Engine + Sync + Send + 'static> Engine for ReadOnlyEngine<E> {
+    fn create_job(&self, _job_name: &str, _job_config: JobConfig) -> Result<()> {
+        self.read_only_error("create_job")
+    }
+
+    fn start_job(&self, _job_name: &str) -> Result<()> {
+        self.read_only_error("start_job")
+    }
+
+    fn stop_job(&self, _job_name: &str) -> Result<()> {
+        self.read_only_error("stop_job")
+    }
+
+    fn stop_all_jobs(&self) -> Result<()> {
+        self.read_only_error("stop_all_jobs")
+    }
+
+    fn cleanup_job(&self, _job_name: &str) -> Result<()> {
+        self.read_only_error("cleanup_job")
+    }
+
+    fn job_info(&self, job_name: &str) -> Result<JobInfo> {
+        self.0.job_info(job_name)
+    }
+
+    fn get_jobs(&self) -> Result<JobsInfo> {
+        self.0.get_jobs()
+    }
+
+    // RPC queries are the main use case of evaluation, and they don't touch node state
+    // managed by BV (jobs, data, config), so they are allowed
+    fn run_jrpc(&self, req: JrpcRequest, timeout: Option<Duration>) -> Result<HttpResponse> {
+        self.0.run_jrpc(req, timeout)
+    }
+
+    fn run_rest(&self, req: RestRequest, timeout: Option<Duration>) -> Result<HttpResponse> {
+        self.0.run_rest(req, timeout)
+    }
+
+    fn run_sh(&self, _body: &str, _timeout: Option<Duration>) -> Result<ShResponse> {
+        self.read_only_error("run_sh")
+    }
+
+    fn sanitize_sh_param(&self, param: &str) -> Result<String> {
+        self.0.sanitize_sh_param(param)
+    }
+
+    fn render_template(&self, _template: &Path, _destination: &Path, _params: &str) -> Result<()> {
+        self.read_only_error("render_template")
+    }
+
+    fn node_params(&self) -> HashMap<String, String> {
+        self.0.node_params()
+    }
+
+    fn node_env(&self) -> NodeEnv {
+        self.0.node_env()
+    }
+
+    fn save_data(&self, _value: &str) -> Result<()> {
+        self.read_only_error("save_data")
+    }
+
+    fn load_data(&self) -> Result<String> {
+        self.0.load_data()
+    }
+
+    fn kv_get(&self, key: &str) -> Result<Option<String>> {
+        self.0.kv_get(key)
+    }
+
+    fn kv_set(&self, _key: &str, _value: &str) -> Result<()> {
+        self.read_only_error("kv_set")
+    }
+
+    fn kv_delete(&self, _key: &str) -> Result<bool> {
+        self.read_only_error("kv_delete")
+    }
+
+    fn kv_list(&self, prefix: &str) -> Result<BTreeMap<String, String>> {
+        self.0.kv_list(prefix)
+    }
+
+    fn kv_compare_and_swap(
+        &self,
+        _key: &str,
+        _expected: Option<String>,
+        _new: &str,
+    ) -> Result<bool> {
+        self.read_only_error("kv_compare_and_swap")
+    }
+
+    fn save_config(&self, _value: &PluginConfig) -> Result<()> {
+        self.read_only_error("save_config")
+    }
+
+    fn load_config(&self) -> Result<PluginConfig> {
+        self.0.load_config()
+    }
+
+    fn log(&self, level: Level, message: &str) {
+        self.0.log(level, message)
+    }
+
+    fn add_task(
+        &self,
+        _task_name: &str,
+        _schedule: &str,
+        _function_name: &str,
+        _function_param: &str,
+    ) -> Result<()> {
+        self.read_only_error("add_task")
+    }
+
+    fn delete_task(&self, _task_name: &str) -> Result<()> {
+        self.read_only_error("delete_task")
+    }
+
+    fn protocol_data_stamp(&self) -> Result<Option<SystemTime>> {
+        self.0.protocol_data_stamp()
+    }
+
+    fn has_protocol_archive(&self) -> Result<bool> {
+        self.0.has_protocol_archive()
+    }
+
+    // don't let secrets (nor node files, e.g. keys) leak to evaluation output
+    fn get_secret(&self, _name: &str) -> Result<Option<Vec<u8>>> {
+        self.read_only_error("get_secret")
+    }
+
+    fn put_secret(&self, _name: &str, _value: Vec<u8>) -> Result<()> {
+        self.read_only_error("put_secret")
+    }
+
+    fn file_read(&self, _path: &Path) -> Result<Vec<u8>> {
+        self.read_only_error("file_read")
+    }
+
+    fn file_write(&self, _path: &Path, _content: Vec<u8>) -> Result<()> {
+        self.read_only_error("file_write")
+    }
 }
 
 fn into_rhai_result<T>(result: Result<T>) -> std::result::Result<T, Box<rhai::EvalAltResult>> {
@@ -878,7 +1214,7 @@ mod tests {
         self, HttpResponse, JobConfig, JobInfo, JobStatus, JobType, JrpcRequest, NodeEnv,
         RestRequest, RestartConfig, RestartPolicy, ShResponse,
     };
-    use crate::plugin::NodeHealth;
+    use crate::plugin::{CustomMetric, NodeHealth};
     use crate::plugin_config::{AlternativeDownload, Job};
     use eyre::bail;
     use mockall::*;
@@ -912,6 +1248,11 @@ mod tests {
             fn node_env(&self) -> NodeEnv;
             fn save_data(&self, value: &str) -> Result<()>;
             fn load_data(&self) -> Result<String>;
+            fn kv_get(&self, key: &str) -> Result<Option<String>>;
+            fn kv_set(&self, key: &str, value: &str) -> Result<()>;
+            fn kv_delete(&self, key: &str) -> Result<bool>;
+            fn kv_list(&self, prefix: &str) -> Result<BTreeMap<String, String>>;
+            fn kv_compare_and_swap(&self, key: &str, expected: Option<String>, new: &str) -> Result<bool>;
             fn save_config(&self, value: &PluginConfig) -> Result<()>;
             fn load_config(&self) -> Result<PluginConfig>;
             fn log(&self, level: Level, message: &str);
@@ -1495,6 +1836,271 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_metrics() -> Result<()> {
+        let script = r#"
+            fn metrics() {
+                #{
+                    peers: #{type: "gauge", value: 12},
+                    sync_lag: #{type: "gauge", value: 0.5},
+                    txs: #{type: "counter", value: 1024},
+                }
+            }
+            "#;
+        let mut babel = MockBabelEngine::new();
+        babel.expect_load_config().returning(|| bail!("no config"));
+        let plugin = RhaiPlugin::from_str(script, babel)?;
+        assert_eq!(
+            CustomMetrics::from_iter([
+                ("peers".to_string(), CustomMetric::Gauge(12.0)),
+                ("sync_lag".to_string(), CustomMetric::Gauge(0.5)),
+                ("txs".to_string(), CustomMetric::Counter(1024)),
+            ]),
+            plugin.metrics()?
+        );
+
+        let mut babel = MockBabelEngine::new();
+        babel.expect_load_config().returning(|| bail!("no config"));
+        let plugin = RhaiPlugin::from_str(
+            r#"fn metrics() { #{ "Peers\nCount": #{type: "gauge", value: 1} } }"#,
+            babel,
+        )?;
+        assert_eq!(
+            "invalid metric name 'Peers\nCount', only [a-z0-9_.] characters are allowed",
+            plugin.metrics().unwrap_err().to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_evaluate() -> Result<()> {
+        let script = r#"
+            const SUFFIX = "_suffix";
+
+            fn height() {
+                77
+            }
+
+            fn suffixed(value) {
+                value + SUFFIX
+            }
+            "#;
+        let mut babel = MockBabelEngine::new();
+        babel.expect_load_config().returning(|| bail!("no config"));
+        babel
+            .expect_load_data()
+            .once()
+            .returning(|| Ok("data".to_string()));
+        babel
+            .expect_save_data()
+            .with(predicate::eq("new data"))
+            .once()
+            .returning(|_| Ok(()));
+        babel
+            .expect_run_jrpc()
+            .with(
+                predicate::eq(JrpcRequest {
+                    host: "http://localhost".to_string(),
+                    method: "info".to_string(),
+                    params: None,
+                    headers: None,
+                }),
+                predicate::eq(None),
+            )
+            .once()
+            .returning(|_, _| {
+                Ok(HttpResponse {
+                    status_code: 200,
+                    body: r#"{"height":77}"#.to_string(),
+                })
+            });
+        let plugin = RhaiPlugin::from_str(script, babel)?;
+        assert_eq!("78", plugin.evaluate("height() + 1", true)?);
+        assert_eq!(
+            "data_suffix",
+            plugin.evaluate("load_data() + SUFFIX", true)?
+        );
+        assert_eq!("77_suffix", plugin.evaluate("suffixed(height())", false)?);
+        assert!(plugin.evaluate(r#"save_data("new data")"#, true).is_err());
+        assert!(format!(
+            "{:#}",
+            plugin.evaluate(r#"get_secret("key")"#, true).unwrap_err()
+        )
+        .contains("'get_secret' is not allowed in read-only mode"));
+        assert!(format!(
+            "{:#}",
+            plugin
+                .evaluate(r#"file_read("/keys/node.key")"#, true)
+                .unwrap_err()
+        )
+        .contains("'file_read' is not allowed in read-only mode"));
+        assert_eq!(
+            "{\"height\":77}",
+            plugin.evaluate(
+                r#"run_jrpc(#{host: "http://localhost", method: "info"}).body"#,
+                true
+            )?
+        );
+        assert_eq!("", plugin.evaluate(r#"save_data("new data")"#, false)?);
+        assert!(plugin.evaluate("height(", true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hooks() -> Result<()> {
+        let script = r#"
+            fn pre_stop() {
+                save_data("pre_stop");
+            }
+
+            fn pre_upgrade() {
+                save_data("pre_upgrade");
+            }
+
+            fn post_upgrade() {
+                save_data("post_upgrade");
+            }
+
+            fn on_properties_changed(changed) {
+                save_data(changed.some_key);
+            }
+
+            fn pre_delete() {
+                save_data("pre_delete");
+            }
+            "#;
+        let mut babel = MockBabelEngine::new();
+        babel.expect_load_config().returning(|| bail!("no config"));
+        for data in [
+            "pre_stop",
+            "pre_upgrade",
+            "post_upgrade",
+            "new value",
+            "pre_delete",
+        ] {
+            babel
+                .expect_save_data()
+                .with(predicate::eq(data))
+                .once()
+                .returning(|_| Ok(()));
+        }
+        let plugin = RhaiPlugin::from_str(script, babel)?;
+        plugin.pre_stop()?;
+        plugin.pre_upgrade()?;
+        plugin.post_upgrade()?;
+        plugin.on_properties_changed(&HashMap::from_iter([(
+            "some_key".to_string(),
+            "new value".to_string(),
+        )]))?;
+        plugin.pre_delete()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_hook_timeout() -> Result<()> {
+        let script = r#"
+            fn pre_stop() {
+                let counter = 0;
+                loop {
+                    counter += 1;
+                }
+            }
+
+            fn pre_delete() {
+                save_data("pre_delete");
+            }
+            "#;
+        let mut babel = MockBabelEngine::new();
+        babel.expect_load_config().returning(|| bail!("no config"));
+        babel
+            .expect_save_data()
+            .with(predicate::eq("pre_delete"))
+            .once()
+            .returning(|_| Ok(()));
+        let mut plugin = RhaiPlugin::from_str(script, babel)?;
+        plugin.hook_timeout = Duration::from_millis(100);
+        assert_eq!(
+            "plugin 'pre_stop' hook timed out",
+            plugin.pre_stop().unwrap_err().to_string()
+        );
+        // deadline is reset, so following hooks are not affected
+        std::thread::sleep(Duration::from_millis(100));
+        plugin.pre_delete()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_kv_storage() -> Result<()> {
+        let script = r#"
+            fn kv_test() {
+                if kv_get("ns/missing") != () {
+                    throw "unexpected value";
+                }
+                kv_set("ns/key", kv_get("ns/key") + "_updated");
+                let swapped = kv_compare_and_swap("ns/counter", (), "1");
+                swapped = swapped && kv_compare_and_swap("ns/counter", "1", "2");
+                let list = kv_list("ns/");
+                `${swapped}|${list.len()}|${list["ns/key"]}|${kv_delete("ns/key")}`
+            }
+            "#;
+        let mut babel = MockBabelEngine::new();
+        babel.expect_load_config().returning(|| bail!("no config"));
+        babel
+            .expect_kv_get()
+            .with(predicate::eq("ns/missing"))
+            .once()
+            .returning(|_| Ok(None));
+        babel
+            .expect_kv_get()
+            .with(predicate::eq("ns/key"))
+            .once()
+            .returning(|_| Ok(Some("value".to_string())));
+        babel
+            .expect_kv_set()
+            .with(predicate::eq("ns/key"), predicate::eq("value_updated"))
+            .once()
+            .returning(|_, _| Ok(()));
+        babel
+            .expect_kv_compare_and_swap()
+            .with(
+                predicate::eq("ns/counter"),
+                predicate::eq(None),
+                predicate::eq("1"),
+            )
+            .once()
+            .returning(|_, _, _| Ok(true));
+        babel
+            .expect_kv_compare_and_swap()
+            .with(
+                predicate::eq("ns/counter"),
+                predicate::eq(Some("1".to_string())),
+                predicate::eq("2"),
+            )
+            .once()
+            .returning(|_, _, _| Ok(true));
+        babel
+            .expect_kv_list()
+            .with(predicate::eq("ns/"))
+            .once()
+            .returning(|_| {
+                Ok(BTreeMap::from_iter([
+                    ("ns/counter".to_string(), "2".to_string()),
+                    ("ns/key".to_string(), "value_updated".to_string()),
+                ]))
+            });
+        babel
+            .expect_kv_delete()
+            .with(predicate::eq("ns/key"))
+            .once()
+            .returning(|_| Ok(true));
+        let plugin = RhaiPlugin::from_str(script, babel)?;
+        assert_eq!(
+            "true|2|value_updated|true",
+            plugin.call_custom_method("kv_test", "")?
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_run_actions_without_jobs() -> Result<()> {
         let mut babel = MockBabelEngine::new();
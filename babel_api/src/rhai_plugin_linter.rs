@@ -10,7 +10,7 @@ use crate::{
 use eyre::{anyhow, bail};
 use std::collections::HashSet;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     path::{Path, PathBuf},
     time::{Duration, SystemTime},
 };
@@ -242,6 +242,31 @@ impl Engine for LinterEngine {
         Ok("".to_string())
     }
 
+    fn kv_get(&self, _key: &str) -> eyre::Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn kv_set(&self, _key: &str, _value: &str) -> eyre::Result<()> {
+        Ok(())
+    }
+
+    fn kv_delete(&self, _key: &str) -> eyre::Result<bool> {
+        Ok(false)
+    }
+
+    fn kv_list(&self, _prefix: &str) -> eyre::Result<BTreeMap<String, String>> {
+        Ok(Default::default())
+    }
+
+    fn kv_compare_and_swap(
+        &self,
+        _key: &str,
+        _expected: Option<String>,
+        _new: &str,
+    ) -> eyre::Result<bool> {
+        Ok(true)
+    }
+
     fn save_config(&self, _value: &PluginConfig) -> eyre::Result<()> {
         Ok(())
     }
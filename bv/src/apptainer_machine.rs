@@ -1,11 +1,15 @@
 use crate::{
     bv_config::ApptainerConfig,
     bv_context::BvContext,
+    disk_quota::{DiskQuota, DiskUsage},
+    image_cache::{self, ImageCache},
+    net_shaping::{self, BandwidthLimit, TrafficUsage},
     node_context, node_env,
     node_env::NODE_ENV_FILE_PATH,
     node_state::{NodeState, VmConfig},
     pal,
-    utils::{get_process_pid, GetProcessIdError},
+    upgrade_dirs::UpgradeDirs,
+    utils::{get_process_pid, is_mount_point, GetProcessIdError},
 };
 use async_trait::async_trait;
 use babel_api::engine::{NodeEnv, PosixSignal};
@@ -18,7 +22,7 @@ use std::{
     ffi::OsStr,
     fmt::Debug,
     mem,
-    net::IpAddr,
+    net::{IpAddr, Ipv6Addr},
     path::{Path, PathBuf},
     str::FromStr,
     time::Duration,
@@ -31,18 +35,22 @@ use tracing::{debug, error};
 pub const DATA_DIR: &str = "data";
 pub const ROOTFS_DIR: &str = "rootfs";
 pub const BACKUP_ROOTFS_DIR: &str = "rootfs_backup";
+pub const STAGING_ROOTFS_DIR: &str = "rootfs_staging";
+pub const OVERLAY_DIR: &str = "overlay";
+pub const BACKUP_OVERLAY_DIR: &str = "overlay_backup";
 pub const PLUGIN_PATH: &str = "var/lib/babel/plugin";
 pub const PLUGIN_MAIN_FILENAME: &str = "main.rhai";
+pub const JOURNAL_DIR: &str = "/run/systemd/journal";
+pub const BABEL_BIN_NAME: &str = "babel";
+pub const BABEL_KILL_TIMEOUT: Duration = Duration::from_secs(60);
+pub const BABEL_BIN_PATH: &str = "/usr/bin/babel";
+pub const BABEL_VAR_PATH: &str = "var/lib/babel";
+pub const DATA_DRIVE_MOUNT_POINT: &str = "/blockjoy";
+pub const PROTOCOL_DATA_PATH: &str = "/blockjoy/protocol_data";
 const CGROUPS_CONF_FILE: &str = "cgroups.toml";
 const APPTAINER_PID_FILE: &str = "apptainer.pid";
-const JOURNAL_DIR: &str = "/run/systemd/journal";
-const BABEL_BIN_NAME: &str = "babel";
-const BABEL_KILL_TIMEOUT: Duration = Duration::from_secs(60);
-const BABEL_BIN_PATH: &str = "/usr/bin/babel";
 const APPTAINER_BIN_NAME: &str = "apptainer";
-const BABEL_VAR_PATH: &str = "var/lib/babel";
-const DATA_DRIVE_MOUNT_POINT: &str = "/blockjoy";
-const PROTOCOL_DATA_PATH: &str = "/blockjoy/protocol_data";
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
 
 pub fn build_rootfs_dir(node_dir: &Path) -> PathBuf {
     node_dir.join(ROOTFS_DIR)
@@ -56,6 +64,7 @@ pub struct ApptainerMachine {
     cgroups_path: PathBuf,
     apptainer_pid_path: PathBuf,
     data_dir: PathBuf,
+    disk_quota: DiskQuota,
 
     apptainer_pid: Option<Pid>,
     babel_pid: Option<Pid>,
@@ -63,13 +72,18 @@ pub struct ApptainerMachine {
     vm_id: String,
     vm_name: String,
     ip: IpAddr,
+    ipv6: Option<Ipv6Addr>,
     net_conf: NetConf,
 
     apptainer_config: ApptainerConfig,
 
     config: Config,
-    backup_chroot_dir: PathBuf,
     config_backup: Option<Config>,
+    upgrade_dirs: UpgradeDirs,
+    /// Set if node rootfs is an overlay on top of cached image, instead of full sandbox.
+    image_cache: Option<ImageCache>,
+    overlay_dir: PathBuf,
+    backup_overlay_dir: PathBuf,
 }
 
 #[derive(Debug, Clone)]
@@ -77,6 +91,14 @@ pub struct NetConf {
     pub mask_bits: u8,
     pub gateway: IpAddr,
     pub bridge: IpAddr,
+    /// Set if host network is dual-stack.
+    pub ipv6: Option<Ipv6NetConf>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Ipv6NetConf {
+    pub mask_bits: u8,
+    pub bridge: Ipv6Addr,
 }
 
 #[derive(Debug, Clone)]
@@ -87,6 +109,8 @@ struct Config {
     node_env: NodeEnv,
 }
 
+/// Create machine for new node (`create` is set) or attach to existing one. Disk quota failure
+/// is fatal only for new node, existing one keeps running without quota, if data are accessible.
 pub async fn new(
     bv_root: &Path,
     net_conf: NetConf,
@@ -94,16 +118,46 @@ pub async fn new(
     node_state: &NodeState,
     babel_path: PathBuf,
     config: ApptainerConfig,
+    create: bool,
 ) -> Result<ApptainerMachine> {
     let node_dir = node_context::build_node_dir(bv_root, node_state.id);
     let chroot_dir = build_rootfs_dir(&node_dir);
-    let backup_chroot_dir = node_dir.join(BACKUP_ROOTFS_DIR);
+    let upgrade_dirs = UpgradeDirs::new(
+        chroot_dir.clone(),
+        node_dir.join(BACKUP_ROOTFS_DIR),
+        node_dir.join(STAGING_ROOTFS_DIR),
+        node_dir.join(image_cache::STAGED_IMAGE_FILE),
+    );
+    let overlay_dir = node_dir.join(OVERLAY_DIR);
+    let backup_overlay_dir = node_dir.join(BACKUP_OVERLAY_DIR);
+    // nodes with full sandbox rootfs, created before image cache was enabled, are kept as they are
+    let image_cache = (overlay_dir.exists() || (config.image_cache && !chroot_dir.exists()))
+        .then(|| ImageCache::new(bv_root));
     let cgroups_path = node_dir.join(CGROUPS_CONF_FILE);
     let apptainer_pid_path = node_dir.join(APPTAINER_PID_FILE);
     let data_dir = node_dir.join(DATA_DIR);
     if !data_dir.exists() {
         fs::create_dir_all(&data_dir).await?;
     }
+    let mut disk_quota = DiskQuota::new(
+        config.disk_quota,
+        &node_dir,
+        data_dir.clone(),
+        node_state.id,
+    );
+    if let Err(err) = disk_quota
+        .apply(disk_size_bytes(&node_state.vm_config))
+        .await
+    {
+        if create || !disk_quota.is_data_accessible().await {
+            return Err(err);
+        }
+        warn!(
+            "node `{}` disk quota is not enforced: {err:#}",
+            node_state.id
+        );
+    }
+    let data_quota_bytes = disk_quota.limit();
     if node_state.initialized && babel_api::utils::protocol_data_stamp(&data_dir)?.is_none() {
         babel_api::utils::touch_protocol_data(&data_dir)?;
     }
@@ -112,16 +166,17 @@ pub async fn new(
         node_dir,
         babel_path,
         chroot_dir,
-        backup_chroot_dir,
         cgroups_path,
         apptainer_pid_path,
         apptainer_pid: None,
         babel_pid: None,
         data_dir,
+        disk_quota,
 
         vm_id: node_state.id.to_string(),
         vm_name: node_state.name.clone(),
         ip: node_state.ip,
+        ipv6: node_state.ipv6,
         net_conf,
 
         apptainer_config: config,
@@ -135,71 +190,152 @@ pub async fn new(
                 node_state,
                 PathBuf::from_str(DATA_DRIVE_MOUNT_POINT)?,
                 PathBuf::from_str(PROTOCOL_DATA_PATH)?,
+                data_quota_bytes,
             ),
         },
         config_backup: None,
+        upgrade_dirs,
+        image_cache,
+        overlay_dir,
+        backup_overlay_dir,
     })
 }
 
+pub(crate) fn disk_size_bytes(vm_config: &VmConfig) -> u64 {
+    vm_config.disk_size_gb * 1_000_000_000
+}
+
 impl ApptainerMachine {
     pub async fn build(&self) -> Result<()> {
-        if !is_built(&self.chroot_dir).await? {
-            run_cmd(
-                "apptainer",
-                [
-                    OsStr::new("build"),
-                    OsStr::new("--force"),
-                    OsStr::new("--sandbox"),
-                    &self.chroot_dir.clone().into_os_string(),
-                    OsStr::new(&self.config.image_uri),
-                ],
-            )
-            .await
-            .map_err(|err| {
-                anyhow!(
-                    "failed to build '{}' from `{}`: {err:#}",
-                    self.vm_id,
-                    self.config.image_uri
-                )
-            })?;
-            fs::create_dir_all(
-                self.chroot_dir
-                    .join(DATA_DRIVE_MOUNT_POINT.trim_start_matches('/')),
-            )
-            .await?;
-            fs::create_dir_all(self.chroot_dir.join(JOURNAL_DIR.trim_start_matches('/'))).await?;
-            fs::create_dir_all(self.chroot_dir.join(BABEL_VAR_PATH)).await?;
+        if let Some(image_cache) = &self.image_cache {
+            self.mount_overlay(image_cache).await?;
+        } else if !is_built(&self.chroot_dir).await? {
+            build_rootfs(&self.chroot_dir, &self.config.image_uri, &self.vm_id).await?;
         }
-        if self.apptainer_config.cpu_limit || self.apptainer_config.memory_limit {
+        self.save_cgroups_config().await?;
+        node_env::save(&self.config.node_env, &self.chroot_dir).await?;
+        Ok(())
+    }
+
+    fn has_cgroups_limits(&self) -> bool {
+        self.apptainer_config.cpu_limit || self.apptainer_config.memory_limit
+    }
+
+    fn memory_limit(&self) -> u64 {
+        self.config.vm.mem_size_mb * 1_000_000
+    }
+
+    fn cpus_list(&self) -> String {
+        self.config
+            .cpus
+            .iter()
+            .map(|cpu| cpu.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Save cgroups config, applied to the container on start.
+    async fn save_cgroups_config(&self) -> Result<()> {
+        if self.has_cgroups_limits() {
             let mut content = String::new();
             if self.apptainer_config.memory_limit {
-                content += &format!(
-                    "memory.limit = {}\n",
-                    self.config.vm.mem_size_mb * 1_000_000,
-                )
+                content += &format!("memory.limit = {}\n", self.memory_limit())
             }
             if self.apptainer_config.cpu_limit && !self.config.cpus.is_empty() {
-                content += &format!(
-                    "cpu.cpus = \"{}\"\n",
-                    self.config
-                        .cpus
-                        .iter()
-                        .map(|cpu| cpu.to_string())
-                        .collect::<Vec<_>>()
-                        .join(","),
-                )
+                content += &format!("cpu.cpus = \"{}\"\n", self.cpus_list())
             }
             fs::write(&self.cgroups_path, content).await?;
         }
+        Ok(())
+    }
 
-        node_env::save(&self.config.node_env, &self.chroot_dir).await?;
+    fn bandwidth_limit(&self) -> BandwidthLimit {
+        self.config
+            .vm
+            .bandwidth
+            .or(self.apptainer_config.bandwidth_limit)
+    }
+
+    /// Host side of node veth pair, `None` if node is not running in its own network namespace.
+    async fn host_veth(&self) -> Result<Option<String>> {
+        if self.apptainer_config.host_network {
+            return Ok(None);
+        }
+        let Some(apptainer_pid) = self.apptainer_pid else {
+            return Ok(None);
+        };
+        Ok(Some(net_shaping::host_veth(apptainer_pid.as_u32()).await?))
+    }
+
+    async fn apply_bandwidth_limit(&self) -> Result<()> {
+        if let Some(veth) = self.host_veth().await? {
+            net_shaping::apply(&veth, &self.bandwidth_limit())
+                .await
+                .with_context(|| format!("failed to apply bandwidth limit on '{veth}'"))?;
+        }
+        Ok(())
+    }
+
+    /// Mount node rootfs as overlay, with cached image as read-only lower layer.
+    async fn mount_overlay(&self, image_cache: &ImageCache) -> Result<()> {
+        if is_mount_point(&self.chroot_dir).await {
+            return Ok(());
+        }
+        let lower_dir = image_cache
+            .acquire(
+                &self.config.image_uri,
+                &self.node_dir.join(image_cache::ROOTFS_IMAGE_FILE),
+            )
+            .await?;
+        let upper_dir = self.overlay_dir.join("upper");
+        let work_dir = self.overlay_dir.join("work");
+        fs::create_dir_all(&upper_dir).await?;
+        fs::create_dir_all(&work_dir).await?;
+        fs::create_dir_all(&self.chroot_dir).await?;
+        run_cmd(
+            "mount",
+            [
+                OsStr::new("-t"),
+                OsStr::new("overlay"),
+                OsStr::new("overlay"),
+                OsStr::new("-o"),
+                OsStr::new(&format!(
+                    "lowerdir={},upperdir={},workdir={}",
+                    lower_dir.display(),
+                    upper_dir.display(),
+                    work_dir.display()
+                )),
+                self.chroot_dir.as_os_str(),
+            ],
+        )
+        .await
+        .map_err(|err| anyhow!("failed to mount '{}' rootfs overlay: {err:#}", self.vm_id))?;
         Ok(())
     }
 
+    async fn unmount_overlay(&self) -> Result<()> {
+        if self.image_cache.is_some() && is_mount_point(&self.chroot_dir).await {
+            run_cmd("umount", [&self.chroot_dir]).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns image uri, if rootfs for it is already built in staging dir
+    /// (or in image cache).
+    async fn staged_image(&self) -> Option<String> {
+        if self.image_cache.is_none() {
+            return self.upgrade_dirs.staged_image().await;
+        }
+        fs::read_to_string(self.upgrade_dirs.staged_image_path())
+            .await
+            .ok()
+    }
+
     pub async fn attach(&mut self) -> Result<()> {
         self.build().await?;
         self.load_apptainer_pid().await?;
-        if self.is_container_running().await {
+        // babel in suspended node is restarted on resume, since it can't be stopped while frozen
+        if self.is_container_running().await && !self.is_frozen().await {
             self.stop_babel(false).await?;
             self.start_babel().await?;
         }
@@ -271,10 +407,7 @@ impl ApptainerMachine {
             let cgroups_path = self.cgroups_path.to_string_lossy();
             let apptainer_pid_path = self.apptainer_pid_path.to_string_lossy();
             let data_path = format!("{}:{}", self.data_dir.display(), DATA_DRIVE_MOUNT_POINT);
-            let net = format!(
-                "IP={}/{};GATEWAY={}",
-                self.ip, self.net_conf.mask_bits, self.net_conf.bridge
-            );
+            let net = self.network_args();
             let mut args = vec![
                 "instance",
                 "run",
@@ -332,6 +465,64 @@ impl ApptainerMachine {
                     self.vm_name
                 );
             }
+            // fresh veth has no limits, so there is nothing to clear
+            if self.bandwidth_limit() != BandwidthLimit::default() {
+                self.apply_bandwidth_limit().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// CNI args for apptainer bridge network, with both addresses if node is dual-stack.
+    fn network_args(&self) -> String {
+        let mut ips = format!("{}/{}", self.ip, self.net_conf.mask_bits);
+        let mut gateways = self.net_conf.bridge.to_string();
+        if let (Some(ipv6), Some(net_conf)) = (self.ipv6, &self.net_conf.ipv6) {
+            ips.push_str(&format!(",{ipv6}/{}", net_conf.mask_bits));
+            gateways.push_str(&format!(",{}", net_conf.bridge));
+        }
+        format!("IP={ips};GATEWAY={gateways}")
+    }
+
+    /// Returns cgroup directories of all node processes (apptainer instance and babel).
+    async fn cgroup_dirs(&self) -> Result<Vec<PathBuf>> {
+        let Some(apptainer_pid) = self.apptainer_pid else {
+            bail!("container for node {} is not running", self.vm_id);
+        };
+        let own_cgroup = process_cgroup_dir("self").await?;
+        let mut dirs = vec![process_cgroup_dir(&apptainer_pid.to_string()).await?];
+        if let Ok(babel_pid) = get_process_pid(BABEL_BIN_NAME, &self.chroot_dir.to_string_lossy()) {
+            let babel_cgroup = process_cgroup_dir(&babel_pid.to_string()).await?;
+            if !dirs.contains(&babel_cgroup) {
+                dirs.push(babel_cgroup);
+            }
+        }
+        if dirs.contains(&own_cgroup) {
+            // freezing it would freeze BV itself
+            bail!("node {} is not running in dedicated cgroup, enable `cpu_limit` or `memory_limit` in apptainer config", self.vm_id);
+        }
+        Ok(dirs)
+    }
+
+    async fn is_frozen(&self) -> bool {
+        let Some(apptainer_pid) = self.apptainer_pid else {
+            return false;
+        };
+        match process_cgroup_dir(&apptainer_pid.to_string()).await {
+            Ok(dir) => fs::read_to_string(dir.join("cgroup.events"))
+                .await
+                .map(|events| events.lines().any(|line| line.trim() == "frozen 1"))
+                .unwrap_or(false),
+            Err(_) => false,
+        }
+    }
+
+    async fn freeze(&self, freeze: bool) -> Result<()> {
+        for dir in self.cgroup_dirs().await? {
+            let path = dir.join("cgroup.freeze");
+            fs::write(&path, if freeze { "1" } else { "0" })
+                .await
+                .with_context(|| format!("failed to write '{}'", path.display()))?;
         }
         Ok(())
     }
@@ -404,6 +595,59 @@ impl ApptainerMachine {
     }
 }
 
+/// Returns cgroup v2 directory of process with given pid (or `self`).
+async fn process_cgroup_dir(pid: &str) -> Result<PathBuf> {
+    let path = format!("/proc/{pid}/cgroup");
+    fs::read_to_string(&path)
+        .await
+        .with_context(|| format!("failed to read '{path}'"))?
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|cgroup| Path::new(CGROUP_ROOT).join(cgroup.trim().trim_start_matches('/')))
+        .ok_or_else(|| anyhow!("process {pid} is not in cgroup v2 hierarchy"))
+}
+
+/// Returns the deepest cgroup containing all given `dirs`, but not `own_cgroup`,
+/// so limits applied to it don't affect BV itself (or other nodes).
+fn common_cgroup_dir(dirs: &[PathBuf], own_cgroup: &Path) -> Result<PathBuf> {
+    let Some((first, rest)) = dirs.split_first() else {
+        bail!("no node cgroup found");
+    };
+    let common = first
+        .ancestors()
+        .find(|ancestor| rest.iter().all(|dir| dir.starts_with(ancestor)))
+        .unwrap_or(Path::new(CGROUP_ROOT));
+    if own_cgroup.starts_with(common)
+        || !common.starts_with(CGROUP_ROOT)
+        || common == Path::new(CGROUP_ROOT)
+    {
+        bail!(
+            "node processes don't share dedicated cgroup (common parent is '{}')",
+            common.display()
+        );
+    }
+    Ok(common.to_path_buf())
+}
+
+pub(crate) async fn build_rootfs(rootfs_dir: &Path, image_uri: &str, vm_id: &str) -> Result<()> {
+    run_cmd(
+        "apptainer",
+        [
+            OsStr::new("build"),
+            OsStr::new("--force"),
+            OsStr::new("--sandbox"),
+            rootfs_dir.as_os_str(),
+            OsStr::new(image_uri),
+        ],
+    )
+    .await
+    .map_err(|err| anyhow!("failed to build '{vm_id}' from `{image_uri}`: {err:#}"))?;
+    fs::create_dir_all(rootfs_dir.join(DATA_DRIVE_MOUNT_POINT.trim_start_matches('/'))).await?;
+    fs::create_dir_all(rootfs_dir.join(JOURNAL_DIR.trim_start_matches('/'))).await?;
+    fs::create_dir_all(rootfs_dir.join(BABEL_VAR_PATH)).await?;
+    Ok(())
+}
+
 async fn is_built(path: &Path) -> Result<bool> {
     if path.exists() {
         let labels_out: serde_json::Value = run_cmd(
@@ -435,7 +679,9 @@ async fn is_built(path: &Path) -> Result<bool> {
 impl pal::VirtualMachine for ApptainerMachine {
     async fn state(&self) -> pal::VmState {
         if self.is_container_running().await {
-            if self.is_babel_running() {
+            if self.is_frozen().await {
+                pal::VmState::SUSPENDED
+            } else if self.is_babel_running() {
                 pal::VmState::RUNNING
             } else {
                 pal::VmState::INVALID
@@ -449,19 +695,32 @@ impl pal::VirtualMachine for ApptainerMachine {
         if self.shutdown().await.is_err() {
             self.force_shutdown().await?;
         }
+        self.disk_quota.release().await?;
+        self.unmount_overlay().await?;
         if self.node_dir.exists() {
             fs::remove_dir_all(&self.node_dir).await?;
         }
+        if let Some(image_cache) = &self.image_cache {
+            image_cache.gc().await;
+        }
         Ok(())
     }
 
     async fn shutdown(&mut self) -> Result<()> {
+        if self.is_frozen().await {
+            // frozen processes can't handle termination signals
+            self.freeze(false).await?;
+        }
         self.stop_babel(false).await?;
         self.stop_container().await?;
         Ok(())
     }
 
     async fn force_shutdown(&mut self) -> Result<()> {
+        if self.is_frozen().await {
+            // frozen processes can't handle termination signals
+            self.freeze(false).await?;
+        }
         self.stop_babel(true).await?;
         self.stop_container().await?;
         Ok(())
@@ -472,6 +731,89 @@ impl pal::VirtualMachine for ApptainerMachine {
         self.start_babel().await
     }
 
+    async fn suspend(&mut self) -> Result<()> {
+        self.freeze(true).await
+    }
+
+    async fn resume(&mut self) -> Result<()> {
+        if self.is_frozen().await {
+            self.freeze(false).await?;
+        }
+        // babel is not restarted on attach to frozen node (e.g. after BV restart),
+        // so old babel is still running, or it is not known at all
+        if self.is_container_running().await && !self.is_babel_running() {
+            self.stop_babel(false).await?;
+            self.start_babel().await?;
+        }
+        Ok(())
+    }
+
+    async fn resize(&mut self, node_state: &NodeState) -> Result<()> {
+        let bandwidth_changed = self.config.vm.bandwidth != node_state.vm_config.bandwidth;
+        self.config.vm = node_state.vm_config.clone();
+        self.config.cpus = node_state.assigned_cpus.clone();
+        self.save_cgroups_config().await?;
+        if bandwidth_changed && self.is_container_running().await {
+            self.apply_bandwidth_limit().await?;
+        }
+        if !self.has_cgroups_limits() || !self.is_container_running().await {
+            return Ok(());
+        }
+        let mut limits = vec![];
+        if self.apptainer_config.memory_limit {
+            limits.push(("memory.max", self.memory_limit().to_string()));
+        }
+        if self.apptainer_config.cpu_limit && !self.config.cpus.is_empty() {
+            limits.push(("cpuset.cpus", self.cpus_list()));
+        }
+        // babel may run in separate cgroup, but limits are for the whole node, so apply them once
+        // on common parent (applying to each cgroup would multiply the limit)
+        let cgroup_dir = common_cgroup_dir(
+            &self.cgroup_dirs().await?,
+            &process_cgroup_dir("self").await?,
+        )?;
+        for (file, value) in &limits {
+            let path = cgroup_dir.join(file);
+            fs::write(&path, value)
+                .await
+                .with_context(|| format!("failed to write '{value}' to '{}'", path.display()))?;
+        }
+        Ok(())
+    }
+
+    async fn stage_upgrade(&mut self, node_state: &NodeState) -> Result<()> {
+        let uri = &node_state.image.uri;
+        if self.image_cache.is_none() {
+            return self
+                .upgrade_dirs
+                .stage(
+                    uri,
+                    build_rootfs(self.upgrade_dirs.staging_dir(), uri, &self.vm_id),
+                )
+                .await;
+        }
+        if self.staged_image().await.as_ref() == Some(uri) {
+            // already staged by previous, not finished upgrade attempt
+            return Ok(());
+        }
+        self.drop_staged().await?;
+        if let Some(image_cache) = &self.image_cache {
+            image_cache
+                .acquire(uri, self.upgrade_dirs.staged_image_path())
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn drop_staged(&mut self) -> Result<()> {
+        let staged = self.upgrade_dirs.staged_image_path().exists();
+        self.upgrade_dirs.drop_staged().await?;
+        if let Some(image_cache) = self.image_cache.as_ref().filter(|_| staged) {
+            image_cache.gc().await;
+        }
+        Ok(())
+    }
+
     async fn upgrade(&mut self, node_state: &NodeState) -> Result<()> {
         if self.is_container_running().await {
             bail!("can't upgrade running vm")
@@ -482,32 +824,96 @@ impl pal::VirtualMachine for ApptainerMachine {
         self.config.vm = node_state.vm_config.clone();
         self.config.cpus = node_state.assigned_cpus.clone();
         self.update_node_env(node_state);
+        if self
+            .config_backup
+            .as_ref()
+            .is_some_and(|backup| backup.vm.disk_size_gb != self.config.vm.disk_size_gb)
+        {
+            self.disk_quota
+                .apply(disk_size_bytes(&node_state.vm_config))
+                .await?;
+            self.config.node_env.data_quota_bytes = self.disk_quota.limit();
+        }
 
-        fs::rename(&self.chroot_dir, &self.backup_chroot_dir).await?;
-        fs::create_dir_all(&self.chroot_dir).await?;
+        if self.image_cache.is_some() {
+            self.unmount_overlay().await?;
+            fs::rename(&self.overlay_dir, &self.backup_overlay_dir).await?;
+            // keep old image referenced, so it is not garbage collected until backup is dropped
+            fs::rename(
+                self.node_dir.join(image_cache::ROOTFS_IMAGE_FILE),
+                self.node_dir.join(image_cache::BACKUP_ROOTFS_IMAGE_FILE),
+            )
+            .await?;
+            // new image is either already staged in cache, or built on mount
+            self.build().await?;
+            let staged_image_path = self.upgrade_dirs.staged_image_path();
+            if staged_image_path.exists() {
+                fs::remove_file(staged_image_path).await?;
+            }
+            return Ok(());
+        }
+        // new rootfs may be already built in background, otherwise it is built from scratch
+        if !self
+            .upgrade_dirs
+            .swap_in_staged(&self.config.image_uri)
+            .await?
+        {
+            fs::create_dir_all(&self.chroot_dir).await?;
+        }
 
         self.build().await
     }
 
     async fn drop_backup(&mut self) -> Result<()> {
-        if self.backup_chroot_dir.exists() {
-            fs::remove_dir_all(&self.backup_chroot_dir).await?
+        self.upgrade_dirs.drop_backup().await?;
+        if let Some(image_cache) = &self.image_cache {
+            if self.backup_overlay_dir.exists() {
+                fs::remove_dir_all(&self.backup_overlay_dir).await?
+            }
+            let backup_image_path = self.node_dir.join(image_cache::BACKUP_ROOTFS_IMAGE_FILE);
+            if backup_image_path.exists() {
+                fs::remove_file(&backup_image_path).await?;
+                image_cache.gc().await;
+            }
         }
         self.config_backup = None;
         Ok(())
     }
 
     async fn rollback(&mut self) -> Result<()> {
-        if self.backup_chroot_dir.exists() {
-            fs::remove_dir_all(&self.chroot_dir).await?;
-            fs::rename(&self.backup_chroot_dir, &self.chroot_dir).await?;
+        self.upgrade_dirs.restore_backup().await?;
+        if self.image_cache.is_some() && self.backup_overlay_dir.exists() {
+            self.unmount_overlay().await?;
+            if self.overlay_dir.exists() {
+                fs::remove_dir_all(&self.overlay_dir).await?;
+            }
+            fs::rename(&self.backup_overlay_dir, &self.overlay_dir).await?;
+            fs::rename(
+                self.node_dir.join(image_cache::BACKUP_ROOTFS_IMAGE_FILE),
+                self.node_dir.join(image_cache::ROOTFS_IMAGE_FILE),
+            )
+            .await?;
         }
 
         if let Some(mut backup) = self.config_backup.take() {
             mem::swap(&mut backup, &mut self.config);
             self.config_backup = Some(backup);
         }
+        if self
+            .config_backup
+            .as_ref()
+            .is_some_and(|upgraded| upgraded.vm.disk_size_gb != self.config.vm.disk_size_gb)
+        {
+            // quota is applied on the host, so restoring config is not enough
+            self.disk_quota
+                .apply(disk_size_bytes(&self.config.vm))
+                .await?;
+            self.config.node_env.data_quota_bytes = self.disk_quota.limit();
+        }
         self.build().await?;
+        if let Some(image_cache) = &self.image_cache {
+            image_cache.gc().await;
+        }
 
         Ok(())
     }
@@ -547,4 +953,50 @@ impl pal::VirtualMachine for ApptainerMachine {
     fn data_dir(&self) -> PathBuf {
         self.node_dir.join(DATA_DIR)
     }
+
+    async fn disk_usage(&self) -> Result<Option<DiskUsage>> {
+        self.disk_quota.usage().await
+    }
+
+    async fn traffic_usage(&self) -> Result<Option<TrafficUsage>> {
+        if !self.is_container_running().await {
+            return Ok(None);
+        }
+        match self.host_veth().await? {
+            Some(veth) => Ok(Some(net_shaping::usage(&veth).await?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_common_cgroup_dir() {
+        let own = Path::new("/sys/fs/cgroup/system.slice/blockvisor.service");
+        let instance = PathBuf::from("/sys/fs/cgroup/system.slice/apptainer-123.scope");
+        assert_eq!(
+            instance,
+            common_cgroup_dir(&[instance.clone()], own).unwrap()
+        );
+        assert_eq!(
+            instance,
+            common_cgroup_dir(&[instance.clone(), instance.join("babel")], own).unwrap()
+        );
+        assert_eq!(
+            "node processes don't share dedicated cgroup (common parent is '/sys/fs/cgroup/system.slice')",
+            common_cgroup_dir(
+                &[
+                    instance,
+                    PathBuf::from("/sys/fs/cgroup/system.slice/apptainer-124.scope")
+                ],
+                own
+            )
+            .unwrap_err()
+            .to_string()
+        );
+        assert!(common_cgroup_dir(&[], own).is_err());
+    }
 }
@@ -1,19 +1,20 @@
-use crate::apptainer_machine::NetConf;
+use crate::apptainer_machine::{Ipv6NetConf, NetConf};
 use crate::{
     apptainer_machine, bv_config,
     bv_config::{ApptainerConfig, SharedConfig},
     bv_context::BvContext,
-    linux_platform,
+    cpu_registry::CpuTopology,
+    firewall, ip_leases, linux_platform,
     node::NODE_REQUEST_TIMEOUT,
     node_context,
     node_state::NodeState,
     nodes_manager::NodesDataCache,
     pal::{self, AvailableResources, NodeConnection, NodeFirewallConfig, Pal, VirtualMachine},
-    services, ufw_wrapper,
+    services,
 };
 use async_trait::async_trait;
 use bv_utils::with_retry;
-use eyre::{bail, Context, Result};
+use eyre::{bail, Result};
 use serde::{Deserialize, Serialize};
 use std::{
     fmt::Debug,
@@ -32,6 +33,7 @@ pub struct ApptainerPlatform {
     base: linux_platform::LinuxPlatform,
     net_conf: NetConf,
     config: ApptainerConfig,
+    firewall_backend: firewall::Backend,
 }
 
 impl Deref for ApptainerPlatform {
@@ -56,8 +58,10 @@ impl ApptainerPlatform {
                 gateway: IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)),
                 bridge: IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)),
                 mask_bits: 0,
+                ipv6: None,
             },
             config: Default::default(),
+            firewall_backend: Default::default(),
         })
     }
 
@@ -68,8 +72,13 @@ impl ApptainerPlatform {
                 gateway: config.net_conf.gateway_ip,
                 bridge: config.net_conf.host_ip,
                 mask_bits: config.net_conf.prefix,
+                ipv6: config.net_conf.ipv6.as_ref().map(|ipv6| Ipv6NetConf {
+                    mask_bits: ipv6.prefix,
+                    bridge: ipv6.host_ip,
+                }),
             },
             config: config.apptainer.clone(),
+            firewall_backend: config.firewall_backend,
         })
     }
 
@@ -77,6 +86,7 @@ impl ApptainerPlatform {
         &self,
         bv_context: &BvContext,
         node_state: &NodeState,
+        create: bool,
     ) -> Result<apptainer_machine::ApptainerMachine> {
         apptainer_machine::new(
             &self.bv_root,
@@ -88,6 +98,7 @@ impl ApptainerPlatform {
                 .apptainer_config
                 .clone()
                 .unwrap_or(self.config.clone()),
+            create,
         )
         .await
     }
@@ -137,7 +148,7 @@ impl Pal for ApptainerPlatform {
         bv_context: &BvContext,
         node_state: &NodeState,
     ) -> Result<Self::VirtualMachine> {
-        let mut vm = self.new_vm(bv_context, node_state).await?;
+        let mut vm = self.new_vm(bv_context, node_state, true).await?;
         if let Err(err) = vm.build().await {
             vm.delete().await?;
             Err(err)
@@ -151,7 +162,7 @@ impl Pal for ApptainerPlatform {
         bv_context: &BvContext,
         node_state: &NodeState,
     ) -> Result<Self::VirtualMachine> {
-        let mut vm = self.new_vm(bv_context, node_state).await?;
+        let mut vm = self.new_vm(bv_context, node_state, false).await?;
         vm.attach().await?;
         Ok(vm)
     }
@@ -160,6 +171,10 @@ impl Pal for ApptainerPlatform {
         linux_platform::available_cpus()
     }
 
+    async fn cpu_topology(&self) -> CpuTopology {
+        linux_platform::cpu_topology()
+    }
+
     async fn available_resources(
         &self,
         nodes_data_cache: NodesDataCache,
@@ -173,25 +188,7 @@ impl Pal for ApptainerPlatform {
     }
 
     async fn used_disk_space_correction(&self, nodes_data_cache: NodesDataCache) -> Result<u64> {
-        let bv_root = self.bv_root().to_path_buf();
-        tokio::task::spawn_blocking(move || {
-            let mut correction = 0;
-            for (id, data) in nodes_data_cache {
-                let data_img_path =
-                    node_context::build_node_dir(&bv_root, id).join(apptainer_machine::DATA_DIR);
-                let actual_data_size =
-                    fs_extra::dir::get_size(&data_img_path).with_context(|| {
-                        format!("can't check size of '{}'", data_img_path.display())
-                    })?;
-                let declared_data_size = data.vm_config.disk_size_gb * 1_000_000_000;
-                debug!("id: {id}; declared: {declared_data_size}; actual: {actual_data_size}");
-                if declared_data_size > actual_data_size {
-                    correction += declared_data_size - actual_data_size;
-                }
-            }
-            Ok(correction)
-        })
-        .await?
+        linux_platform::used_disk_space_correction(self.bv_root(), nodes_data_cache).await
     }
 
     type RecoveryBackoff = linux_platform::RecoveryBackoff;
@@ -200,11 +197,22 @@ impl Pal for ApptainerPlatform {
     }
 
     async fn apply_firewall_config(&self, config: NodeFirewallConfig) -> Result<()> {
-        ufw_wrapper::apply_firewall_config(config).await
+        self.firewall_backend.apply(config).await
     }
 
     async fn cleanup_firewall_config(&self, id: Uuid) -> Result<()> {
-        ufw_wrapper::cleanup_node_rules(id).await
+        self.firewall_backend.cleanup(id).await
+    }
+
+    async fn firewall_status(
+        &self,
+        config: NodeFirewallConfig,
+    ) -> Result<Option<firewall::Status>> {
+        Ok(Some(self.firewall_backend.status(config).await?))
+    }
+
+    async fn probe_ip(&self, iface: &str, ip: IpAddr) -> Result<bool> {
+        ip_leases::probe(iface, ip).await
     }
 }
 
@@ -222,7 +230,7 @@ fn default_bridge_ifa() -> String {
 }
 
 #[derive(Debug)]
-enum NodeConnectionState {
+pub(crate) enum NodeConnectionState {
     Closed,
     Broken,
     Babel(pal::BabelClient),
@@ -11,6 +11,7 @@
 use crate::{
     babel_engine_service::{self, BabelEngineServer},
     bv_config::SharedConfig,
+    kv_store::KvStore,
     node::NODE_REQUEST_TIMEOUT,
     node_context::NodeContext,
     node_state::{NodeImage, NodeProperties},
@@ -24,7 +25,7 @@ use babel_api::{
         HttpResponse, JobConfig, JobInfo, JobType, JobsInfo, JrpcRequest, NodeEnv, RestRequest,
         ShResponse,
     },
-    plugin::{Plugin, ProtocolStatus},
+    plugin::{CustomMetrics, Plugin, ProtocolStatus},
     plugin_config::PluginConfig,
     utils::Binary,
 };
@@ -35,7 +36,7 @@ use bv_utils::{
 };
 use eyre::{bail, Error, Result, WrapErr};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fmt::Debug,
     fs,
     path::{Path, PathBuf},
@@ -73,6 +74,7 @@ pub struct BabelEngine<N, P> {
     api_config: SharedConfig,
     plugin: P,
     node_context: NodeContext,
+    kv_store: KvStore,
     engine_rx: mpsc::Receiver<EngineRequest>,
     engine_tx: mpsc::Sender<EngineRequest>,
     server: Option<BabelEngineServer>,
@@ -92,12 +94,14 @@ impl<N: NodeConnection, P: Plugin + Clone + Send + 'static> BabelEngine<N, P> {
         scheduler_tx: mpsc::Sender<scheduler::Action>,
     ) -> Result<Self> {
         let (engine_tx, engine_rx) = mpsc::channel(64);
+        let kv_store = KvStore::new(node_context.plugin_kv.clone());
         let engine = Engine {
             node_id: node_info.node_id,
             tx: engine_tx.clone(),
             params: node_info.properties.clone(),
             node_env: node_env.clone(),
             node_context: node_context.clone(),
+            kv_store: kv_store.clone(),
         };
         let plugin = plugin_builder(engine)?;
         let mut babel_engine = Self {
@@ -106,6 +110,7 @@ impl<N: NodeConnection, P: Plugin + Clone + Send + 'static> BabelEngine<N, P> {
             api_config,
             plugin,
             node_context,
+            kv_store,
             engine_rx,
             engine_tx,
             server: None,
@@ -150,6 +155,7 @@ impl<N: NodeConnection, P: Plugin + Clone + Send + 'static> BabelEngine<N, P> {
             params: self.node_info.properties.clone(),
             node_env,
             node_context: self.node_context.clone(),
+            kv_store: self.kv_store.clone(),
         };
         self.plugin = plugin_builder(engine)?;
         self.capabilities = self
@@ -207,6 +213,11 @@ impl<N: NodeConnection, P: Plugin + Clone + Send + 'static> BabelEngine<N, P> {
         self.on_plugin(|plugin| plugin.protocol_status()).await
     }
 
+    /// Returns custom, protocol specific metrics.
+    pub async fn metrics(&mut self) -> Result<CustomMetrics> {
+        self.on_plugin(|plugin| plugin.metrics()).await
+    }
+
     pub async fn upload(&mut self) -> Result<()> {
         self.on_plugin(|plugin| plugin.upload()).await
     }
@@ -235,6 +246,7 @@ impl<N: NodeConnection, P: Plugin + Clone + Send + 'static> BabelEngine<N, P> {
             "address" => self.address().await?,
             "consensus" => self.consensus().await?.to_string(),
             "protocol_status" => serde_json::to_string(&self.protocol_status().await?)?,
+            "metrics" => serde_json::to_string(&self.metrics().await?)?,
             "upload" => serde_json::to_string(&self.upload().await?)?,
             _ => {
                 let method_name = name.to_owned();
@@ -245,6 +257,40 @@ impl<N: NodeConnection, P: Plugin + Clone + Send + 'static> BabelEngine<N, P> {
         })
     }
 
+    /// Hook called before node is stopped.
+    pub async fn pre_stop(&mut self) -> Result<()> {
+        self.on_plugin(|plugin| plugin.pre_stop()).await
+    }
+
+    /// Hook called before node is upgraded.
+    pub async fn pre_upgrade(&mut self) -> Result<()> {
+        self.on_plugin(|plugin| plugin.pre_upgrade()).await
+    }
+
+    /// Hook called after node is upgraded and started.
+    pub async fn post_upgrade(&mut self) -> Result<()> {
+        self.on_plugin(|plugin| plugin.post_upgrade()).await
+    }
+
+    /// Hook called when properties of running node are changed.
+    pub async fn on_properties_changed(&mut self, changed: NodeProperties) -> Result<()> {
+        self.on_plugin(move |plugin| plugin.on_properties_changed(&changed))
+            .await
+    }
+
+    /// Hook called before node is deleted.
+    pub async fn pre_delete(&mut self) -> Result<()> {
+        self.on_plugin(|plugin| plugin.pre_delete()).await
+    }
+
+    /// Evaluate arbitrary script in the plugin context.
+    #[instrument(skip(self), fields(id = % self.node_info.node_id), err, ret(Debug))]
+    pub async fn evaluate(&mut self, script: &str, read_only: bool) -> Result<String> {
+        let script = script.to_owned();
+        self.on_plugin(move |plugin| plugin.evaluate(&script, read_only))
+            .await
+    }
+
     /// Returns the methods that are supported by this protocol. Calling any method on this
     /// protocol that is not listed here will result in an error being returned.
     pub fn capabilities(&self) -> &Vec<String> {
@@ -639,6 +685,7 @@ pub struct Engine {
     params: NodeProperties,
     node_env: NodeEnv,
     node_context: NodeContext,
+    kv_store: KvStore,
 }
 
 type ResponseTx<T> = tokio::sync::oneshot::Sender<T>;
@@ -856,6 +903,27 @@ impl babel_api::engine::Engine for Engine {
         Ok(fs::read_to_string(&self.node_context.plugin_data)?)
     }
 
+    fn kv_get(&self, key: &str) -> Result<Option<String>> {
+        self.kv_store.get(key)
+    }
+
+    fn kv_set(&self, key: &str, value: &str) -> Result<()> {
+        self.kv_store.set(key, value)
+    }
+
+    fn kv_delete(&self, key: &str) -> Result<bool> {
+        self.kv_store.delete(key)
+    }
+
+    fn kv_list(&self, prefix: &str) -> Result<BTreeMap<String, String>> {
+        self.kv_store.list(prefix)
+    }
+
+    fn kv_compare_and_swap(&self, key: &str, expected: Option<String>, new: &str) -> Result<bool> {
+        self.kv_store
+            .compare_and_swap(key, expected.as_deref(), new)
+    }
+
     /// Save plugin config to persistent storage.
     fn save_config(&self, value: &PluginConfig) -> Result<()> {
         Ok(fs::write(
@@ -1154,6 +1222,33 @@ mod tests {
                 health: NodeHealth::Neutral,
             })
         }
+        fn metrics(&self) -> Result<CustomMetrics> {
+            self.engine.run_sh("metrics", None)?;
+            Ok(Default::default())
+        }
+        fn pre_stop(&self) -> Result<()> {
+            self.engine.run_sh("pre_stop", None)?;
+            Ok(())
+        }
+        fn pre_upgrade(&self) -> Result<()> {
+            self.engine.run_sh("pre_upgrade", None)?;
+            Ok(())
+        }
+        fn post_upgrade(&self) -> Result<()> {
+            self.engine.run_sh("post_upgrade", None)?;
+            Ok(())
+        }
+        fn on_properties_changed(&self, _changed: &HashMap<String, String>) -> Result<()> {
+            self.engine.run_sh("on_properties_changed", None)?;
+            Ok(())
+        }
+        fn pre_delete(&self) -> Result<()> {
+            self.engine.run_sh("pre_delete", None)?;
+            Ok(())
+        }
+        fn evaluate(&self, script: &str, _read_only: bool) -> Result<String> {
+            Ok(self.engine.run_sh(script, None)?.stdout)
+        }
         fn call_custom_method(&self, name: &str, param: &str) -> Result<String> {
             self.engine.create_job(
                 name,
@@ -1267,6 +1362,8 @@ mod tests {
             let node_context = NodeContext {
                 plugin_data: tmp_root.join("data"),
                 plugin_config: tmp_root.join("config"),
+                plugin_kv: tmp_root.join("kv"),
+                snapshots_dir: Default::default(),
                 nodes_dir: Default::default(),
                 node_dir: Default::default(),
             };
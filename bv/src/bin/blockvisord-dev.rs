@@ -1,7 +1,8 @@
 use blockvisord::linux_platform::bv_root;
 use blockvisord::{
-    bv_config, bv_config::SharedConfig, internal_server, nodes_manager::NodesManager, pal::Pal,
-    set_bv_status, ServiceStatus,
+    apptainer_platform::ApptainerPlatform, bv_config, bv_config::Runtime, bv_config::SharedConfig,
+    firecracker_platform::FirecrackerPlatform, internal_server, nodes_manager::NodesManager,
+    nspawn_platform::NspawnPlatform, pal::Pal, set_bv_status, ServiceStatus,
 };
 use bv_utils::{logging::setup_logging, run_flag::RunFlag};
 use eyre::Result;
@@ -21,8 +22,20 @@ async fn main() -> Result<()> {
     set_bv_status(ServiceStatus::Ok).await;
 
     let config = bv_config::Config::load(&bv_root()).await?;
-    let pal = blockvisord::apptainer_platform::ApptainerPlatform::new(&config).await?;
-    run_server(config, pal).await?;
+    match config.runtime {
+        Runtime::Apptainer => {
+            let pal = ApptainerPlatform::new(&config).await?;
+            run_server(config, pal).await?;
+        }
+        Runtime::Nspawn => {
+            let pal = NspawnPlatform::new(&config).await?;
+            run_server(config, pal).await?;
+        }
+        Runtime::Firecracker => {
+            let pal = FirecrackerPlatform::new(&config).await?;
+            run_server(config, pal).await?;
+        }
+    }
     Ok(())
 }
 
@@ -1,5 +1,8 @@
 use blockvisord::linux_platform::bv_root;
-use blockvisord::{blockvisord::BlockvisorD, bv_config};
+use blockvisord::{
+    apptainer_platform::ApptainerPlatform, blockvisord::BlockvisorD, bv_config, bv_config::Runtime,
+    firecracker_platform::FirecrackerPlatform, nspawn_platform::NspawnPlatform,
+};
 use bv_utils::{logging::setup_logging, run_flag::RunFlag};
 use eyre::Result;
 use tracing::info;
@@ -14,7 +17,19 @@ async fn main() -> Result<()> {
         env!("CARGO_PKG_VERSION")
     );
     let config = bv_config::Config::load(&bv_root()).await?;
-    let pal = blockvisord::apptainer_platform::ApptainerPlatform::new(&config).await?;
-    BlockvisorD::new(pal, config).await?.run(run).await?;
+    match config.runtime {
+        Runtime::Apptainer => {
+            let pal = ApptainerPlatform::new(&config).await?;
+            BlockvisorD::new(pal, config).await?.run(run).await?;
+        }
+        Runtime::Nspawn => {
+            let pal = NspawnPlatform::new(&config).await?;
+            BlockvisorD::new(pal, config).await?.run(run).await?;
+        }
+        Runtime::Firecracker => {
+            let pal = FirecrackerPlatform::new(&config).await?;
+            BlockvisorD::new(pal, config).await?.run(run).await?;
+        }
+    }
     Ok(())
 }
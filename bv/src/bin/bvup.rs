@@ -54,6 +54,23 @@ pub struct CmdArgs {
     #[clap(long = "available-ips")]
     pub available_ips: Option<String>,
 
+    /// Network IPv6 gateway address, enables dual-stack node networking.
+    #[clap(long = "ipv6-gateway-ip", requires_all = ["ipv6_host_ip", "ipv6_available_ips"])]
+    pub ipv6_gateway_ip: Option<String>,
+
+    /// Host IPv6 address.
+    #[clap(long = "ipv6-host-ip", requires_all = ["ipv6_gateway_ip", "ipv6_available_ips"])]
+    pub ipv6_host_ip: Option<String>,
+
+    /// Nodes IPv6 subnet prefix in bits.
+    #[clap(long = "ipv6-prefix", default_value = "64")]
+    pub ipv6_prefix: u8,
+
+    /// IPv6 addresses available to be used by nodes, in the same format as `--available-ips`.
+    /// Pool can't be bigger than /112 (65536 addresses).
+    #[clap(long = "ipv6-available-ips", requires_all = ["ipv6_gateway_ip", "ipv6_host_ip"])]
+    pub ipv6_available_ips: Option<String>,
+
     /// Blockvisor service port.
     #[clap(long = "port")]
     pub blockvisor_port: Option<u16>,
@@ -169,6 +186,22 @@ async fn main() -> Result<()> {
             net_conf.override_ips(&value)?;
         }
 
+        if let (Some(gateway_ip), Some(host_ip), Some(available_ips)) = (
+            &cmd_args.ipv6_gateway_ip,
+            &cmd_args.ipv6_host_ip,
+            &cmd_args.ipv6_available_ips,
+        ) {
+            if cmd_args.use_host_network {
+                bail!("IPv6 node networking can't be used with host network");
+            }
+            net_conf.ipv6 = Some(bv_config::Ipv6NetConf::new(
+                gateway_ip,
+                host_ip,
+                cmd_args.ipv6_prefix,
+                available_ips,
+            )?);
+        }
+
         let host_info = HostInfo::collect()?;
         let cpu_count = host_info
             .cpu_count
@@ -196,6 +229,15 @@ async fn main() -> Result<()> {
                     .join(",")
             )
         );
+        if let Some(ipv6) = &net_conf.ipv6 {
+            println!("IPv6 gateway address:{:>16}", ipv6.gateway_ip);
+            println!("IPv6 host address:   {:>16}", ipv6.host_ip);
+            println!("IPv6 subnet prefix   {:>16}", ipv6.prefix);
+            println!(
+                "Available node IPv6s: {} addresses",
+                ipv6.available_ips.len()
+            );
+        }
         println!("CPU count:           {:>16}", cpu_count);
         println!(
             "Total mem:           {:>16.3} GB",
@@ -213,6 +255,9 @@ async fn main() -> Result<()> {
 
         if !cmd_args.use_host_network {
             run_cmd("sysctl", ["-w", "net.ipv4.ip_forward=1"]).await?;
+            if net_conf.ipv6.is_some() {
+                run_cmd("sysctl", ["-w", "net.ipv6.conf.all.forwarding=1"]).await?;
+            }
             const APPTAINER_NET_CONFIG_DIR: &str = "etc/apptainer/network";
             const USR_LOCAL: &str = "usr/local";
             let apptainer_net_dir = if bv_root.join(APPTAINER_NET_CONFIG_DIR).exists() {
@@ -232,6 +277,14 @@ async fn main() -> Result<()> {
                 &[
                     ("bridge_ifa", &bridge_ifa),
                     ("host_ip", &net_conf.host_ip.to_string()),
+                    (
+                        "host_ipv6",
+                        &net_conf
+                            .ipv6
+                            .as_ref()
+                            .map(|ipv6| ipv6.host_ip.to_string())
+                            .unwrap_or_default(),
+                    ),
                 ],
             )?;
         }
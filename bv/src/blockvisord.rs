@@ -7,7 +7,7 @@ use crate::{
     node_state::VmStatus,
     nodes_manager::{MaybeNode, NodesManager},
     pal::{CommandsStream, Pal, ServiceConnector},
-    self_updater,
+    rpc_proxy, self_updater,
     services::{
         self,
         api::{self, common, pb, MetricsServiceClient},
@@ -20,11 +20,14 @@ use crate::{
 use bv_utils::run_flag::RunFlag;
 use eyre::{Context, Result};
 use metrics::{counter, Counter};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+use metrics_util::MetricKindMask;
 use std::{
     collections::{hash_map::DefaultHasher, HashMap},
     fmt::Debug,
     hash::{Hash, Hasher},
-    net::SocketAddr,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
     sync::Arc,
     time::Instant,
 };
@@ -39,14 +42,26 @@ use tracing::{debug, error, info, warn};
 
 const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
 const RECOVERY_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+const FIREWALL_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 const INFO_UPDATE_INTERVAL: Duration = Duration::from_secs(30);
 const CLUSTER_UPDATES_INTERVAL: Duration = Duration::from_secs(30);
+/// Protocol state reported to API for suspended nodes.
+const SUSPENDED_PROTOCOL_STATE: &str = "suspended";
+/// Gauges not updated for that long are dropped from metrics endpoint.
+const METRICS_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// Commands processing time ranges from milliseconds (start/stop) to many minutes (create/upgrade
+/// with image build).
+const COMMAND_DURATION_BUCKETS: &[f64] = &[
+    0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0,
+];
 
 lazy_static::lazy_static! {
     pub static ref BV_HOST_METRICS_COUNTER: Counter = counter!("bv.periodic.host.metrics.calls");
     pub static ref BV_HOST_METRICS_TIME_MS_COUNTER: Counter = counter!("bv.periodic.host.metrics.ms");
     pub static ref BV_NODES_RECOVERY_COUNTER: Counter = counter!("bv.periodic.nodes.recovery.calls");
     pub static ref BV_NODES_RECOVERY_TIME_MS_COUNTER: Counter = counter!("bv.periodic.nodes.recovery.ms");
+    pub static ref BV_NODES_FIREWALL_COUNTER: Counter = counter!("bv.periodic.nodes.firewall.calls");
+    pub static ref BV_NODES_FIREWALL_TIME_MS_COUNTER: Counter = counter!("bv.periodic.nodes.firewall.ms");
     pub static ref BV_NODES_METRICS_COUNTER: Counter = counter!("bv.periodic.nodes.metrics.calls");
     pub static ref BV_NODES_METRICS_TIME_MS_COUNTER: Counter = counter!("bv.periodic.nodes.metrics.ms");
     pub static ref BV_NODES_INFO_COUNTER: Counter = counter!("bv.periodic.nodes.info.calls");
@@ -103,6 +118,13 @@ where
         }
         let self_updater_future = self_updater.run(run.clone());
 
+        if let Some(port) = config.metrics_port {
+            let ip = config
+                .metrics_address
+                .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+            Self::install_metrics_exporter(SocketAddr::new(ip, port))?;
+        }
+
         let cmds_connector = self.pal.create_commands_stream_connector(&self.config);
         let nodes_manager = NodesManager::load(self.pal, self.config.clone()).await?;
         let nodes_manager = Arc::new(nodes_manager);
@@ -131,6 +153,9 @@ where
             Self::cluster_updates(run.clone(), nodes_manager.clone(), self.cluster.clone());
 
         let nodes_recovery_future = Self::nodes_recovery(run.clone(), nodes_manager.clone());
+        let nodes_firewall_future = Self::nodes_firewall(run.clone(), nodes_manager.clone());
+        let upgrade_verifications_future =
+            Self::upgrade_verifications(run.clone(), nodes_manager.clone());
 
         let node_updates_future =
             Self::node_updates(run.clone(), nodes_manager.clone(), self.config.clone());
@@ -139,6 +164,12 @@ where
             Self::node_metrics(run.clone(), nodes_manager.clone(), self.config.clone());
         let host_metrics_future =
             Self::host_metrics(run.clone(), nodes_manager.clone(), self.config.clone());
+        let rpc_proxy_future = Self::rpc_proxy(
+            run.clone(),
+            bv_root.clone(),
+            config.rpc_proxy.clone(),
+            nodes_manager.rpc_routes(),
+        );
 
         // send up-to-date information about host software
         if let Err(e) = hosts::send_info_update(self.config.clone()).await {
@@ -150,9 +181,12 @@ where
             mqtt_notification_future,
             cluster_updates_future,
             nodes_recovery_future,
+            nodes_firewall_future,
+            upgrade_verifications_future,
             node_updates_future,
             node_metrics_future,
             host_metrics_future,
+            rpc_proxy_future,
             self_updater_future
         );
         info!("Stopping...");
@@ -161,6 +195,37 @@ where
         Ok(())
     }
 
+    /// Install Prometheus recorder, so all metrics recorded via `metrics` facade
+    /// are exposed by HTTP `/metrics` endpoint.
+    fn install_metrics_exporter(addr: SocketAddr) -> Result<()> {
+        PrometheusBuilder::new()
+            .with_http_listener(addr)
+            // per node gauges are not updated anymore once node is stopped or deleted,
+            // while all other gauges are updated periodically
+            .idle_timeout(MetricKindMask::GAUGE, Some(METRICS_IDLE_TIMEOUT))
+            .set_buckets_for_metric(
+                Matcher::Full(services::api::COMMAND_DURATION_HISTOGRAM.to_string()),
+                COMMAND_DURATION_BUCKETS,
+            )?
+            .install()
+            .with_context(|| format!("failed to start metrics exporter on {addr}"))?;
+        info!("Metrics exporter listening on {addr}");
+        Ok(())
+    }
+
+    async fn rpc_proxy(
+        run: RunFlag,
+        bv_root: PathBuf,
+        config: Option<rpc_proxy::Config>,
+        routes: rpc_proxy::Routes,
+    ) {
+        if let Some(config) = config {
+            if let Err(err) = rpc_proxy::run(&bv_root, config, routes, run).await {
+                error!("RPC proxy failed: {err:#}");
+            }
+        }
+    }
+
     async fn create_internal_api_server(
         config: SharedConfig,
         mut run: RunFlag,
@@ -303,6 +368,26 @@ where
         }
     }
 
+    /// This task completes upgrade verifications interrupted by previous bv run.
+    /// Unfinished verification is resumed again on next start.
+    async fn upgrade_verifications(mut run: RunFlag, nodes_manager: Arc<NodesManager<P>>) {
+        run.select(nodes_manager.resume_upgrade_verifications())
+            .await;
+    }
+
+    /// This task runs periodically to make sure firewall rules applied on the host
+    /// are equal to expected nodes firewall config.
+    async fn nodes_firewall(mut run: RunFlag, nodes_manager: Arc<NodesManager<P>>) {
+        while run.load() {
+            let now = Instant::now();
+            nodes_manager.reconcile_firewall().await;
+            BV_NODES_FIREWALL_COUNTER.increment(1);
+            BV_NODES_FIREWALL_TIME_MS_COUNTER.increment(now.elapsed().as_millis() as u64);
+            run.select(sleep(with_jitter(FIREWALL_CHECK_INTERVAL)))
+                .await;
+        }
+    }
+
     /// This task runs periodically to send important info about nodes to API.
     async fn node_updates(
         mut run: RunFlag,
@@ -356,11 +441,20 @@ where
             }
 
             for (node_id, config_id, status, p2p_address) in updates {
-                let vm_status = match status {
-                    VmStatus::Running => Some(common::NodeState::Running),
-                    VmStatus::Stopped => Some(common::NodeState::Stopped),
-                    VmStatus::Failed => Some(common::NodeState::Failed),
-                    VmStatus::Busy => None,
+                let (vm_status, protocol) = match status {
+                    VmStatus::Running => (Some(common::NodeState::Running), None),
+                    VmStatus::Stopped => (Some(common::NodeState::Stopped), None),
+                    VmStatus::Failed => (Some(common::NodeState::Failed), None),
+                    // API has no notion of suspended node, but it still holds its resources,
+                    // so it is reported as running, with protocol state telling it is suspended
+                    VmStatus::Suspended => (
+                        Some(common::NodeState::Running),
+                        Some(common::ProtocolStatus {
+                            state: SUSPENDED_PROTOCOL_STATE.to_string(),
+                            health: common::NodeHealth::Neutral.into(),
+                        }),
+                    ),
+                    VmStatus::Busy => (None, None),
                 };
                 let report = pb::NodeServiceReportStatusRequest {
                     node_id: node_id.to_string(),
@@ -368,7 +462,7 @@ where
                     status: vm_status.map(|state| common::NodeStatus {
                         state: state.into(),
                         next: None,
-                        protocol: None,
+                        protocol,
                     }),
                     p2p_address,
                 };
@@ -404,6 +498,7 @@ where
                 .await;
             let now = Instant::now();
             let mut metrics = node_metrics::collect_metrics(nodes_manager.clone()).await;
+            metrics.set_all_gauges();
             let mut job_info_cache_update: HashMap<(uuid::Uuid, String), u64> = HashMap::new();
             // do not bother api with empty updates
             if metrics.has_any() {
@@ -458,8 +553,8 @@ where
                 .await
             {
                 Ok(metrics) => {
+                    metrics.set_all_gauges();
                     if let Ok(mut client) = Self::connect_metrics_service(&config).await {
-                        metrics.set_all_gauges();
                         let metrics =
                             pb::MetricsServiceHostRequest::new(config.read().await.id, metrics);
                         if let Err(err) = api_with_retry!(client, client.host(metrics.clone())) {
@@ -1,19 +1,27 @@
 use crate::{
-    apptainer_machine::ROOTFS_DIR,
-    bv_cli::{ClusterCommand, HostCommand, JobCommand, NodeCommand, ProtocolCommand},
-    bv_config::SharedConfig,
+    apptainer_machine::{DATA_DIR, ROOTFS_DIR},
+    bv_cli::{
+        ClusterCommand, FirewallCommand, HostCommand, ImagesCommand, JobCommand, NodeCommand,
+        PluginCommand, ProtocolCommand, SnapshotCommand,
+    },
+    bv_config::{Config, Runtime, SharedConfig},
     hosts::{self, HostInfo},
     internal_server,
     internal_server::CreateNodeRequest,
+    ip_leases::IpStatus,
     linux_platform::bv_root,
-    node_context::build_node_dir,
+    node_bundle,
+    node_context::{build_node_dir, NodeContext},
     node_env::NODE_ENV_FILE_PATH,
+    node_snapshot,
     node_state::{ProtocolImageKey, VmStatus},
+    nodes_manager::{RolloutOptions, RolloutStatus},
     pretty_table::{PrettyTable, PrettyTableRow},
     services,
     services::protocol::ProtocolService,
+    utils,
 };
-use babel_api::engine::JobStatus;
+use babel_api::{engine::JobStatus, plugin::CustomMetric};
 use bv_utils::{cmd::ask_confirm, rpc::RPC_CONNECT_TIMEOUT};
 use chrono::{DateTime, Utc};
 use cli_table::print_stdout;
@@ -21,6 +29,7 @@ use eyre::{bail, Result};
 use std::{
     ffi::OsStr,
     fs,
+    io::Write,
     ops::{Deref, DerefMut},
 };
 use tokio::process::Command;
@@ -45,6 +54,30 @@ pub async fn process_host_command(
             println!("CPU count:      {:>10}", info.cpu_count);
             println!("Total mem:      {:>10.3} GB", to_gb(info.memory_bytes));
             println!("Total disk:     {:>10.3} GB", to_gb(info.disk_space_bytes));
+            let allocation: Result<_> = async {
+                let mut client = NodeClient::new(bv_url).await?;
+                Ok(client.get_cpu_allocation(()).await?.into_inner())
+            }
+            .await;
+            match allocation {
+                Ok(allocation) => {
+                    println!("CPU allocation: {:>10?}", allocation.policy);
+                    for numa_node in allocation.numa_nodes {
+                        println!(
+                            "  NUMA node {}:   {}/{} CPUs free, {} whole cores free, {} cores partially used",
+                            numa_node.id,
+                            numa_node.free_cpus,
+                            numa_node.total_cpus,
+                            numa_node.free_whole_cores,
+                            numa_node.partially_used_cores
+                        );
+                    }
+                    for hint in allocation.hints {
+                        println!("  Hint: {hint}");
+                    }
+                }
+                Err(err) => println!("CPU allocation: unavailable ({err:#})"),
+            }
         }
         HostCommand::Update => {
             hosts::send_info_update(config).await?;
@@ -76,6 +109,46 @@ pub async fn process_host_command(
             );
             println!("Uptime [h:m:s]: {:>13}", fmt_seconds(metrics.uptime_secs));
         }
+        HostCommand::Images { command } => {
+            let mut client = NodeClient::new(bv_url).await?;
+            match command {
+                ImagesCommand::List => {
+                    let images = client.list_images(()).await?.into_inner();
+                    if !images.is_empty() {
+                        println!("{:<24} {:>10} {:<6} URI", "CREATED", "SIZE [GB]", "NODES");
+                        for image in images {
+                            println!(
+                                "{:<24} {:>10.3} {:<6} {}",
+                                image.created_at.format("%Y-%m-%d %H:%M:%S"),
+                                to_gb(image.size_bytes),
+                                image.used_by.len(),
+                                image.uri
+                            );
+                        }
+                    }
+                }
+                ImagesCommand::Prune => {
+                    for uri in client.prune_images(()).await?.into_inner() {
+                        println!("Removed `{uri}`");
+                    }
+                }
+            }
+        }
+        HostCommand::Ips { probe } => {
+            let mut client = NodeClient::new(bv_url).await?;
+            let ips = client.get_ip_leases(probe).await?.into_inner();
+            if !ips.is_empty() {
+                println!("{:<40} {:<10} DETAILS", "IP", "STATUS");
+                for info in ips {
+                    let (status, details) = match info.status {
+                        IpStatus::Free => ("free", String::new()),
+                        IpStatus::Leased { node_id } => ("leased", format!("node `{node_id}`")),
+                        IpStatus::Conflict { reason } => ("CONFLICT", reason),
+                    };
+                    println!("{:<40} {:<10} {details}", info.ip, status);
+                }
+            }
+        }
     }
 
     Ok(())
@@ -97,6 +170,7 @@ pub async fn process_node_command(bv_url: String, command: NodeCommand) -> Resul
                 nodes.retain(|n| n.state.tags.iter().any(|tag| tags.contains(tag)));
             }
             if !nodes.is_empty() {
+                let rollout = client.get_rollout_status(()).await?.into_inner();
                 let mut table = vec![];
                 for node in nodes {
                     table.push(PrettyTableRow {
@@ -111,9 +185,15 @@ pub async fn process_node_command(bv_url: String, command: NodeCommand) -> Resul
                         status: node.status,
                         ip: node.state.ip.to_string(),
                         uptime: fmt_opt(node.state.started_at.map(fmt_uptime)),
+                        rollout: fmt_opt(rollout.get(&node.state.id)),
                     })
                 }
                 print_stdout(table.to_pretty_table())?;
+                for (id, status) in rollout {
+                    if let RolloutStatus::Failed(message) = status {
+                        println!("Rolling upgrade of node `{id}` failed: {message}");
+                    }
+                }
             } else {
                 println!("No nodes found.");
             }
@@ -166,14 +246,56 @@ pub async fn process_node_command(bv_url: String, command: NodeCommand) -> Resul
             client.stop_nodes(&ids, force).await?;
             client.start_nodes(&ids).await?;
         }
+        NodeCommand::Suspend { id_or_names } => {
+            for id in client.get_node_ids(id_or_names).await? {
+                client.suspend_node(id).await?;
+                println!("Suspended node `{id}`");
+            }
+        }
+        NodeCommand::Resize {
+            id_or_name,
+            cpus,
+            memory_mb,
+        } => {
+            let id = client.resolve_id_or_name(&id_or_name).await?;
+            client.resize_node((id, cpus, memory_mb)).await?;
+            println!("Resized node `{id_or_name}`");
+        }
+        NodeCommand::Resume { id_or_names } => {
+            for id in client.get_node_ids(id_or_names).await? {
+                client.resume_node(id).await?;
+                println!("Resumed node `{id}`");
+            }
+        }
         NodeCommand::Upgrade {
             mut id_or_names,
             version,
             build,
             all,
+            tags,
+            max_parallel,
+            pause_on_failure,
+            canary,
         } => {
             if id_or_names.is_empty() {
-                if all {
+                if !tags.is_empty() {
+                    id_or_names = client
+                        .get_nodes(true)
+                        .await?
+                        .into_inner()
+                        .into_iter()
+                        .filter_map(|n| {
+                            n.state
+                                .tags
+                                .iter()
+                                .any(|tag| tags.contains(tag))
+                                .then_some(n.state.id.to_string())
+                        })
+                        .collect();
+                    if id_or_names.is_empty() {
+                        bail!("no nodes found with given tags");
+                    }
+                } else if all {
                     id_or_names = client
                         .get_nodes(true)
                         .await?
@@ -186,6 +308,23 @@ pub async fn process_node_command(bv_url: String, command: NodeCommand) -> Resul
                 }
             }
             let ids = client.get_node_ids(id_or_names).await?;
+            if let Some(max_parallel) = max_parallel {
+                client
+                    .client
+                    .rolling_upgrade((
+                        ids,
+                        version,
+                        build,
+                        RolloutOptions {
+                            max_parallel,
+                            pause_on_failure,
+                            canary,
+                        },
+                    ))
+                    .await?;
+                println!("Rolling upgrade started, see `bv node list` for progress");
+                return Ok(());
+            }
             for id in ids {
                 if let Err(err) = client.upgrade_node((id, version.clone(), build)).await {
                     println!("Failed to upgrade node `{id}`: {err:#}");
@@ -417,6 +556,9 @@ pub async fn process_node_command(bv_url: String, command: NodeCommand) -> Resul
             println!("Status:         {}", node_info.status);
             println!("Ip:             {}", node_info.state.ip);
             println!("Gateway:        {}", node_info.state.gateway);
+            if let Some(ipv6) = node_info.state.ipv6 {
+                println!("Ipv6:           {ipv6}");
+            }
             println!(
                 "Uptime [h:m:s]: {}",
                 fmt_opt(node_info.state.started_at.map(fmt_uptime))
@@ -429,6 +571,31 @@ pub async fn process_node_command(bv_url: String, command: NodeCommand) -> Resul
             println!("Block height:   {}", fmt_opt(metrics.height));
             println!("Block age:      {}", fmt_opt(metrics.block_age));
             println!("In consensus:   {}", fmt_opt(metrics.consensus));
+            if let Some(disk_usage) = metrics.disk_usage {
+                println!(
+                    "Data disk:      {:.3}/{:.3} GB",
+                    disk_usage.used_bytes as f64 / 1_000_000_000.0,
+                    disk_usage.quota_bytes as f64 / 1_000_000_000.0
+                );
+            }
+            if let Some(traffic) = metrics.traffic {
+                println!(
+                    "Network:        rx {:.3} GB, tx {:.3} GB",
+                    traffic.received_bytes as f64 / 1_000_000_000.0,
+                    traffic.sent_bytes as f64 / 1_000_000_000.0
+                );
+            }
+            if !metrics.custom.is_empty() {
+                println!("Metrics:");
+                let mut custom = metrics.custom.into_iter().collect::<Vec<_>>();
+                custom.sort_by(|(a, _), (b, _)| a.cmp(b));
+                for (name, metric) in custom {
+                    match metric {
+                        CustomMetric::Gauge(value) => println!("  - {name}: {value} (gauge)"),
+                        CustomMetric::Counter(value) => println!("  - {name}: {value} (counter)"),
+                    }
+                }
+            }
             if !metrics.jobs.is_empty() {
                 println!("Jobs:");
                 for (name, mut info) in metrics.jobs {
@@ -494,6 +661,158 @@ pub async fn process_node_command(bv_url: String, command: NodeCommand) -> Resul
             };
             client.reload_plugin(id).await?;
         }
+        NodeCommand::Plugin { command } => match command {
+            PluginCommand::Eval {
+                id_or_name,
+                script,
+                write,
+            } => {
+                let id = client.resolve_id_or_name(&id_or_name).await?;
+                let result = client.evaluate((id, script, !write)).await?.into_inner();
+                println!("{result}");
+            }
+            PluginCommand::Repl { id_or_name, write } => {
+                let id = client.resolve_id_or_name(&id_or_name).await?;
+                println!(
+                    "Evaluating Rhai in '{id_or_name}' plugin context{}. Type 'exit' or Ctrl+D to quit.",
+                    if write { "" } else { " (read-only)" }
+                );
+                let mut lines = std::io::stdin().lines();
+                loop {
+                    print!("> ");
+                    std::io::stdout().flush()?;
+                    let Some(line) = lines.next() else {
+                        println!();
+                        break;
+                    };
+                    let line = line?;
+                    let script = line.trim();
+                    if script == "exit" {
+                        break;
+                    } else if script.is_empty() {
+                        continue;
+                    }
+                    match client.evaluate((id, script.to_string(), !write)).await {
+                        Ok(result) => println!("{}", result.into_inner()),
+                        Err(status) => println!("Error: {}", status.message()),
+                    }
+                }
+            }
+        },
+        NodeCommand::Export {
+            id_or_name,
+            path,
+            with_data,
+        } => {
+            let id = client.resolve_id_or_name(&id_or_name).await?;
+            let node = client.get_node(id).await?.into_inner();
+            if with_data && node.status != VmStatus::Stopped {
+                bail!("Node must be stopped to export protocol data, use `bv node stop` first");
+            }
+            let context = NodeContext::build(&bv_root(), id);
+            // firecracker data drive is mounted on the host only while VM is not running
+            if with_data
+                && Config::load(&bv_root()).await?.runtime == Runtime::Firecracker
+                && !utils::is_mount_point(&context.node_dir.join(DATA_DIR)).await
+            {
+                bail!(
+                    "Node data drive is not mounted on the host, make sure node VM is not running"
+                );
+            }
+            let jobs = match client.get_node_jobs(id).await {
+                Ok(jobs) => jobs.into_inner(),
+                Err(_) => Default::default(),
+            };
+            node_bundle::export(node.state, jobs, &context, with_data, &path)?;
+            println!("Node `{id}` exported to `{}`", path.display());
+        }
+        NodeCommand::Import { path, start } => {
+            let bundle = node_bundle::verify(&path)?;
+            let node = client.import_node(bundle.state.clone()).await?.into_inner();
+            let id = node.state.id;
+            node_bundle::restore(&bundle, &path, &NodeContext::build(&bv_root(), id))?;
+            println!(
+                "Node `{id}` imported with name `{}` and IP `{}`",
+                node.state.name, node.state.ip
+            );
+            if start {
+                client.start_nodes(&[id]).await?;
+            }
+        }
+        NodeCommand::Snapshot { command } => match command {
+            SnapshotCommand::Create { id_or_name, name } => {
+                let id = client.resolve_id_or_name(&id_or_name).await?;
+                let name = name.unwrap_or_else(|| Utc::now().format("%Y%m%d-%H%M%S").to_string());
+                let snapshot = client.create_snapshot((id, name)).await?.into_inner();
+                println!(
+                    "Snapshot `{}` of node `{id_or_name}` created using {:?}",
+                    snapshot.name, snapshot.method
+                );
+            }
+            SnapshotCommand::List { id_or_name } => {
+                let id = client.resolve_id_or_name(&id_or_name).await?;
+                let snapshots = client.list_snapshots(id).await?.into_inner();
+                if !snapshots.is_empty() {
+                    println!(
+                        "{:<24} {:<24} {:<10} {:<14} DATA STAMP",
+                        "NAME", "CREATED", "METHOD", "IMAGE"
+                    );
+                    for snapshot in snapshots {
+                        let method = match snapshot.method {
+                            node_snapshot::SnapshotMethod::Btrfs => "btrfs",
+                            node_snapshot::SnapshotMethod::Zfs { .. } => "zfs",
+                            node_snapshot::SnapshotMethod::Copy => "copy",
+                        };
+                        let data_stamp = snapshot
+                            .data_stamp
+                            .map(|stamp| DateTime::<Utc>::from(stamp).to_string())
+                            .unwrap_or("-".to_string());
+                        println!(
+                            "{:<24} {:<24} {method:<10} {:<14} {data_stamp}",
+                            snapshot.name,
+                            snapshot.created_at.format("%Y-%m-%d %H:%M:%S"),
+                            snapshot.image_version,
+                        );
+                    }
+                }
+            }
+            SnapshotCommand::Restore {
+                id_or_name,
+                name,
+                start,
+            } => {
+                let id = client.resolve_id_or_name(&id_or_name).await?;
+                client.restore_snapshot((id, name.clone())).await?;
+                println!("Node `{id_or_name}` restored from snapshot `{name}`");
+                if start {
+                    client.start_nodes(&[id]).await?;
+                }
+            }
+            SnapshotCommand::Delete { id_or_name, name } => {
+                let id = client.resolve_id_or_name(&id_or_name).await?;
+                client.delete_snapshot((id, name.clone())).await?;
+                println!("Snapshot `{name}` of node `{id_or_name}` deleted");
+            }
+        },
+        NodeCommand::Firewall { command } => match command {
+            FirewallCommand::Status { id_or_name } => {
+                let id = client.resolve_id_or_name(&id_or_name).await?;
+                let status = client.get_node_firewall_status(id).await?.into_inner();
+                println!("Backend:  {:?}", status.backend);
+                println!(
+                    "Status:   {}",
+                    if status.drift { "DRIFT" } else { "in sync" }
+                );
+                println!("Expected:");
+                for rule in status.expected {
+                    println!("  - {rule}");
+                }
+                println!("Actual:");
+                for rule in status.actual {
+                    println!("  - {rule}");
+                }
+            }
+        },
     }
     Ok(())
 }
@@ -127,6 +127,33 @@ pub enum NodeCommand {
         force: bool,
     },
 
+    /// Suspend running node, by freezing its processes without stopping them.
+    Suspend {
+        /// One or more node id or names.
+        #[clap(required(false))]
+        id_or_names: Vec<String>,
+    },
+
+    /// Resume suspended node.
+    Resume {
+        /// One or more node id or names.
+        #[clap(required(false))]
+        id_or_names: Vec<String>,
+    },
+
+    /// Change node CPU and/or memory allocation in place, without rebuilding the node.
+    #[clap(group(ArgGroup::new("resources").required(true).multiple(true).args(& ["cpus", "memory_mb"])))]
+    Resize {
+        /// The id or name of the node.
+        id_or_name: String,
+        /// New number of vCPUs.
+        #[clap(long)]
+        cpus: Option<usize>,
+        /// New memory size in MB.
+        #[clap(long)]
+        memory_mb: Option<u64>,
+    },
+
     /// Trigger node upgrade.
     Upgrade {
         /// Version of image, or skip to use latest,
@@ -144,6 +171,23 @@ pub enum NodeCommand {
         /// Upgrade all nodes on this host.
         #[clap(long, short)]
         all: bool,
+
+        /// One or more tags. Filter nodes by give tags.
+        #[clap(long)]
+        tags: Vec<String>,
+
+        /// Run rolling upgrade - upgrade at most given number of nodes at a time,
+        /// and wait until they are healthy, before starting next batch.
+        #[clap(long)]
+        max_parallel: Option<usize>,
+
+        /// Stop rolling upgrade on first failure.
+        #[clap(long, requires = "max_parallel")]
+        pause_on_failure: bool,
+
+        /// Upgrade single (first) node alone, before rest of nodes, and stop if it fails.
+        #[clap(long, requires = "max_parallel")]
+        canary: bool,
     },
 
     /// Delete node and clean up resources.
@@ -220,6 +264,116 @@ pub enum NodeCommand {
         /// The id or name of the node.
         id_or_name: String,
     },
+
+    /// Evaluate Rhai scripts in the context of node plugin.
+    Plugin {
+        #[clap(subcommand)]
+        command: PluginCommand,
+    },
+
+    /// Export node into transferable bundle, that can be imported on other host.
+    Export {
+        /// The id or name of the node.
+        id_or_name: String,
+        /// Path to the bundle directory (must not exist or be empty).
+        path: PathBuf,
+        /// Export also protocol data. Node must be stopped.
+        #[clap(long, default_value = "false")]
+        with_data: bool,
+    },
+
+    /// Import node from bundle created with `bv node export`.
+    Import {
+        /// Path to the bundle directory.
+        path: PathBuf,
+        /// Start node after import.
+        #[clap(long, default_value = "false")]
+        start: bool,
+    },
+
+    /// Manage local snapshots of node protocol data.
+    Snapshot {
+        #[clap(subcommand)]
+        command: SnapshotCommand,
+    },
+
+    /// Inspect node firewall rules.
+    Firewall {
+        #[clap(subcommand)]
+        command: FirewallCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SnapshotCommand {
+    /// Create snapshot of node protocol data. Node must be stopped.
+    Create {
+        /// The id or name of the node.
+        id_or_name: String,
+        /// Snapshot name, current timestamp is used if not provided.
+        #[clap(long)]
+        name: Option<String>,
+    },
+
+    /// Show node snapshots list.
+    #[clap(alias = "ls")]
+    List {
+        /// The id or name of the node.
+        id_or_name: String,
+    },
+
+    /// Replace node protocol data with snapshot. Node must be stopped.
+    Restore {
+        /// The id or name of the node.
+        id_or_name: String,
+        /// Snapshot name.
+        name: String,
+        /// Start node after restore.
+        #[clap(long, default_value = "false")]
+        start: bool,
+    },
+
+    /// Delete snapshot.
+    Delete {
+        /// The id or name of the node.
+        id_or_name: String,
+        /// Snapshot name.
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum FirewallCommand {
+    /// Compare expected node firewall rules with rules actually applied on the host.
+    Status {
+        /// The id or name of the node.
+        id_or_name: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum PluginCommand {
+    /// Evaluate Rhai expression and print its result.
+    Eval {
+        /// The id or name of the node.
+        id_or_name: String,
+        /// Rhai expression to be evaluated.
+        script: String,
+        /// Allow calling functions that modify node state (e.g. starting jobs or saving data),
+        /// running shell commands and reading secrets or files.
+        #[clap(long, default_value = "false")]
+        write: bool,
+    },
+
+    /// Start interactive session, where each line is evaluated as separate Rhai expression.
+    Repl {
+        /// The id or name of the node.
+        id_or_name: String,
+        /// Allow calling functions that modify node state (e.g. starting jobs or saving data),
+        /// running shell commands and reading secrets or files.
+        #[clap(long, default_value = "false")]
+        write: bool,
+    },
 }
 
 #[derive(Subcommand)]
@@ -288,6 +442,29 @@ pub enum HostCommand {
     /// Collect metrics about the current host
     #[clap(alias = "m")]
     Metrics,
+
+    /// Manage host level cache of node images.
+    Images {
+        #[clap(subcommand)]
+        command: ImagesCommand,
+    },
+
+    /// Show host IP pool with free, leased and conflicting addresses.
+    Ips {
+        /// Probe free addresses for conflicts on node network (ARP/NDP) first.
+        #[clap(long)]
+        probe: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ImagesCommand {
+    /// Show cached images list.
+    #[clap(alias = "ls")]
+    List,
+
+    /// Remove cached images that are not used by any node.
+    Prune,
 }
 
 #[derive(Subcommand)]
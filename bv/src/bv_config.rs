@@ -1,15 +1,26 @@
-use crate::{api_config::ApiConfig, services::AuthToken, utils};
+use crate::{
+    api_config::ApiConfig, cpu_registry::CpuAllocationPolicy, disk_quota::DiskQuotaMethod,
+    firewall, image_verification, net_shaping::BandwidthLimit, rpc_proxy, services::AuthToken,
+    utils,
+};
 use bv_utils::cmd::run_cmd;
 use cidr_utils::cidr::IpCidr;
 use eyre::{anyhow, bail, Context, Result};
 use serde::{Deserialize, Serialize};
-use std::{net::IpAddr, path::Path, str::FromStr};
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 use sysinfo::{System, SystemExt};
 use tokio::fs;
 use tracing::debug;
 
 pub const CONFIG_PATH: &str = "etc/blockvisor.json";
 pub const DEFAULT_BRIDGE_IFACE: &str = "bvbr0";
+/// IPv6 subnets are usually huge (e.g. /64), so only explicitly given part of it can be used
+/// as nodes IPv6 pool.
+const MAX_IPV6_POOL_BITS: u8 = 16;
 
 pub fn default_blockvisor_port() -> u16 {
     9001
@@ -77,6 +88,16 @@ pub struct ApptainerConfig {
     pub host_network: bool,
     pub cpu_limit: bool,
     pub memory_limit: bool,
+    /// Method used to enforce node data disk quota.
+    #[serde(default)]
+    pub disk_quota: DiskQuotaMethod,
+    /// Build each image only once in host level cache, and use it as read-only base
+    /// of node rootfs overlay, instead of building full rootfs for each node.
+    #[serde(default)]
+    pub image_cache: bool,
+    /// Default network bandwidth limits, applied to nodes that don't declare their own.
+    #[serde(default)]
+    pub bandwidth_limit: BandwidthLimit,
 }
 
 impl Default for ApptainerConfig {
@@ -86,10 +107,48 @@ impl Default for ApptainerConfig {
             host_network: false,
             cpu_limit: true,
             memory_limit: true,
+            disk_quota: DiskQuotaMethod::Disabled,
+            image_cache: false,
+            bandwidth_limit: Default::default(),
         }
     }
 }
 
+/// Runtime used to run nodes on the host.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Runtime {
+    /// Apptainer containers, with configurable networking and resources limits.
+    #[default]
+    Apptainer,
+    /// Lightweight `systemd-nspawn` (or plain chroot) runtime with host networking,
+    /// intended for CI and development.
+    Nspawn,
+    /// Firecracker microVMs, for VM level isolation of nodes.
+    Firecracker,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct NspawnConfig {
+    /// Run nodes in plain chroot (with own user, mount and PID namespaces) instead of `systemd-nspawn`.
+    #[serde(default)]
+    pub chroot: bool,
+    /// Local rootfs tarball used for all nodes, instead of the one pointed by node image `file://` uri.
+    #[serde(default)]
+    pub rootfs_tarball: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct FirecrackerConfig {
+    /// Uncompressed guest kernel (`vmlinux`) used to boot node VMs.
+    /// Defaults to `firecracker/vmlinux` in BV data directory.
+    #[serde(default)]
+    pub kernel_image: Option<PathBuf>,
+    /// Extra guest kernel boot arguments.
+    #[serde(default)]
+    pub boot_args: Option<String>,
+}
+
 #[derive(Default, Deserialize, Serialize, Debug, Clone)]
 pub struct Config {
     /// Host uuid
@@ -124,9 +183,48 @@ pub struct Config {
     pub cluster_seed_urls: Option<Vec<String>>,
     /// Apptainer configuration
     pub apptainer: ApptainerConfig,
+    /// Runtime used to run nodes.
+    #[serde(default)]
+    pub runtime: Runtime,
+    /// Lightweight runtime configuration, used only if `runtime` is `nspawn`.
+    #[serde(default)]
+    pub nspawn: NspawnConfig,
+    /// Firecracker configuration, used only if `runtime` is `firecracker`.
+    #[serde(default)]
+    pub firecracker: FirecrackerConfig,
     /// Run in maintenance mode - use on your own risk.
     #[serde(default)]
     pub maintenance_mode: bool,
+    /// Port on which Prometheus `/metrics` endpoint is exposed.
+    /// Endpoint is disabled if not set.
+    #[serde(default)]
+    pub metrics_port: Option<u16>,
+    /// Address on which metrics endpoint listens, localhost if not set.
+    /// Endpoint has no authentication, so it shall be exposed only on trusted networks.
+    #[serde(default)]
+    pub metrics_address: Option<IpAddr>,
+    /// Time window after node upgrade, in which node health is watched and upgrade
+    /// is automatically rolled back if node is not healthy. Verification is disabled if not set.
+    #[serde(default)]
+    pub upgrade_verification_secs: Option<u64>,
+    /// Strategy used to select host CPUs assigned to nodes.
+    #[serde(default)]
+    pub cpu_allocation_policy: CpuAllocationPolicy,
+    /// Tool used to apply node firewall rules.
+    #[serde(default)]
+    pub firewall_backend: firewall::Backend,
+    /// Probe node IP addresses on node network (ARP for IPv4, NDP for IPv6) before node is created
+    /// or started, so address conflicts are reported instead of breaking node networking.
+    /// Requires `arping` and `ndisc6` tools.
+    #[serde(default)]
+    pub ip_conflict_detection: bool,
+    /// Built-in TLS reverse proxy exposing node RPC as `https://<dns_name>`. Disabled if not set.
+    #[serde(default)]
+    pub rpc_proxy: Option<rpc_proxy::Config>,
+    /// Verify that node images are pinned by digest and signed by trusted keys, before nodes
+    /// are created or upgraded from them. Requires `cosign` tool. Disabled if not set.
+    #[serde(default)]
+    pub image_verification: Option<image_verification::Config>,
 }
 
 impl Config {
@@ -162,6 +260,9 @@ pub struct NetConf {
     pub host_ip: IpAddr,
     pub prefix: u8,
     pub available_ips: Vec<IpAddr>,
+    /// Optional IPv6 network config, if set each node gets also IPv6 address (dual-stack).
+    #[serde(default)]
+    pub ipv6: Option<Ipv6NetConf>,
 }
 
 impl Default for NetConf {
@@ -171,10 +272,47 @@ impl Default for NetConf {
             host_ip: IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)),
             prefix: 32,
             available_ips: vec![],
+            ipv6: None,
         }
     }
 }
 
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct Ipv6NetConf {
+    pub gateway_ip: Ipv6Addr,
+    pub host_ip: Ipv6Addr,
+    pub prefix: u8,
+    pub available_ips: Vec<Ipv6Addr>,
+}
+
+impl Ipv6NetConf {
+    /// `available_ips` has the same format as in `NetConf::override_ips`.
+    pub fn new(gateway_ip: &str, host_ip: &str, prefix: u8, available_ips: &str) -> Result<Self> {
+        let gateway_ip = Ipv6Addr::from_str(gateway_ip)
+            .with_context(|| format!("invalid IPv6 gateway ip '{gateway_ip}'"))?;
+        let host_ip = Ipv6Addr::from_str(host_ip)
+            .with_context(|| format!("invalid IPv6 host ip '{host_ip}'"))?;
+        if prefix > 128 {
+            bail!("invalid IPv6 subnet prefix '{prefix}'");
+        }
+        let mut ips = vec![];
+        for ip in parse_ips(available_ips)? {
+            let IpAddr::V6(ip) = ip else {
+                bail!("'{ip}' is not an IPv6 address");
+            };
+            if ip != host_ip && ip != gateway_ip {
+                ips.push(ip);
+            }
+        }
+        Ok(Self {
+            gateway_ip,
+            host_ip,
+            prefix,
+            available_ips: ips,
+        })
+    }
+}
+
 impl NetConf {
     pub async fn new(ifa_name: &str) -> Result<Self> {
         Self::from_json(&run_cmd("ip", ["--json", "route"]).await?, ifa_name)
@@ -216,6 +354,7 @@ impl NetConf {
                             gateway_ip: gateway,
                             host_ip,
                             prefix,
+                            ipv6: None,
                             available_ips: if let (Some(ip_from), Some(ip_to)) =
                                 (ips.next(), ips.next_back())
                             {
@@ -246,58 +385,73 @@ impl NetConf {
     }
 
     pub fn override_ips(&mut self, value: &str) -> Result<()> {
-        self.available_ips.clear();
-        for item in value.split(",") {
-            if item.contains("/") {
-                let cidr = IpCidr::from_str(item)
-                    .with_context(|| format!("cannot parse '{item}' as cidr"))?;
-                let prefix = get_bits(&cidr);
-                let mut ips = cidr.iter();
-                if prefix <= 30 {
-                    // For routing mask values <= 30, first and last IPs are
-                    // base and broadcast addresses and are unusable.
-                    ips.next();
-                    ips.next_back();
-                }
-                self.available_ips.append(&mut ips.collect::<Vec<_>>());
-            } else if item.contains("-") {
-                let mut split = item.split("-");
-                let ip_from = split
-                    .next()
-                    .ok_or(anyhow!("missing from"))
-                    .and_then(|ip| IpAddr::from_str(ip).map_err(|err| anyhow!("{err:#}")))
-                    .with_context(|| format!("invalid ip range '{item}'"))?;
-                let ip_to = split
-                    .next()
-                    .ok_or(anyhow!("missing to"))
-                    .and_then(|ip| IpAddr::from_str(ip).map_err(|err| anyhow!("{err:#}")))
-                    .with_context(|| format!("invalid ip range '{item}'"))?;
-                if split.next().is_some() {
-                    bail!("invalid ip range '{item}'")
-                }
-                self.available_ips
-                    .append(&mut ips_from_range(ip_from, ip_to)?);
-            } else {
-                self.available_ips.push(
-                    IpAddr::from_str(item)
-                        .with_context(|| format!("invalid ip provided '{item}'"))?,
-                );
-            }
-        }
+        self.available_ips = parse_ips(value)?;
         self.available_ips
             .retain(|ip| *ip != self.host_ip && *ip != self.gateway_ip);
         Ok(())
     }
 }
 
+/// Parse comma separated list of IPs, dash IP ranges, or CIDRs.
+fn parse_ips(value: &str) -> Result<Vec<IpAddr>> {
+    let mut available_ips = vec![];
+    for item in value.split(",") {
+        if item.contains("/") {
+            let cidr =
+                IpCidr::from_str(item).with_context(|| format!("cannot parse '{item}' as cidr"))?;
+            let prefix = get_bits(&cidr);
+            if matches!(cidr, IpCidr::V6(_)) && prefix < 128 - MAX_IPV6_POOL_BITS {
+                bail!(
+                    "IPv6 range '{item}' is too large, use at least /{}",
+                    128 - MAX_IPV6_POOL_BITS
+                );
+            }
+            let mut ips = cidr.iter();
+            if prefix <= 30 {
+                // For routing mask values <= 30, first and last IPs are
+                // base and broadcast addresses and are unusable.
+                ips.next();
+                ips.next_back();
+            }
+            available_ips.append(&mut ips.collect::<Vec<_>>());
+        } else if item.contains("-") {
+            let mut split = item.split("-");
+            let ip_from = split
+                .next()
+                .ok_or(anyhow!("missing from"))
+                .and_then(|ip| IpAddr::from_str(ip).map_err(|err| anyhow!("{err:#}")))
+                .with_context(|| format!("invalid ip range '{item}'"))?;
+            let ip_to = split
+                .next()
+                .ok_or(anyhow!("missing to"))
+                .and_then(|ip| IpAddr::from_str(ip).map_err(|err| anyhow!("{err:#}")))
+                .with_context(|| format!("invalid ip range '{item}'"))?;
+            if split.next().is_some() {
+                bail!("invalid ip range '{item}'")
+            }
+            available_ips.append(&mut ips_from_range(ip_from, ip_to)?);
+        } else {
+            available_ips.push(
+                IpAddr::from_str(item).with_context(|| format!("invalid ip provided '{item}'"))?,
+            );
+        }
+    }
+    Ok(available_ips)
+}
+
 fn ips_from_range(ip_from: IpAddr, ip_to: IpAddr) -> Result<Vec<IpAddr>> {
     Ok(match (ip_from, ip_to) {
         (IpAddr::V4(ip_from), IpAddr::V4(ip_to)) => Ok(ipnet::IpAddrRange::from(
             ipnet::Ipv4AddrRange::new(ip_from, ip_to),
         )),
-        (IpAddr::V6(ip_from), IpAddr::V6(ip_to)) => Ok(ipnet::IpAddrRange::from(
-            ipnet::Ipv6AddrRange::new(ip_from, ip_to),
-        )),
+        (IpAddr::V6(ip_from), IpAddr::V6(ip_to)) => {
+            if u128::from(ip_to).saturating_sub(u128::from(ip_from)) >> MAX_IPV6_POOL_BITS != 0 {
+                bail!("IPv6 range ({ip_from}, {ip_to}) is too large");
+            }
+            Ok(ipnet::IpAddrRange::from(ipnet::Ipv6AddrRange::new(
+                ip_from, ip_to,
+            )))
+        }
         _ => Err(anyhow!("invalid ip range: ({ip_from}, {ip_to}")),
     }?
     .collect())
@@ -373,7 +527,26 @@ pub mod tests {
                 IpAddr::from(Ipv4Addr::from_str("192.69.220.93").unwrap()),
                 IpAddr::from(Ipv4Addr::from_str("192.69.220.94").unwrap()),
             ],
+            ipv6: None,
         };
         assert_eq!(expected, NetConf::from_json(json, "bvbr0").unwrap());
     }
+
+    #[test]
+    fn test_ipv6_net_conf() {
+        let net_conf =
+            Ipv6NetConf::new("fd00::1", "fd00::2", 64, "fd00::1-fd00::4,fd00::10/127").unwrap();
+        assert_eq!(
+            vec![
+                Ipv6Addr::from_str("fd00::3").unwrap(),
+                Ipv6Addr::from_str("fd00::4").unwrap(),
+                Ipv6Addr::from_str("fd00::10").unwrap(),
+                Ipv6Addr::from_str("fd00::11").unwrap(),
+            ],
+            net_conf.available_ips
+        );
+        assert!(Ipv6NetConf::new("fd00::1", "fd00::2", 64, "fd00::/64").is_err());
+        assert!(Ipv6NetConf::new("fd00::1", "fd00::2", 64, "fd00::1-fd01::1").is_err());
+        assert!(Ipv6NetConf::new("fd00::1", "fd00::2", 64, "192.168.0.1").is_err());
+    }
 }
@@ -1,4 +1,5 @@
-use crate::bv_config::{ApptainerConfig, Config};
+use crate::bv_config::{ApptainerConfig, Config, Runtime};
+use std::time::Duration;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct BvContext {
@@ -6,18 +7,21 @@ pub struct BvContext {
     pub name: String,
     pub url: String,
     pub bridge: Option<String>,
+    pub upgrade_verification: Option<Duration>,
 }
 
 impl BvContext {
     pub fn from_config(config: Config, apptainer_config: Option<ApptainerConfig>) -> Self {
+        let host_network = apptainer_config.unwrap_or(config.apptainer).host_network;
         Self {
             id: config.id,
             name: config.name,
             url: config.api_config.blockjoy_api_url,
-            bridge: if !apptainer_config.unwrap_or(config.apptainer).host_network {
-                Some(config.iface)
-            } else {
-                None
+            upgrade_verification: config.upgrade_verification_secs.map(Duration::from_secs),
+            bridge: match config.runtime {
+                Runtime::Apptainer if !host_network => Some(config.iface),
+                Runtime::Firecracker => Some(config.iface),
+                _ => None,
             },
         }
     }
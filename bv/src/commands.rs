@@ -32,4 +32,6 @@ pub enum Error {
     NodeUpgradeRollback(eyre::Error),
     #[error("node upgrade failed with: '{0:#}'; and then rollback failed with: '{1:#}'; node ended in failed state")]
     NodeUpgradeFailure(String, eyre::Error),
+    #[error("node image verification failed: '{0:#}'")]
+    ImageVerificationFailed(eyre::Error),
 }
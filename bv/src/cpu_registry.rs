@@ -1,35 +1,538 @@
-use eyre::bail;
-use std::sync::Arc;
+use eyre::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+    sync::Arc,
+};
 use tokio::sync::Mutex;
 
+/// Strategy used to select CPUs for the node.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CpuAllocationPolicy {
+    /// Allocate compact sets - fill the most utilized NUMA node that still fits the node,
+    /// and keep SMT siblings together.
+    #[default]
+    Pack,
+    /// Balance nodes across NUMA nodes and prefer one hardware thread per physical core.
+    Spread,
+    /// Like `Pack`, but only allocate physical cores with all SMT siblings free,
+    /// so nodes never share physical core.
+    WholeCores,
+}
+
+/// Logical CPU location in the host topology.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct CpuInfo {
+    pub id: usize,
+    pub package: usize,
+    pub numa_node: usize,
+    /// Physical core id, unique within the package. CPUs with the same `package` and `core`
+    /// are SMT siblings.
+    pub core: usize,
+}
+
+impl CpuInfo {
+    fn core_key(&self) -> (usize, usize) {
+        (self.package, self.core)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct CpuTopology(pub Vec<CpuInfo>);
+
+impl CpuTopology {
+    /// Topology without any NUMA or SMT information - every CPU is a separate core.
+    pub fn flat(cpu_count: usize) -> Self {
+        Self(
+            (0..cpu_count)
+                .map(|id| CpuInfo {
+                    id,
+                    package: 0,
+                    numa_node: 0,
+                    core: id,
+                })
+                .collect(),
+        )
+    }
+
+    /// Read topology of online CPUs from sysfs (e.g. `/sys/devices/system/cpu`).
+    pub fn load(sysfs_cpu_dir: &Path) -> eyre::Result<Self> {
+        let read_number = |path: &Path| -> eyre::Result<usize> {
+            Ok(fs::read_to_string(path)
+                .with_context(|| format!("failed to read '{}'", path.display()))?
+                .trim()
+                .parse()?)
+        };
+        let mut cpus = vec![];
+        for id in parse_cpu_list(&fs::read_to_string(sysfs_cpu_dir.join("online"))?)? {
+            let cpu_dir = sysfs_cpu_dir.join(format!("cpu{id}"));
+            let topology_dir = cpu_dir.join("topology");
+            let numa_node = fs::read_dir(&cpu_dir)?
+                .filter_map(|entry| entry.ok())
+                .find_map(|entry| {
+                    entry
+                        .file_name()
+                        .to_str()?
+                        .strip_prefix("node")?
+                        .parse()
+                        .ok()
+                })
+                .unwrap_or_default();
+            cpus.push(CpuInfo {
+                id,
+                package: read_number(&topology_dir.join("physical_package_id"))?,
+                numa_node,
+                core: read_number(&topology_dir.join("core_id"))?,
+            });
+        }
+        if cpus.is_empty() {
+            bail!("no online cpus found in '{}'", sysfs_cpu_dir.display());
+        }
+        Ok(Self(cpus))
+    }
+
+    fn siblings(&self, cpu: &CpuInfo) -> impl Iterator<Item = &CpuInfo> {
+        let key = cpu.core_key();
+        self.0.iter().filter(move |other| other.core_key() == key)
+    }
+}
+
+/// Parse kernel cpu list format, e.g. `0-3,8,10-11`.
+fn parse_cpu_list(list: &str) -> eyre::Result<Vec<usize>> {
+    let mut cpus = vec![];
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        match range.split_once('-') {
+            Some((first, last)) => cpus.extend(first.parse::<usize>()?..=last.parse()?),
+            None => cpus.push(range.parse()?),
+        }
+    }
+    Ok(cpus)
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct NumaNodeAllocation {
+    pub id: usize,
+    pub total_cpus: usize,
+    pub free_cpus: usize,
+    /// Number of physical cores, with all SMT siblings free.
+    pub free_whole_cores: usize,
+    /// Number of physical cores, with some SMT siblings used and some free.
+    pub partially_used_cores: usize,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct CpuAllocationInfo {
+    pub policy: CpuAllocationPolicy,
+    pub numa_nodes: Vec<NumaNodeAllocation>,
+    /// Defragmentation hints for the operator.
+    pub hints: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Registry {
+    topology: CpuTopology,
+    policy: CpuAllocationPolicy,
+    free: BTreeSet<usize>,
+}
+
+impl Registry {
+    fn is_core_free(&self, cpu: &CpuInfo) -> bool {
+        self.topology
+            .siblings(cpu)
+            .all(|sibling| self.free.contains(&sibling.id))
+    }
+
+    /// Free CPUs that can be allocated with current policy, grouped by NUMA node.
+    fn allocatable(&self) -> BTreeMap<usize, Vec<&CpuInfo>> {
+        let mut numa_nodes: BTreeMap<usize, Vec<&CpuInfo>> = BTreeMap::new();
+        for cpu in self.topology.0.iter().filter(|cpu| {
+            self.free.contains(&cpu.id)
+                && (self.policy != CpuAllocationPolicy::WholeCores || self.is_core_free(cpu))
+        }) {
+            numa_nodes.entry(cpu.numa_node).or_default().push(cpu);
+        }
+        numa_nodes
+    }
+
+    /// Order CPUs of single NUMA node, according to policy. Higher ids go first,
+    /// so low numbered CPUs (usually busy with host tasks) are used last.
+    fn order_within_numa_node(&self, cpus: &mut Vec<&CpuInfo>) {
+        match self.policy {
+            CpuAllocationPolicy::Pack | CpuAllocationPolicy::WholeCores => {
+                // fill partially used cores first, then keep siblings together
+                cpus.sort_by_key(|cpu| {
+                    (
+                        self.is_core_free(cpu),
+                        std::cmp::Reverse(cpu.core_key()),
+                        std::cmp::Reverse(cpu.id),
+                    )
+                });
+            }
+            CpuAllocationPolicy::Spread => {
+                // one thread per free physical core first
+                let mut rank: BTreeMap<(usize, usize), usize> = BTreeMap::new();
+                let mut ranked: Vec<_> = {
+                    let mut sorted = cpus.clone();
+                    sorted.sort_by_key(|cpu| std::cmp::Reverse(cpu.id));
+                    sorted
+                        .into_iter()
+                        .map(|cpu| {
+                            let sibling_rank = rank.entry(cpu.core_key()).or_default();
+                            *sibling_rank += 1;
+                            (*sibling_rank, !self.is_core_free(cpu), cpu)
+                        })
+                        .collect()
+                };
+                ranked.sort_by_key(|(sibling_rank, partially_used, cpu)| {
+                    (
+                        *sibling_rank,
+                        *partially_used,
+                        std::cmp::Reverse(cpu.core_key()),
+                        std::cmp::Reverse(cpu.id),
+                    )
+                });
+                *cpus = ranked.into_iter().map(|(_, _, cpu)| cpu).collect();
+            }
+        }
+    }
+
+    fn select(&self, count: usize) -> Option<Vec<usize>> {
+        let mut numa_nodes: Vec<_> = self.allocatable().into_iter().rev().collect();
+        if numa_nodes.iter().map(|(_, cpus)| cpus.len()).sum::<usize>() < count {
+            return None;
+        }
+        let fitting = numa_nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, cpus))| cpus.len() >= count);
+        let fitting = match self.policy {
+            CpuAllocationPolicy::Pack | CpuAllocationPolicy::WholeCores => {
+                fitting.min_by_key(|(_, (_, cpus))| cpus.len())
+            }
+            CpuAllocationPolicy::Spread => fitting
+                .rev()
+                .max_by_key(|(_, (_, cpus))| cpus.len())
+                .map(|(index, numa_node)| (index, numa_node)),
+        }
+        .map(|(index, _)| index);
+        let mut selected = vec![];
+        if let Some(index) = fitting {
+            numa_nodes = vec![numa_nodes.swap_remove(index)];
+        } else {
+            // node must span multiple NUMA nodes, so use as few as possible
+            numa_nodes.sort_by_key(|(_, cpus)| std::cmp::Reverse(cpus.len()));
+        }
+        for (_, mut cpus) in numa_nodes {
+            self.order_within_numa_node(&mut cpus);
+            for cpu in cpus {
+                if selected.len() == count {
+                    break;
+                }
+                selected.push(cpu.id);
+            }
+        }
+        Some(selected)
+    }
+
+    fn allocation_info(&self, nodes: &[(String, Vec<usize>)]) -> CpuAllocationInfo {
+        let mut numa_nodes: BTreeMap<usize, NumaNodeAllocation> = BTreeMap::new();
+        let mut counted_cores = BTreeSet::new();
+        for cpu in &self.topology.0 {
+            let numa_node = numa_nodes
+                .entry(cpu.numa_node)
+                .or_insert_with(|| NumaNodeAllocation {
+                    id: cpu.numa_node,
+                    total_cpus: 0,
+                    free_cpus: 0,
+                    free_whole_cores: 0,
+                    partially_used_cores: 0,
+                });
+            numa_node.total_cpus += 1;
+            if self.free.contains(&cpu.id) {
+                numa_node.free_cpus += 1;
+            }
+            if counted_cores.insert(cpu.core_key()) {
+                let free_siblings = self
+                    .topology
+                    .siblings(cpu)
+                    .filter(|sibling| self.free.contains(&sibling.id))
+                    .count();
+                if free_siblings == self.topology.siblings(cpu).count() {
+                    numa_node.free_whole_cores += 1;
+                } else if free_siblings > 0 {
+                    numa_node.partially_used_cores += 1;
+                }
+            }
+        }
+
+        let mut hints = vec![];
+        let by_id: BTreeMap<_, _> = self.topology.0.iter().map(|cpu| (cpu.id, cpu)).collect();
+        let mut core_owners: BTreeMap<(usize, usize), BTreeSet<&str>> = BTreeMap::new();
+        for (name, cpus) in nodes {
+            let node_numa_nodes: BTreeSet<_> = cpus
+                .iter()
+                .filter_map(|id| by_id.get(id))
+                .map(|cpu| cpu.numa_node)
+                .collect();
+            if node_numa_nodes.len() > 1 {
+                hints.push(format!(
+                    "node `{name}` spans NUMA nodes {node_numa_nodes:?}, resize or recreate it when single NUMA node has enough free CPUs"
+                ));
+            }
+            for cpu in cpus.iter().filter_map(|id| by_id.get(id)) {
+                core_owners
+                    .entry(cpu.core_key())
+                    .or_default()
+                    .insert(name.as_str());
+            }
+        }
+        for ((package, core), owners) in core_owners {
+            if owners.len() > 1 {
+                hints.push(format!(
+                    "nodes {owners:?} share physical core {core} of package {package}, consider `whole_cores` allocation policy"
+                ));
+            }
+        }
+        let allocatable = self.allocatable();
+        let total_allocatable: usize = allocatable.values().map(|cpus| cpus.len()).sum();
+        let max_in_numa_node = allocatable
+            .values()
+            .map(|cpus| cpus.len())
+            .max()
+            .unwrap_or_default();
+        if numa_nodes.len() > 1 && total_allocatable > max_in_numa_node {
+            hints.push(format!(
+                "{total_allocatable} CPUs can be allocated, but only {max_in_numa_node} within single NUMA node"
+            ));
+        }
+        if self.policy == CpuAllocationPolicy::WholeCores && self.free.len() > total_allocatable {
+            hints.push(format!(
+                "{} free CPUs are on partially used cores and can't be allocated with `whole_cores` policy",
+                self.free.len() - total_allocatable
+            ));
+        }
+        CpuAllocationInfo {
+            policy: self.policy,
+            numa_nodes: numa_nodes.into_values().collect(),
+            hints,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
-pub struct CpuRegistry(Arc<Mutex<Vec<usize>>>);
+pub struct CpuRegistry(Arc<Mutex<Registry>>);
 
 impl CpuRegistry {
     pub fn new(available_cpus: usize) -> Self {
-        Self(Arc::new(Mutex::new(
-            (0..available_cpus).collect::<Vec<_>>(),
-        )))
+        Self::with_topology(
+            CpuTopology::flat(available_cpus),
+            CpuAllocationPolicy::default(),
+        )
+    }
+
+    pub fn with_topology(topology: CpuTopology, policy: CpuAllocationPolicy) -> Self {
+        Self(Arc::new(Mutex::new(Registry {
+            free: topology.0.iter().map(|cpu| cpu.id).collect(),
+            topology,
+            policy,
+        })))
     }
 
     pub async fn acquire(&self, count: usize) -> eyre::Result<Vec<usize>> {
         let mut registry = self.0.lock().await;
-        let len = registry.len();
-        if count > len {
+        let Some(cpus) = registry.select(count) else {
             bail!("not enough cpu cores")
+        };
+        for cpu in &cpus {
+            registry.free.remove(cpu);
         }
-        Ok(registry.drain(len - count..).collect())
+        Ok(cpus)
     }
 
     pub async fn mark_acquired(&self, cpus: &[usize]) {
-        self.0.lock().await.retain(|cpu| !cpus.contains(cpu));
+        let mut registry = self.0.lock().await;
+        for cpu in cpus {
+            registry.free.remove(cpu);
+        }
     }
 
     pub async fn release(&self, cpus: &mut Vec<usize>) {
-        self.0.lock().await.append(cpus);
+        self.0.lock().await.free.extend(cpus.drain(..));
     }
 
     pub async fn len(&self) -> usize {
-        self.0.lock().await.len()
+        self.0.lock().await.free.len()
+    }
+
+    /// Current allocation summary, with defragmentation hints based on CPUs assigned to `nodes`.
+    pub async fn allocation_info(&self, nodes: &[(String, Vec<usize>)]) -> CpuAllocationInfo {
+        self.0.lock().await.allocation_info(nodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::TempDir;
+
+    /// 2 NUMA nodes (one per package), 4 physical cores each, 2 threads per core.
+    /// Linux style enumeration - cpuN and cpuN+8 are siblings.
+    fn dual_socket_topology() -> CpuTopology {
+        CpuTopology(
+            (0..16)
+                .map(|id| CpuInfo {
+                    id,
+                    package: (id % 8) / 4,
+                    numa_node: (id % 8) / 4,
+                    core: id % 4,
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_parse_cpu_list() -> eyre::Result<()> {
+        assert_eq!(
+            vec![0, 1, 2, 3, 8, 10, 11],
+            parse_cpu_list("0-3,8,10-11\n")?
+        );
+        assert_eq!(vec![0], parse_cpu_list("0")?);
+        assert!(parse_cpu_list("a-b").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_topology() -> eyre::Result<()> {
+        let sysfs = TempDir::new()?.to_path_buf();
+        fs::create_dir_all(&sysfs)?;
+        fs::write(sysfs.join("online"), "0-1,3\n")?;
+        for (id, package, core, numa_node) in [(0, 0, 0, 0), (1, 1, 0, 1), (3, 1, 1, 1)] {
+            let cpu_dir = sysfs.join(format!("cpu{id}"));
+            fs::create_dir_all(cpu_dir.join("topology"))?;
+            fs::create_dir_all(cpu_dir.join(format!("node{numa_node}")))?;
+            fs::write(
+                cpu_dir.join("topology/physical_package_id"),
+                format!("{package}\n"),
+            )?;
+            fs::write(cpu_dir.join("topology/core_id"), format!("{core}\n"))?;
+        }
+        let topology = CpuTopology::load(&sysfs)?;
+        assert_eq!(
+            vec![
+                CpuInfo {
+                    id: 0,
+                    package: 0,
+                    numa_node: 0,
+                    core: 0
+                },
+                CpuInfo {
+                    id: 1,
+                    package: 1,
+                    numa_node: 1,
+                    core: 0
+                },
+                CpuInfo {
+                    id: 3,
+                    package: 1,
+                    numa_node: 1,
+                    core: 1
+                },
+            ],
+            topology.0
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_flat_allocation() -> eyre::Result<()> {
+        let registry = CpuRegistry::new(4);
+        assert_eq!(vec![3], registry.acquire(1).await?);
+        assert_eq!(vec![2, 1], registry.acquire(2).await?);
+        assert!(registry.acquire(2).await.is_err());
+        registry.release(&mut vec![2]).await;
+        assert_eq!(2, registry.len().await);
+        assert_eq!(vec![2, 0], registry.acquire(2).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pack_allocation() -> eyre::Result<()> {
+        let registry =
+            CpuRegistry::with_topology(dual_socket_topology(), CpuAllocationPolicy::Pack);
+        // siblings together, within single NUMA node
+        assert_eq!(vec![15, 7, 14], registry.acquire(3).await?);
+        // partially used core first, then the same (best fitting) NUMA node
+        assert_eq!(vec![6, 13, 5], registry.acquire(3).await?);
+        // doesn't fit into first NUMA node anymore
+        assert_eq!(vec![11, 3, 10, 2, 9], registry.acquire(5).await?);
+        // must span NUMA nodes
+        let cpus = registry.acquire(5).await?;
+        assert_eq!(vec![1, 8, 0, 12, 4], cpus);
+        assert_eq!(0, registry.len().await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spread_allocation() -> eyre::Result<()> {
+        let registry =
+            CpuRegistry::with_topology(dual_socket_topology(), CpuAllocationPolicy::Spread);
+        // one thread per physical core
+        assert_eq!(vec![15, 14, 13], registry.acquire(3).await?);
+        // the other NUMA node has more free cpus now
+        assert_eq!(vec![11, 10], registry.acquire(2).await?);
+        assert_eq!(vec![12, 7], registry.acquire(2).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_whole_cores_allocation() -> eyre::Result<()> {
+        let registry =
+            CpuRegistry::with_topology(dual_socket_topology(), CpuAllocationPolicy::WholeCores);
+        assert_eq!(vec![15, 7, 14], registry.acquire(3).await?);
+        // sibling 6 of cpu 14 is left free, but is not used by other nodes
+        assert_eq!(vec![13, 5, 12, 4], registry.acquire(4).await?);
+        assert_eq!(vec![11, 3], registry.acquire(2).await?);
+        let info = registry.allocation_info(&[]).await;
+        assert_eq!(
+            vec!["1 free CPUs are on partially used cores and can't be allocated with `whole_cores` policy".to_string()],
+            info.hints
+        );
+        assert_eq!(
+            NumaNodeAllocation {
+                id: 0,
+                total_cpus: 8,
+                free_cpus: 1,
+                free_whole_cores: 0,
+                partially_used_cores: 1,
+            },
+            info.numa_nodes[0]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_allocation_hints() -> eyre::Result<()> {
+        let registry =
+            CpuRegistry::with_topology(dual_socket_topology(), CpuAllocationPolicy::Pack);
+        let nodes = vec![
+            ("a".to_string(), vec![15, 7, 14, 3]),
+            ("b".to_string(), vec![6]),
+        ];
+        for (_, cpus) in &nodes {
+            registry.mark_acquired(cpus).await;
+        }
+        let info = registry.allocation_info(&nodes).await;
+        assert_eq!(
+            vec![
+                "node `a` spans NUMA nodes {0, 1}, resize or recreate it when single NUMA node has enough free CPUs".to_string(),
+                "nodes {\"a\", \"b\"} share physical core 2 of package 1, consider `whole_cores` allocation policy".to_string(),
+                "11 CPUs can be allocated, but only 7 within single NUMA node".to_string(),
+            ],
+            info.hints
+        );
+        Ok(())
     }
 }
@@ -0,0 +1,383 @@
+//! Enforcement of node data disk quota (declared by `VmConfig.disk_size_gb`), so single node
+//! can't fill the whole host disk and take down all other nodes.
+//!
+//! Two methods are supported:
+//! - `project` - XFS/ext4 project quota set on node data dir. Filesystem on which nodes
+//!   are stored must be mounted with `prjquota` option.
+//! - `loopback` - node data dir is a mount point of per node, sparse ext4 image.
+
+use crate::utils::is_mount_point;
+use bv_utils::cmd::run_cmd;
+use eyre::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+const DATA_IMAGE_FILENAME: &str = "data.img";
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DiskQuotaMethod {
+    /// Declared disk size is used only for admission accounting.
+    #[default]
+    Disabled,
+    Project,
+    Loopback,
+}
+
+/// Node data usage versus its quota.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct DiskUsage {
+    pub used_bytes: u64,
+    pub quota_bytes: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DiskQuota {
+    method: DiskQuotaMethod,
+    data_dir: PathBuf,
+    image_path: PathBuf,
+    project_id: u32,
+    quota_bytes: u64,
+}
+
+impl DiskQuota {
+    pub fn new(method: DiskQuotaMethod, node_dir: &Path, data_dir: PathBuf, node_id: Uuid) -> Self {
+        Self {
+            method,
+            data_dir,
+            image_path: node_dir.join(DATA_IMAGE_FILENAME),
+            project_id: project_id(node_id),
+            quota_bytes: 0,
+        }
+    }
+
+    /// Quota in bytes, `None` if quota is not enforced.
+    pub fn limit(&self) -> Option<u64> {
+        (self.method != DiskQuotaMethod::Disabled).then_some(self.quota_bytes)
+    }
+
+    /// Enforce given quota on node data dir. It is safe to call it multiple times,
+    /// e.g. on every node attach or when node declared disk size is changed.
+    pub async fn apply(&mut self, quota_bytes: u64) -> Result<()> {
+        self.quota_bytes = quota_bytes;
+        if self.method == DiskQuotaMethod::Loopback
+            && !self.image_path.exists()
+            && fs::read_dir(&self.data_dir)
+                .await?
+                .next_entry()
+                .await?
+                .is_some()
+        {
+            warn!(
+                "data dir '{}' is not empty, loopback disk quota can't be enabled for existing node",
+                self.data_dir.display()
+            );
+            self.method = DiskQuotaMethod::Disabled;
+        }
+        match self.method {
+            DiskQuotaMethod::Disabled => Ok(()),
+            DiskQuotaMethod::Project => self.apply_project_quota().await,
+            DiskQuotaMethod::Loopback => self.apply_loopback_quota().await,
+        }
+        .with_context(|| {
+            format!(
+                "failed to apply {:?} disk quota on '{}'",
+                self.method,
+                self.data_dir.display()
+            )
+        })
+    }
+
+    /// Check if node data are accessible in data dir, even if quota couldn't be applied.
+    /// With loopback quota, data are stored in the image, so data dir must be its mount point.
+    pub async fn is_data_accessible(&self) -> bool {
+        self.method != DiskQuotaMethod::Loopback
+            || !self.image_path.exists()
+            || is_mount_point(&self.data_dir).await
+    }
+
+    /// Remove quota, so node data dir can be deleted.
+    pub async fn release(&self) -> Result<()> {
+        match self.method {
+            DiskQuotaMethod::Disabled => {}
+            DiskQuotaMethod::Project => {
+                let mount_point = mount_point(&self.data_dir).await?;
+                set_project_limit(self.project_id, 0, &mount_point).await?;
+            }
+            DiskQuotaMethod::Loopback => {
+                if is_mount_point(&self.data_dir).await {
+                    run_cmd("umount", [&self.data_dir]).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Current usage of node data, `None` if quota is not enforced.
+    pub async fn usage(&self) -> Result<Option<DiskUsage>> {
+        if self.method == DiskQuotaMethod::Disabled {
+            return Ok(None);
+        }
+        // with project quota, `statfs` on project dir reports quota instead of filesystem limits
+        let out = run_cmd(
+            "df",
+            [
+                OsStr::new("--block-size=1"),
+                OsStr::new("--output=used"),
+                self.data_dir.as_os_str(),
+            ],
+        )
+        .await?;
+        let used_bytes = out
+            .lines()
+            .nth(1)
+            .ok_or_else(|| anyhow!("invalid `df` output: {out}"))?
+            .trim()
+            .parse()?;
+        Ok(Some(DiskUsage {
+            used_bytes,
+            quota_bytes: self.quota_bytes,
+        }))
+    }
+
+    async fn apply_project_quota(&mut self) -> Result<()> {
+        match dir_project_id(&self.data_dir).await? {
+            // keep already assigned id, it may be the one that resolved collision
+            Some(project_id) => self.project_id = project_id,
+            None => {
+                self.project_id =
+                    next_free_project_id(self.project_id, &self.used_project_ids().await?);
+                // mark all existing files, new ones inherit project id from data dir
+                run_cmd(
+                    "chattr",
+                    [
+                        OsStr::new("-R"),
+                        OsStr::new("-p"),
+                        OsStr::new(&self.project_id.to_string()),
+                        self.data_dir.as_os_str(),
+                    ],
+                )
+                .await?;
+            }
+        }
+        run_cmd("chattr", [OsStr::new("+P"), self.data_dir.as_os_str()]).await?;
+        let mount_point = mount_point(&self.data_dir).await?;
+        set_project_limit(self.project_id, self.quota_bytes, &mount_point).await
+    }
+
+    /// Project ids assigned to data dirs of other nodes.
+    async fn used_project_ids(&self) -> Result<HashSet<u32>> {
+        let mut used = HashSet::new();
+        let (Some(data_dir_name), Some(nodes_dir)) = (
+            self.data_dir.file_name(),
+            self.data_dir.parent().and_then(Path::parent),
+        ) else {
+            return Ok(used);
+        };
+        let mut entries = fs::read_dir(nodes_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let data_dir = entry.path().join(data_dir_name);
+            if data_dir != self.data_dir && data_dir.is_dir() {
+                if let Some(project_id) = dir_project_id(&data_dir).await? {
+                    used.insert(project_id);
+                }
+            }
+        }
+        Ok(used)
+    }
+
+    async fn apply_loopback_quota(&self) -> Result<()> {
+        if !self.image_path.exists() {
+            info!("Creating data image: {}", self.image_path.display());
+            fs::File::create(&self.image_path)
+                .await?
+                .set_len(self.quota_bytes)
+                .await?;
+            run_cmd(
+                "mkfs.ext4",
+                [
+                    OsStr::new("-q"),
+                    OsStr::new("-F"),
+                    OsStr::new("-m"),
+                    OsStr::new("0"),
+                    self.image_path.as_os_str(),
+                ],
+            )
+            .await?;
+        }
+        let image_size = fs::metadata(&self.image_path).await?.len();
+        if image_size > self.quota_bytes {
+            warn!(
+                "data image '{}' can't be shrunk from {image_size} to {} bytes",
+                self.image_path.display(),
+                self.quota_bytes
+            );
+        }
+        let mounted = is_mount_point(&self.data_dir).await;
+        if image_size < self.quota_bytes {
+            info!(
+                "Growing data image '{}' to {} bytes",
+                self.image_path.display(),
+                self.quota_bytes
+            );
+            fs::OpenOptions::new()
+                .write(true)
+                .open(&self.image_path)
+                .await?
+                .set_len(self.quota_bytes)
+                .await?;
+            if mounted {
+                let device = run_cmd(
+                    "findmnt",
+                    [
+                        OsStr::new("--noheadings"),
+                        OsStr::new("--output=SOURCE"),
+                        self.data_dir.as_os_str(),
+                    ],
+                )
+                .await?;
+                let device = device.trim();
+                run_cmd("losetup", ["--set-capacity", device]).await?;
+                run_cmd("resize2fs", [device]).await?;
+            } else {
+                run_cmd(
+                    "e2fsck",
+                    [
+                        OsStr::new("-f"),
+                        OsStr::new("-p"),
+                        self.image_path.as_os_str(),
+                    ],
+                )
+                .await?;
+                run_cmd("resize2fs", [&self.image_path]).await?;
+            }
+        }
+        if !mounted {
+            run_cmd(
+                "mount",
+                [
+                    OsStr::new("-o"),
+                    OsStr::new("loop"),
+                    self.image_path.as_os_str(),
+                    self.data_dir.as_os_str(),
+                ],
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Project id derived from node id, so it is stable across BV restarts and host migrations.
+/// Actual id may differ if it collides with other node, see `next_free_project_id`.
+fn project_id(node_id: Uuid) -> u32 {
+    let bytes = node_id.as_bytes();
+    // project id 0 is the default project of all files, so it can't be used
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).max(1)
+}
+
+/// Given project id, or the next one not used yet, if it collides with other node.
+fn next_free_project_id(mut project_id: u32, used: &HashSet<u32>) -> u32 {
+    while used.contains(&project_id) {
+        project_id = project_id.wrapping_add(1).max(1);
+    }
+    project_id
+}
+
+/// Project id assigned to given dir, `None` if it is the default one.
+async fn dir_project_id(path: &Path) -> Result<Option<u32>> {
+    let out = run_cmd(
+        "lsattr",
+        [OsStr::new("-d"), OsStr::new("-p"), path.as_os_str()],
+    )
+    .await?;
+    let project_id: u32 = out
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| anyhow!("invalid `lsattr` output: {out}"))?
+        .parse()?;
+    Ok((project_id != 0).then_some(project_id))
+}
+
+async fn mount_point(path: &Path) -> Result<String> {
+    let out = run_cmd(
+        "findmnt",
+        [
+            OsStr::new("--noheadings"),
+            OsStr::new("--output=TARGET"),
+            OsStr::new("--target"),
+            path.as_os_str(),
+        ],
+    )
+    .await?;
+    let mount_point = out.trim();
+    if mount_point.is_empty() {
+        bail!("can't find mount point of '{}'", path.display());
+    }
+    Ok(mount_point.to_string())
+}
+
+async fn set_project_limit(project_id: u32, bytes: u64, mount_point: &str) -> Result<()> {
+    // limits are given in 1KiB blocks, 0 means no limit
+    let blocks = bytes.div_ceil(1024).to_string();
+    run_cmd(
+        "setquota",
+        [
+            "-P",
+            &project_id.to_string(),
+            "0",
+            &blocks,
+            "0",
+            "0",
+            mount_point,
+        ],
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_project_id() {
+        assert_eq!(
+            0x12345678,
+            project_id(Uuid::parse_str("12345678-9abc-def0-1234-56789abcdef0").unwrap())
+        );
+        assert_eq!(1, project_id(Uuid::nil()));
+    }
+
+    #[test]
+    fn test_next_free_project_id() {
+        assert_eq!(7, next_free_project_id(7, &HashSet::from([1, 8])));
+        assert_eq!(9, next_free_project_id(7, &HashSet::from([7, 8])));
+        assert_eq!(
+            1,
+            next_free_project_id(u32::MAX, &HashSet::from([u32::MAX]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disabled_quota() -> Result<()> {
+        let mut quota = DiskQuota::new(
+            DiskQuotaMethod::Disabled,
+            Path::new("/node"),
+            PathBuf::from("/node/data"),
+            Uuid::new_v4(),
+        );
+        quota.apply(1_000_000_000).await?;
+        assert_eq!(None, quota.limit());
+        assert_eq!(None, quota.usage().await?);
+        quota.release().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1097 @@
+//! Firecracker microVM node runtime, for VM level isolation of nodes on multi-tenant hosts.
+//!
+//! Node rootfs is built from image (with apptainer, the same way as for containers) and packed
+//! into ext4 root drive, while protocol data are kept on separate ext4 data drive. Data drive
+//! is mounted on the host (as node `data` dir) only while VM is not running. VM is attached
+//! to the bridge with tap device, and babel (run and supervised by init script generated by BV)
+//! is connected over vsock.
+
+use crate::{
+    apptainer_machine::{
+        self, disk_size_bytes, NetConf, BABEL_BIN_PATH, DATA_DIR, DATA_DRIVE_MOUNT_POINT,
+        PLUGIN_MAIN_FILENAME, PLUGIN_PATH, PROTOCOL_DATA_PATH,
+    },
+    bv_config::FirecrackerConfig,
+    bv_context::BvContext,
+    image_cache,
+    net_shaping::{self, TrafficUsage},
+    node_context, node_env,
+    node_env::NODE_ENV_FILE_PATH,
+    node_state::{NodeState, VmConfig},
+    pal,
+    utils::is_mount_point,
+    BV_VAR_PATH,
+};
+use async_trait::async_trait;
+use babel_api::engine::NodeEnv;
+use bv_utils::{
+    cmd::{run_cmd, CmdError},
+    system::{gracefully_terminate_process, is_process_running},
+};
+use eyre::{anyhow, bail, Context, Result};
+use serde_json::json;
+use std::{
+    ffi::OsStr,
+    mem,
+    net::{IpAddr, Ipv4Addr},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Stdio,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use sysinfo::{Pid, PidExt};
+use tokio::{fs, process::Command, time::sleep};
+use tracing::{debug, warn};
+
+pub const VSOCK_SOCKET_FILE: &str = "firecracker.vsock";
+pub const BABEL_VSOCK_PORT: u32 = 4001;
+pub const ENGINE_VSOCK_PORT: u32 = 4002;
+const VM_DIR: &str = "vm";
+const BACKUP_VM_DIR: &str = "vm_backup";
+const STAGING_VM_DIR: &str = "vm_staging";
+const BUILD_DIR: &str = "rootfs_build";
+const ROOT_IMAGE_FILE: &str = "root.img";
+const ROOT_MOUNT_DIR: &str = "root_mnt";
+const PLUGIN_DIR: &str = "plugin";
+const DATA_IMAGE_FILE: &str = "data.img";
+const CONFIG_FILE: &str = "firecracker.json";
+const LOG_FILE: &str = "firecracker.log";
+const PID_FILE: &str = "firecracker.pid";
+const API_SOCKET_FILE: &str = "firecracker.socket";
+const DEFAULT_KERNEL_IMAGE: &str = "firecracker/vmlinux";
+const FIRECRACKER_BIN_NAME: &str = "firecracker";
+const INIT_PATH: &str = "/usr/bin/bv-init";
+const GUEST_CID: u32 = 3;
+/// Guest init bridges babel sockets to vsock with `socat`, so it must be provided by image.
+const SOCAT_PATHS: [&str; 3] = ["usr/bin/socat", "bin/socat", "usr/local/bin/socat"];
+/// Free space left on root drive, on top of rootfs size.
+const ROOT_IMAGE_HEADROOM_MB: u64 = 1024;
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(90);
+const KILL_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub struct FirecrackerMachine {
+    node_dir: PathBuf,
+    babel_path: PathBuf,
+    vm_dir: PathBuf,
+    backup_vm_dir: PathBuf,
+    staging_vm_dir: PathBuf,
+    staged_image_path: PathBuf,
+    data_dir: PathBuf,
+    data_image_path: PathBuf,
+    pid_path: PathBuf,
+    api_socket_path: PathBuf,
+    vsock_path: PathBuf,
+    kernel_image: PathBuf,
+    boot_args: Option<String>,
+
+    pid: Option<Pid>,
+
+    vm_id: String,
+    vm_name: String,
+    ip: IpAddr,
+    tap: String,
+    bridge: String,
+    net_conf: NetConf,
+
+    config: Config,
+    config_backup: Option<Config>,
+}
+
+#[derive(Debug, Clone)]
+struct Config {
+    image_uri: String,
+    vm: VmConfig,
+    node_env: NodeEnv,
+}
+
+pub async fn new(
+    bv_root: &Path,
+    net_conf: NetConf,
+    bv_context: &BvContext,
+    node_state: &NodeState,
+    babel_path: PathBuf,
+    config: FirecrackerConfig,
+) -> Result<FirecrackerMachine> {
+    if node_state.ipv6.is_some() || net_conf.ipv6.is_some() {
+        bail!(
+            "firecracker runtime doesn't support IPv6, but it is configured for node {}",
+            node_state.id
+        );
+    }
+    let node_dir = node_context::build_node_dir(bv_root, node_state.id);
+    let data_dir = node_dir.join(DATA_DIR);
+    if !data_dir.exists() {
+        fs::create_dir_all(&data_dir).await?;
+    }
+    let bridge = bv_context
+        .bridge
+        .clone()
+        .ok_or_else(|| anyhow!("firecracker runtime requires bridge network"))?;
+    let mut machine = FirecrackerMachine {
+        vm_dir: node_dir.join(VM_DIR),
+        backup_vm_dir: node_dir.join(BACKUP_VM_DIR),
+        staging_vm_dir: node_dir.join(STAGING_VM_DIR),
+        staged_image_path: node_dir.join(image_cache::STAGED_IMAGE_FILE),
+        data_image_path: node_dir.join(DATA_IMAGE_FILE),
+        pid_path: node_dir.join(PID_FILE),
+        api_socket_path: node_dir.join(API_SOCKET_FILE),
+        vsock_path: node_dir.join(VSOCK_SOCKET_FILE),
+        kernel_image: config
+            .kernel_image
+            .unwrap_or_else(|| bv_root.join(BV_VAR_PATH).join(DEFAULT_KERNEL_IMAGE)),
+        boot_args: config.boot_args,
+        node_dir,
+        babel_path,
+        data_dir,
+        pid: None,
+
+        vm_id: node_state.id.to_string(),
+        vm_name: node_state.name.clone(),
+        ip: node_state.ip,
+        tap: tap_name(&node_state.id.to_string()),
+        bridge,
+        net_conf,
+
+        config: Config {
+            image_uri: node_state.image.uri.clone(),
+            vm: node_state.vm_config.clone(),
+            node_env: node_env::new(
+                bv_context,
+                node_state,
+                PathBuf::from_str(DATA_DRIVE_MOUNT_POINT)?,
+                PathBuf::from_str(PROTOCOL_DATA_PATH)?,
+                // data drive size is hard limit
+                Some(disk_size_bytes(&node_state.vm_config)),
+            ),
+        },
+        config_backup: None,
+    };
+    machine.load_pid().await?;
+    if !machine.is_running() {
+        machine.create_data_image().await?;
+        machine.mount_data().await?;
+        if node_state.initialized
+            && babel_api::utils::protocol_data_stamp(&machine.data_dir)?.is_none()
+        {
+            babel_api::utils::touch_protocol_data(&machine.data_dir)?;
+        }
+    }
+    Ok(machine)
+}
+
+/// Host side tap device name, must fit into 15 chars.
+fn tap_name(vm_id: &str) -> String {
+    format!("fc{}", &vm_id.replace('-', "")[..12])
+}
+
+fn netmask(mask_bits: u8) -> Ipv4Addr {
+    Ipv4Addr::from(
+        u32::MAX
+            .checked_shl(32u32.saturating_sub(mask_bits as u32))
+            .unwrap_or(0),
+    )
+}
+
+impl FirecrackerMachine {
+    pub async fn build(&self) -> Result<()> {
+        if !is_built(&self.vm_dir) {
+            build_vm_dir(&self.vm_dir, &self.config.image_uri, &self.vm_id).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn attach(&mut self) -> Result<()> {
+        self.build().await
+    }
+
+    async fn load_pid(&mut self) -> Result<()> {
+        if self.pid_path.exists() {
+            let pid = Pid::from_str(fs::read_to_string(&self.pid_path).await?.trim())?;
+            if is_process_running(pid) {
+                self.pid = Some(pid);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_running(&self) -> bool {
+        self.pid.map(is_process_running).unwrap_or(false)
+    }
+
+    /// Create sparse data drive, or grow existing one if node disk size was increased.
+    async fn create_data_image(&self) -> Result<()> {
+        let size = disk_size_bytes(&self.config.vm);
+        if !self.data_image_path.exists() {
+            fs::File::create(&self.data_image_path)
+                .await?
+                .set_len(size)
+                .await?;
+            run_cmd(
+                "mkfs.ext4",
+                [
+                    OsStr::new("-q"),
+                    OsStr::new("-F"),
+                    self.data_image_path.as_os_str(),
+                ],
+            )
+            .await
+            .map_err(|err| anyhow!("failed to create '{}' data drive: {err:#}", self.vm_id))?;
+        } else if fs::metadata(&self.data_image_path).await?.len() < size {
+            self.unmount_data().await?;
+            fs::OpenOptions::new()
+                .write(true)
+                .open(&self.data_image_path)
+                .await?
+                .set_len(size)
+                .await?;
+            // e2fsck exits with 1 if errors were corrected
+            match run_cmd(
+                "e2fsck",
+                [
+                    OsStr::new("-f"),
+                    OsStr::new("-p"),
+                    self.data_image_path.as_os_str(),
+                ],
+            )
+            .await
+            {
+                Ok(_) | Err(CmdError::Failed { code: 1, .. }) => {}
+                Err(err) => bail!("failed to check '{}' data drive: {err:#}", self.vm_id),
+            }
+            run_cmd("resize2fs", [&self.data_image_path])
+                .await
+                .map_err(|err| anyhow!("failed to resize '{}' data drive: {err:#}", self.vm_id))?;
+        }
+        Ok(())
+    }
+
+    /// Mount data drive on the host, so node data are accessible while VM is not running.
+    async fn mount_data(&self) -> Result<()> {
+        if !is_mount_point(&self.data_dir).await {
+            run_cmd(
+                "mount",
+                [
+                    OsStr::new("-o"),
+                    OsStr::new("loop"),
+                    self.data_image_path.as_os_str(),
+                    self.data_dir.as_os_str(),
+                ],
+            )
+            .await
+            .map_err(|err| anyhow!("failed to mount '{}' data drive: {err:#}", self.vm_id))?;
+        }
+        Ok(())
+    }
+
+    async fn unmount_data(&self) -> Result<()> {
+        if is_mount_point(&self.data_dir).await {
+            run_cmd("umount", [&self.data_dir]).await?;
+        }
+        Ok(())
+    }
+
+    /// Copy current babel, node env and init script into root drive.
+    async fn prepare_root_drive(&self) -> Result<()> {
+        let mount_dir = self.node_dir.join(ROOT_MOUNT_DIR);
+        fs::create_dir_all(&mount_dir).await?;
+        run_cmd(
+            "mount",
+            [
+                OsStr::new("-o"),
+                OsStr::new("loop"),
+                self.vm_dir.join(ROOT_IMAGE_FILE).as_os_str(),
+                mount_dir.as_os_str(),
+            ],
+        )
+        .await
+        .map_err(|err| anyhow!("failed to mount '{}' root drive: {err:#}", self.vm_id))?;
+        let res: Result<()> = async {
+            fs::copy(
+                &self.babel_path,
+                mount_dir.join(BABEL_BIN_PATH.trim_start_matches('/')),
+            )
+            .await
+            .with_context(|| format!("babel binary not found: {}", self.babel_path.display()))?;
+            node_env::save(&self.config.node_env, &mount_dir).await?;
+            let init_path = mount_dir.join(INIT_PATH.trim_start_matches('/'));
+            fs::write(&init_path, init_script(&self.vm_name.replace('_', "-"))).await?;
+            fs::set_permissions(&init_path, std::fs::Permissions::from_mode(0o755)).await?;
+            Ok(())
+        }
+        .await;
+        run_cmd("umount", [&mount_dir]).await?;
+        res
+    }
+
+    async fn create_tap(&self) -> Result<()> {
+        if run_cmd("ip", ["link", "show", &self.tap]).await.is_err() {
+            run_cmd("ip", ["tuntap", "add", "dev", &self.tap, "mode", "tap"]).await?;
+        }
+        run_cmd("ip", ["link", "set", &self.tap, "master", &self.bridge]).await?;
+        run_cmd("ip", ["link", "set", &self.tap, "up"]).await?;
+        // fresh tap has no limits, so there is nothing to clear
+        if self.config.vm.bandwidth != Default::default() {
+            net_shaping::apply(&self.tap, &self.config.vm.bandwidth)
+                .await
+                .with_context(|| format!("failed to apply bandwidth limit on '{}'", self.tap))?;
+        }
+        Ok(())
+    }
+
+    async fn delete_tap(&self) -> Result<()> {
+        if run_cmd("ip", ["link", "show", &self.tap]).await.is_ok() {
+            run_cmd("ip", ["link", "delete", &self.tap]).await?;
+        }
+        Ok(())
+    }
+
+    fn kernel_boot_args(&self) -> String {
+        let mut args = format!(
+            "console=ttyS0 reboot=k panic=1 pci=off init={INIT_PATH} ip={}::{}:{}::eth0:off",
+            self.ip,
+            self.net_conf.bridge,
+            netmask(self.net_conf.mask_bits)
+        );
+        if let Some(extra) = &self.boot_args {
+            args.push(' ');
+            args.push_str(extra);
+        }
+        args
+    }
+
+    fn vm_config(&self) -> serde_json::Value {
+        json!({
+            "boot-source": {
+                "kernel_image_path": self.kernel_image,
+                "boot_args": self.kernel_boot_args(),
+            },
+            "drives": [
+                {
+                    "drive_id": "rootfs",
+                    "path_on_host": self.vm_dir.join(ROOT_IMAGE_FILE),
+                    "is_root_device": true,
+                    "is_read_only": false,
+                },
+                {
+                    "drive_id": "data",
+                    "path_on_host": self.data_image_path,
+                    "is_root_device": false,
+                    "is_read_only": false,
+                },
+            ],
+            "machine-config": {
+                "vcpu_count": self.config.vm.vcpu_count,
+                "mem_size_mib": self.config.vm.mem_size_mb,
+            },
+            "network-interfaces": [
+                {
+                    "iface_id": "eth0",
+                    "host_dev_name": self.tap,
+                },
+            ],
+            "vsock": {
+                "guest_cid": GUEST_CID,
+                "uds_path": self.vsock_path,
+            },
+        })
+    }
+
+    async fn start_vm(&mut self) -> Result<()> {
+        self.unmount_data().await?;
+        self.create_data_image().await?;
+        self.prepare_root_drive().await?;
+        self.create_tap().await?;
+        for path in [&self.api_socket_path, &self.vsock_path] {
+            if path.exists() {
+                fs::remove_file(path).await?;
+            }
+        }
+        let config_path = self.node_dir.join(CONFIG_FILE);
+        fs::write(
+            &config_path,
+            serde_json::to_string_pretty(&self.vm_config())?,
+        )
+        .await?;
+        let log = std::fs::File::create(self.node_dir.join(LOG_FILE))?;
+        let mut cmd = Command::new(FIRECRACKER_BIN_NAME);
+        cmd.arg("--api-sock")
+            .arg(&self.api_socket_path)
+            .arg("--config-file")
+            .arg(&config_path)
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log);
+        debug!("start vm {}: '{:?}'", self.vm_id, cmd);
+        let pid = cmd
+            .spawn()?
+            .id()
+            .map(Pid::from_u32)
+            .ok_or_else(|| anyhow!("failed to start vm {}", self.vm_id))?;
+        if let Err(err) = fs::write(&self.pid_path, pid.to_string()).await {
+            warn!(
+                "failed to save firecracker pid to file '{}': {:#}",
+                self.pid_path.display(),
+                err
+            );
+        }
+        self.pid = Some(pid);
+        Ok(())
+    }
+
+    async fn stop_vm(&mut self, force: bool) -> Result<()> {
+        if let Some(pid) = self.pid.filter(|pid| is_process_running(*pid)) {
+            if force {
+                // firecracker exits immediately on SIGTERM
+                gracefully_terminate_process(pid, KILL_TIMEOUT).await;
+            } else {
+                if self.is_paused().await {
+                    // paused guest can't handle shutdown request
+                    self.set_vm_state("Resumed").await?;
+                }
+                self.api_call(
+                    "PUT",
+                    "/actions",
+                    Some(json!({ "action_type": "SendCtrlAltDel" })),
+                )
+                .await?;
+                let start = Instant::now();
+                while is_process_running(pid) && start.elapsed() < SHUTDOWN_TIMEOUT {
+                    sleep(Duration::from_secs(1)).await;
+                }
+            }
+            if is_process_running(pid) {
+                bail!("failed to stop vm {}", self.vm_id);
+            }
+        }
+        self.pid = None;
+        for path in [&self.pid_path, &self.api_socket_path, &self.vsock_path] {
+            if path.exists() {
+                fs::remove_file(path).await?;
+            }
+        }
+        self.delete_tap().await?;
+        self.mount_data().await
+    }
+
+    /// Call Firecracker API, exposed on VM process UDS.
+    async fn api_call(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<String> {
+        let mut args = vec![
+            "--silent".to_string(),
+            "--show-error".to_string(),
+            "--fail".to_string(),
+            "--unix-socket".to_string(),
+            self.api_socket_path.to_string_lossy().to_string(),
+            "-X".to_string(),
+            method.to_string(),
+        ];
+        if let Some(body) = body {
+            args.push("-H".to_string());
+            args.push("Content-Type: application/json".to_string());
+            args.push("-d".to_string());
+            args.push(body.to_string());
+        }
+        args.push(format!("http://localhost{path}"));
+        run_cmd("curl", args)
+            .await
+            .map_err(|err| anyhow!("firecracker API call for {} failed: {err:#}", self.vm_id))
+    }
+
+    async fn set_vm_state(&self, state: &str) -> Result<()> {
+        self.api_call("PATCH", "/vm", Some(json!({ "state": state })))
+            .await?;
+        Ok(())
+    }
+
+    async fn is_paused(&self) -> bool {
+        match self.api_call("GET", "/", None).await {
+            Ok(info) => serde_json::from_str::<serde_json::Value>(&info)
+                .map(|info| info["state"] == "Paused")
+                .unwrap_or(false),
+            Err(_) => false,
+        }
+    }
+
+    /// Returns image uri, if root drive for it is already built in staging dir.
+    async fn staged_image(&self) -> Option<String> {
+        if !self.staging_vm_dir.exists() {
+            return None;
+        }
+        fs::read_to_string(&self.staged_image_path).await.ok()
+    }
+}
+
+/// Guest init, that setup guest, bridge babel sockets to vsock and keep babel running.
+fn init_script(hostname: &str) -> String {
+    format!(
+        r#"#!/bin/sh
+# generated by blockvisor
+mount -t proc proc /proc
+mount -t sysfs sysfs /sys
+mount -t devtmpfs devtmpfs /dev 2>/dev/null
+# let init handle ctrl-alt-del, sent by firecracker on shutdown request
+echo 0 > /proc/sys/kernel/ctrl-alt-del
+mkdir -p {DATA_DRIVE_MOUNT_POINT}
+mount /dev/vdb {DATA_DRIVE_MOUNT_POINT}
+hostname {hostname}
+set -a
+. /{NODE_ENV_FILE_PATH}
+set +a
+rm -f /engine.socket /babel.socket
+socat UNIX-LISTEN:/engine.socket,fork VSOCK-CONNECT:2:{ENGINE_VSOCK_PORT} &
+socat VSOCK-LISTEN:{BABEL_VSOCK_PORT},fork,reuseaddr UNIX-CONNECT:/babel.socket &
+shutdown() {{
+  kill -TERM $babel_pid
+  wait $babel_pid
+  sync
+  umount {DATA_DRIVE_MOUNT_POINT}
+  reboot -f
+}}
+trap shutdown INT TERM
+while true; do
+  {BABEL_BIN_PATH} &
+  babel_pid=$!
+  wait $babel_pid
+  sleep 1
+done
+"#
+    )
+}
+
+async fn build_vm_dir(vm_dir: &Path, image_uri: &str, vm_id: &str) -> Result<()> {
+    fs::create_dir_all(vm_dir).await?;
+    let build_dir = vm_dir.join(BUILD_DIR);
+    let res: Result<()> = async {
+        apptainer_machine::build_rootfs(&build_dir, image_uri, vm_id).await?;
+        check_guest_tools(&build_dir, image_uri)?;
+        // plugin is loaded by BV on the host, so keep its copy outside root drive
+        let plugin_dir = vm_dir.join(PLUGIN_DIR);
+        if plugin_dir.exists() {
+            fs::remove_dir_all(&plugin_dir).await?;
+        }
+        run_cmd(
+            "cp",
+            [
+                OsStr::new("-a"),
+                build_dir.join(PLUGIN_PATH).as_os_str(),
+                plugin_dir.as_os_str(),
+            ],
+        )
+        .await?;
+        let rootfs_size = {
+            let build_dir = build_dir.clone();
+            tokio::task::spawn_blocking(move || fs_extra::dir::get_size(build_dir)).await??
+        };
+        let size_mb = rootfs_size / 1_000_000 * 5 / 4 + ROOT_IMAGE_HEADROOM_MB;
+        // create image under temporary name, so partially created one is never used
+        let tmp_image = vm_dir.join(format!("{ROOT_IMAGE_FILE}.tmp"));
+        run_cmd(
+            "mkfs.ext4",
+            [
+                OsStr::new("-q"),
+                OsStr::new("-F"),
+                OsStr::new("-d"),
+                build_dir.as_os_str(),
+                tmp_image.as_os_str(),
+                OsStr::new(&format!("{size_mb}M")),
+            ],
+        )
+        .await
+        .map_err(|err| anyhow!("failed to create '{vm_id}' root drive: {err:#}"))?;
+        fs::rename(&tmp_image, vm_dir.join(ROOT_IMAGE_FILE)).await?;
+        Ok(())
+    }
+    .await;
+    if build_dir.exists() {
+        fs::remove_dir_all(&build_dir).await?;
+    }
+    res
+}
+
+/// Check if rootfs provides tools used by guest init.
+fn check_guest_tools(rootfs_dir: &Path, image_uri: &str) -> Result<()> {
+    // rootfs symlinks may be absolute, so don't follow them on the host
+    if !SOCAT_PATHS
+        .iter()
+        .any(|path| rootfs_dir.join(path).symlink_metadata().is_ok())
+    {
+        bail!("image '{image_uri}' doesn't provide `socat`, required by firecracker runtime");
+    }
+    Ok(())
+}
+
+fn is_built(vm_dir: &Path) -> bool {
+    vm_dir.join(ROOT_IMAGE_FILE).exists()
+}
+
+#[async_trait]
+impl pal::VirtualMachine for FirecrackerMachine {
+    async fn state(&self) -> pal::VmState {
+        if self.is_running() {
+            if self.is_paused().await {
+                pal::VmState::SUSPENDED
+            } else {
+                pal::VmState::RUNNING
+            }
+        } else {
+            pal::VmState::SHUTOFF
+        }
+    }
+
+    async fn delete(&mut self) -> Result<()> {
+        if self.shutdown().await.is_err() {
+            self.force_shutdown().await?;
+        }
+        self.delete_tap().await?;
+        self.unmount_data().await?;
+        let root_mount_dir = self.node_dir.join(ROOT_MOUNT_DIR);
+        if is_mount_point(&root_mount_dir).await {
+            run_cmd("umount", [&root_mount_dir]).await?;
+        }
+        if self.node_dir.exists() {
+            fs::remove_dir_all(&self.node_dir).await?;
+        }
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        self.stop_vm(false).await
+    }
+
+    async fn force_shutdown(&mut self) -> Result<()> {
+        self.stop_vm(true).await
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        if !self.is_running() {
+            self.start_vm().await?;
+        }
+        Ok(())
+    }
+
+    async fn suspend(&mut self) -> Result<()> {
+        self.set_vm_state("Paused").await
+    }
+
+    async fn resume(&mut self) -> Result<()> {
+        if self.is_paused().await {
+            self.set_vm_state("Resumed").await?;
+        }
+        Ok(())
+    }
+
+    async fn resize(&mut self, node_state: &NodeState) -> Result<()> {
+        let bandwidth_changed = self.config.vm.bandwidth != node_state.vm_config.bandwidth;
+        // vCPUs, memory and disk size are applied on next VM start
+        self.config.vm = node_state.vm_config.clone();
+        if bandwidth_changed && self.is_running() {
+            net_shaping::apply(&self.tap, &self.config.vm.bandwidth)
+                .await
+                .with_context(|| format!("failed to apply bandwidth limit on '{}'", self.tap))?;
+        }
+        Ok(())
+    }
+
+    async fn stage_upgrade(&mut self, node_state: &NodeState) -> Result<()> {
+        if self.staged_image().await.as_ref() == Some(&node_state.image.uri) {
+            // already staged by previous, not finished upgrade attempt
+            return Ok(());
+        }
+        self.drop_staged().await?;
+        if let Err(err) =
+            build_vm_dir(&self.staging_vm_dir, &node_state.image.uri, &self.vm_id).await
+        {
+            if let Err(cleanup_err) = self.drop_staged().await {
+                warn!(
+                    "failed to cleanup staging dir for {}: {cleanup_err:#}",
+                    self.vm_id
+                );
+            }
+            return Err(err);
+        }
+        fs::write(&self.staged_image_path, &node_state.image.uri).await?;
+        Ok(())
+    }
+
+    async fn drop_staged(&mut self) -> Result<()> {
+        if self.staging_vm_dir.exists() {
+            fs::remove_dir_all(&self.staging_vm_dir).await?;
+        }
+        if self.staged_image_path.exists() {
+            fs::remove_file(&self.staged_image_path).await?;
+        }
+        Ok(())
+    }
+
+    async fn upgrade(&mut self, node_state: &NodeState) -> Result<()> {
+        if self.is_running() {
+            bail!("can't upgrade running vm")
+        }
+
+        self.config_backup = Some(self.config.clone());
+        self.config.image_uri = node_state.image.uri.clone();
+        self.config.vm = node_state.vm_config.clone();
+        self.update_node_env(node_state);
+        self.config.node_env.data_quota_bytes = Some(disk_size_bytes(&self.config.vm));
+
+        fs::rename(&self.vm_dir, &self.backup_vm_dir).await?;
+        if self.staged_image().await.as_ref() == Some(&self.config.image_uri) {
+            // new root drive was built in background, so just swap it
+            fs::rename(&self.staging_vm_dir, &self.vm_dir).await?;
+            fs::remove_file(&self.staged_image_path).await?;
+        } else {
+            self.drop_staged().await?;
+        }
+
+        self.build().await
+    }
+
+    async fn drop_backup(&mut self) -> Result<()> {
+        if self.backup_vm_dir.exists() {
+            fs::remove_dir_all(&self.backup_vm_dir).await?
+        }
+        self.config_backup = None;
+        Ok(())
+    }
+
+    async fn rollback(&mut self) -> Result<()> {
+        if self.backup_vm_dir.exists() {
+            if self.vm_dir.exists() {
+                fs::remove_dir_all(&self.vm_dir).await?;
+            }
+            fs::rename(&self.backup_vm_dir, &self.vm_dir).await?;
+        }
+        // data drive is never shrunk, so only restored config is needed
+        if let Some(mut backup) = self.config_backup.take() {
+            mem::swap(&mut backup, &mut self.config);
+            self.config_backup = Some(backup);
+        }
+        self.build().await
+    }
+
+    async fn recover(&mut self) -> Result<()> {
+        // babel is supervised inside VM, so whole VM is restarted
+        if self.is_running() {
+            self.force_shutdown().await?;
+        }
+        self.start().await
+    }
+
+    fn node_env(&self) -> NodeEnv {
+        self.config.node_env.clone()
+    }
+
+    fn update_node_env(&mut self, node_state: &NodeState) {
+        node_env::update_state(&mut self.config.node_env, node_state);
+    }
+
+    fn plugin_path(&self) -> PathBuf {
+        self.vm_dir.join(PLUGIN_DIR).join(PLUGIN_MAIN_FILENAME)
+    }
+
+    fn data_dir(&self) -> PathBuf {
+        self.data_dir.clone()
+    }
+
+    fn check_data_dir_access(&self) -> Result<()> {
+        if self.is_running() {
+            bail!(
+                "'{}' data drive is attached to running VM, so node data are not accessible on the host",
+                self.vm_id
+            );
+        }
+        Ok(())
+    }
+
+    async fn traffic_usage(&self) -> Result<Option<TrafficUsage>> {
+        if !self.is_running() {
+            return Ok(None);
+        }
+        Ok(Some(net_shaping::usage(&self.tap).await?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        apptainer_machine::Ipv6NetConf,
+        node::tests::{default_bv_context, default_node_state},
+        pal::VirtualMachine,
+    };
+    use assert_fs::TempDir;
+    use std::net::Ipv6Addr;
+
+    fn test_net_conf() -> NetConf {
+        NetConf {
+            mask_bits: 24,
+            gateway: IpAddr::from_str("172.16.0.1").unwrap(),
+            bridge: IpAddr::from_str("172.16.0.2").unwrap(),
+            ipv6: None,
+        }
+    }
+
+    /// Machine with the same paths as built by `new`, but without creating data drive on the host.
+    fn test_machine(bv_root: &Path, node_state: &NodeState) -> FirecrackerMachine {
+        let node_dir = node_context::build_node_dir(bv_root, node_state.id);
+        FirecrackerMachine {
+            vm_dir: node_dir.join(VM_DIR),
+            backup_vm_dir: node_dir.join(BACKUP_VM_DIR),
+            staging_vm_dir: node_dir.join(STAGING_VM_DIR),
+            staged_image_path: node_dir.join(image_cache::STAGED_IMAGE_FILE),
+            data_dir: node_dir.join(DATA_DIR),
+            data_image_path: node_dir.join(DATA_IMAGE_FILE),
+            pid_path: node_dir.join(PID_FILE),
+            api_socket_path: node_dir.join(API_SOCKET_FILE),
+            vsock_path: node_dir.join(VSOCK_SOCKET_FILE),
+            kernel_image: bv_root.join(BV_VAR_PATH).join(DEFAULT_KERNEL_IMAGE),
+            boot_args: Some("quiet".to_string()),
+            node_dir,
+            babel_path: bv_root.join("babel"),
+            pid: None,
+            vm_id: node_state.id.to_string(),
+            vm_name: node_state.name.clone(),
+            ip: node_state.ip,
+            tap: tap_name(&node_state.id.to_string()),
+            bridge: "bvbr7".to_string(),
+            net_conf: test_net_conf(),
+            config: Config {
+                image_uri: node_state.image.uri.clone(),
+                vm: node_state.vm_config.clone(),
+                node_env: node_env::new(
+                    &default_bv_context(),
+                    node_state,
+                    PathBuf::from_str(DATA_DRIVE_MOUNT_POINT).unwrap(),
+                    PathBuf::from_str(PROTOCOL_DATA_PATH).unwrap(),
+                    Some(disk_size_bytes(&node_state.vm_config)),
+                ),
+            },
+            config_backup: None,
+        }
+    }
+
+    async fn write_root_image(vm_dir: &Path, content: &str) -> Result<()> {
+        fs::create_dir_all(vm_dir).await?;
+        fs::write(vm_dir.join(ROOT_IMAGE_FILE), content).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_network_params() {
+        assert_eq!(
+            "fc1b8b8d7c2e4f",
+            tap_name("1b8b8d7c-2e4f-4a5e-9c1d-2b0f7a6c3e11")
+        );
+        assert_eq!(Ipv4Addr::new(255, 255, 255, 0), netmask(24));
+        assert_eq!(Ipv4Addr::new(255, 255, 255, 192), netmask(26));
+        assert_eq!(Ipv4Addr::new(0, 0, 0, 0), netmask(0));
+    }
+
+    #[test]
+    fn test_init_script() {
+        let script = init_script("node-name");
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.contains("hostname node-name\n"));
+        assert!(script.contains("mount /dev/vdb /blockjoy\n"));
+        assert!(script.contains(". /var/lib/babel/node_env\n"));
+        assert!(script.contains("VSOCK-CONNECT:2:4002"));
+        assert!(script.contains("VSOCK-LISTEN:4001"));
+        assert!(script.contains("  /usr/bin/babel &\n"));
+    }
+
+    #[tokio::test]
+    async fn test_check_guest_tools() -> Result<()> {
+        let rootfs_dir = TempDir::new()?.to_path_buf();
+        let err = check_guest_tools(&rootfs_dir, "docker://test").unwrap_err();
+        assert!(err.to_string().contains("doesn't provide `socat`"));
+        fs::create_dir_all(rootfs_dir.join("usr/bin")).await?;
+        // dangling on the host, but valid in the guest
+        std::os::unix::fs::symlink("/bin/busybox", rootfs_dir.join("usr/bin/socat"))?;
+        check_guest_tools(&rootfs_dir, "docker://test")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_refused() -> Result<()> {
+        let tmp_root = TempDir::new()?.to_path_buf();
+        let mut node_state = default_node_state();
+        node_state.ipv6 = Some(Ipv6Addr::from_str("fd00::10")?);
+        let err = new(
+            &tmp_root,
+            test_net_conf(),
+            &default_bv_context(),
+            &node_state,
+            tmp_root.join("babel"),
+            Default::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("doesn't support IPv6"));
+
+        node_state.ipv6 = None;
+        let mut net_conf = test_net_conf();
+        net_conf.ipv6 = Some(Ipv6NetConf {
+            mask_bits: 64,
+            bridge: Ipv6Addr::from_str("fd00::1")?,
+        });
+        assert!(new(
+            &tmp_root,
+            net_conf,
+            &default_bv_context(),
+            &node_state,
+            tmp_root.join("babel"),
+            Default::default(),
+        )
+        .await
+        .is_err());
+        // nothing is created for refused node
+        assert!(!node_context::build_node_dir(&tmp_root, node_state.id).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bridge_required() -> Result<()> {
+        let tmp_root = TempDir::new()?.to_path_buf();
+        let mut bv_context = default_bv_context();
+        bv_context.bridge = None;
+        let err = new(
+            &tmp_root,
+            test_net_conf(),
+            &bv_context,
+            &default_node_state(),
+            tmp_root.join("babel"),
+            Default::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("requires bridge network"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_config() {
+        let tmp_root = Path::new("/bv");
+        let node_state = default_node_state();
+        let machine = test_machine(tmp_root, &node_state);
+        let node_dir = node_context::build_node_dir(tmp_root, node_state.id);
+
+        assert_eq!(
+            "console=ttyS0 reboot=k panic=1 pci=off init=/usr/bin/bv-init \
+             ip=172.16.0.10::172.16.0.2:255.255.255.0::eth0:off quiet",
+            machine.kernel_boot_args()
+        );
+        let config = machine.vm_config();
+        assert_eq!(
+            json!(tmp_root.join(BV_VAR_PATH).join(DEFAULT_KERNEL_IMAGE)),
+            config["boot-source"]["kernel_image_path"]
+        );
+        assert_eq!(
+            json!(machine.kernel_boot_args()),
+            config["boot-source"]["boot_args"]
+        );
+        assert_eq!(
+            json!(node_dir.join("vm/root.img")),
+            config["drives"][0]["path_on_host"]
+        );
+        assert_eq!(json!(true), config["drives"][0]["is_root_device"]);
+        assert_eq!(
+            json!(node_dir.join("data.img")),
+            config["drives"][1]["path_on_host"]
+        );
+        assert_eq!(json!(false), config["drives"][1]["is_root_device"]);
+        assert_eq!(
+            json!(node_state.vm_config.vcpu_count),
+            config["machine-config"]["vcpu_count"]
+        );
+        assert_eq!(
+            json!(node_state.vm_config.mem_size_mb),
+            config["machine-config"]["mem_size_mib"]
+        );
+        assert_eq!(
+            json!("fc4931bafa92d9"),
+            config["network-interfaces"][0]["host_dev_name"]
+        );
+        assert_eq!(json!(GUEST_CID), config["vsock"]["guest_cid"]);
+        assert_eq!(
+            json!(node_dir.join(VSOCK_SOCKET_FILE)),
+            config["vsock"]["uds_path"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_not_running_machine() -> Result<()> {
+        let tmp_root = TempDir::new()?.to_path_buf();
+        let mut node_state = default_node_state();
+        let mut machine = test_machine(&tmp_root, &node_state);
+        let node_dir = node_context::build_node_dir(&tmp_root, node_state.id);
+
+        assert_eq!(pal::VmState::SHUTOFF, machine.state().await);
+        assert_eq!(None, machine.traffic_usage().await?);
+        assert_eq!(node_dir.join("vm/plugin/main.rhai"), machine.plugin_path());
+        assert_eq!(node_dir.join(DATA_DIR), machine.data_dir());
+        machine.check_data_dir_access()?;
+
+        // new resources are only remembered, until next VM start
+        node_state.vm_config.vcpu_count = 4;
+        node_state.vm_config.bandwidth.egress_mbit = Some(100);
+        machine.resize(&node_state).await?;
+        assert_eq!(node_state.vm_config, machine.config.vm);
+
+        // already built root drive is not rebuilt
+        write_root_image(&machine.vm_dir, "v1").await?;
+        machine.attach().await?;
+        assert_eq!(None, machine.pid);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_and_rollback() -> Result<()> {
+        let tmp_root = TempDir::new()?.to_path_buf();
+        let node_state = default_node_state();
+        let mut machine = test_machine(&tmp_root, &node_state);
+        write_root_image(&machine.vm_dir, "v1").await?;
+
+        let mut upgraded_state = node_state.clone();
+        upgraded_state.image.uri = "image.uri.v2".to_string();
+        upgraded_state.vm_config.disk_size_gb = 5;
+        // pretend that new root drive was built in background
+        write_root_image(&machine.staging_vm_dir, "v2").await?;
+        fs::write(&machine.staged_image_path, "image.uri.v2").await?;
+        // already staged, so no rebuild is needed
+        machine.stage_upgrade(&upgraded_state).await?;
+
+        machine.upgrade(&upgraded_state).await?;
+        assert_eq!(
+            "v2",
+            fs::read_to_string(machine.vm_dir.join(ROOT_IMAGE_FILE)).await?
+        );
+        assert_eq!("image.uri.v2", machine.config.image_uri);
+        assert_eq!(upgraded_state.vm_config, machine.config.vm);
+        assert_eq!(
+            Some(disk_size_bytes(&upgraded_state.vm_config)),
+            machine.node_env().data_quota_bytes
+        );
+
+        machine.rollback().await?;
+        assert_eq!(
+            "v1",
+            fs::read_to_string(machine.vm_dir.join(ROOT_IMAGE_FILE)).await?
+        );
+        assert_eq!(node_state.image.uri, machine.config.image_uri);
+        assert_eq!(node_state.vm_config, machine.config.vm);
+        assert_eq!(
+            Some(disk_size_bytes(&node_state.vm_config)),
+            machine.node_env().data_quota_bytes
+        );
+
+        machine.drop_backup().await?;
+        assert!(machine.config_backup.is_none());
+        Ok(())
+    }
+}
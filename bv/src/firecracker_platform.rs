@@ -0,0 +1,284 @@
+use crate::{
+    apptainer_machine::NetConf,
+    apptainer_platform::NodeConnectionState,
+    bv_config,
+    bv_config::{FirecrackerConfig, SharedConfig},
+    bv_context::BvContext,
+    cpu_registry::CpuTopology,
+    firecracker_machine, firewall, linux_platform,
+    node::NODE_REQUEST_TIMEOUT,
+    node_context,
+    node_state::NodeState,
+    nodes_manager::NodesDataCache,
+    pal::{self, AvailableResources, NodeConnection, NodeFirewallConfig, Pal, VirtualMachine},
+    services,
+};
+use async_trait::async_trait;
+use bv_utils::with_retry;
+use eyre::{bail, Result};
+use std::{
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+};
+use tracing::debug;
+use uuid::Uuid;
+
+/// Platform running nodes in Firecracker microVMs, attached to the bridge.
+#[derive(Debug)]
+pub struct FirecrackerPlatform {
+    base: linux_platform::LinuxPlatform,
+    net_conf: NetConf,
+    config: FirecrackerConfig,
+    firewall_backend: firewall::Backend,
+}
+
+impl Deref for FirecrackerPlatform {
+    type Target = linux_platform::LinuxPlatform;
+
+    fn deref(&self) -> &Self::Target {
+        &self.base
+    }
+}
+
+impl DerefMut for FirecrackerPlatform {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.base
+    }
+}
+
+impl FirecrackerPlatform {
+    pub async fn new(config: &bv_config::Config) -> Result<Self> {
+        if config.net_conf.ipv6.is_some() {
+            // guest network is configured by kernel `ip=` boot argument, which is IPv4 only
+            bail!("dual-stack (IPv6) host network is not supported by firecracker runtime");
+        }
+        Ok(Self {
+            base: linux_platform::LinuxPlatform::new().await?,
+            net_conf: NetConf {
+                gateway: config.net_conf.gateway_ip,
+                bridge: config.net_conf.host_ip,
+                mask_bits: config.net_conf.prefix,
+                ipv6: None,
+            },
+            config: config.firecracker.clone(),
+            firewall_backend: config.firewall_backend,
+        })
+    }
+
+    async fn new_vm(
+        &self,
+        bv_context: &BvContext,
+        node_state: &NodeState,
+    ) -> Result<firecracker_machine::FirecrackerMachine> {
+        firecracker_machine::new(
+            &self.bv_root,
+            self.net_conf.clone(),
+            bv_context,
+            node_state,
+            self.babel_path.clone(),
+            self.config.clone(),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl Pal for FirecrackerPlatform {
+    fn bv_root(&self) -> &Path {
+        self.base.bv_root.as_path()
+    }
+
+    fn babel_path(&self) -> &Path {
+        self.base.babel_path.as_path()
+    }
+
+    fn job_runner_path(&self) -> &Path {
+        self.base.job_runner_path.as_path()
+    }
+
+    type CommandsStream = services::mqtt::MqttStream;
+    type CommandsStreamConnector = services::mqtt::MqttConnector;
+    fn create_commands_stream_connector(
+        &self,
+        config: &SharedConfig,
+    ) -> Self::CommandsStreamConnector {
+        services::mqtt::MqttConnector {
+            config: config.clone(),
+        }
+    }
+
+    type ApiServiceConnector = services::DefaultConnector;
+    fn create_api_service_connector(&self, config: &SharedConfig) -> Self::ApiServiceConnector {
+        services::DefaultConnector {
+            config: config.clone(),
+        }
+    }
+
+    type NodeConnection = VsockNodeConnection;
+    fn create_node_connection(&self, node_id: Uuid) -> Self::NodeConnection {
+        VsockNodeConnection::new(node_context::build_node_dir(self.bv_root(), node_id))
+    }
+
+    type VirtualMachine = firecracker_machine::FirecrackerMachine;
+
+    async fn create_vm(
+        &self,
+        bv_context: &BvContext,
+        node_state: &NodeState,
+    ) -> Result<Self::VirtualMachine> {
+        let mut vm = self.new_vm(bv_context, node_state).await?;
+        if let Err(err) = vm.build().await {
+            vm.delete().await?;
+            Err(err)
+        } else {
+            Ok(vm)
+        }
+    }
+
+    async fn attach_vm(
+        &self,
+        bv_context: &BvContext,
+        node_state: &NodeState,
+    ) -> Result<Self::VirtualMachine> {
+        let mut vm = self.new_vm(bv_context, node_state).await?;
+        vm.attach().await?;
+        Ok(vm)
+    }
+
+    async fn available_cpus(&self) -> usize {
+        linux_platform::available_cpus()
+    }
+
+    async fn cpu_topology(&self) -> CpuTopology {
+        linux_platform::cpu_topology()
+    }
+
+    async fn available_resources(
+        &self,
+        nodes_data_cache: NodesDataCache,
+    ) -> Result<AvailableResources> {
+        self.base
+            .available_resources(
+                nodes_data_cache.clone(),
+                self.used_disk_space_correction(nodes_data_cache).await?,
+            )
+            .await
+    }
+
+    async fn used_disk_space_correction(&self, nodes_data_cache: NodesDataCache) -> Result<u64> {
+        linux_platform::used_disk_space_correction(self.bv_root(), nodes_data_cache).await
+    }
+
+    type RecoveryBackoff = linux_platform::RecoveryBackoff;
+    fn create_recovery_backoff(&self) -> Self::RecoveryBackoff {
+        Default::default()
+    }
+
+    async fn apply_firewall_config(&self, config: NodeFirewallConfig) -> Result<()> {
+        self.firewall_backend.apply(config).await
+    }
+
+    async fn cleanup_firewall_config(&self, id: Uuid) -> Result<()> {
+        self.firewall_backend.cleanup(id).await
+    }
+
+    async fn firewall_status(
+        &self,
+        config: NodeFirewallConfig,
+    ) -> Result<Option<firewall::Status>> {
+        Ok(Some(self.firewall_backend.status(config).await?))
+    }
+}
+
+/// Connection to babel running inside VM, over Firecracker vsock. Babel is connected via host side
+/// vsock UDS, while connections from the guest to engine port are forwarded by Firecracker
+/// to `<vsock UDS>_<port>` socket.
+#[derive(Debug)]
+pub struct VsockNodeConnection {
+    vsock_path: PathBuf,
+    engine_socket_path: PathBuf,
+    state: NodeConnectionState,
+}
+
+impl VsockNodeConnection {
+    pub fn new(node_path: PathBuf) -> Self {
+        let vsock_path = node_path.join(firecracker_machine::VSOCK_SOCKET_FILE);
+        Self {
+            engine_socket_path: node_path.join(format!(
+                "{}_{}",
+                firecracker_machine::VSOCK_SOCKET_FILE,
+                firecracker_machine::ENGINE_VSOCK_PORT
+            )),
+            vsock_path,
+            state: NodeConnectionState::Closed,
+        }
+    }
+
+    fn new_babel_client(&self) -> pal::BabelClient {
+        babel_api::babel::babel_client::BabelClient::with_interceptor(
+            bv_utils::rpc::build_vsock_channel(
+                &self.vsock_path,
+                firecracker_machine::BABEL_VSOCK_PORT,
+            ),
+            bv_utils::rpc::DefaultTimeout(NODE_REQUEST_TIMEOUT),
+        )
+    }
+}
+
+#[async_trait]
+impl NodeConnection for VsockNodeConnection {
+    async fn setup(&mut self) -> Result<()> {
+        self.attach().await
+    }
+
+    async fn attach(&mut self) -> Result<()> {
+        self.state = NodeConnectionState::Babel(self.new_babel_client());
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.state = NodeConnectionState::Closed;
+    }
+
+    fn is_closed(&self) -> bool {
+        matches!(self.state, NodeConnectionState::Closed)
+    }
+
+    fn mark_broken(&mut self) {
+        self.state = NodeConnectionState::Broken;
+    }
+
+    fn is_broken(&self) -> bool {
+        matches!(self.state, NodeConnectionState::Broken)
+    }
+
+    async fn test(&mut self) -> Result<()> {
+        let mut client = self.new_babel_client();
+        with_retry!(client.get_version(()))?;
+        // update connection state (otherwise it still may be seen as broken)
+        self.state = NodeConnectionState::Babel(client);
+        Ok(())
+    }
+
+    async fn babel_client(&mut self) -> Result<&mut pal::BabelClient> {
+        match &mut self.state {
+            NodeConnectionState::Closed => {
+                bail!("node connection is closed")
+            }
+            NodeConnectionState::Babel { .. } => {}
+            NodeConnectionState::Broken => {
+                debug!("Reconnecting to babel");
+                self.attach().await?;
+            }
+        };
+        if let NodeConnectionState::Babel(client) = &mut self.state {
+            Ok(client)
+        } else {
+            unreachable!()
+        }
+    }
+
+    fn engine_socket_path(&self) -> &Path {
+        &self.engine_socket_path
+    }
+}
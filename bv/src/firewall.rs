@@ -1,9 +1,26 @@
+use crate::{nft_wrapper, pal::NodeFirewallConfig, ufw_wrapper};
 use cidr_utils::cidr::IpCidr;
 use eyre::{bail, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use uuid::Uuid;
 
-pub fn check_rules(rules: &[Rule]) -> Result<()> {
-    for rule in rules {
+/// Rule name is used as nftables rule comment, which is limited to 128 bytes.
+pub const MAX_RULE_NAME_LEN: usize = 128;
+
+pub fn check_rules(config: &Config) -> Result<()> {
+    for (name, ips) in &config.ip_sets {
+        if ips.is_empty() {
+            bail!("firewall ip set `{name}` is empty")
+        }
+        for ip in ips {
+            if !IpCidr::is_ip_cidr(ip) {
+                bail!("invalid ip address `{ip}` in firewall ip set `{name}`")
+            }
+        }
+    }
+    for rule in &config.rules {
+        check_rule_name(&rule.name)?;
         for ip in &rule.ips {
             if !IpCidr::is_ip_cidr(ip) {
                 bail!(
@@ -13,6 +30,40 @@ pub fn check_rules(rules: &[Rule]) -> Result<()> {
                 )
             }
         }
+        for ip_set in &rule.ip_sets {
+            if !config.ip_sets.contains_key(ip_set) {
+                bail!(
+                    "unknown ip set `{}` in firewall rule `{}`",
+                    ip_set,
+                    rule.name
+                )
+            }
+        }
+        for range in &rule.port_ranges {
+            if range.from == 0 || range.from > range.to {
+                bail!(
+                    "invalid port range `{}-{}` in firewall rule `{}`",
+                    range.from,
+                    range.to,
+                    rule.name
+                )
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Rule name is put into backend rules (e.g. as quoted comment), so it must not contain
+/// characters that could break out of it.
+fn check_rule_name(name: &str) -> Result<()> {
+    if name.len() > MAX_RULE_NAME_LEN {
+        bail!("firewall rule name `{name}` is longer than {MAX_RULE_NAME_LEN} characters")
+    }
+    if !name
+        .chars()
+        .all(|c| (c.is_ascii_graphic() || c == ' ') && c != '"' && c != '\\')
+    {
+        bail!("invalid firewall rule name `{}`, only printable ASCII characters except quotes and backslash are allowed", name.escape_debug())
     }
     Ok(())
 }
@@ -30,8 +81,30 @@ pub struct Rule {
     pub protocol: Option<Protocol>,
     /// Ip(s) compliant with CIDR notation.
     pub ips: Vec<String>,
-    /// List of ports. Empty means all.
+    /// Names of `Config::ip_sets`, which ips are matched in addition to `ips`.
+    #[serde(default)]
+    pub ip_sets: Vec<String>,
+    /// List of ports. Empty (together with `port_ranges`) means all.
     pub ports: Vec<u16>,
+    /// List of inclusive port ranges.
+    #[serde(default)]
+    pub port_ranges: Vec<PortRange>,
+    /// Log packets that match rule.
+    #[serde(default)]
+    pub log: bool,
+}
+
+impl Rule {
+    pub fn has_ports(&self) -> bool {
+        !self.ports.is_empty() || !self.port_ranges.is_empty()
+    }
+}
+
+/// Inclusive range of ports.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PortRange {
+    pub from: u16,
+    pub to: u16,
 }
 
 /// Firewall configuration that is applied to node traffic.
@@ -43,6 +116,23 @@ pub struct Config {
     pub default_out: Action,
     /// Set of rules to be applied.
     pub rules: Vec<Rule>,
+    /// Named sets of ips (compliant with CIDR notation), that can be reused by rules.
+    #[serde(default)]
+    pub ip_sets: BTreeMap<String, Vec<String>>,
+}
+
+impl Config {
+    /// All ips matched by given rule, including ips from referenced sets.
+    /// Empty means any ip.
+    pub fn rule_ips(&self, rule: &Rule) -> Vec<String> {
+        let mut ips = rule.ips.clone();
+        for ip_set in &rule.ip_sets {
+            if let Some(set_ips) = self.ip_sets.get(ip_set) {
+                ips.extend(set_ips.iter().cloned());
+            }
+        }
+        ips
+    }
 }
 
 impl Default for Config {
@@ -51,6 +141,7 @@ impl Default for Config {
             default_in: Action::Reject,
             default_out: Action::Allow,
             rules: vec![],
+            ip_sets: Default::default(),
         }
     }
 }
@@ -64,6 +155,84 @@ pub enum Action {
     Deny,
     /// Reject packets with explicit response.
     Reject,
+    /// Allow packets, but deny connections from ip that initiated
+    /// more than 6 connections within last 30 seconds.
+    Limit,
+}
+
+/// Rule features that API firewall rule model has no fields for (`limit` action, logging and port
+/// ranges). They are carried as tags appended to API rule description,
+/// e.g. `RPC access [bv:limit] [bv:log] [bv:port_ranges=30000-30100,40000-40010]`,
+/// so nodes created by API get them as well.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ApiRuleExtensions {
+    pub limit: bool,
+    pub log: bool,
+    pub port_ranges: Vec<PortRange>,
+}
+
+impl ApiRuleExtensions {
+    const TAG_PREFIX: &'static str = "[bv:";
+
+    /// Append extension tags to API rule `description`.
+    pub fn encode(&self, description: Option<String>) -> Option<String> {
+        let mut tags = vec![];
+        if self.limit {
+            tags.push(format!("{}limit]", Self::TAG_PREFIX));
+        }
+        if self.log {
+            tags.push(format!("{}log]", Self::TAG_PREFIX));
+        }
+        if !self.port_ranges.is_empty() {
+            let ranges = self
+                .port_ranges
+                .iter()
+                .map(|range| format!("{}-{}", range.from, range.to))
+                .collect::<Vec<_>>();
+            tags.push(format!(
+                "{}port_ranges={}]",
+                Self::TAG_PREFIX,
+                ranges.join(",")
+            ));
+        }
+        if tags.is_empty() {
+            return description;
+        }
+        let tags = tags.join(" ");
+        Some(match description {
+            Some(description) if !description.is_empty() => format!("{description} {tags}"),
+            _ => tags,
+        })
+    }
+
+    /// Parse extension tags from API rule `description`. Unknown tags (e.g. added by newer `nib`)
+    /// are ignored.
+    pub fn decode(description: Option<&str>) -> Result<Self> {
+        let mut extensions = Self::default();
+        let tags = description
+            .unwrap_or_default()
+            .split_whitespace()
+            .filter_map(|word| word.strip_prefix(Self::TAG_PREFIX)?.strip_suffix(']'));
+        for tag in tags {
+            match tag.split_once('=') {
+                None if tag == "limit" => extensions.limit = true,
+                None if tag == "log" => extensions.log = true,
+                Some(("port_ranges", ranges)) => {
+                    for range in ranges.split(',') {
+                        let Some((from, to)) = range.split_once('-') else {
+                            bail!("invalid port range `{range}` in firewall rule description");
+                        };
+                        extensions.port_ranges.push(PortRange {
+                            from: from.parse()?,
+                            to: to.parse()?,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(extensions)
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -82,3 +251,184 @@ pub enum Protocol {
     Udp,
     Both,
 }
+
+/// Tool used to apply node firewall rules on the host.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// Separate `ufw` rule for each node rule and port.
+    #[default]
+    Ufw,
+    /// Single `nftables` chain per node, replaced atomically.
+    Nftables,
+}
+
+impl Backend {
+    pub async fn apply(self, config: NodeFirewallConfig) -> Result<()> {
+        match self {
+            Backend::Ufw => ufw_wrapper::apply_firewall_config(config).await,
+            Backend::Nftables => nft_wrapper::apply_firewall_config(config).await,
+        }
+    }
+
+    pub async fn cleanup(self, id: Uuid) -> Result<()> {
+        match self {
+            Backend::Ufw => ufw_wrapper::cleanup_node_rules(id).await,
+            Backend::Nftables => nft_wrapper::cleanup_node_rules(id).await,
+        }
+    }
+
+    pub async fn status(self, config: NodeFirewallConfig) -> Result<Status> {
+        match self {
+            Backend::Ufw => ufw_wrapper::node_rules_status(config).await,
+            Backend::Nftables => nft_wrapper::node_rules_status(config).await,
+        }
+    }
+}
+
+/// Node firewall rules expected by BV versus rules actually found on the host.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Status {
+    pub backend: Backend,
+    pub expected: Vec<String>,
+    pub actual: Vec<String>,
+    /// Actual rules doesn't match expected ones (e.g. were removed manually),
+    /// so node is not protected as expected.
+    pub drift: bool,
+}
+
+/// Compare rules by given keys (i.e. rules normalized in backend specific way), since backends
+/// normalize rules when applied, so actual rules can't be compared with expected ones literally.
+pub fn is_drift(mut expected_keys: Vec<String>, mut actual_keys: Vec<String>) -> bool {
+    expected_keys.sort();
+    actual_keys.sort();
+    expected_keys != actual_keys
+}
+
+/// Rule ips (addresses or CIDRs) of the same family as given node address.
+pub fn family_ips(ips: &[String], node_ip: &str) -> Vec<String> {
+    let ipv6 = node_ip.contains(':');
+    ips.iter()
+        .filter(|ip| ip.contains(':') == ipv6)
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(name: &str) -> Rule {
+        Rule {
+            name: name.to_string(),
+            action: Action::Allow,
+            direction: Direction::In,
+            protocol: None,
+            ips: vec![],
+            ip_sets: vec![],
+            ports: vec![],
+            port_ranges: vec![],
+            log: false,
+        }
+    }
+
+    #[test]
+    fn test_check_rules() {
+        let mut config = Config {
+            rules: vec![rule("ok")],
+            ip_sets: BTreeMap::from([("set".to_string(), vec!["10.0.0.0/8".to_string()])]),
+            ..Default::default()
+        };
+        config.rules[0].ips = vec!["1.2.3.4".to_string()];
+        config.rules[0].ip_sets = vec!["set".to_string()];
+        config.rules[0].port_ranges = vec![PortRange { from: 80, to: 80 }];
+        check_rules(&config).unwrap();
+        assert_eq!(
+            vec!["1.2.3.4".to_string(), "10.0.0.0/8".to_string()],
+            config.rule_ips(&config.rules[0])
+        );
+
+        let mut invalid = config.clone();
+        invalid.rules[0].name = "name\"\nadd rule inet filter input accept".to_string();
+        assert_eq!(
+            "invalid firewall rule name `name\\\"\\nadd rule inet filter input accept`, only printable ASCII characters except quotes and backslash are allowed",
+            check_rules(&invalid).unwrap_err().to_string()
+        );
+        invalid.rules[0].name = "a".repeat(MAX_RULE_NAME_LEN + 1);
+        assert!(check_rules(&invalid).is_err());
+        invalid.rules[0].name = "a".repeat(MAX_RULE_NAME_LEN);
+        check_rules(&invalid).unwrap();
+
+        let mut invalid = config.clone();
+        invalid.rules[0].ip_sets = vec!["unknown".to_string()];
+        assert_eq!(
+            "unknown ip set `unknown` in firewall rule `ok`",
+            check_rules(&invalid).unwrap_err().to_string()
+        );
+
+        let mut invalid = config.clone();
+        invalid.rules[0].port_ranges = vec![PortRange { from: 81, to: 80 }];
+        assert_eq!(
+            "invalid port range `81-80` in firewall rule `ok`",
+            check_rules(&invalid).unwrap_err().to_string()
+        );
+
+        let mut invalid = config.clone();
+        invalid
+            .ip_sets
+            .insert("set".to_string(), vec!["invalid".to_string()]);
+        assert_eq!(
+            "invalid ip address `invalid` in firewall ip set `set`",
+            check_rules(&invalid).unwrap_err().to_string()
+        );
+
+        let mut invalid = config;
+        invalid.ip_sets.insert("set".to_string(), vec![]);
+        assert_eq!(
+            "firewall ip set `set` is empty",
+            check_rules(&invalid).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_api_rule_extensions() -> Result<()> {
+        let extensions = ApiRuleExtensions {
+            limit: true,
+            log: true,
+            port_ranges: vec![
+                PortRange {
+                    from: 30000,
+                    to: 30100,
+                },
+                PortRange {
+                    from: 40000,
+                    to: 40010,
+                },
+            ],
+        };
+        let description = extensions.encode(Some("RPC access".to_string()));
+        assert_eq!(
+            Some("RPC access [bv:limit] [bv:log] [bv:port_ranges=30000-30100,40000-40010]"),
+            description.as_deref()
+        );
+        assert_eq!(
+            extensions,
+            ApiRuleExtensions::decode(description.as_deref())?
+        );
+        assert_eq!(
+            Some("[bv:log]".to_string()),
+            ApiRuleExtensions {
+                log: true,
+                ..Default::default()
+            }
+            .encode(None)
+        );
+        assert_eq!(None, ApiRuleExtensions::default().encode(None));
+        assert_eq!(
+            ApiRuleExtensions::default(),
+            ApiRuleExtensions::decode(Some("plain [description] [bv:unknown]"))?
+        );
+        assert!(ApiRuleExtensions::decode(Some("[bv:port_ranges=30000]")).is_err());
+        Ok(())
+    }
+}
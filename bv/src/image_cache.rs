@@ -0,0 +1,400 @@
+//! Host level cache of node images. Each image is built only once into read-only rootfs,
+//! that is then used as a lower layer of per node overlay.
+//!
+//! Nodes reference cached images by files in node directory (containing image URI), so images
+//! that are not referenced by any node can be garbage collected.
+
+use crate::{apptainer_machine, node_context, BV_VAR_PATH};
+use chrono::{DateTime, Utc};
+use eyre::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{fs, sync::Mutex};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+pub const IMAGES_DIR: &str = "images";
+/// File in node dir, with URI of image used as base of node rootfs.
+pub const ROOTFS_IMAGE_FILE: &str = "rootfs_image";
+/// File in node dir, with URI of image used as base of node rootfs backup (kept until upgrade is done).
+pub const BACKUP_ROOTFS_IMAGE_FILE: &str = "rootfs_backup_image";
+/// File in node dir, with URI of image staged for upgrade.
+pub const STAGED_IMAGE_FILE: &str = "staged_image";
+const IMAGE_REF_FILES: [&str; 3] = [
+    ROOTFS_IMAGE_FILE,
+    BACKUP_ROOTFS_IMAGE_FILE,
+    STAGED_IMAGE_FILE,
+];
+const IMAGE_META_FILENAME: &str = "image.json";
+const ROOTFS_DIR: &str = "rootfs";
+
+/// Guards cache modifications, so image is never removed between build and node reference.
+/// It is not held while image is built, so other images can be acquired or pruned meanwhile.
+static CACHE_LOCK: Mutex<()> = Mutex::const_new(());
+
+lazy_static::lazy_static! {
+    /// Per image build locks, so the same image is never built twice in parallel.
+    static ref BUILD_LOCKS: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>> = Default::default();
+}
+
+/// Reference to image build lock. Lock is removed from `BUILD_LOCKS` once the last reference
+/// is dropped.
+struct BuildLock {
+    key: String,
+    lock: Arc<Mutex<()>>,
+}
+
+impl BuildLock {
+    fn new(key: &str) -> Self {
+        let lock = BUILD_LOCKS
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        Self {
+            key: key.to_string(),
+            lock,
+        }
+    }
+}
+
+impl Drop for BuildLock {
+    fn drop(&mut self) {
+        // references are cloned only under `BUILD_LOCKS` lock, so count can't grow meanwhile
+        let mut locks = BUILD_LOCKS.lock().unwrap();
+        if Arc::strong_count(&self.lock) <= 2 {
+            locks.remove(&self.key);
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+struct ImageMeta {
+    uri: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct CachedImage {
+    pub uri: String,
+    pub created_at: DateTime<Utc>,
+    pub size_bytes: u64,
+    /// Nodes that use this image as base of current, backup or staged rootfs.
+    pub used_by: Vec<Uuid>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ImageCache {
+    images_dir: PathBuf,
+    nodes_dir: PathBuf,
+}
+
+impl ImageCache {
+    pub fn new(bv_root: &Path) -> Self {
+        Self {
+            images_dir: bv_root.join(BV_VAR_PATH).join(IMAGES_DIR),
+            nodes_dir: node_context::build_nodes_dir(bv_root),
+        }
+    }
+
+    /// Get read-only rootfs of given image, build it first if not cached yet.
+    /// Image is referenced by `ref_path` file, so it won't be garbage collected until the file
+    /// is removed.
+    pub async fn acquire(&self, uri: &str, ref_path: &Path) -> Result<PathBuf> {
+        let key = image_key(uri);
+        let image_dir = self.images_dir.join(&key);
+        let rootfs_dir = image_dir.join(ROOTFS_DIR);
+        let meta_path = image_dir.join(IMAGE_META_FILENAME);
+        if self.add_ref(uri, ref_path, &meta_path, None).await? {
+            return Ok(rootfs_dir);
+        }
+        let build_lock = BuildLock::new(&key);
+        let _build_guard = build_lock.lock.lock().await;
+        // image may be already built by concurrent `acquire`
+        if self.add_ref(uri, ref_path, &meta_path, None).await? {
+            return Ok(rootfs_dir);
+        }
+        info!("Building cached image `{uri}`");
+        if image_dir.exists() {
+            // remnants of interrupted build
+            fs::remove_dir_all(&image_dir).await?;
+        }
+        fs::create_dir_all(&image_dir).await?;
+        apptainer_machine::build_rootfs(&rootfs_dir, uri, "image cache").await?;
+        let meta = ImageMeta {
+            uri: uri.to_string(),
+            created_at: Utc::now(),
+        };
+        self.add_ref(uri, ref_path, &meta_path, Some(meta)).await?;
+        Ok(rootfs_dir)
+    }
+
+    /// Reference cached image by `ref_path` file. Returns `false` if image is not cached.
+    /// Meta file of just built image is written here, under cache lock, so image is never pruned
+    /// before it is referenced.
+    async fn add_ref(
+        &self,
+        uri: &str,
+        ref_path: &Path,
+        meta_path: &Path,
+        built: Option<ImageMeta>,
+    ) -> Result<bool> {
+        let _lock = CACHE_LOCK.lock().await;
+        if let Some(meta) = built {
+            fs::write(meta_path, serde_json::to_string(&meta)?).await?;
+        } else if !meta_path.exists() {
+            return Ok(false);
+        }
+        fs::write(ref_path, uri)
+            .await
+            .with_context(|| format!("failed to save image ref '{}'", ref_path.display()))?;
+        Ok(true)
+    }
+
+    /// List all cached images.
+    pub async fn list(&self) -> Result<Vec<CachedImage>> {
+        let mut used_by = self.images_usage().await?;
+        let mut images = vec![];
+        for (image_dir, meta) in self.load_images().await? {
+            let size_bytes =
+                tokio::task::spawn_blocking(move || fs_extra::dir::get_size(image_dir)).await??;
+            images.push(CachedImage {
+                used_by: used_by.remove(&image_key(&meta.uri)).unwrap_or_default(),
+                uri: meta.uri,
+                created_at: meta.created_at,
+                size_bytes,
+            });
+        }
+        images.sort_by_key(|image| image.created_at);
+        Ok(images)
+    }
+
+    /// Remove images that are not used by any node. Returns URIs of removed images.
+    pub async fn prune(&self) -> Result<Vec<String>> {
+        let _lock = CACHE_LOCK.lock().await;
+        let used_by = self.images_usage().await?;
+        let mut removed = vec![];
+        for (image_dir, meta) in self.load_images().await? {
+            if !used_by.contains_key(&image_key(&meta.uri)) {
+                info!("Removing unused cached image `{}`", meta.uri);
+                fs::remove_dir_all(&image_dir).await?;
+                removed.push(meta.uri);
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Same as `prune`, but only log errors, since it is called as a cleanup after other operation.
+    pub async fn gc(&self) {
+        if let Err(err) = self.prune().await {
+            warn!("failed to garbage collect image cache: {err:#}");
+        }
+    }
+
+    async fn load_images(&self) -> Result<Vec<(PathBuf, ImageMeta)>> {
+        let mut images = vec![];
+        if !self.images_dir.exists() {
+            return Ok(images);
+        }
+        let mut entries = fs::read_dir(&self.images_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let meta_path = entry.path().join(IMAGE_META_FILENAME);
+            if !meta_path.exists() {
+                // image is being built or build was interrupted
+                continue;
+            }
+            let meta = serde_json::from_str(&fs::read_to_string(&meta_path).await?)
+                .with_context(|| format!("invalid image meta '{}'", meta_path.display()))?;
+            images.push((entry.path(), meta));
+        }
+        Ok(images)
+    }
+
+    /// Map of image keys to ids of nodes that reference them.
+    async fn images_usage(&self) -> Result<HashMap<String, Vec<Uuid>>> {
+        let mut usage: HashMap<String, Vec<Uuid>> = HashMap::new();
+        if !self.nodes_dir.exists() {
+            return Ok(usage);
+        }
+        let mut entries = fs::read_dir(&self.nodes_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(id) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| Uuid::parse_str(name).ok())
+            else {
+                continue;
+            };
+            for ref_file in IMAGE_REF_FILES {
+                let ref_path = entry.path().join(ref_file);
+                if ref_path.exists() {
+                    let nodes = usage
+                        .entry(image_key(&fs::read_to_string(&ref_path).await?))
+                        .or_default();
+                    if !nodes.contains(&id) {
+                        nodes.push(id);
+                    }
+                }
+            }
+        }
+        Ok(usage)
+    }
+}
+
+/// Cache key of given image. Images pinned by digest (e.g. `docker://repo/image@sha256:...`) are
+/// keyed by the digest, so the same image referenced by different URIs is cached only once.
+/// Other URIs may point to different content over time, so those are keyed by URI hash.
+fn image_key(uri: &str) -> String {
+    if let Some((algorithm, digest)) = uri
+        .rsplit_once('@')
+        .and_then(|(_, digest)| digest.split_once(':'))
+    {
+        if !algorithm.is_empty()
+            && !digest.is_empty()
+            && algorithm.chars().all(|c| c.is_ascii_alphanumeric())
+            && digest.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            return format!("{algorithm}-{}", digest.to_ascii_lowercase());
+        }
+    }
+    format!("{:x}", sha2::Sha256::digest(uri.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::TempDir;
+
+    async fn cache_image(cache: &ImageCache, uri: &str) -> Result<()> {
+        let image_dir = cache.images_dir.join(image_key(uri));
+        fs::create_dir_all(image_dir.join(ROOTFS_DIR)).await?;
+        fs::write(image_dir.join(ROOTFS_DIR).join("file"), "content").await?;
+        let meta = ImageMeta {
+            uri: uri.to_string(),
+            created_at: Utc::now(),
+        };
+        fs::write(
+            image_dir.join(IMAGE_META_FILENAME),
+            serde_json::to_string(&meta)?,
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_and_prune() -> Result<()> {
+        let bv_root = TempDir::new()?.to_path_buf();
+        let cache = ImageCache::new(&bv_root);
+        assert!(cache.list().await?.is_empty());
+
+        cache_image(&cache, "docker://a:1").await?;
+        cache_image(&cache, "docker://a:2").await?;
+        cache_image(&cache, "docker://b:1").await?;
+        // image being built
+        fs::create_dir_all(cache.images_dir.join(image_key("docker://c:1"))).await?;
+        let node_id = Uuid::new_v4();
+        let node_dir = cache.nodes_dir.join(node_id.to_string());
+        fs::create_dir_all(&node_dir).await?;
+        // acquire already cached image just adds reference
+        cache
+            .acquire("docker://a:2", &node_dir.join(ROOTFS_IMAGE_FILE))
+            .await?;
+        fs::write(node_dir.join(BACKUP_ROOTFS_IMAGE_FILE), "docker://a:1").await?;
+
+        let images = cache.list().await?;
+        assert_eq!(3, images.len());
+        let usage: HashMap<_, _> = images
+            .into_iter()
+            .map(|image| (image.uri, image.used_by))
+            .collect();
+        assert_eq!(vec![node_id], usage["docker://a:1"]);
+        assert_eq!(vec![node_id], usage["docker://a:2"]);
+        assert!(usage["docker://b:1"].is_empty());
+
+        assert_eq!(vec!["docker://b:1".to_string()], cache.prune().await?);
+        fs::remove_file(node_dir.join(BACKUP_ROOTFS_IMAGE_FILE)).await?;
+        cache.gc().await;
+        let images = cache.list().await?;
+        assert_eq!(1, images.len());
+        assert_eq!("docker://a:2", images[0].uri);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_acquire_while_building() -> Result<()> {
+        let bv_root = TempDir::new()?.to_path_buf();
+        let cache = ImageCache::new(&bv_root);
+        cache_image(&cache, "docker://a:1").await?;
+        let node_dir = cache.nodes_dir.join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&node_dir).await?;
+
+        // simulate `docker://b:1` being built by another node
+        let uri = "docker://b:1";
+        let build_lock = BuildLock::new(&image_key(uri));
+        let build_guard = build_lock.lock.lock().await;
+        fs::create_dir_all(cache.images_dir.join(image_key(uri)).join(ROOTFS_DIR)).await?;
+        let ref_path = node_dir.join(STAGED_IMAGE_FILE);
+        let waiting = tokio::spawn({
+            let cache = cache.clone();
+            let ref_path = ref_path.clone();
+            async move { cache.acquire(uri, &ref_path).await }
+        });
+
+        // other images are still available, and cache can be pruned, while image is built
+        cache
+            .acquire("docker://a:1", &node_dir.join(ROOTFS_IMAGE_FILE))
+            .await?;
+        assert!(cache.prune().await?.is_empty());
+        assert!(!waiting.is_finished());
+
+        // once built, waiting node just references it, instead of building it again
+        cache_image(&cache, uri).await?;
+        drop(build_guard);
+        waiting.await??;
+        assert_eq!(uri, fs::read_to_string(&ref_path).await?);
+        // build lock is released once nobody uses it
+        assert!(BUILD_LOCKS.lock().unwrap().contains_key(&image_key(uri)));
+        drop(build_lock);
+        assert!(!BUILD_LOCKS.lock().unwrap().contains_key(&image_key(uri)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pinned_image_key() -> Result<()> {
+        let digest = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let pinned = format!("docker://repo/image@sha256:{digest}");
+        let tagged_pinned = format!("docker://mirror/image:1.0@sha256:{digest}");
+        assert_eq!(format!("sha256-{digest}"), image_key(&pinned));
+        assert_eq!(image_key(&pinned), image_key(&tagged_pinned));
+        assert_ne!(
+            image_key("docker://repo/image:1"),
+            image_key("docker://repo/image:2")
+        );
+        assert_ne!(
+            format!("sha256-{digest}"),
+            image_key("docker://repo/image@sha256:../..")
+        );
+
+        // image pinned by the same digest is referenced, but not cached twice
+        let bv_root = TempDir::new()?.to_path_buf();
+        let cache = ImageCache::new(&bv_root);
+        cache_image(&cache, &pinned).await?;
+        let node_id = Uuid::new_v4();
+        let node_dir = cache.nodes_dir.join(node_id.to_string());
+        fs::create_dir_all(&node_dir).await?;
+        cache
+            .acquire(&tagged_pinned, &node_dir.join(ROOTFS_IMAGE_FILE))
+            .await?;
+        let images = cache.list().await?;
+        assert_eq!(1, images.len());
+        assert_eq!(vec![node_id], images[0].used_by);
+        assert!(cache.prune().await?.is_empty());
+        Ok(())
+    }
+}
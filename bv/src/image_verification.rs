@@ -0,0 +1,289 @@
+//! Optional verification of node images, before node is created or upgraded from them.
+//!
+//! Since tags are mutable, images must be pinned by digest (`<uri>@sha256:<hex>`), and signed
+//! with `cosign` by one of trusted keys. Signature is verified against pinned reference,
+//! so rootfs is built exactly from the image that was signed.
+
+use crate::node_state::NodeImage;
+use async_trait::async_trait;
+use bv_utils::cmd::run_cmd;
+use eyre::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+use tracing::debug;
+
+const COSIGN_BIN_NAME: &str = "cosign";
+const DIGEST_PREFIX: &str = "sha256:";
+const DIGEST_HEX_LEN: usize = 64;
+/// Only images stored in OCI registries can be signed.
+const SIGNED_URI_SCHEMES: [&str; 2] = ["docker://", "oras://"];
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Cosign public keys (PEM files). Image must be signed by at least one of them.
+    pub trusted_keys: Vec<PathBuf>,
+    /// Don't check signature in transparency log, e.g. for images signed with `--tlog-upload=false`.
+    #[serde(default)]
+    pub ignore_tlog: bool,
+}
+
+/// Digest (`sha256:<hex>`) to which image uri is pinned, `None` if image is referenced by tag only.
+pub fn image_digest(uri: &str) -> Option<&str> {
+    split_digest(uri).1
+}
+
+/// Pin image uri to given digest. Tag is dropped, since references with both tag and digest
+/// are not supported by apptainer.
+pub fn pin_digest(uri: &str, digest: &str) -> String {
+    let name = split_digest(uri).0;
+    let name_start = name.rfind('/').map(|index| index + 1).unwrap_or_default();
+    let name = match name[name_start..].rfind(':') {
+        Some(tag_start) => &name[..name_start + tag_start],
+        None => name,
+    };
+    format!("{name}@{digest}")
+}
+
+fn split_digest(uri: &str) -> (&str, Option<&str>) {
+    match uri.rsplit_once('@') {
+        Some((base, digest)) if is_valid_digest(digest) => (base, Some(digest)),
+        _ => (uri, None),
+    }
+}
+
+fn is_valid_digest(digest: &str) -> bool {
+    digest.strip_prefix(DIGEST_PREFIX).is_some_and(|hex| {
+        hex.len() == DIGEST_HEX_LEN && hex.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+    })
+}
+
+/// Check that image is pinned by digest and signed by one of trusted keys.
+pub async fn verify(config: &Config, image: &NodeImage) -> Result<()> {
+    verify_with(config, image, &SysRunner).await
+}
+
+#[async_trait]
+trait CosignRunner {
+    async fn run<'a>(&self, args: &[&'a OsStr]) -> Result<()>;
+}
+
+struct SysRunner;
+
+#[async_trait]
+impl CosignRunner for SysRunner {
+    async fn run<'a>(&self, args: &[&'a OsStr]) -> Result<()> {
+        run_cmd(COSIGN_BIN_NAME, args).await?;
+        Ok(())
+    }
+}
+
+async fn verify_with(config: &Config, image: &NodeImage, runner: &impl CosignRunner) -> Result<()> {
+    let reference = signed_reference(image)?;
+    if config.trusted_keys.is_empty() {
+        bail!("no trusted keys configured");
+    }
+    let mut errors = vec![];
+    for key in &config.trusted_keys {
+        match cosign_verify(config, key, &reference, runner).await {
+            Ok(()) => {
+                debug!("Image `{reference}` signed by `{}`", key.display());
+                return Ok(());
+            }
+            Err(err) => errors.push(format!("{}: {err:#}", key.display())),
+        }
+    }
+    bail!(
+        "image `{}` is not signed by any of trusted keys - {}",
+        image.uri,
+        errors.join("; ")
+    )
+}
+
+/// Registry reference of the image, that signature is verified against.
+fn signed_reference(image: &NodeImage) -> Result<String> {
+    if image_digest(&image.uri).is_none() {
+        bail!("image `{}` is not pinned by digest", image.uri);
+    }
+    SIGNED_URI_SCHEMES
+        .iter()
+        .find_map(|scheme| image.uri.strip_prefix(scheme))
+        .map(|reference| reference.to_string())
+        .ok_or_else(|| {
+            anyhow!(
+                "image `{}` can't be verified, only registry images (`{}`) can be signed",
+                image.uri,
+                SIGNED_URI_SCHEMES.join("`, `")
+            )
+        })
+}
+
+async fn cosign_verify(
+    config: &Config,
+    key: &Path,
+    reference: &str,
+    runner: &impl CosignRunner,
+) -> Result<()> {
+    let mut args = vec![OsStr::new("verify"), OsStr::new("--key"), key.as_os_str()];
+    if config.ignore_tlog {
+        args.push(OsStr::new("--insecure-ignore-tlog=true"));
+    }
+    args.push(OsStr::new(reference));
+    runner.run(&args).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockall::*;
+
+    mock! {
+        pub TestRunner {}
+
+        #[async_trait]
+        impl CosignRunner for TestRunner {
+            async fn run<'a>(&self, args: &[&'a OsStr]) -> Result<()>;
+        }
+    }
+
+    const DIGEST: &str = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn image(uri: &str) -> NodeImage {
+        NodeImage {
+            id: "image-id".to_string(),
+            version: "1.2.3".to_string(),
+            config_id: "config-id".to_string(),
+            archive_id: "archive-id".to_string(),
+            store_key: "store-key".to_string(),
+            uri: uri.to_string(),
+            min_babel_version: "1.2.3".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_image_digest() {
+        assert_eq!(None, image_digest("docker://registry:5000/image:tag"));
+        assert_eq!(
+            Some(DIGEST),
+            image_digest(&format!("docker://registry:5000/image:tag@{DIGEST}"))
+        );
+        assert_eq!(None, image_digest("docker://image@sha256:0123"));
+        assert_eq!(
+            None,
+            image_digest(&format!("docker://image@{}", DIGEST.to_uppercase()))
+        );
+        assert_eq!(
+            format!("docker://registry:5000/image@{DIGEST}"),
+            pin_digest("docker://registry:5000/image:tag", DIGEST)
+        );
+        assert_eq!(
+            format!("docker://registry:5000/image@{DIGEST}"),
+            pin_digest("docker://registry:5000/image", DIGEST)
+        );
+        let other_digest = format!("sha256:{}", "f".repeat(DIGEST_HEX_LEN));
+        assert_eq!(
+            format!("docker://image@{other_digest}"),
+            pin_digest(&format!("docker://image@{DIGEST}"), &other_digest)
+        );
+    }
+
+    #[test]
+    fn test_signed_reference() -> Result<()> {
+        assert_eq!(
+            format!("registry/image@{DIGEST}"),
+            signed_reference(&image(&format!("docker://registry/image@{DIGEST}")))?
+        );
+        assert_eq!(
+            "image `docker://image:tag` is not pinned by digest",
+            signed_reference(&image("docker://image:tag"))
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            format!("image `file:///images/image.sif@{DIGEST}` can't be verified, only registry images (`docker://`, `oras://`) can be signed"),
+            signed_reference(&image(&format!("file:///images/image.sif@{DIGEST}")))
+                .unwrap_err()
+                .to_string()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify() -> Result<()> {
+        let image = image(&format!("docker://registry/image@{DIGEST}"));
+        let mut config = Config {
+            trusted_keys: vec![PathBuf::from("/keys/a.pub"), PathBuf::from("/keys/b.pub")],
+            ignore_tlog: true,
+        };
+        let reference = format!("registry/image@{DIGEST}");
+
+        // signed by second trusted key
+        let mut runner = MockTestRunner::new();
+        let expected_reference = reference.clone();
+        runner
+            .expect_run()
+            .once()
+            .withf(move |args| {
+                args == [
+                    OsStr::new("verify"),
+                    OsStr::new("--key"),
+                    OsStr::new("/keys/a.pub"),
+                    OsStr::new("--insecure-ignore-tlog=true"),
+                    OsStr::new(&expected_reference),
+                ]
+            })
+            .returning(|_| bail!("no matching signatures"));
+        let expected_reference = reference.clone();
+        runner
+            .expect_run()
+            .once()
+            .withf(move |args| {
+                args == [
+                    OsStr::new("verify"),
+                    OsStr::new("--key"),
+                    OsStr::new("/keys/b.pub"),
+                    OsStr::new("--insecure-ignore-tlog=true"),
+                    OsStr::new(&expected_reference),
+                ]
+            })
+            .returning(|_| Ok(()));
+        verify_with(&config, &image, &runner).await?;
+
+        // not signed by any of trusted keys
+        config.trusted_keys.pop();
+        config.ignore_tlog = false;
+        let mut runner = MockTestRunner::new();
+        runner
+            .expect_run()
+            .once()
+            .withf(move |args| {
+                args == [
+                    OsStr::new("verify"),
+                    OsStr::new("--key"),
+                    OsStr::new("/keys/a.pub"),
+                    OsStr::new(&reference),
+                ]
+            })
+            .returning(|_| bail!("no matching signatures"));
+        assert_eq!(
+            format!("image `docker://registry/image@{DIGEST}` is not signed by any of trusted keys - /keys/a.pub: no matching signatures"),
+            verify_with(&config, &image, &runner)
+                .await
+                .unwrap_err()
+                .to_string()
+        );
+
+        // cosign is not even called without trusted keys
+        config.trusted_keys.clear();
+        assert_eq!(
+            "no trusted keys configured",
+            verify_with(&config, &image, &MockTestRunner::new())
+                .await
+                .unwrap_err()
+                .to_string()
+        );
+        Ok(())
+    }
+}
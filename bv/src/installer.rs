@@ -529,7 +529,9 @@ mod tests {
     use crate::hosts;
     use crate::internal_server;
     use crate::node_metrics;
+    use crate::node_snapshot;
     use crate::node_state;
+    use crate::nodes_manager;
     use assert_fs::TempDir;
     use babel_api::engine::JobsInfo;
     use bv_tests_utils::rpc::test_channel;
@@ -585,11 +587,43 @@ mod tests {
             async fn delete_node(&self, request: tonic::Request<Uuid>) -> Result<tonic::Response<()>, tonic::Status>;
             async fn start_node(&self, request: tonic::Request<Uuid>) -> Result<tonic::Response<()>, tonic::Status>;
             async fn stop_node(&self, request: tonic::Request<(Uuid, bool)>) -> Result<tonic::Response<()>, tonic::Status>;
+            async fn suspend_node(
+                &self,
+                request: tonic::Request<Uuid>,
+            ) -> Result<tonic::Response<()>, tonic::Status>;
+            async fn resize_node(
+                &self,
+                request: tonic::Request<(Uuid, Option<usize>, Option<u64>)>,
+            ) -> Result<tonic::Response<()>, tonic::Status>;
+            async fn resume_node(
+                &self,
+                request: tonic::Request<Uuid>,
+            ) -> Result<tonic::Response<()>, tonic::Status>;
             async fn upgrade_node(&self, request: tonic::Request<(Uuid, Option<String>, Option<u64>)>) -> Result<tonic::Response<()>, tonic::Status>;
             async fn upgrade_dev_node(
                 &self,
                 request: tonic::Request<node_state::NodeState>,
             ) -> Result<tonic::Response<internal_server::NodeDisplayInfo>, tonic::Status>;
+            async fn import_node(
+                &self,
+                request: tonic::Request<node_state::NodeState>,
+            ) -> Result<tonic::Response<internal_server::NodeDisplayInfo>, tonic::Status>;
+            async fn rolling_upgrade(
+                &self,
+                request: tonic::Request<(
+                    Vec<Uuid>,
+                    Option<String>,
+                    Option<u64>,
+                    nodes_manager::RolloutOptions,
+                )>,
+            ) -> Result<tonic::Response<()>, tonic::Status>;
+            async fn get_rollout_status(
+                &self,
+                request: tonic::Request<()>,
+            ) -> Result<
+                tonic::Response<std::collections::HashMap<Uuid, nodes_manager::RolloutStatus>>,
+                tonic::Status,
+            >;
             async fn get_node_jobs(
                 &self,
                 request: tonic::Request<Uuid>,
@@ -630,10 +664,30 @@ mod tests {
                 &self,
                 request: tonic::Request<Uuid>
             ) -> Result<tonic::Response<()>, tonic::Status>;
+            async fn evaluate(
+                &self,
+                request: tonic::Request<(Uuid, String, bool)>,
+            ) -> Result<tonic::Response<String>, tonic::Status>;
             async fn get_node_metrics(
                 &self,
                 request: tonic::Request<Uuid>,
             ) -> Result<tonic::Response<node_metrics::Metric>, tonic::Status>;
+            async fn list_snapshots(
+                &self,
+                request: tonic::Request<Uuid>,
+            ) -> Result<tonic::Response<Vec<node_snapshot::Snapshot>>, tonic::Status>;
+            async fn create_snapshot(
+                &self,
+                request: tonic::Request<(Uuid, String)>,
+            ) -> Result<tonic::Response<node_snapshot::Snapshot>, tonic::Status>;
+            async fn restore_snapshot(
+                &self,
+                request: tonic::Request<(Uuid, String)>,
+            ) -> Result<tonic::Response<node_snapshot::Snapshot>, tonic::Status>;
+            async fn delete_snapshot(
+                &self,
+                request: tonic::Request<(Uuid, String)>,
+            ) -> Result<tonic::Response<()>, tonic::Status>;
             async fn get_cluster_status(
                 &self,
                 request: tonic::Request<()>,
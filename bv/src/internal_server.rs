@@ -3,9 +3,13 @@ use crate::{
     bv_config,
     bv_config::SharedConfig,
     cluster::ClusterData,
-    hosts,
+    cpu_registry::CpuAllocationInfo,
+    firewall, hosts,
+    image_cache::{CachedImage, ImageCache},
+    ip_leases::IpInfo,
+    node_snapshot::Snapshot,
     node_state::{NodeProperties, NodeState, ProtocolImageKey, VmStatus},
-    nodes_manager::{self, MaybeNode, NodesManager},
+    nodes_manager::{self, MaybeNode, NodesManager, RolloutOptions, RolloutStatus},
     pal::Pal,
     services::{
         self,
@@ -17,9 +21,9 @@ use babel_api::engine::JobsInfo;
 use eyre::{anyhow, bail, Context};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{fmt::Debug, sync::Arc};
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
 use tonic::{Request, Response, Status};
-use tracing::instrument;
+use tracing::{error, instrument};
 use uuid::Uuid;
 
 // Data that we display in cli
@@ -44,14 +48,29 @@ trait Service {
     fn health() -> ServiceStatus;
     fn start_update() -> ServiceStatus;
     fn get_host_metrics() -> hosts::HostMetrics;
+    fn get_cpu_allocation() -> CpuAllocationInfo;
+    fn list_images() -> Vec<CachedImage>;
+    fn prune_images() -> Vec<String>;
+    fn get_ip_leases(probe: bool) -> Vec<IpInfo>;
     fn get_node(id: Uuid) -> NodeDisplayInfo;
     fn get_nodes(local: bool) -> Vec<NodeDisplayInfo>;
     fn create_node(req: CreateNodeRequest) -> NodeDisplayInfo;
     fn create_dev_node(req: NodeState) -> NodeDisplayInfo;
     fn start_node(id: Uuid);
     fn stop_node(id: Uuid, force: bool);
+    fn suspend_node(id: Uuid);
+    fn resize_node(id: Uuid, vcpu_count: Option<usize>, mem_size_mb: Option<u64>);
+    fn resume_node(id: Uuid);
     fn upgrade_node(id: Uuid, version: Option<String>, build: Option<u64>);
     fn upgrade_dev_node(req: NodeState) -> NodeDisplayInfo;
+    fn import_node(req: NodeState) -> NodeDisplayInfo;
+    fn rolling_upgrade(
+        ids: Vec<Uuid>,
+        version: Option<String>,
+        build: Option<u64>,
+        options: RolloutOptions,
+    );
+    fn get_rollout_status() -> HashMap<Uuid, RolloutStatus>;
     fn delete_node(id: Uuid);
     fn get_node_jobs(id: Uuid) -> JobsInfo;
     fn get_node_job_info(id: Uuid, job_name: String) -> babel_api::engine::JobInfo;
@@ -63,7 +82,13 @@ trait Service {
     fn list_capabilities(id: Uuid) -> Vec<String>;
     fn run(id: Uuid, method: String, param: String) -> String;
     fn reload_plugin(id: Uuid);
+    fn evaluate(id: Uuid, script: String, read_only: bool) -> String;
     fn get_node_metrics(id: Uuid) -> node_metrics::Metric;
+    fn get_node_firewall_status(id: Uuid) -> firewall::Status;
+    fn list_snapshots(id: Uuid) -> Vec<Snapshot>;
+    fn create_snapshot(id: Uuid, name: String) -> Snapshot;
+    fn restore_snapshot(id: Uuid, name: String) -> Snapshot;
+    fn delete_snapshot(id: Uuid, name: String);
     fn get_cluster_status() -> String; // TODO: update with proper struct
 }
 
@@ -145,6 +170,53 @@ where
         ))
     }
 
+    #[instrument(skip(self), ret(Debug))]
+    async fn get_cpu_allocation(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<CpuAllocationInfo>, Status> {
+        status_check().await?;
+        Ok(Response::new(
+            self.nodes_manager.cpu_allocation_info().await,
+        ))
+    }
+
+    #[instrument(skip(self), ret(Debug))]
+    async fn list_images(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<Vec<CachedImage>>, Status> {
+        status_check().await?;
+        Ok(Response::new(
+            ImageCache::new(self.nodes_manager.pal().bv_root())
+                .list()
+                .await
+                .map_err(|e| Status::unknown(format!("{e:#}")))?,
+        ))
+    }
+
+    #[instrument(skip(self), ret(Debug))]
+    async fn prune_images(&self, _request: Request<()>) -> Result<Response<Vec<String>>, Status> {
+        status_check().await?;
+        Ok(Response::new(
+            ImageCache::new(self.nodes_manager.pal().bv_root())
+                .prune()
+                .await
+                .map_err(|e| Status::unknown(format!("{e:#}")))?,
+        ))
+    }
+
+    #[instrument(skip(self), ret(Debug))]
+    async fn get_ip_leases(&self, request: Request<bool>) -> Result<Response<Vec<IpInfo>>, Status> {
+        status_check().await?;
+        Ok(Response::new(
+            self.nodes_manager
+                .ip_leases(request.into_inner())
+                .await
+                .map_err(|e| Status::unknown(format!("{e:#}")))?,
+        ))
+    }
+
     #[instrument(skip(self), ret(Debug))]
     async fn get_node(&self, request: Request<Uuid>) -> Result<Response<NodeDisplayInfo>, Status> {
         status_check().await?;
@@ -257,6 +329,40 @@ where
         Ok(Response::new(()))
     }
 
+    #[instrument(skip(self), ret(Debug))]
+    async fn suspend_node(&self, request: Request<Uuid>) -> Result<Response<()>, Status> {
+        status_check().await?;
+        self.nodes_manager
+            .suspend(request.into_inner())
+            .await
+            .map_err(|e| Status::unknown(format!("{e:#}")))?;
+        Ok(Response::new(()))
+    }
+
+    #[instrument(skip(self), ret(Debug))]
+    async fn resize_node(
+        &self,
+        request: Request<(Uuid, Option<usize>, Option<u64>)>,
+    ) -> Result<Response<()>, Status> {
+        status_check().await?;
+        let (id, vcpu_count, mem_size_mb) = request.into_inner();
+        self.nodes_manager
+            .resize(id, vcpu_count, mem_size_mb)
+            .await
+            .map_err(|e| Status::unknown(format!("{e:#}")))?;
+        Ok(Response::new(()))
+    }
+
+    #[instrument(skip(self), ret(Debug))]
+    async fn resume_node(&self, request: Request<Uuid>) -> Result<Response<()>, Status> {
+        status_check().await?;
+        self.nodes_manager
+            .resume(request.into_inner())
+            .await
+            .map_err(|e| Status::unknown(format!("{e:#}")))?;
+        Ok(Response::new(()))
+    }
+
     #[instrument(skip(self), ret(Debug))]
     async fn upgrade_node(
         &self,
@@ -267,43 +373,76 @@ where
         if self.is_dev_node(id).await {
             Err(Status::unimplemented("dev node upgrade is not supported"))
         } else {
-            let node = self
-                .nodes_manager
-                .node_state_cache(id)
-                .await
-                .map_err(|e| Status::unknown(format!("{e:#}")))?;
-            let image = services::connect_to_api_service(
-                &self.config,
-                pb::image_service_client::ImageServiceClient::with_interceptor,
-            )
-            .await
-            .map_err(|e| Status::unknown(format!("Error connecting to api: {e:#}")))?
-            .get_image(pb::ImageServiceGetImageRequest {
-                version_key: Some(node.image_key.clone().into()),
-                org_id: None,
-                semantic_version: version,
-                build_version: build,
-            })
+            trigger_upgrade(&self.config, &self.nodes_manager, id, version, build).await?;
+            Ok(Response::new(()))
+        }
+    }
+
+    #[instrument(skip(self), ret(Debug))]
+    async fn import_node(
+        &self,
+        request: Request<NodeState>,
+    ) -> Result<Response<NodeDisplayInfo>, Status> {
+        status_check().await?;
+        let state = self
+            .nodes_manager
+            .import(request.into_inner())
             .await
-            .map_err(|e| Status::unknown(format!("{e:#}")))?
-            .into_inner()
-            .image
-            .ok_or(Status::not_found(format!(
-                "image for {}/{} not found",
-                node.image_key.protocol_key, node.image_key.variant_key
-            )))?;
+            .map_err(|err| Status::unknown(format!("{err:#}")))?;
+        Ok(Response::new(NodeDisplayInfo {
+            state,
+            status: VmStatus::Stopped,
+        }))
+    }
 
-            self.connect_to_node_service()
-                .await?
-                .upgrade_image(pb::NodeServiceUpgradeImageRequest {
-                    node_ids: vec![id.to_string()],
-                    image_id: image.image_id,
-                    org_id: None,
+    #[instrument(skip(self), ret(Debug))]
+    async fn rolling_upgrade(
+        &self,
+        request: Request<(Vec<Uuid>, Option<String>, Option<u64>, RolloutOptions)>,
+    ) -> Result<Response<()>, Status> {
+        status_check().await?;
+        let (ids, version, build, options) = request.into_inner();
+        for id in &ids {
+            if self.is_dev_node(*id).await {
+                return Err(Status::unimplemented(format!(
+                    "dev node upgrade is not supported: {id}"
+                )));
+            }
+        }
+        self.nodes_manager
+            .begin_rollout(&ids)
+            .await
+            .map_err(|err| Status::already_exists(format!("{err:#}")))?;
+        let config = self.config.clone();
+        let nodes_manager = self.nodes_manager.clone();
+        tokio::spawn(async move {
+            if let Err(err) = nodes_manager
+                .rolling_upgrade(ids, options, |id| {
+                    let config = config.clone();
+                    let nodes_manager = nodes_manager.clone();
+                    let version = version.clone();
+                    async move {
+                        trigger_upgrade(&config, &nodes_manager, id, version, build)
+                            .await
+                            .map_err(|err| anyhow!("{}", err.message()))
+                    }
                 })
                 .await
-                .map_err(|e| Status::unknown(format!("{e:#}")))?;
-            Ok(Response::new(()))
-        }
+            {
+                // details are in nodes rollout status
+                error!("{err:#}");
+            }
+        });
+        Ok(Response::new(()))
+    }
+
+    #[instrument(skip(self), ret(Debug))]
+    async fn get_rollout_status(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<HashMap<Uuid, RolloutStatus>>, Status> {
+        status_check().await?;
+        Ok(Response::new(self.nodes_manager.rollout_status().await))
     }
 
     #[instrument(skip(self), ret(Debug))]
@@ -495,6 +634,22 @@ where
         Ok(Response::new(value))
     }
 
+    /// Evaluate script in the context of given node plugin.
+    #[instrument(skip(self), ret(Debug))]
+    async fn evaluate(
+        &self,
+        request: Request<(Uuid, String, bool)>,
+    ) -> Result<Response<String>, Status> {
+        status_check().await?;
+        let (id, script, read_only) = request.into_inner();
+        let value = self
+            .nodes_manager
+            .evaluate(id, &script, read_only)
+            .await
+            .map_err(|e| Status::unknown(format!("{e:#}")))?;
+        Ok(Response::new(value))
+    }
+
     /// Reload babel plugin for given node.
     #[instrument(skip(self), ret(Debug))]
     async fn reload_plugin(&self, request: Request<Uuid>) -> Result<Response<()>, Status> {
@@ -507,6 +662,65 @@ where
         Ok(Response::new(()))
     }
 
+    #[instrument(skip(self))]
+    async fn list_snapshots(
+        &self,
+        request: Request<Uuid>,
+    ) -> Result<Response<Vec<Snapshot>>, Status> {
+        status_check().await?;
+        let id = request.into_inner();
+        let snapshots = self
+            .nodes_manager
+            .snapshots(id)
+            .await
+            .map_err(|e| Status::unknown(format!("{e:#}")))?;
+        Ok(Response::new(snapshots))
+    }
+
+    #[instrument(skip(self), ret(Debug))]
+    async fn create_snapshot(
+        &self,
+        request: Request<(Uuid, String)>,
+    ) -> Result<Response<Snapshot>, Status> {
+        status_check().await?;
+        let (id, name) = request.into_inner();
+        let snapshot = self
+            .nodes_manager
+            .create_snapshot(id, &name)
+            .await
+            .map_err(|e| Status::unknown(format!("{e:#}")))?;
+        Ok(Response::new(snapshot))
+    }
+
+    #[instrument(skip(self), ret(Debug))]
+    async fn restore_snapshot(
+        &self,
+        request: Request<(Uuid, String)>,
+    ) -> Result<Response<Snapshot>, Status> {
+        status_check().await?;
+        let (id, name) = request.into_inner();
+        let snapshot = self
+            .nodes_manager
+            .restore_snapshot(id, &name)
+            .await
+            .map_err(|e| Status::unknown(format!("{e:#}")))?;
+        Ok(Response::new(snapshot))
+    }
+
+    #[instrument(skip(self), ret(Debug))]
+    async fn delete_snapshot(
+        &self,
+        request: Request<(Uuid, String)>,
+    ) -> Result<Response<()>, Status> {
+        status_check().await?;
+        let (id, name) = request.into_inner();
+        self.nodes_manager
+            .delete_snapshot(id, &name)
+            .await
+            .map_err(|e| Status::unknown(format!("{e:#}")))?;
+        Ok(Response::new(()))
+    }
+
     #[instrument(skip(self), ret(Debug))]
     async fn get_node_metrics(
         &self,
@@ -522,6 +736,21 @@ where
         Ok(Response::new(metrics))
     }
 
+    #[instrument(skip(self), ret(Debug))]
+    async fn get_node_firewall_status(
+        &self,
+        request: Request<Uuid>,
+    ) -> Result<Response<firewall::Status>, Status> {
+        status_check().await?;
+        let id = request.into_inner();
+        let status = self
+            .nodes_manager
+            .firewall_status(id)
+            .await
+            .map_err(|e| Status::unknown(format!("{e:#}")))?;
+        Ok(Response::new(status))
+    }
+
     #[instrument(skip(self), ret(Debug))]
     async fn get_cluster_status(&self, _request: Request<()>) -> Result<Response<String>, Status> {
         status_check().await?;
@@ -540,6 +769,68 @@ where
     }
 }
 
+/// Request node upgrade to given image version via API, which then sends back upgrade command.
+async fn trigger_upgrade<P>(
+    config: &SharedConfig,
+    nodes_manager: &NodesManager<P>,
+    id: Uuid,
+    version: Option<String>,
+    build: Option<u64>,
+) -> Result<(), Status>
+where
+    P: Pal + Send + Sync + Debug + 'static,
+    P::NodeConnection: Send + Sync,
+    P::ApiServiceConnector: Send + Sync,
+    P::VirtualMachine: Send + Sync,
+    P::RecoveryBackoff: Send + Sync + 'static,
+{
+    let node = nodes_manager
+        .node_state_cache(id)
+        .await
+        .map_err(|e| Status::unknown(format!("{e:#}")))?;
+    let image = services::connect_to_api_service(
+        config,
+        pb::image_service_client::ImageServiceClient::with_interceptor,
+    )
+    .await
+    .map_err(|e| Status::unknown(format!("Error connecting to api: {e:#}")))?
+    .get_image(pb::ImageServiceGetImageRequest {
+        version_key: Some(node.image_key.clone().into()),
+        org_id: None,
+        semantic_version: version,
+        build_version: build,
+    })
+    .await
+    .map_err(|e| Status::unknown(format!("{e:#}")))?
+    .into_inner()
+    .image
+    .ok_or(Status::not_found(format!(
+        "image for {}/{} not found",
+        node.image_key.protocol_key, node.image_key.variant_key
+    )))?;
+    if image.image_id == node.image.id {
+        return Err(Status::failed_precondition(format!(
+            "node already runs image {}",
+            image.image_id
+        )));
+    }
+
+    services::connect_to_api_service(
+        config,
+        pb::node_service_client::NodeServiceClient::with_interceptor,
+    )
+    .await
+    .map_err(|e| Status::unknown(format!("Error connecting to api: {e:#}")))?
+    .upgrade_image(pb::NodeServiceUpgradeImageRequest {
+        node_ids: vec![id.to_string()],
+        image_id: image.image_id,
+        org_id: None,
+    })
+    .await
+    .map_err(|e| Status::unknown(format!("{e:#}")))?;
+    Ok(())
+}
+
 impl<P> State<P>
 where
     P: Pal + Send + Sync + Debug + 'static,
@@ -0,0 +1,310 @@
+//! Explicit lease table of node IP addresses and duplicate address detection on node network
+//! segment, so IP conflicts (e.g. other machine using address from host pool) are reported
+//! instead of silently breaking node networking.
+
+use bv_utils::cmd::{run_cmd, CmdError};
+use chrono::{DateTime, Utc};
+use eyre::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use tracing::warn;
+use uuid::Uuid;
+
+/// Number of ARP/NDP probes sent to detect duplicate address.
+const PROBE_COUNT: &str = "2";
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct IpLease {
+    pub ip: IpAddr,
+    pub node_id: Uuid,
+    pub leased_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct IpConflict {
+    pub ip: IpAddr,
+    pub detected_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct IpLeases {
+    #[serde(default)]
+    leases: Vec<IpLease>,
+    /// Addresses found to be used by other hosts on node network segment by the last probe.
+    #[serde(default)]
+    conflicts: Vec<IpConflict>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum IpStatus {
+    Free,
+    Leased { node_id: Uuid },
+    Conflict { reason: String },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct IpInfo {
+    pub ip: IpAddr,
+    pub status: IpStatus,
+}
+
+impl IpLeases {
+    /// Lease given addresses to node. Nothing is leased if any of them is already leased
+    /// by other node.
+    pub fn lease(&mut self, node_id: Uuid, ips: &[IpAddr]) -> Result<()> {
+        for ip in ips {
+            if let Some(lease) = self
+                .leases
+                .iter()
+                .find(|lease| lease.ip == *ip && lease.node_id != node_id)
+            {
+                bail!(
+                    "ip address `{ip}` is already leased by node `{}`",
+                    lease.node_id
+                );
+            }
+        }
+        let leased_at = Utc::now();
+        for ip in ips {
+            if !self.leases.iter().any(|lease| lease.ip == *ip) {
+                self.leases.push(IpLease {
+                    ip: *ip,
+                    node_id,
+                    leased_at,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn leases(&self) -> &[IpLease] {
+        &self.leases
+    }
+
+    /// Release all addresses leased by node.
+    pub fn release(&mut self, node_id: Uuid) {
+        self.leases.retain(|lease| lease.node_id != node_id);
+    }
+
+    /// Make leases match addresses of existing nodes, e.g. after BV upgrade from version
+    /// without lease table, or if node was removed manually.
+    pub fn sync(&mut self, nodes: &[(Uuid, Vec<IpAddr>)]) {
+        self.leases.retain(|lease| {
+            nodes
+                .iter()
+                .any(|(id, ips)| *id == lease.node_id && ips.contains(&lease.ip))
+        });
+        for (id, ips) in nodes {
+            if let Err(err) = self.lease(*id, ips) {
+                warn!("inconsistent IP leases: {err:#}");
+            }
+        }
+    }
+
+    /// First address from `pool` that is neither leased nor known to be used by other host.
+    pub fn pick_free<T: Into<IpAddr> + Copy>(
+        &self,
+        pool: impl IntoIterator<Item = T>,
+    ) -> Option<T> {
+        pool.into_iter().find(|ip| {
+            let ip = (*ip).into();
+            !self.leases.iter().any(|lease| lease.ip == ip)
+                && !self.conflicts.iter().any(|conflict| conflict.ip == ip)
+        })
+    }
+
+    /// Record result of duplicate address detection.
+    pub fn set_conflict(&mut self, ip: IpAddr, conflict: bool) {
+        self.conflicts.retain(|item| item.ip != ip);
+        if conflict {
+            self.conflicts.push(IpConflict {
+                ip,
+                detected_at: Utc::now(),
+            });
+        }
+    }
+
+    /// Status of all addresses from host pool, plus leased addresses that are out of it
+    /// (empty pool means that any address is allowed).
+    pub fn status(&self, pool: &[IpAddr]) -> Vec<IpInfo> {
+        let mut ips = pool.to_vec();
+        for lease in &self.leases {
+            if !ips.contains(&lease.ip) {
+                ips.push(lease.ip);
+            }
+        }
+        ips.into_iter()
+            .map(|ip| {
+                let lease = self.leases.iter().find(|lease| lease.ip == ip);
+                let status = if let Some(conflict) = self.conflicts.iter().find(|c| c.ip == ip) {
+                    IpStatus::Conflict {
+                        reason: format!(
+                            "used by other host on node network (detected at {})",
+                            conflict.detected_at.format("%Y-%m-%d %H:%M:%S")
+                        ),
+                    }
+                } else if let Some(lease) = lease {
+                    if pool.is_empty() || pool.contains(&ip) {
+                        IpStatus::Leased {
+                            node_id: lease.node_id,
+                        }
+                    } else {
+                        IpStatus::Conflict {
+                            reason: format!(
+                                "leased by node `{}`, but out of host pool",
+                                lease.node_id
+                            ),
+                        }
+                    }
+                } else {
+                    IpStatus::Free
+                };
+                IpInfo { ip, status }
+            })
+            .collect()
+    }
+}
+
+/// Check if given address is used by other host on network segment attached to `iface`,
+/// with ARP (IPv4) or NDP (IPv6) duplicate address detection.
+pub async fn probe(iface: &str, ip: IpAddr) -> Result<bool> {
+    let ip_str = ip.to_string();
+    match ip {
+        IpAddr::V4(_) => {
+            // in DAD mode arping exits with 1 if any reply was received
+            match run_cmd(
+                "arping",
+                ["-D", "-q", "-c", PROBE_COUNT, "-I", iface, &ip_str],
+            )
+            .await
+            {
+                Ok(_) => Ok(false),
+                Err(CmdError::Failed { code: 1, .. }) => Ok(true),
+                Err(err) => Err(err.into()),
+            }
+        }
+        IpAddr::V6(_) => {
+            // ndisc6 exits with 0 only if neighbor advertisement was received
+            match run_cmd("ndisc6", ["-q", "-r", PROBE_COUNT, &ip_str, iface]).await {
+                Ok(_) => Ok(true),
+                Err(CmdError::Failed { .. }) => Ok(false),
+                Err(err) => Err(err.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn ip(value: &str) -> IpAddr {
+        IpAddr::from_str(value).unwrap()
+    }
+
+    #[test]
+    fn test_lease_and_release() -> Result<()> {
+        let mut leases = IpLeases::default();
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+        leases.lease(first, &[ip("192.168.0.2"), ip("fd00::2")])?;
+        // leasing again to the same node is noop
+        leases.lease(first, &[ip("192.168.0.2")])?;
+        assert_eq!(
+            format!("ip address `fd00::2` is already leased by node `{first}`"),
+            leases
+                .lease(second, &[ip("192.168.0.3"), ip("fd00::2")])
+                .unwrap_err()
+                .to_string()
+        );
+        leases.lease(second, &[ip("192.168.0.3")])?;
+        assert_eq!(3, leases.leases.len());
+
+        leases.release(first);
+        leases.lease(second, &[ip("fd00::2")])?;
+        assert_eq!(
+            vec![(ip("192.168.0.3"), second), (ip("fd00::2"), second)],
+            leases
+                .leases
+                .iter()
+                .map(|lease| (lease.ip, lease.node_id))
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync() -> Result<()> {
+        let mut leases = IpLeases::default();
+        let removed = Uuid::new_v4();
+        let existing = Uuid::new_v4();
+        let not_leased = Uuid::new_v4();
+        leases.lease(removed, &[ip("192.168.0.2")])?;
+        leases.lease(existing, &[ip("192.168.0.3"), ip("fd00::3")])?;
+        leases.sync(&[
+            (existing, vec![ip("192.168.0.3")]),
+            (not_leased, vec![ip("192.168.0.4")]),
+        ]);
+        assert_eq!(
+            vec![
+                (ip("192.168.0.3"), existing),
+                (ip("192.168.0.4"), not_leased)
+            ],
+            leases
+                .leases
+                .iter()
+                .map(|lease| (lease.ip, lease.node_id))
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_status() -> Result<()> {
+        let mut leases = IpLeases::default();
+        let node_id = Uuid::new_v4();
+        let other_id = Uuid::new_v4();
+        leases.lease(node_id, &[ip("192.168.0.2")])?;
+        leases.lease(other_id, &[ip("10.0.0.2")])?;
+        leases.set_conflict(ip("192.168.0.4"), true);
+        leases.set_conflict(ip("192.168.0.3"), true);
+        leases.set_conflict(ip("192.168.0.3"), false);
+
+        let status = leases.status(&[ip("192.168.0.2"), ip("192.168.0.3"), ip("192.168.0.4")]);
+        assert_eq!(4, status.len());
+        assert_eq!(
+            IpInfo {
+                ip: ip("192.168.0.2"),
+                status: IpStatus::Leased { node_id },
+            },
+            status[0]
+        );
+        assert_eq!(
+            IpInfo {
+                ip: ip("192.168.0.3"),
+                status: IpStatus::Free,
+            },
+            status[1]
+        );
+        assert!(matches!(status[2].status, IpStatus::Conflict { .. }));
+        assert_eq!(
+            Some(ip("192.168.0.3")),
+            leases.pick_free([ip("192.168.0.2"), ip("192.168.0.4"), ip("192.168.0.3")])
+        );
+        assert_eq!(
+            None,
+            leases.pick_free([ip("192.168.0.2"), ip("192.168.0.4")])
+        );
+        assert_eq!(
+            IpInfo {
+                ip: ip("10.0.0.2"),
+                status: IpStatus::Conflict {
+                    reason: format!("leased by node `{other_id}`, but out of host pool"),
+                },
+            },
+            status[3]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+//! Simple, file based key-value store used by babel plugins as persistent storage.
+//! Whole store is kept in single JSON file, that is atomically replaced on each update.
+
+use eyre::{anyhow, Context, Result};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Clone)]
+pub struct KvStore {
+    path: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+impl KvStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            lock: Default::default(),
+        }
+    }
+
+    /// Get value stored under `key`.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        let _guard = self.lock()?;
+        Ok(self.load()?.remove(key))
+    }
+
+    /// Store `value` under `key`.
+    pub fn set(&self, key: &str, value: &str) -> Result<()> {
+        let _guard = self.lock()?;
+        let mut data = self.load()?;
+        data.insert(key.to_owned(), value.to_owned());
+        self.save(&data)
+    }
+
+    /// Delete `key`. Returns `true` if key existed.
+    pub fn delete(&self, key: &str) -> Result<bool> {
+        let _guard = self.lock()?;
+        let mut data = self.load()?;
+        if data.remove(key).is_some() {
+            self.save(&data)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// List all entries with keys starting with given `prefix`.
+    pub fn list(&self, prefix: &str) -> Result<BTreeMap<String, String>> {
+        let _guard = self.lock()?;
+        let mut data = self.load()?;
+        data.retain(|key, _| key.starts_with(prefix));
+        Ok(data)
+    }
+
+    /// Store `new` value under `key`, but only if current value is equal to `expected`
+    /// (`None` means that key shall not exist). Returns `true` if value has been swapped.
+    pub fn compare_and_swap(&self, key: &str, expected: Option<&str>, new: &str) -> Result<bool> {
+        let _guard = self.lock()?;
+        let mut data = self.load()?;
+        if data.get(key).map(|value| value.as_str()) == expected {
+            data.insert(key.to_owned(), new.to_owned());
+            self.save(&data)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, ()>> {
+        self.lock
+            .lock()
+            .map_err(|err| anyhow!("kv store lock poisoned: {err:#}"))
+    }
+
+    fn load(&self) -> Result<BTreeMap<String, String>> {
+        if !self.path.exists() {
+            return Ok(Default::default());
+        }
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("failed to read kv store: {}", self.path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("failed to parse kv store: {}", self.path.display()))
+    }
+
+    fn save(&self, data: &BTreeMap<String, String>) -> Result<()> {
+        // write to temporary file first and then rename it, so store is never left half-written
+        let tmp_path = tmp_path(&self.path);
+        let mut file = fs::File::create(&tmp_path)
+            .with_context(|| format!("failed to create kv store: {}", tmp_path.display()))?;
+        // data must be on disk before rename, otherwise store may be empty after power loss
+        file.write_all(serde_json::to_string(data)?.as_bytes())
+            .and_then(|_| file.sync_all())
+            .with_context(|| format!("failed to write kv store: {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("failed to save kv store: {}", self.path.display()))?;
+        // make rename itself durable
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::File::open(dir)
+                .and_then(|dir| dir.sync_all())
+                .with_context(|| format!("failed to sync kv store dir: {}", dir.display()))?;
+        }
+        Ok(())
+    }
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    path.with_extension(format!(
+        "{}_tmp",
+        path.extension().unwrap_or_default().to_string_lossy()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::TempDir;
+
+    #[test]
+    fn test_kv_store() -> Result<()> {
+        let tmp_root = TempDir::new()?.to_path_buf();
+        fs::create_dir_all(&tmp_root)?;
+        let store = KvStore::new(tmp_root.join("kv.json"));
+
+        assert_eq!(None, store.get("a/key")?);
+        assert!(!store.delete("a/key")?);
+        assert!(store.list("")?.is_empty());
+
+        store.set("a/key", "value")?;
+        store.set("a/other", "other value")?;
+        store.set("b/key", "b value")?;
+        assert_eq!(Some("value".to_string()), store.get("a/key")?);
+        assert_eq!(
+            BTreeMap::from_iter([
+                ("a/key".to_string(), "value".to_string()),
+                ("a/other".to_string(), "other value".to_string()),
+            ]),
+            store.list("a/")?
+        );
+
+        assert!(!store.compare_and_swap("a/key", Some("wrong"), "new value")?);
+        assert!(!store.compare_and_swap("a/key", None, "new value")?);
+        assert!(store.compare_and_swap("a/key", Some("value"), "new value")?);
+        assert!(store.compare_and_swap("c/key", None, "c value")?);
+        assert_eq!(Some("new value".to_string()), store.get("a/key")?);
+        assert_eq!(Some("c value".to_string()), store.get("c/key")?);
+
+        assert!(store.delete("a/other")?);
+        // make sure data are persisted
+        let store = KvStore::new(tmp_root.join("kv.json"));
+        assert_eq!(None, store.get("a/other")?);
+        assert_eq!(3, store.list("")?.len());
+        Ok(())
+    }
+}
@@ -10,28 +10,43 @@ pub mod bv_config;
 pub mod bv_context;
 pub mod cluster;
 pub mod commands;
-mod cpu_registry;
+pub mod cpu_registry;
+pub mod disk_quota;
+pub mod firecracker_machine;
+pub mod firecracker_platform;
 pub mod firewall;
 pub mod hosts;
+pub mod image_cache;
+pub mod image_verification;
 pub mod installer;
 pub mod internal_server;
+pub mod ip_leases;
+pub mod kv_store;
 pub mod linux_platform;
+pub mod net_shaping;
+pub mod nft_wrapper;
 pub mod nib;
 pub mod nib_cli;
 pub mod nib_config;
 pub mod nib_meta;
 pub mod node;
+pub mod node_bundle;
 pub mod node_context;
 pub mod node_env;
 pub mod node_metrics;
+pub mod node_snapshot;
 pub mod node_state;
 pub mod nodes_manager;
+pub mod nspawn_machine;
+pub mod nspawn_platform;
 pub mod pal;
 pub mod pretty_table;
+pub mod rpc_proxy;
 pub mod scheduler;
 pub mod self_updater;
 pub mod services;
 pub mod ufw_wrapper;
+pub mod upgrade_dirs;
 pub mod utils;
 
 use serde::{Deserialize, Serialize};
@@ -1,15 +1,24 @@
 /// Default Platform Abstraction Layer implementation for Linux.
 use crate::{
+    apptainer_machine,
+    cpu_registry::CpuTopology,
+    node_context,
     nodes_manager::NodesDataCache,
     pal::{self, AvailableResources},
     BV_VAR_PATH,
 };
 use bv_utils::exp_backoff_timeout;
 use eyre::{anyhow, bail, Context, Result};
-use std::{fs, path::PathBuf, time::Instant};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Instant,
+};
 use sysinfo::{DiskExt, System, SystemExt};
+use tracing::{debug, warn};
 
 const ENV_BV_ROOT_KEY: &str = "BV_ROOT";
+const SYSFS_CPU_PATH: &str = "sys/devices/system/cpu";
 
 #[derive(Debug)]
 pub struct LinuxPlatform {
@@ -28,6 +37,39 @@ pub fn available_cpus() -> usize {
     sys.cpus().len()
 }
 
+/// Read CPU topology from sysfs, fallback to flat topology if not available.
+pub fn cpu_topology() -> CpuTopology {
+    CpuTopology::load(&bv_root().join(SYSFS_CPU_PATH)).unwrap_or_else(|err| {
+        warn!("failed to read cpu topology, NUMA and SMT won't be taken into account: {err:#}");
+        CpuTopology::flat(available_cpus())
+    })
+}
+
+/// Regarding sparse files used for data images, used disk space need manual correction
+/// that include declared data size of each node.
+pub async fn used_disk_space_correction(
+    bv_root: &Path,
+    nodes_data_cache: NodesDataCache,
+) -> Result<u64> {
+    let bv_root = bv_root.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut correction = 0;
+        for (id, data) in nodes_data_cache {
+            let data_img_path =
+                node_context::build_node_dir(&bv_root, id).join(apptainer_machine::DATA_DIR);
+            let actual_data_size = fs_extra::dir::get_size(&data_img_path)
+                .with_context(|| format!("can't check size of '{}'", data_img_path.display()))?;
+            let declared_data_size = data.vm_config.disk_size_gb * 1_000_000_000;
+            debug!("id: {id}; declared: {declared_data_size}; actual: {actual_data_size}");
+            if declared_data_size > actual_data_size {
+                correction += declared_data_size - actual_data_size;
+            }
+        }
+        Ok(correction)
+    })
+    .await?
+}
+
 impl LinuxPlatform {
     pub async fn new() -> Result<Self> {
         let bv_root = bv_root();
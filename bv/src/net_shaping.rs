@@ -0,0 +1,194 @@
+//! Per node network bandwidth shaping and traffic accounting, so single node (e.g. archive node
+//! sync) can't saturate the uplink of the whole host.
+//!
+//! Everything is done on host side of node veth pair, so it can't be altered from inside the node:
+//! - traffic to node is shaped with `tc` HTB qdisc (veth egress),
+//! - traffic from node is policed with `tc` ingress qdisc (veth ingress),
+//! - rx/tx counters are read from veth statistics.
+
+use bv_utils::cmd::run_cmd;
+use eyre::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::fs;
+
+/// Name of network interface inside node network namespace, created by apptainer bridge network.
+const NODE_IFACE: &str = "eth0";
+const SYS_CLASS_NET: &str = "/sys/class/net";
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BandwidthLimit {
+    /// Max rate of traffic to node (download) in Mbit/s, not limited if not set.
+    #[serde(default)]
+    pub ingress_mbit: Option<u64>,
+    /// Max rate of traffic from node (upload) in Mbit/s, not limited if not set.
+    #[serde(default)]
+    pub egress_mbit: Option<u64>,
+}
+
+impl BandwidthLimit {
+    /// Limits not set explicitly are taken from `default`.
+    pub fn or(self, default: BandwidthLimit) -> Self {
+        Self {
+            ingress_mbit: self.ingress_mbit.or(default.ingress_mbit),
+            egress_mbit: self.egress_mbit.or(default.egress_mbit),
+        }
+    }
+}
+
+/// Node network traffic counters, as seen by the node.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct TrafficUsage {
+    pub received_bytes: u64,
+    pub sent_bytes: u64,
+}
+
+/// Find host side of veth pair connected to node, running in network namespace of given process.
+pub async fn host_veth(pid: u32) -> Result<String> {
+    let netns = format!("--net=/proc/{pid}/ns/net");
+    let node_link = run_cmd(
+        "nsenter",
+        [netns.as_str(), "ip", "-o", "link", "show", NODE_IFACE],
+    )
+    .await
+    .with_context(|| format!("failed to find node interface in netns of process {pid}"))?;
+    let peer_index = parse_peer_index(&node_link)?;
+    let host_links = run_cmd("ip", ["-o", "link", "show"]).await?;
+    find_link_name(&host_links, peer_index)
+        .ok_or_else(|| anyhow!("can't find host side of node veth (index {peer_index})"))
+}
+
+/// Apply (or remove if not set) bandwidth limits on given host veth. It is safe to call it multiple
+/// times, e.g. on every node start or when node limits are changed.
+pub async fn apply(veth: &str, limit: &BandwidthLimit) -> Result<()> {
+    // ignore errors, since there may be nothing to remove
+    let _ = run_cmd("tc", ["qdisc", "del", "dev", veth, "root"]).await;
+    let _ = run_cmd("tc", ["qdisc", "del", "dev", veth, "ingress"]).await;
+    if let Some(rate) = limit.ingress_mbit {
+        for cmd in htb_cmds(veth, rate) {
+            run_cmd("tc", cmd.split_whitespace()).await?;
+        }
+    }
+    if let Some(rate) = limit.egress_mbit {
+        for cmd in police_cmds(veth, rate) {
+            run_cmd("tc", cmd.split_whitespace()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Read node traffic counters from given host veth.
+pub async fn usage(veth: &str) -> Result<TrafficUsage> {
+    let stats = Path::new(SYS_CLASS_NET).join(veth).join("statistics");
+    // host side of veth transmits what node receives and vice versa
+    Ok(TrafficUsage {
+        received_bytes: read_counter(&stats.join("tx_bytes")).await?,
+        sent_bytes: read_counter(&stats.join("rx_bytes")).await?,
+    })
+}
+
+async fn read_counter(path: &Path) -> Result<u64> {
+    let value = fs::read_to_string(path)
+        .await
+        .with_context(|| format!("failed to read '{}'", path.display()))?;
+    Ok(value.trim().parse()?)
+}
+
+/// `tc` commands shaping traffic to node.
+fn htb_cmds(veth: &str, rate_mbit: u64) -> [String; 2] {
+    [
+        format!("qdisc add dev {veth} root handle 1: htb default 1"),
+        format!("class add dev {veth} parent 1: classid 1:1 htb rate {rate_mbit}mbit ceil {rate_mbit}mbit"),
+    ]
+}
+
+/// `tc` commands policing traffic from node.
+fn police_cmds(veth: &str, rate_mbit: u64) -> [String; 2] {
+    // allow bursts of ~100ms of traffic, but not less than few full size packets
+    let burst = (rate_mbit * 12_500).max(15_000);
+    [
+        format!("qdisc add dev {veth} handle ffff: ingress"),
+        format!("filter add dev {veth} parent ffff: matchall action police rate {rate_mbit}mbit burst {burst} drop"),
+    ]
+}
+
+/// Parse index of veth peer from `ip -o link show` output, e.g. `2: eth0@if15: <BROADCAST...`.
+fn parse_peer_index(link: &str) -> Result<u32> {
+    let Some((_, rest)) = link.split_once("@if") else {
+        bail!("'{NODE_IFACE}' is not a veth interface: {link}");
+    };
+    let index = rest.split_once(':').map(|(index, _)| index).unwrap_or(rest);
+    index
+        .parse()
+        .with_context(|| format!("invalid veth peer index in: {link}"))
+}
+
+/// Find name of link with given index in `ip -o link show` output.
+fn find_link_name(links: &str, index: u32) -> Option<String> {
+    let prefix = format!("{index}: ");
+    links.lines().find_map(|line| {
+        let name = line.strip_prefix(&prefix)?.split(':').next()?;
+        Some(name.split('@').next().unwrap_or(name).to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_host_veth() -> Result<()> {
+        let node_link = "2: eth0@if15: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc noqueue state UP mode DEFAULT group default qlen 1000\\    link/ether 6a:1c:2e:b1:07:43 brd ff:ff:ff:ff:ff:ff link-netnsid 0";
+        assert_eq!(15, parse_peer_index(node_link)?);
+        assert!(parse_peer_index("1: lo: <LOOPBACK,UP,LOWER_UP> mtu 65536").is_err());
+
+        let host_links = r#"1: lo: <LOOPBACK,UP,LOWER_UP> mtu 65536 qdisc noqueue state UNKNOWN mode DEFAULT group default qlen 1000\    link/loopback 00:00:00:00:00:00 brd 00:00:00:00:00:00
+5: bvbr0: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc noqueue state UP mode DEFAULT group default qlen 1000\    link/ether 52:54:00:12:34:56 brd ff:ff:ff:ff:ff:ff
+15: veth3f0a1b2c@if2: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc noqueue master bvbr0 state UP mode DEFAULT group default\    link/ether 2e:7d:4b:90:c1:0e brd ff:ff:ff:ff:ff:ff link-netnsid 1
+"#;
+        assert_eq!(
+            Some("veth3f0a1b2c".to_string()),
+            find_link_name(host_links, 15)
+        );
+        assert_eq!(Some("bvbr0".to_string()), find_link_name(host_links, 5));
+        assert_eq!(None, find_link_name(host_links, 2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tc_cmds() {
+        assert_eq!(
+            [
+                "qdisc add dev veth0 root handle 1: htb default 1",
+                "class add dev veth0 parent 1: classid 1:1 htb rate 100mbit ceil 100mbit",
+            ],
+            htb_cmds("veth0", 100)
+        );
+        assert_eq!(
+            [
+                "qdisc add dev veth0 handle ffff: ingress",
+                "filter add dev veth0 parent ffff: matchall action police rate 1mbit burst 15000 drop",
+            ],
+            police_cmds("veth0", 1)
+        );
+    }
+
+    #[test]
+    fn test_limit_defaults() {
+        let default = BandwidthLimit {
+            ingress_mbit: Some(1000),
+            egress_mbit: Some(200),
+        };
+        assert_eq!(
+            BandwidthLimit {
+                ingress_mbit: Some(1000),
+                egress_mbit: Some(50),
+            },
+            BandwidthLimit {
+                ingress_mbit: None,
+                egress_mbit: Some(50),
+            }
+            .or(default)
+        );
+    }
+}
@@ -0,0 +1,898 @@
+//! nftables firewall backend. All node rules are kept in one chain per node, that is
+//! replaced atomically by single `nft -f` transaction, so node is never left unprotected.
+//!
+//! Traffic is dispatched to node chains by `nodes` and `nodes6` verdict maps (node IPv4/IPv6
+//! address -> `jump` to node chain), used by shared base chains in `inet blockvisor` table.
+
+use crate::firewall::{self, Action, Direction, Protocol, Rule};
+use crate::pal::NodeFirewallConfig;
+use async_trait::async_trait;
+use eyre::{bail, Result};
+use std::net::IpAddr;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+const TABLE: &str = "inet blockvisor";
+const NODES_MAP: &str = "nodes";
+const NODES6_MAP: &str = "nodes6";
+
+pub async fn apply_firewall_config(config: NodeFirewallConfig) -> Result<()> {
+    apply_firewall_config_with(config, &SysRunner).await
+}
+
+pub async fn cleanup_node_rules(node_id: Uuid) -> Result<()> {
+    cleanup_node_rules_with(node_id, &SysRunner).await
+}
+
+pub async fn node_rules_status(config: NodeFirewallConfig) -> Result<firewall::Status> {
+    node_rules_status_with(config, &SysRunner).await
+}
+
+#[async_trait]
+trait NftRunner {
+    async fn run<'a>(&self, args: &[&'a str], input: Option<&'a str>) -> Result<String>;
+}
+
+struct SysRunner;
+
+#[async_trait]
+impl NftRunner for SysRunner {
+    async fn run<'a>(&self, args: &[&'a str], input: Option<&'a str>) -> Result<String> {
+        let mut child = tokio::process::Command::new("nft")
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            if let Some(input) = input {
+                stdin.write_all(input.as_bytes()).await?;
+            }
+        }
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            let args_str = args.join(" ");
+            bail!("Failed to run command 'nft {args_str}', got output: `{output:?}`");
+        } else {
+            Ok(String::from_utf8(output.stdout)?)
+        }
+    }
+}
+
+async fn apply_firewall_config_with(
+    config: NodeFirewallConfig,
+    runner: &impl NftRunner,
+) -> Result<()> {
+    setup_base(runner).await?;
+    let chain = chain_name(config.id);
+    let mut script = format!(
+        "add chain {TABLE} {chain}\n\
+         flush chain {TABLE} {chain}\n"
+    );
+    for (set, addr_type) in limit_sets(config.id) {
+        script.push_str(&format!(
+            "add set {TABLE} {set} {{ type {addr_type}; flags dynamic; timeout 1m; }}\n\
+             flush set {TABLE} {set}\n"
+        ));
+    }
+    let node_ips = config
+        .node_ips()
+        .iter()
+        .map(|ip| ip.to_string())
+        .collect::<Vec<_>>();
+    // remove elements of previous node ips, or left by other node that used the same ip
+    for map in [NODES_MAP, NODES6_MAP] {
+        for (ip, target) in list_nodes(runner, map).await? {
+            if target == chain || node_ips.contains(&ip) {
+                script.push_str(&format!("delete element {TABLE} {map} {{ {ip} }}\n"));
+            }
+        }
+    }
+    for expr in node_rule_exprs(&config) {
+        script.push_str(&format!("add rule {TABLE} {chain} {expr}\n"));
+    }
+    for element in node_elements(&config) {
+        script.push_str(&format!("add element {TABLE} {element}\n"));
+    }
+    runner.run(&["-f", "-"], Some(&script)).await?;
+    Ok(())
+}
+
+async fn cleanup_node_rules_with(node_id: Uuid, runner: &impl NftRunner) -> Result<()> {
+    setup_base(runner).await?;
+    let chain = chain_name(node_id);
+    let mut script = String::new();
+    for map in [NODES_MAP, NODES6_MAP] {
+        for (ip, target) in list_nodes(runner, map).await? {
+            if target == chain {
+                script.push_str(&format!("delete element {TABLE} {map} {{ {ip} }}\n"));
+            }
+        }
+    }
+    // add before delete, so it doesn't fail if chain doesn't exist
+    script.push_str(&format!(
+        "add chain {TABLE} {chain}\n\
+         delete chain {TABLE} {chain}\n"
+    ));
+    for (set, addr_type) in limit_sets(node_id) {
+        script.push_str(&format!(
+            "add set {TABLE} {set} {{ type {addr_type}; flags dynamic; timeout 1m; }}\n\
+             delete set {TABLE} {set}\n"
+        ));
+    }
+    runner.run(&["-f", "-"], Some(&script)).await?;
+    Ok(())
+}
+
+async fn node_rules_status_with(
+    config: NodeFirewallConfig,
+    runner: &impl NftRunner,
+) -> Result<firewall::Status> {
+    let chain = chain_name(config.id);
+    let mut expected = node_rule_exprs(&config);
+    expected.extend(
+        node_elements(&config)
+            .into_iter()
+            .map(|element| element_entry(&element)),
+    );
+    // missing table or chain is not an error here, but a drift
+    let mut actual = runner
+        .run(&["list", "chain", "inet", "blockvisor", &chain], None)
+        .await
+        .unwrap_or_default()
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty() && !line.ends_with('{') && line != "}")
+        .collect::<Vec<_>>();
+    for map in [NODES_MAP, NODES6_MAP] {
+        for (ip, target) in list_nodes(runner, map).await.unwrap_or_default() {
+            if target == chain {
+                actual.push(format!("{ip} : jump {chain}"));
+            }
+        }
+    }
+    Ok(firewall::Status {
+        backend: firewall::Backend::Nftables,
+        drift: firewall::is_drift(
+            expected.iter().map(|line| rule_key(line)).collect(),
+            actual.iter().map(|line| rule_key(line)).collect(),
+        ),
+        expected,
+        actual,
+    })
+}
+
+/// Rule with whitespaces and order of anonymous set elements normalized, so expected rules can
+/// be compared with the ones printed by nft, including verdict and comment.
+fn rule_key(line: &str) -> String {
+    let mut key = String::new();
+    let mut rest = line;
+    while let Some((before, after)) = rest.split_once('{') {
+        let Some((set, after)) = after.split_once('}') else {
+            break;
+        };
+        let mut elements = set
+            .split(',')
+            .map(|element| element.split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|element| !element.is_empty())
+            .collect::<Vec<_>>();
+        elements.sort();
+        key.push_str(before);
+        key.push_str(&format!(" {{ {} }} ", elements.join(", ")));
+        rest = after;
+    }
+    key.push_str(rest);
+    key.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// All node rules, in order they are added to node chain.
+fn node_rule_exprs(config: &NodeFirewallConfig) -> Vec<String> {
+    let default_rules = [
+        default_rule("default in", &config.config.default_in, Direction::In),
+        default_rule("default out", &config.config.default_out, Direction::Out),
+    ];
+    let mut exprs = vec![];
+    for node_ip in config.node_ips() {
+        let limit_set = match node_ip {
+            IpAddr::V4(_) => limit_set_name(config.id),
+            IpAddr::V6(_) => limit6_set_name(config.id),
+        };
+        let node_ip = node_ip.to_string();
+        // the same precedence as in ufw backend - the last rule wins
+        for rule in config.config.rules.iter().rev().chain(&default_rules) {
+            let all_ips = config.config.rule_ips(rule);
+            let ips = firewall::family_ips(&all_ips, &node_ip);
+            // rule doesn't apply to this address if it has no ips of the same family
+            if all_ips.is_empty() || !ips.is_empty() {
+                exprs.extend(rule_exprs(&node_ip, &ips, &limit_set, rule));
+            }
+        }
+    }
+    exprs
+}
+
+/// Verdict map elements dispatching node traffic to node chain (without `add element <table>` part).
+fn node_elements(config: &NodeFirewallConfig) -> Vec<String> {
+    let chain = chain_name(config.id);
+    config
+        .node_ips()
+        .into_iter()
+        .map(|ip| {
+            let map = match ip {
+                IpAddr::V4(_) => NODES_MAP,
+                IpAddr::V6(_) => NODES6_MAP,
+            };
+            format!("{map} {{ {ip} : jump {chain} }}")
+        })
+        .collect()
+}
+
+/// Map element as printed by `nft list map`.
+fn element_entry(element: &str) -> String {
+    element
+        .split(['{', '}'])
+        .nth(1)
+        .unwrap_or(element)
+        .trim()
+        .to_string()
+}
+
+/// Create table with base chains dispatching traffic to node chains. It is idempotent,
+/// so it is safe to run it before each node config change.
+async fn setup_base(runner: &impl NftRunner) -> Result<()> {
+    let mut script = format!(
+        "add table {TABLE}\n\
+         add map {TABLE} {NODES_MAP} {{ type ipv4_addr : verdict; }}\n\
+         add map {TABLE} {NODES6_MAP} {{ type ipv6_addr : verdict; }}\n"
+    );
+    // separate base chains for inbound and outbound traffic, so accept verdict of one node
+    // doesn't bypass rules of the other one, when nodes talk to each other;
+    // established connections are accepted first (as in ufw base ruleset), so node still gets
+    // replies to its own outbound connections, even if inbound traffic is denied by default
+    for (name, hook, priority, addr) in [
+        ("forward_in", "forward", "filter", "daddr"),
+        ("forward_out", "forward", "filter + 1", "saddr"),
+        ("input", "input", "filter", "daddr"),
+        ("output", "output", "filter", "saddr"),
+    ] {
+        script.push_str(&format!(
+            "add chain {TABLE} {name} {{ type filter hook {hook} priority {priority}; policy accept; }}\n\
+             flush chain {TABLE} {name}\n\
+             add rule {TABLE} {name} ct state established,related accept\n\
+             add rule {TABLE} {name} ip {addr} vmap @{NODES_MAP}\n\
+             add rule {TABLE} {name} ip6 {addr} vmap @{NODES6_MAP}\n"
+        ));
+    }
+    runner.run(&["-f", "-"], Some(&script)).await?;
+    Ok(())
+}
+
+/// List verdict map elements as (ip, chain) pairs.
+async fn list_nodes(runner: &impl NftRunner, map: &str) -> Result<Vec<(String, String)>> {
+    let stdout = runner
+        .run(&["list", "map", "inet", "blockvisor", map], None)
+        .await?;
+    Ok(stdout
+        .split(['{', '}', ',', '\n'])
+        .filter_map(|element| {
+            element
+                .trim()
+                .split_once(" : jump ")
+                .map(|(ip, chain)| (ip.trim().to_string(), chain.trim().to_string()))
+        })
+        .collect())
+}
+
+fn chain_name(node_id: Uuid) -> String {
+    format!("node-{node_id}")
+}
+
+/// Dynamic set, used to track connection rate of remote ips matched by `limit` rules.
+fn limit_set_name(node_id: Uuid) -> String {
+    format!("node-{node_id}-limit")
+}
+
+/// The same as `limit_set_name`, but for IPv6 remote ips.
+fn limit6_set_name(node_id: Uuid) -> String {
+    format!("node-{node_id}-limit6")
+}
+
+/// Node limit sets with their address types.
+fn limit_sets(node_id: Uuid) -> [(String, &'static str); 2] {
+    [
+        (limit_set_name(node_id), "ipv4_addr"),
+        (limit6_set_name(node_id), "ipv6_addr"),
+    ]
+}
+
+fn default_rule(name: &str, action: &Action, direction: Direction) -> Rule {
+    Rule {
+        name: name.to_string(),
+        action: action.clone(),
+        direction,
+        protocol: None,
+        ips: vec![],
+        ip_sets: vec![],
+        ports: vec![],
+        port_ranges: vec![],
+        log: false,
+    }
+}
+
+/// Translate firewall rule into nft rule expressions (without `add rule <table> <chain>` part).
+fn rule_exprs(node_ip: &str, ips: &[String], limit_set: &str, rule: &Rule) -> Vec<String> {
+    let (local, remote) = match rule.direction {
+        Direction::In => ("daddr", "saddr"),
+        Direction::Out => ("saddr", "daddr"),
+    };
+    let family = if node_ip.contains(':') { "ip6" } else { "ip" };
+    let mut matches = vec![format!("{family} {local} {node_ip}")];
+    if !ips.is_empty() {
+        matches.push(format!("{family} {remote} {}", set(ips)));
+    }
+    let proto = match rule.protocol {
+        Some(Protocol::Tcp) => Some("tcp"),
+        Some(Protocol::Udp) => Some("udp"),
+        Some(Protocol::Both) | None => None,
+    };
+    if !rule.has_ports() {
+        if let Some(proto) = proto {
+            matches.push(format!("meta l4proto {proto}"));
+        }
+    } else {
+        let mut ports = rule
+            .ports
+            .iter()
+            .map(|port| port.to_string())
+            .collect::<Vec<_>>();
+        ports.extend(port_ranges(&rule.port_ranges));
+        let ports = set(&ports);
+        match proto {
+            Some(proto) => matches.push(format!("{proto} dport {ports}")),
+            None => matches.push(format!("meta l4proto {{ tcp, udp }} th dport {ports}")),
+        }
+    }
+    let matches = matches.join(" ");
+    let log = if rule.log {
+        // prefix is limited to 127 characters, rule name is already checked to be printable ASCII
+        let prefix = format!("bv {}: ", rule.name);
+        format!(
+            "log prefix \"{}\" ",
+            prefix.chars().take(127).collect::<String>()
+        )
+    } else {
+        String::new()
+    };
+    let comment = format!("comment \"{}\"", rule.name);
+    match rule.action {
+        Action::Limit => vec![
+            // the same limit as in ufw - 6 new connections within 30 seconds
+            format!(
+                "{matches} ct state new add @{limit_set} {{ {family} {remote} limit rate over 12/minute burst 6 packets }} {log}drop {comment}"
+            ),
+            format!("{matches} accept {comment}"),
+        ],
+        Action::Allow | Action::Deny | Action::Reject => {
+            vec![format!("{matches} {log}{} {comment}", verdict(&rule.action))]
+        }
+    }
+}
+
+fn port_ranges(ranges: &[firewall::PortRange]) -> Vec<String> {
+    ranges
+        .iter()
+        .map(|range| format!("{}-{}", range.from, range.to))
+        .collect()
+}
+
+fn set<T: ToString>(items: &[T]) -> String {
+    let items = items
+        .iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>();
+    format!("{{ {} }}", items.join(", "))
+}
+
+fn verdict(action: &Action) -> &'static str {
+    match action {
+        Action::Allow | Action::Limit => "accept",
+        Action::Deny => "drop",
+        Action::Reject => "reject",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firewall;
+    use mockall::*;
+    use std::collections::BTreeMap;
+    use std::net::Ipv6Addr;
+    use std::str::FromStr;
+
+    mock! {
+        pub TestRunner {}
+
+        #[async_trait]
+        impl NftRunner for TestRunner {
+            async fn run<'a>(&self, args: &[&'a str], input: Option<&'a str>) -> Result<String>;
+        }
+    }
+
+    const BASE_SCRIPT: &str = "add table inet blockvisor\n\
+        add map inet blockvisor nodes { type ipv4_addr : verdict; }\n\
+        add map inet blockvisor nodes6 { type ipv6_addr : verdict; }\n\
+        add chain inet blockvisor forward_in { type filter hook forward priority filter; policy accept; }\n\
+        flush chain inet blockvisor forward_in\n\
+        add rule inet blockvisor forward_in ct state established,related accept\n\
+        add rule inet blockvisor forward_in ip daddr vmap @nodes\n\
+        add rule inet blockvisor forward_in ip6 daddr vmap @nodes6\n\
+        add chain inet blockvisor forward_out { type filter hook forward priority filter + 1; policy accept; }\n\
+        flush chain inet blockvisor forward_out\n\
+        add rule inet blockvisor forward_out ct state established,related accept\n\
+        add rule inet blockvisor forward_out ip saddr vmap @nodes\n\
+        add rule inet blockvisor forward_out ip6 saddr vmap @nodes6\n\
+        add chain inet blockvisor input { type filter hook input priority filter; policy accept; }\n\
+        flush chain inet blockvisor input\n\
+        add rule inet blockvisor input ct state established,related accept\n\
+        add rule inet blockvisor input ip daddr vmap @nodes\n\
+        add rule inet blockvisor input ip6 daddr vmap @nodes6\n\
+        add chain inet blockvisor output { type filter hook output priority filter; policy accept; }\n\
+        flush chain inet blockvisor output\n\
+        add rule inet blockvisor output ct state established,related accept\n\
+        add rule inet blockvisor output ip saddr vmap @nodes\n\
+        add rule inet blockvisor output ip6 saddr vmap @nodes6\n";
+
+    const NODES_MAP_LIST: &str = "table inet blockvisor {\n\
+        \tmap nodes {\n\
+        \t\ttype ipv4_addr : verdict\n\
+        \t\telements = { 192.168.0.7 : jump node-5931bafa-92d9-4521-9fc6-a77eee047530,\n\
+        \t\t\t     192.168.0.13 : jump node-4931bafa-92d9-4521-9fc6-a77eee047530,\n\
+        \t\t\t     192.168.0.21 : jump node-6931bafa-92d9-4521-9fc6-a77eee047530 }\n\
+        \t}\n\
+        }\n";
+
+    fn expect_script(mock_runner: &mut MockTestRunner, expected_script: &'static str) {
+        mock_runner
+            .expect_run()
+            .once()
+            .withf(move |args, input| args == ["-f", "-"] && *input == Some(expected_script))
+            .returning(|_, _| Ok(String::default()));
+    }
+
+    fn expect_list(mock_runner: &mut MockTestRunner, map: &'static str, output: &'static str) {
+        mock_runner
+            .expect_run()
+            .once()
+            .withf(move |args, input| {
+                args == ["list", "map", "inet", "blockvisor", map] && input.is_none()
+            })
+            .returning(|_, _| Ok(output.to_string()));
+    }
+
+    fn default_config() -> NodeFirewallConfig {
+        NodeFirewallConfig {
+            id: Uuid::parse_str("4931bafa-92d9-4521-9fc6-a77eee047530").unwrap(),
+            ip: IpAddr::from_str("192.168.0.7").unwrap(),
+            ipv6: None,
+            bridge: Some("bvbr0".to_string()),
+            config: firewall::Config {
+                default_in: Action::Deny,
+                default_out: Action::Allow,
+                rules: vec![],
+                ip_sets: Default::default(),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_failed() -> Result<()> {
+        let config = default_config();
+        let mut mock_runner = MockTestRunner::new();
+        mock_runner
+            .expect_run()
+            .once()
+            .withf(|args, input| args == ["-f", "-"] && *input == Some(BASE_SCRIPT))
+            .returning(|_, _| bail!("test_error"));
+
+        assert_eq!(
+            "test_error",
+            apply_firewall_config_with(config, &mock_runner)
+                .await
+                .unwrap_err()
+                .to_string()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack() -> Result<()> {
+        let mut config = default_config();
+        config.ipv6 = Some(Ipv6Addr::from_str("fd00::7").unwrap());
+        config.config.rules = vec![
+            Rule {
+                name: "p2p".to_string(),
+                action: Action::Allow,
+                direction: Direction::In,
+                protocol: Some(Protocol::Tcp),
+                ips: vec![],
+                ip_sets: vec![],
+                ports: vec![30303],
+                port_ranges: vec![],
+                log: false,
+            },
+            Rule {
+                name: "rpc".to_string(),
+                action: Action::Limit,
+                direction: Direction::In,
+                protocol: Some(Protocol::Tcp),
+                ips: vec!["1.2.3.4".to_string(), "2001:db8::/32".to_string()],
+                ip_sets: vec![],
+                ports: vec![8545],
+                port_ranges: vec![],
+                log: false,
+            },
+            Rule {
+                name: "v4 only".to_string(),
+                action: Action::Deny,
+                direction: Direction::Out,
+                protocol: None,
+                ips: vec!["10.0.0.0/8".to_string()],
+                ip_sets: vec![],
+                ports: vec![],
+                port_ranges: vec![],
+                log: false,
+            },
+        ];
+        let mut mock_runner = MockTestRunner::new();
+        expect_script(&mut mock_runner, BASE_SCRIPT);
+        expect_list(&mut mock_runner, "nodes", "");
+        expect_list(
+            &mut mock_runner,
+            "nodes6",
+            "table inet blockvisor {\n\
+            \tmap nodes6 {\n\
+            \t\ttype ipv6_addr : verdict\n\
+            \t\telements = { fd00::7 : jump node-6931bafa-92d9-4521-9fc6-a77eee047530 }\n\
+            \t}\n\
+            }\n",
+        );
+        expect_script(
+            &mut mock_runner,
+            "add chain inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530\n\
+            flush chain inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530\n\
+            add set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit { type ipv4_addr; flags dynamic; timeout 1m; }\n\
+            flush set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit\n\
+            add set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit6 { type ipv6_addr; flags dynamic; timeout 1m; }\n\
+            flush set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit6\n\
+            delete element inet blockvisor nodes6 { fd00::7 }\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip saddr 192.168.0.7 ip daddr { 10.0.0.0/8 } drop comment \"v4 only\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip daddr 192.168.0.7 ip saddr { 1.2.3.4 } tcp dport { 8545 } ct state new add @node-4931bafa-92d9-4521-9fc6-a77eee047530-limit { ip saddr limit rate over 12/minute burst 6 packets } drop comment \"rpc\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip daddr 192.168.0.7 ip saddr { 1.2.3.4 } tcp dport { 8545 } accept comment \"rpc\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip daddr 192.168.0.7 tcp dport { 30303 } accept comment \"p2p\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip daddr 192.168.0.7 drop comment \"default in\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip saddr 192.168.0.7 accept comment \"default out\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip6 daddr fd00::7 ip6 saddr { 2001:db8::/32 } tcp dport { 8545 } ct state new add @node-4931bafa-92d9-4521-9fc6-a77eee047530-limit6 { ip6 saddr limit rate over 12/minute burst 6 packets } drop comment \"rpc\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip6 daddr fd00::7 ip6 saddr { 2001:db8::/32 } tcp dport { 8545 } accept comment \"rpc\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip6 daddr fd00::7 tcp dport { 30303 } accept comment \"p2p\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip6 daddr fd00::7 drop comment \"default in\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip6 saddr fd00::7 accept comment \"default out\"\n\
+            add element inet blockvisor nodes { 192.168.0.7 : jump node-4931bafa-92d9-4521-9fc6-a77eee047530 }\n\
+            add element inet blockvisor nodes6 { fd00::7 : jump node-4931bafa-92d9-4521-9fc6-a77eee047530 }\n",
+        );
+
+        apply_firewall_config_with(config, &mock_runner).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_no_rules() -> Result<()> {
+        let config = default_config();
+        let mut mock_runner = MockTestRunner::new();
+        // replies to node outbound connections are accepted by `BASE_SCRIPT` chains,
+        // before traffic gets to node chain with default in drop
+        expect_script(&mut mock_runner, BASE_SCRIPT);
+        expect_list(&mut mock_runner, "nodes", NODES_MAP_LIST);
+        expect_list(&mut mock_runner, "nodes6", "");
+        expect_script(
+            &mut mock_runner,
+            "add chain inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530\n\
+            flush chain inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530\n\
+            add set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit { type ipv4_addr; flags dynamic; timeout 1m; }\n\
+            flush set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit\n\
+            add set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit6 { type ipv6_addr; flags dynamic; timeout 1m; }\n\
+            flush set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit6\n\
+            delete element inet blockvisor nodes { 192.168.0.7 }\n\
+            delete element inet blockvisor nodes { 192.168.0.13 }\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip daddr 192.168.0.7 drop comment \"default in\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip saddr 192.168.0.7 accept comment \"default out\"\n\
+            add element inet blockvisor nodes { 192.168.0.7 : jump node-4931bafa-92d9-4521-9fc6-a77eee047530 }\n",
+        );
+
+        apply_firewall_config_with(config, &mock_runner).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_with_rules() -> Result<()> {
+        let mut config = default_config();
+        config.config.rules = vec![
+            Rule {
+                name: "rule A".to_string(),
+                action: Action::Allow,
+                direction: Direction::Out,
+                protocol: None,
+                ips: vec!["ip.is.validated.before".to_string()],
+                ip_sets: vec![],
+                ports: vec![7],
+                port_ranges: vec![],
+                log: false,
+            },
+            Rule {
+                name: "rule B".to_string(),
+                action: Action::Reject,
+                direction: Direction::In,
+                protocol: Some(Protocol::Tcp),
+                ips: vec!["1.2.3.4".to_string(), "10.0.0.0/8".to_string()],
+                ip_sets: vec![],
+                ports: vec![144, 77],
+                port_ranges: vec![],
+                log: false,
+            },
+            Rule {
+                name: "no ports".to_string(),
+                action: Action::Allow,
+                direction: Direction::Out,
+                protocol: Some(Protocol::Udp),
+                ips: vec![],
+                ip_sets: vec![],
+                ports: vec![],
+                port_ranges: vec![],
+                log: false,
+            },
+            Rule {
+                name: "".to_string(),
+                action: Action::Deny,
+                direction: Direction::In,
+                protocol: Some(Protocol::Both),
+                ips: vec![],
+                ip_sets: vec![],
+                ports: vec![7],
+                port_ranges: vec![],
+                log: false,
+            },
+        ];
+        let mut mock_runner = MockTestRunner::new();
+        expect_script(&mut mock_runner, BASE_SCRIPT);
+        expect_list(&mut mock_runner, "nodes", "");
+        expect_list(&mut mock_runner, "nodes6", "");
+        expect_script(
+            &mut mock_runner,
+            "add chain inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530\n\
+            flush chain inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530\n\
+            add set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit { type ipv4_addr; flags dynamic; timeout 1m; }\n\
+            flush set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit\n\
+            add set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit6 { type ipv6_addr; flags dynamic; timeout 1m; }\n\
+            flush set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit6\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip daddr 192.168.0.7 meta l4proto { tcp, udp } th dport { 7 } drop comment \"\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip saddr 192.168.0.7 meta l4proto udp accept comment \"no ports\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip daddr 192.168.0.7 ip saddr { 1.2.3.4, 10.0.0.0/8 } tcp dport { 144, 77 } reject comment \"rule B\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip saddr 192.168.0.7 ip daddr { ip.is.validated.before } meta l4proto { tcp, udp } th dport { 7 } accept comment \"rule A\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip daddr 192.168.0.7 drop comment \"default in\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip saddr 192.168.0.7 accept comment \"default out\"\n\
+            add element inet blockvisor nodes { 192.168.0.7 : jump node-4931bafa-92d9-4521-9fc6-a77eee047530 }\n",
+        );
+
+        apply_firewall_config_with(config, &mock_runner).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_extended_rules() -> Result<()> {
+        let mut config = default_config();
+        config.config.ip_sets =
+            BTreeMap::from([("clients".to_string(), vec!["10.0.0.0/8".to_string()])]);
+        config.config.rules = vec![
+            Rule {
+                name: "p2p".to_string(),
+                action: Action::Allow,
+                direction: Direction::In,
+                protocol: None,
+                ips: vec![],
+                ip_sets: vec![],
+                ports: vec![30303],
+                port_ranges: vec![firewall::PortRange {
+                    from: 40000,
+                    to: 40100,
+                }],
+                log: false,
+            },
+            Rule {
+                name: "rpc".to_string(),
+                action: Action::Limit,
+                direction: Direction::In,
+                protocol: Some(Protocol::Tcp),
+                ips: vec!["1.2.3.4".to_string()],
+                ip_sets: vec!["clients".to_string()],
+                ports: vec![8545],
+                port_ranges: vec![],
+                log: true,
+            },
+        ];
+        let mut mock_runner = MockTestRunner::new();
+        expect_script(&mut mock_runner, BASE_SCRIPT);
+        expect_list(&mut mock_runner, "nodes", "");
+        expect_list(&mut mock_runner, "nodes6", "");
+        expect_script(
+            &mut mock_runner,
+            "add chain inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530\n\
+            flush chain inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530\n\
+            add set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit { type ipv4_addr; flags dynamic; timeout 1m; }\n\
+            flush set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit\n\
+            add set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit6 { type ipv6_addr; flags dynamic; timeout 1m; }\n\
+            flush set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit6\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip daddr 192.168.0.7 ip saddr { 1.2.3.4, 10.0.0.0/8 } tcp dport { 8545 } ct state new add @node-4931bafa-92d9-4521-9fc6-a77eee047530-limit { ip saddr limit rate over 12/minute burst 6 packets } log prefix \"bv rpc: \" drop comment \"rpc\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip daddr 192.168.0.7 ip saddr { 1.2.3.4, 10.0.0.0/8 } tcp dport { 8545 } accept comment \"rpc\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip daddr 192.168.0.7 meta l4proto { tcp, udp } th dport { 30303, 40000-40100 } accept comment \"p2p\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip daddr 192.168.0.7 drop comment \"default in\"\n\
+            add rule inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530 ip saddr 192.168.0.7 accept comment \"default out\"\n\
+            add element inet blockvisor nodes { 192.168.0.7 : jump node-4931bafa-92d9-4521-9fc6-a77eee047530 }\n",
+        );
+
+        apply_firewall_config_with(config, &mock_runner).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cleanup() -> Result<()> {
+        let mut mock_runner = MockTestRunner::new();
+        expect_script(&mut mock_runner, BASE_SCRIPT);
+        expect_list(&mut mock_runner, "nodes", NODES_MAP_LIST);
+        expect_list(&mut mock_runner, "nodes6", "");
+        expect_script(
+            &mut mock_runner,
+            "delete element inet blockvisor nodes { 192.168.0.13 }\n\
+            add chain inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530\n\
+            delete chain inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530\n\
+            add set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit { type ipv4_addr; flags dynamic; timeout 1m; }\n\
+            delete set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit\n\
+            add set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit6 { type ipv6_addr; flags dynamic; timeout 1m; }\n\
+            delete set inet blockvisor node-4931bafa-92d9-4521-9fc6-a77eee047530-limit6\n",
+        );
+
+        cleanup_node_rules_with(
+            Uuid::parse_str("4931bafa-92d9-4521-9fc6-a77eee047530").unwrap(),
+            &mock_runner,
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_node_rules_status() -> Result<()> {
+        let config = default_config();
+        let mut mock_runner = MockTestRunner::new();
+        mock_runner
+            .expect_run()
+            .once()
+            .withf(|args, input| {
+                args == [
+                    "list",
+                    "chain",
+                    "inet",
+                    "blockvisor",
+                    "node-4931bafa-92d9-4521-9fc6-a77eee047530",
+                ] && input.is_none()
+            })
+            .returning(|_, _| {
+                Ok("table inet blockvisor {\n\
+                \tchain node-4931bafa-92d9-4521-9fc6-a77eee047530 {\n\
+                \t\tip daddr 192.168.0.7 drop comment \"default in\"\n\
+                \t\tip saddr 192.168.0.7 accept comment \"default out\"\n\
+                \t}\n\
+                }\n"
+                .to_string())
+            });
+        expect_list(&mut mock_runner, "nodes", NODES_MAP_LIST);
+        expect_list(&mut mock_runner, "nodes6", "");
+
+        let status = node_rules_status_with(config, &mock_runner).await?;
+        assert_eq!(
+            vec![
+                "ip daddr 192.168.0.7 drop comment \"default in\"",
+                "ip saddr 192.168.0.7 accept comment \"default out\"",
+                "192.168.0.7 : jump node-4931bafa-92d9-4521-9fc6-a77eee047530",
+            ],
+            status.expected
+        );
+        assert_eq!(
+            vec![
+                "ip daddr 192.168.0.7 drop comment \"default in\"",
+                "ip saddr 192.168.0.7 accept comment \"default out\"",
+                "192.168.0.13 : jump node-4931bafa-92d9-4521-9fc6-a77eee047530",
+            ],
+            status.actual
+        );
+        // node ip is not dispatched to node chain
+        assert!(status.drift);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_node_rules_status_edited_rule() -> Result<()> {
+        async fn status_with_chain(chain: &'static str) -> Result<firewall::Status> {
+            let mut mock_runner = MockTestRunner::new();
+            mock_runner
+                .expect_run()
+                .once()
+                .withf(|args, _| args[..2] == ["list", "chain"])
+                .returning(move |_, _| Ok(chain.to_string()));
+            expect_list(
+                &mut mock_runner,
+                "nodes",
+                "table inet blockvisor {\n\
+                \tmap nodes {\n\
+                \t\ttype ipv4_addr : verdict\n\
+                \t\telements = { 192.168.0.7 : jump node-4931bafa-92d9-4521-9fc6-a77eee047530 }\n\
+                \t}\n\
+                }\n",
+            );
+            expect_list(&mut mock_runner, "nodes6", "");
+            let mut config = default_config();
+            config.config.rules = vec![Rule {
+                name: "rpc".to_string(),
+                action: Action::Allow,
+                direction: Direction::In,
+                protocol: Some(Protocol::Tcp),
+                ips: vec!["1.2.3.4".to_string(), "1.2.3.0/28".to_string()],
+                ip_sets: vec![],
+                ports: vec![8545],
+                port_ranges: vec![],
+                log: false,
+            }];
+            node_rules_status_with(config, &mock_runner).await
+        }
+
+        // nft prints set elements in its own order
+        let status = status_with_chain(
+            "table inet blockvisor {\n\
+            \tchain node-4931bafa-92d9-4521-9fc6-a77eee047530 {\n\
+            \t\tip daddr 192.168.0.7 ip saddr { 1.2.3.0/28, 1.2.3.4 } tcp dport { 8545 } accept comment \"rpc\"\n\
+            \t\tip daddr 192.168.0.7 drop comment \"default in\"\n\
+            \t\tip saddr 192.168.0.7 accept comment \"default out\"\n\
+            \t}\n\
+            }\n",
+        )
+        .await?;
+        assert!(!status.drift);
+
+        // rule edited in place, but comment left untouched
+        let status = status_with_chain(
+            "table inet blockvisor {\n\
+            \tchain node-4931bafa-92d9-4521-9fc6-a77eee047530 {\n\
+            \t\tip daddr 192.168.0.7 ip saddr { 1.2.3.0/28, 1.2.3.4 } tcp dport { 8545 } accept comment \"rpc\"\n\
+            \t\tip daddr 192.168.0.7 accept comment \"default in\"\n\
+            \t\tip saddr 192.168.0.7 accept comment \"default out\"\n\
+            \t}\n\
+            }\n",
+        )
+        .await?;
+        assert!(status.drift);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_node_rules_status_missing_table() -> Result<()> {
+        let mut mock_runner = MockTestRunner::new();
+        mock_runner
+            .expect_run()
+            .times(3)
+            .returning(|_, _| bail!("No such file or directory"));
+
+        let status = node_rules_status_with(default_config(), &mock_runner).await?;
+        assert!(status.actual.is_empty());
+        assert!(status.drift);
+        Ok(())
+    }
+}
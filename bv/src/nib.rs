@@ -1,12 +1,13 @@
 use crate::nib_meta::StorePointer;
 use crate::{
     apptainer_machine::{self, PLUGIN_MAIN_FILENAME, PLUGIN_PATH},
-    bv_config, firewall,
+    bv_config, firewall, image_verification,
     internal_server::{self, service_client::ServiceClient, NodeDisplayInfo},
+    net_shaping,
     nib_cli::{ImageCommand, NodeChecks, ProtocolCommand},
     nib_meta::{
-        self, ArchivePointer, FirewallConfig, ImageProperty, RamdiskConfig, Variant,
-        VariantMetadata, Visibility,
+        self, ArchivePointer, BandwidthLimit, FirewallConfig, ImageProperty, RamdiskConfig,
+        Variant, VariantMetadata, Visibility,
     },
     node_context,
     node_state::{NodeImage, NodeProperties, NodeState, ProtocolImageKey, VmConfig, VmStatus},
@@ -95,7 +96,16 @@ pub async fn process_image_command(
             path,
             variant,
         } => {
-            let image: nib_meta::Image = serde_yaml_ng::from_str(&fs::read_to_string(path).await?)?;
+            let mut image: nib_meta::Image =
+                serde_yaml_ng::from_str(&fs::read_to_string(path).await?)?;
+            // dev node may be played from local image, so pinning is best effort here
+            match pin_container_uri(&image.container_uri).await {
+                Ok(pinned_uri) => image.container_uri = pinned_uri,
+                Err(err) => println!(
+                    "WARNING! {err:#}, dev_node is created from not pinned `{}`",
+                    image.container_uri
+                ),
+            }
             let variant = pick_variant(image.variants.clone(), variant)?;
             let image_variant = ImageVariant::build(&image, variant);
             let properties = build_properties(&image_variant.properties, props)?;
@@ -224,6 +234,7 @@ pub async fn process_image_command(
                     node_org_id: "org-id".to_string(),
                     data_mount_point: PathBuf::from("/blockjoy"),
                     protocol_data_path: PathBuf::from("/blockjoy/protocol_data"),
+                    data_quota_bytes: None,
                 },
                 properties,
             );
@@ -249,7 +260,10 @@ pub async fn process_image_command(
             let min_babel_version =
                 min_babel_version.unwrap_or(env!("CARGO_PKG_VERSION").to_string());
             let mut client = services::protocol::ProtocolService::new(connector).await?;
-            let image: nib_meta::Image = serde_yaml_ng::from_str(&fs::read_to_string(path).await?)?;
+            let mut image: nib_meta::Image =
+                serde_yaml_ng::from_str(&fs::read_to_string(path).await?)?;
+            // record digest of pushed image, so API created nodes can pass image verification
+            image.container_uri = pin_container_uri(&image.container_uri).await?;
             let image_variants: Vec<_> = image
                 .variants
                 .iter()
@@ -361,6 +375,44 @@ pub async fn load_bv_config(bv_root: &Path) -> eyre::Result<bv_config::Config> {
     Ok(serde_json::from_str(&fs::read_to_string(&bv_path).await?)?)
 }
 
+/// Pin container image to its registry digest, so nodes are built exactly from pushed image,
+/// even if tag is moved later.
+async fn pin_container_uri(container_uri: &str) -> eyre::Result<String> {
+    if image_verification::image_digest(container_uri).is_some() {
+        return Ok(container_uri.to_string());
+    }
+    let Some(reference) = container_uri.strip_prefix("docker://") else {
+        println!("WARNING! container_uri `{container_uri}` is not pinned by digest, nodes created from it won't pass image verification");
+        return Ok(container_uri.to_string());
+    };
+    let manifest: serde_json::Value = serde_json::from_str(
+        &run_cmd(
+            "docker",
+            [
+                "buildx",
+                "imagetools",
+                "inspect",
+                "--format",
+                "{{json .Manifest}}",
+                reference,
+            ],
+        )
+        .await
+        .with_context(|| format!("failed to resolve `{container_uri}` digest"))?,
+    )?;
+    let digest = manifest
+        .get("digest")
+        .and_then(|digest| digest.as_str())
+        .ok_or_else(|| anyhow!("missing digest in `{container_uri}` manifest"))?;
+    let pinned_uri = image_verification::pin_digest(container_uri, digest);
+    ensure!(
+        image_verification::image_digest(&pinned_uri).is_some(),
+        "unsupported `{container_uri}` digest `{digest}`"
+    );
+    println!("Container image pinned to `{pinned_uri}`");
+    Ok(pinned_uri)
+}
+
 async fn extract_image_fs(rootfs_path: &Path, container_image_uri: &str) -> eyre::Result<()> {
     let uri = container_image_uri
         .split_once("://")
@@ -482,6 +534,7 @@ pub struct ImageVariant {
     pub min_memory_mb: u64,
     pub min_disk_gb: u64,
     pub ramdisks: Vec<RamdiskConfig>,
+    pub bandwidth: Option<BandwidthLimit>,
     pub metadata: Vec<VariantMetadata>,
     pub dns_scheme: Option<String>,
 }
@@ -522,6 +575,7 @@ impl ImageVariant {
             min_memory_mb: variant.min_memory_mb,
             min_disk_gb: variant.min_disk_gb,
             ramdisks: variant.ramdisks,
+            bandwidth: variant.bandwidth,
             metadata,
             dns_scheme: variant.dns_scheme.or(image.dns_scheme.clone()),
         }
@@ -532,12 +586,12 @@ impl ImageVariant {
         semver::Version::from_str(&self.version).with_context(|| "version must be semantic")?;
         Uri::from_str(&self.container_uri).with_context(|| "invalid container_uri")?;
         self.validate_keys()?;
-        self.validate_ips()?;
+        firewall::check_rules(&self.firewall_config.clone().into())?;
         if let Some(suspicious_rule) = self
             .firewall_config
             .rules
             .iter()
-            .find(|rule| rule.ports.is_empty())
+            .find(|rule| rule.ports.is_empty() && rule.port_ranges.is_empty())
         {
             println!(
                 "WARNING! Rule {} has empty ports array. It means opening all ports. Are you sure?",
@@ -547,20 +601,6 @@ impl ImageVariant {
         Ok(())
     }
 
-    fn validate_ips(&self) -> eyre::Result<()> {
-        for rule in &self.firewall_config.rules {
-            for ip in &rule.ips {
-                ensure!(
-                    cidr_utils::cidr::IpCidr::is_ip_cidr(&ip.ip),
-                    "invalid ip ({}) in rule '{}'",
-                    ip.ip,
-                    rule.key
-                );
-            }
-        }
-        Ok(())
-    }
-
     fn validate_variant_sku(&self) -> eyre::Result<()> {
         ensure!(
             self
@@ -609,6 +649,9 @@ impl ImageVariant {
         for item in &self.firewall_config.rules {
             ensure_kebab_case!("firewall.rule.key", item.key);
         }
+        for item in &self.firewall_config.ip_sets {
+            ensure_kebab_case!("firewall.ip_set.key", item.key);
+        }
         Ok(())
     }
 }
@@ -652,6 +695,8 @@ impl DevNode {
                 dev_mode: true,
                 ip,
                 gateway,
+                // assigned by BV, if host network is dual-stack
+                ipv6: None,
                 properties,
                 firewall: image_variant.firewall_config.into(),
                 display_name: "".to_string(),
@@ -776,6 +821,7 @@ impl From<nib_meta::Action> for firewall::Action {
             nib_meta::Action::Allow => firewall::Action::Allow,
             nib_meta::Action::Deny => firewall::Action::Deny,
             nib_meta::Action::Reject => firewall::Action::Reject,
+            nib_meta::Action::Limit => firewall::Action::Limit,
         }
     }
 }
@@ -807,7 +853,17 @@ impl From<nib_meta::FirewallRule> for firewall::Rule {
             direction: value.direction.into(),
             protocol: Some(value.protocol.into()),
             ips: value.ips.into_iter().map(|ip| ip.ip).collect(),
+            ip_sets: value.ip_sets,
             ports: value.ports.into_iter().map(|port| port.port).collect(),
+            port_ranges: value
+                .port_ranges
+                .into_iter()
+                .map(|range| firewall::PortRange {
+                    from: range.from,
+                    to: range.to,
+                })
+                .collect(),
+            log: value.log,
         }
     }
 }
@@ -818,6 +874,11 @@ impl From<nib_meta::FirewallConfig> for firewall::Config {
             default_out: value.default_out.into(),
             default_in: value.default_in.into(),
             rules: value.rules.into_iter().map(|rule| rule.into()).collect(),
+            ip_sets: value
+                .ip_sets
+                .into_iter()
+                .map(|set| (set.key, set.ips.into_iter().map(|ip| ip.ip).collect()))
+                .collect(),
         }
     }
 }
@@ -836,6 +897,14 @@ impl VmConfig {
                     ram_disk_size_mb: ramdisk.size_mb,
                 })
                 .collect(),
+            bandwidth: value
+                .bandwidth
+                .as_ref()
+                .map(|bandwidth| net_shaping::BandwidthLimit {
+                    ingress_mbit: bandwidth.ingress_mbit,
+                    egress_mbit: bandwidth.egress_mbit,
+                })
+                .unwrap_or_default(),
         }
     }
 }
@@ -27,6 +27,8 @@ pub struct Variant {
     pub min_disk_gb: u64,
     #[serde(default)]
     pub ramdisks: Vec<RamdiskConfig>,
+    #[serde(default)]
+    pub bandwidth: Option<BandwidthLimit>,
 
     // overrides
     pub dns_scheme: Option<String>,
@@ -78,11 +80,19 @@ pub struct RamdiskConfig {
     pub size_mb: u64,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BandwidthLimit {
+    pub ingress_mbit: Option<u64>,
+    pub egress_mbit: Option<u64>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct FirewallConfig {
     pub default_in: Action,
     pub default_out: Action,
     pub rules: Vec<FirewallRule>,
+    #[serde(default)]
+    pub ip_sets: Vec<IpSet>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -94,7 +104,13 @@ pub struct FirewallRule {
     pub action: Action,
     #[serde(default)]
     pub ips: Vec<IpName>,
+    #[serde(default)]
+    pub ip_sets: Vec<String>,
     pub ports: Vec<PortName>,
+    #[serde(default)]
+    pub port_ranges: Vec<PortRangeName>,
+    #[serde(default)]
+    pub log: bool,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -103,6 +119,7 @@ pub enum Action {
     Allow,
     Deny,
     Reject,
+    Limit,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -140,6 +157,20 @@ pub struct PortName {
     pub name: Option<String>,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PortRangeName {
+    pub from: u16,
+    pub to: u16,
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct IpSet {
+    pub key: String,
+    pub description: Option<String>,
+    pub ips: Vec<IpName>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct ImageProperty {
     pub key: String,
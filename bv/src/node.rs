@@ -7,18 +7,31 @@ use crate::{
     command_failed, commands,
     commands::into_internal,
     cpu_registry::CpuRegistry,
+    disk_quota::DiskUsage,
+    firewall,
+    net_shaping::TrafficUsage,
     node_context::NodeContext,
+    node_snapshot::{Snapshot, Snapshots},
     node_state::{CpuAssignmentUpdate, NodeState, UpgradeState, UpgradeStep, VmStatus},
     pal::{self, NodeConnection, NodeFirewallConfig, Pal, RecoverBackoff, VirtualMachine},
     scheduler,
 };
 use babel_api::engine::NodeEnv;
-use babel_api::{engine::JobStatus, rhai_plugin::RhaiPlugin, utils::BabelConfig};
+use babel_api::{
+    engine::{JobStatus, JobsInfo},
+    plugin::NodeHealth,
+    rhai_plugin::{
+        RhaiPlugin, ON_PROPERTIES_CHANGED_FN_NAME, POST_UPGRADE_FN_NAME, PRE_DELETE_FN_NAME,
+        PRE_STOP_FN_NAME, PRE_UPGRADE_FN_NAME,
+    },
+    utils::BabelConfig,
+};
 use bv_utils::{rpc::with_timeout, with_retry};
 use chrono::Utc;
 use eyre::{anyhow, bail, Context, Report, Result};
+use metrics::counter;
 use std::{fmt::Debug, path::Path, sync::Arc, time::Duration};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, RwLock};
 use tokio::{fs, time::Instant};
 use tracing::{debug, error, info, instrument, warn};
 use uuid::Uuid;
@@ -27,6 +40,7 @@ pub const NODE_REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
 const DEFAULT_UPGRADE_RETRY_HINT: Duration = Duration::from_secs(3600);
 const NODE_STOP_TIMEOUT: Duration = Duration::from_secs(60);
 const NODE_STOPPED_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const UPGRADE_VERIFICATION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
 
 pub type BabelEngine<N> = babel_engine::BabelEngine<N, RhaiPlugin<babel_engine::Engine>>;
 
@@ -89,6 +103,7 @@ impl<P: Pal> MaybeNode<P> {
             pal.apply_firewall_config(NodeFirewallConfig {
                 id: self.state.id,
                 ip: self.state.ip,
+                ipv6: self.state.ipv6,
                 bridge: bridge.clone(),
                 config: self.state.firewall.clone(),
             })
@@ -182,7 +197,7 @@ impl<P: Pal + Debug> Node<P> {
     pub async fn attach(
         pal: Arc<P>,
         api_config: SharedConfig,
-        state: NodeState,
+        mut state: NodeState,
         scheduler_tx: mpsc::Sender<scheduler::Action>,
         cpu_registry: CpuRegistry,
     ) -> Result<Self> {
@@ -212,7 +227,13 @@ impl<P: Pal + Debug> Node<P> {
             .with_context(|| "attach vm failed")?;
         let plugin_path = machine.plugin_path();
         let node_env = machine.node_env();
-        if machine.state().await == pal::VmState::RUNNING {
+        let vm_state = machine.state().await;
+        if let Some(status) = stale_suspension(state.expected_status, &vm_state) {
+            warn!("node {node_id} is not suspended anymore (e.g. after host reboot), expected status reset to {status}");
+            state.expected_status = status;
+            state.save(&context.nodes_dir).await?;
+        }
+        if vm_state == pal::VmState::RUNNING {
             debug!("connecting to babel ...");
             // Since this is the startup phase it doesn't make sense to wait a long time
             // for the nodes to come online. For that reason we restrict the allowed delay
@@ -268,12 +289,64 @@ impl<P: Pal + Debug> Node<P> {
         self.state.id
     }
 
+    /// Returns node data usage versus its quota, if quota is enforced.
+    pub async fn disk_usage(&self) -> Option<DiskUsage> {
+        self.machine.disk_usage().await.unwrap_or_else(|err| {
+            warn!("failed to get node {} disk usage: {err:#}", self.id());
+            None
+        })
+    }
+
+    /// Returns node network traffic counters, if available.
+    pub async fn traffic_usage(&self) -> Option<TrafficUsage> {
+        self.machine.traffic_usage().await.unwrap_or_else(|err| {
+            warn!("failed to get node {} traffic usage: {err:#}", self.id());
+            None
+        })
+    }
+
+    /// Compare firewall rules actually applied on the host with node firewall config.
+    /// Returns `None` if platform doesn't support such check.
+    pub async fn firewall_status(&self) -> Result<Option<firewall::Status>> {
+        self.pal.firewall_status(self.firewall_config()).await
+    }
+
+    /// Re-apply node firewall config if rules applied on the host drifted from it.
+    /// Returns `true` if drift was detected.
+    pub async fn reconcile_firewall(&mut self) -> Result<bool> {
+        if self.state.upgrade_state.active {
+            // firewall is applied as one of upgrade steps
+            return Ok(false);
+        }
+        match self.firewall_status().await? {
+            Some(status) if status.drift => {
+                counter!("bv.node.firewall.drift", "node_id" => self.id().to_string()).increment(1);
+                self.pal
+                    .apply_firewall_config(self.firewall_config())
+                    .await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn firewall_config(&self) -> NodeFirewallConfig {
+        NodeFirewallConfig {
+            id: self.state.id,
+            ip: self.state.ip,
+            ipv6: self.state.ipv6,
+            bridge: self.bv_context.bridge.clone(),
+            config: self.state.firewall.clone(),
+        }
+    }
+
     /// Returns the actual status of the node.
     pub async fn status(&self) -> VmStatus {
         let machine_status = match self.machine.state().await {
             pal::VmState::RUNNING => VmStatus::Running,
             pal::VmState::SHUTOFF => VmStatus::Stopped,
             pal::VmState::INVALID => VmStatus::Failed,
+            pal::VmState::SUSPENDED => VmStatus::Suspended,
         };
         if machine_status == self.state.expected_status {
             if machine_status == VmStatus::Running // node is running, but
@@ -290,6 +363,11 @@ impl<P: Pal + Debug> Node<P> {
         }
     }
 
+    /// Returns state of node machine, as reported by runtime.
+    pub async fn machine_state(&self) -> pal::VmState {
+        self.machine.state().await
+    }
+
     /// Returns the expected status of the node.
     pub fn expected_status(&self) -> VmStatus {
         self.state.expected_status
@@ -305,6 +383,9 @@ impl<P: Pal + Debug> Node<P> {
     /// Starts the node.
     #[instrument(skip(self))]
     pub async fn start(&mut self) -> Result<()> {
+        if self.check_suspension().await? {
+            return self.resume().await;
+        }
         let status = self.status().await;
         if status == VmStatus::Failed && self.expected_status() == VmStatus::Stopped {
             bail!("can't start node which is not stopped properly");
@@ -347,6 +428,17 @@ impl<P: Pal + Debug> Node<P> {
     /// Stops the running node.
     #[instrument(skip(self))]
     pub async fn stop(&mut self, force: bool) -> Result<()> {
+        if self.check_suspension().await? {
+            // processes must be thawed first, so they can be gracefully shut down
+            self.resume().await?;
+        }
+        if self.babel_engine.has_capability(PRE_STOP_FN_NAME)
+            && self.status().await == VmStatus::Running
+        {
+            if let Err(err) = run_hook(PRE_STOP_FN_NAME, self.babel_engine.pre_stop()).await {
+                warn!("{err:#}");
+            }
+        }
         self.save_expected_status(VmStatus::Stopped).await?;
         if self.status().await == VmStatus::Stopped {
             return Ok(());
@@ -363,7 +455,7 @@ impl<P: Pal + Debug> Node<P> {
         self.save_state().await?;
         match self.machine.state().await {
             pal::VmState::SHUTOFF => {}
-            pal::VmState::RUNNING | pal::VmState::INVALID => {
+            pal::VmState::RUNNING | pal::VmState::INVALID | pal::VmState::SUSPENDED => {
                 if let Err(err) = self.machine.shutdown().await {
                     warn!("Graceful shutdown failed: {err:#}");
                     self.machine
@@ -389,6 +481,48 @@ impl<P: Pal + Debug> Node<P> {
         Ok(())
     }
 
+    /// Suspends the running node, by freezing all its processes.
+    #[instrument(skip(self))]
+    pub async fn suspend(&mut self) -> Result<()> {
+        match self.status().await {
+            VmStatus::Suspended => return Ok(()),
+            VmStatus::Running => {}
+            status => bail!("can't suspend node in {status} state"),
+        }
+        if self.state.upgrade_state.active {
+            bail!("can't suspend node while upgrade is in progress");
+        }
+        self.machine.suspend().await?;
+        // babel is frozen too, so don't even try to talk to it
+        self.babel_engine.node_connection.close();
+        self.save_expected_status(VmStatus::Suspended).await?;
+        debug!("Node suspended");
+        Ok(())
+    }
+
+    /// Resumes suspended node.
+    #[instrument(skip(self))]
+    pub async fn resume(&mut self) -> Result<()> {
+        if self.state.expected_status != VmStatus::Suspended {
+            bail!("node is not suspended");
+        }
+        if !self.check_suspension().await? {
+            // nothing is frozen, so there is nothing to resume
+            return Ok(());
+        }
+        self.machine.resume().await?;
+        self.babel_engine.node_connection.attach().await?;
+        // node may be attached while suspended (e.g. after BV restart), when job runner is not checked
+        check_job_runner(
+            &mut self.babel_engine.node_connection,
+            self.pal.job_runner_path(),
+        )
+        .await?;
+        self.save_expected_status(VmStatus::Running).await?;
+        debug!("Node resumed");
+        Ok(())
+    }
+
     pub async fn restart(&mut self, force: bool) -> Result<()> {
         self.state.restarting = true;
         self.stop(force).await?;
@@ -399,6 +533,13 @@ impl<P: Pal + Debug> Node<P> {
     /// Deletes the node.
     #[instrument(skip(self))]
     pub async fn delete(&mut self) -> Result<()> {
+        if self.babel_engine.has_capability(PRE_DELETE_FN_NAME)
+            && self.status().await == VmStatus::Running
+        {
+            if let Err(err) = run_hook(PRE_DELETE_FN_NAME, self.babel_engine.pre_delete()).await {
+                warn!("{err:#}");
+            }
+        }
         // set expected to `Stopped` just in case of delete errors
         self.save_expected_status(VmStatus::Stopped).await?;
         self.babel_engine.stop().await?;
@@ -407,17 +548,21 @@ impl<P: Pal + Debug> Node<P> {
             .release(&mut self.state.assigned_cpus)
             .await;
         self.pal.cleanup_firewall_config(self.state.id).await?;
+        if let Err(err) = self.snapshots().delete_all().await {
+            warn!("failed to delete node snapshots: {err:#}");
+        }
         self.context.delete().await
     }
 
     pub async fn update(&mut self, config_update: ConfigUpdate) -> commands::Result<()> {
         let status = self.status().await;
-        if status == VmStatus::Failed {
+        if status == VmStatus::Failed || status == VmStatus::Suspended {
             return Err(commands::Error::Internal(anyhow!(
-                "can't update node in Failed state"
+                "can't update node in {status} state"
             )));
         }
-        let params_changed = !config_update.new_values.is_empty();
+        let changed_properties = config_update.new_values.clone();
+        let params_changed = !changed_properties.is_empty();
         if params_changed {
             if status == VmStatus::Running
                 && self
@@ -453,6 +598,7 @@ impl<P: Pal + Debug> Node<P> {
                 .apply_firewall_config(NodeFirewallConfig {
                     id: self.state.id,
                     ip: self.state.ip,
+                    ipv6: self.state.ipv6,
                     bridge: self.bv_context.bridge.clone(),
                     config: self.state.firewall.clone(),
                 })
@@ -468,21 +614,96 @@ impl<P: Pal + Debug> Node<P> {
         }
         self.machine.update_node_env(&self.state);
         self.node_env = self.machine.node_env();
+        if params_changed {
+            // make sure plugin see up-to-date properties
+            self.babel_engine
+                .update_node_info(self.state.image.clone(), self.state.properties.clone());
+            self.reload_plugin().await?;
+        }
         if params_changed && status == VmStatus::Running {
-            self.babel_engine.init().await?;
+            if self
+                .babel_engine
+                .has_capability(ON_PROPERTIES_CHANGED_FN_NAME)
+            {
+                run_hook(
+                    ON_PROPERTIES_CHANGED_FN_NAME,
+                    self.babel_engine.on_properties_changed(changed_properties),
+                )
+                .await?;
+            } else {
+                self.babel_engine.init().await?;
+            }
             self.state.initialized = true;
             self.save_state().await?;
         }
         Ok(())
     }
 
+    /// Changes node CPU and memory allocation in place, without rebuilding the VM.
+    #[instrument(skip(self))]
+    pub async fn resize(
+        &mut self,
+        vcpu_count: Option<usize>,
+        mem_size_mb: Option<u64>,
+    ) -> Result<()> {
+        if self.status().await == VmStatus::Failed {
+            bail!("can't resize node in Failed state");
+        }
+        if self.state.upgrade_state.active {
+            bail!("can't resize node while upgrade is in progress");
+        }
+        let original_vm_config = self.state.vm_config.clone();
+        let original_cpus = self.state.assigned_cpus.clone();
+        let mut released_cpus = vec![];
+        if let Some(vcpu_count) = vcpu_count {
+            if vcpu_count == 0 {
+                bail!("node needs at least one vcpu");
+            }
+            let assigned = self.state.assigned_cpus.len();
+            if vcpu_count > assigned {
+                let mut acquired = self.cpu_registry.acquire(vcpu_count - assigned).await?;
+                self.state.assigned_cpus.append(&mut acquired);
+            } else {
+                released_cpus = self.state.assigned_cpus.split_off(vcpu_count);
+            }
+            self.state.vm_config.vcpu_count = vcpu_count;
+        }
+        if let Some(mem_size_mb) = mem_size_mb {
+            self.state.vm_config.mem_size_mb = mem_size_mb;
+        }
+        if let Err(err) = self.machine.resize(&self.state).await {
+            // give back just acquired cpus and restore previous allocation
+            let mut acquired: Vec<_> = self
+                .state
+                .assigned_cpus
+                .iter()
+                .filter(|cpu| !original_cpus.contains(cpu))
+                .copied()
+                .collect();
+            self.cpu_registry.release(&mut acquired).await;
+            self.state.assigned_cpus = original_cpus;
+            self.state.vm_config = original_vm_config;
+            if let Err(rollback_err) = self.machine.resize(&self.state).await {
+                warn!("failed to restore previous VM resources: {rollback_err:#}");
+            }
+            return Err(err);
+        }
+        self.cpu_registry.release(&mut released_cpus).await;
+        self.save_state().await?;
+        info!(
+            "Node resized to {} vcpu and {} MB of memory",
+            self.state.vm_config.vcpu_count, self.state.vm_config.mem_size_mb
+        );
+        Ok(())
+    }
+
     /// Updates OS image and related config for VM.
     #[instrument(skip(self))]
     pub async fn upgrade(&mut self, desired_state: NodeState) -> commands::Result<()> {
         let status = self.status().await;
-        if status == VmStatus::Failed {
+        if status == VmStatus::Failed || status == VmStatus::Suspended {
             return Err(commands::Error::Internal(anyhow!(
-                "can't upgrade node in Failed state"
+                "can't upgrade node in {status} state"
             )));
         }
 
@@ -501,11 +722,29 @@ impl<P: Pal + Debug> Node<P> {
                         retry_hint: DEFAULT_UPGRADE_RETRY_HINT,
                     });
                 }
+                // build new VM while the old one is still running, to minimize downtime
+                self.machine.stage_upgrade(&desired_state).await?;
+                // call hook right before node is stopped, so node doesn't keep running old version
+                // after pre-upgrade side effects (e.g. migrations) are done
+                if self.babel_engine.has_capability(PRE_UPGRADE_FN_NAME) {
+                    if let Err(err) =
+                        run_hook(PRE_UPGRADE_FN_NAME, self.babel_engine.pre_upgrade()).await
+                    {
+                        if let Err(drop_err) = self.machine.drop_staged().await {
+                            warn!(
+                                "failed to drop staged VM after pre_upgrade failure: {drop_err:#}"
+                            );
+                        }
+                        return Err(err.into());
+                    }
+                }
+                self.state.upgrade_state.steps.push(UpgradeStep::Staged);
                 self.state.upgrade_state.steps.push(UpgradeStep::Stop);
                 self.state.restarting = true;
                 self.stop(false).await?;
             }
             self.state.upgrade_state.active = true;
+            self.machine.check_data_dir_access()?;
             self.state.upgrade_state.data_stamp = babel_api::utils::protocol_data_stamp(&data_dir)?;
             let mut state_backup: StateBackup = desired_state.into();
             state_backup.swap_state(&mut self.state);
@@ -516,47 +755,114 @@ impl<P: Pal + Debug> Node<P> {
         if let Some(err) = &self.state.upgrade_state.need_rollback {
             self.rollback(err.clone()).await
         } else if let Err(err) = self.try_upgrade().await {
-            let error_str = format!("{err:#}");
-            if self.state.upgrade_state.data_stamp
-                != babel_api::utils::protocol_data_stamp(&data_dir)?
-            {
-                command_failed!(commands::Error::NodeUpgradeFailure(error_str, anyhow!("can't rollback node upgrade if 'init' was already started - protocol data could be changed")))
-            }
-            if let Some(mut state_backup) = self.state.upgrade_state.state_backup.take() {
-                state_backup.swap_state(&mut self.state);
-                self.state.upgrade_state.state_backup = Some(state_backup);
-                self.state.upgrade_state.need_rollback = Some(error_str.clone());
-                self.save_state().await?;
-                self.rollback(error_str).await
-            } else {
-                command_failed!(commands::Error::NodeUpgradeFailure(
-                    error_str,
-                    anyhow!("can't rollback node upgrade - backup not found")
-                ))
-            }
+            self.upgrade_failed(err).await
+        } else if self
+            .state
+            .upgrade_state
+            .steps
+            .contains(&UpgradeStep::Verification)
+        {
+            // upgrade is completed by `finish_upgrade_verification`, once verification is done
+            Ok(())
         } else {
-            info!("Node upgraded");
-            // some steps may still need cleanup
-            if let Some(UpgradeStep::CpuAssignment(CpuAssignmentUpdate::ReleasedCpus(cpus))) = &self
+            self.upgrade_succeeded().await
+        }
+    }
+
+    /// Returns upgrade verification window, if upgraded node is waiting for verification.
+    /// Verification may be disabled meanwhile (e.g. config changed before bv restart),
+    /// then pending verification is done with single final check.
+    pub fn pending_upgrade_verification(&self) -> Option<Duration> {
+        if self.state.upgrade_state.active
+            && self.state.upgrade_state.need_rollback.is_none()
+            && self
                 .state
                 .upgrade_state
                 .steps
-                .iter()
-                .find(|item| matches!(item, UpgradeStep::CpuAssignment(_)))
-            {
-                self.cpu_registry.release(&mut cpus.clone()).await;
-            }
-            if self.state.upgrade_state.steps.contains(&UpgradeStep::Vm) {
-                if let Err(err) = self.machine.drop_backup().await {
-                    warn!("failed to cleanup VM backup after upgrade: {err:#}");
-                }
+                .contains(&UpgradeStep::Verification)
+        {
+            Some(self.bv_context.upgrade_verification.unwrap_or_default())
+        } else {
+            None
+        }
+    }
+
+    /// Check if upgraded node looks healthy. Protocol status errors are tolerated (e.g. while node
+    /// is still starting), unless it is the `final_check` at the end of verification window.
+    pub async fn check_upgraded(&mut self, baseline: &JobsInfo, final_check: bool) -> Result<()> {
+        if self.status().await != VmStatus::Running {
+            bail!("upgrade verification failed: node is not running");
+        }
+        check_upgraded_jobs(baseline, &self.babel_engine.get_jobs().await?)?;
+        match self.babel_engine.protocol_status().await {
+            Ok(status) if status.health == NodeHealth::Unhealthy => bail!(
+                "upgrade verification failed: protocol is unhealthy (state '{}')",
+                status.state
+            ),
+            Ok(_) => Ok(()),
+            Err(err) if final_check => {
+                Err(err.wrap_err("upgrade verification failed: can't get protocol status"))
             }
-            self.state.upgrade_state.active = false;
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Complete upgrade with verification `result`, upgrade is rolled back if verification failed.
+    pub async fn finish_upgrade_verification(
+        &mut self,
+        result: Result<()>,
+    ) -> commands::Result<()> {
+        if let Err(err) = result {
+            self.upgrade_failed(err).await
+        } else {
+            self.upgrade_succeeded().await
+        }
+    }
+
+    async fn upgrade_failed(&mut self, err: Report) -> commands::Result<()> {
+        let data_dir = self.machine.data_dir();
+        let error_str = format!("{err:#}");
+        self.machine.check_data_dir_access()?;
+        if self.state.upgrade_state.data_stamp != babel_api::utils::protocol_data_stamp(&data_dir)?
+        {
+            command_failed!(commands::Error::NodeUpgradeFailure(error_str, anyhow!("can't rollback node upgrade if 'init' was already started - protocol data could be changed")))
+        }
+        if let Some(mut state_backup) = self.state.upgrade_state.state_backup.take() {
+            state_backup.swap_state(&mut self.state);
+            self.state.upgrade_state.state_backup = Some(state_backup);
+            self.state.upgrade_state.need_rollback = Some(error_str.clone());
             self.save_state().await?;
-            Ok(())
+            self.rollback(error_str).await
+        } else {
+            command_failed!(commands::Error::NodeUpgradeFailure(
+                error_str,
+                anyhow!("can't rollback node upgrade - backup not found")
+            ))
         }
     }
 
+    async fn upgrade_succeeded(&mut self) -> commands::Result<()> {
+        info!("Node upgraded");
+        // some steps may still need cleanup
+        if let Some(UpgradeStep::CpuAssignment(CpuAssignmentUpdate::ReleasedCpus(cpus))) = &self
+            .state
+            .upgrade_state
+            .steps
+            .iter()
+            .find(|item| matches!(item, UpgradeStep::CpuAssignment(_)))
+        {
+            self.cpu_registry.release(&mut cpus.clone()).await;
+        }
+        if self.state.upgrade_state.steps.contains(&UpgradeStep::Vm) {
+            if let Err(err) = self.machine.drop_backup().await {
+                warn!("failed to cleanup VM backup after upgrade: {err:#}");
+            }
+        }
+        self.state.upgrade_state.active = false;
+        self.save_state().await?;
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     async fn rollback(&mut self, err: String) -> commands::Result<()> {
         if let Err(rollback_err) = self.try_rollback().await {
@@ -627,6 +933,7 @@ impl<P: Pal + Debug> Node<P> {
                 .apply_firewall_config(NodeFirewallConfig {
                     id: self.state.id,
                     ip: self.state.ip,
+                    ipv6: self.state.ipv6,
                     bridge: self.bv_context.bridge.clone(),
                     config: self.state.firewall.clone(),
                 })
@@ -638,6 +945,17 @@ impl<P: Pal + Debug> Node<P> {
 
         if self.state.upgrade_state.steps.contains(&UpgradeStep::Stop) {
             self.start().await?;
+            if self.babel_engine.has_capability(POST_UPGRADE_FN_NAME) {
+                run_hook(POST_UPGRADE_FN_NAME, self.babel_engine.post_upgrade()).await?;
+            }
+            if self.bv_context.upgrade_verification.is_some()
+                && self
+                    .state
+                    .upgrade_state
+                    .insert_step(UpgradeStep::Verification)
+            {
+                self.save_state().await?;
+            }
         }
 
         debug!("Node upgraded");
@@ -680,6 +998,17 @@ impl<P: Pal + Debug> Node<P> {
             self.state.upgrade_state.steps.swap_remove(vm_index);
             self.save_state().await?;
         }
+        if let Some(staged_index) = self
+            .state
+            .upgrade_state
+            .steps
+            .iter()
+            .position(|item| matches!(item, UpgradeStep::Staged))
+        {
+            self.machine.drop_staged().await?;
+            self.state.upgrade_state.steps.swap_remove(staged_index);
+            self.save_state().await?;
+        }
         if let Some(plugin_index) = self
             .state
             .upgrade_state
@@ -712,6 +1041,7 @@ impl<P: Pal + Debug> Node<P> {
                 .apply_firewall_config(NodeFirewallConfig {
                     id: self.state.id,
                     ip: self.state.ip,
+                    ipv6: self.state.ipv6,
                     bridge: self.bv_context.bridge.clone(),
                     config: self.state.firewall.clone(),
                 })
@@ -739,11 +1069,54 @@ impl<P: Pal + Debug> Node<P> {
             .await
     }
 
+    /// Returns node protocol data snapshots.
+    pub fn snapshots(&self) -> Snapshots {
+        Snapshots::new(&self.context, self.machine.data_dir())
+    }
+
+    /// Creates local snapshot of node protocol data. Node must be stopped.
+    #[instrument(skip(self))]
+    pub async fn create_snapshot(&mut self, name: &str) -> Result<Snapshot> {
+        self.check_snapshot_allowed().await?;
+        self.snapshots()
+            .create(name, &self.state.image.version)
+            .await
+    }
+
+    /// Restores node protocol data from local snapshot. Node must be stopped.
+    #[instrument(skip(self))]
+    pub async fn restore_snapshot(&mut self, name: &str) -> Result<Snapshot> {
+        self.check_snapshot_allowed().await?;
+        let snapshot = self.snapshots().restore(name).await?;
+        if snapshot.image_version != self.state.image.version {
+            warn!(
+                "snapshot '{name}' was created with image version {}, but node is running {}",
+                snapshot.image_version, self.state.image.version
+            );
+        }
+        // let plugin `init` reevaluate restored data on next start
+        self.state.initialized = false;
+        self.save_state().await?;
+        Ok(snapshot)
+    }
+
+    async fn check_snapshot_allowed(&self) -> Result<()> {
+        if self.state.upgrade_state.active {
+            bail!("can't use snapshots while node upgrade is in progress");
+        }
+        if self.status().await != VmStatus::Stopped {
+            bail!("node must be stopped to create or restore snapshot");
+        }
+        self.machine.check_data_dir_access()?;
+        Ok(())
+    }
+
     pub async fn recover(&mut self) -> Result<()> {
         if self.recovery_backoff.backoff() {
             return Ok(());
         }
         let id = self.id();
+        counter!("bv.node.recovery.attempts", "node_id" => id.to_string()).increment(1);
         match self.state.expected_status {
             VmStatus::Running => {
                 let vm_state = self.machine.state().await;
@@ -781,6 +1154,12 @@ impl<P: Pal + Debug> Node<P> {
             VmStatus::Failed => {
                 warn!("Recovery: node with ID `{id}` cannot be recovered");
             }
+            VmStatus::Suspended => {
+                if self.check_suspension().await? {
+                    // suspended on purpose, so nothing to recover
+                    debug!("Recovery: node with ID `{id}` is suspended");
+                }
+            }
             VmStatus::Busy => unreachable!(),
         }
         Ok(())
@@ -796,6 +1175,26 @@ impl<P: Pal + Debug> Node<P> {
         Ok(())
     }
 
+    /// Returns `true` if node is expected to be suspended and it really is. Suspension doesn't
+    /// survive host reboot nor container stop, so stale `Suspended` expected status is reset.
+    async fn check_suspension(&mut self) -> Result<bool> {
+        if self.state.expected_status != VmStatus::Suspended {
+            return Ok(false);
+        }
+        let vm_state = self.machine.state().await;
+        match stale_suspension(self.state.expected_status, &vm_state) {
+            Some(status) => {
+                warn!(
+                    "node {} is not suspended anymore, expected status reset to {status}",
+                    self.id()
+                );
+                self.save_expected_status(status).await?;
+                Ok(false)
+            }
+            None => Ok(true),
+        }
+    }
+
     async fn save_expected_status(&mut self, status: VmStatus) -> Result<()> {
         self.state.expected_status = status;
         self.save_state().await
@@ -868,6 +1267,82 @@ impl<P: Pal + Debug> Node<P> {
     }
 }
 
+/// Watch upgraded node for verification window and then complete (or roll back) the upgrade.
+/// Node lock is taken only for the time of each check, so node is still accessible
+/// for metrics, recovery or CLI during the window.
+pub async fn verify_upgrade<P: Pal + Debug>(
+    node_lock: &RwLock<Node<P>>,
+    window: Duration,
+) -> commands::Result<()> {
+    info!("Verifying upgraded node for {}s", window.as_secs());
+    let deadline = Instant::now() + window;
+    let baseline = node_lock.write().await.babel_engine.get_jobs().await;
+    let result = async {
+        let baseline = baseline?;
+        loop {
+            let now = Instant::now();
+            let final_check = now >= deadline;
+            if !final_check {
+                tokio::time::sleep(UPGRADE_VERIFICATION_CHECK_INTERVAL.min(deadline - now)).await;
+            }
+            node_lock
+                .write()
+                .await
+                .check_upgraded(&baseline, final_check)
+                .await?;
+            if final_check {
+                break Ok(());
+            }
+        }
+    }
+    .await;
+    node_lock
+        .write()
+        .await
+        .finish_upgrade_verification(result)
+        .await
+}
+
+/// Expected status matching actual VM state, if node is expected to be suspended, but it is not.
+fn stale_suspension(expected_status: VmStatus, vm_state: &pal::VmState) -> Option<VmStatus> {
+    match vm_state {
+        _ if expected_status != VmStatus::Suspended => None,
+        pal::VmState::SUSPENDED => None,
+        pal::VmState::SHUTOFF => Some(VmStatus::Stopped),
+        pal::VmState::RUNNING | pal::VmState::INVALID => Some(VmStatus::Running),
+    }
+}
+
+/// Check if jobs are not failing after upgrade. Restart counts are compared to `baseline`
+/// taken right after node start.
+fn check_upgraded_jobs(baseline: &JobsInfo, jobs: &JobsInfo) -> Result<()> {
+    for (name, info) in jobs {
+        if let JobStatus::Finished { exit_code, message } = &info.status {
+            if *exit_code != Some(0) {
+                bail!("upgrade verification failed: job '{name}' failed with {exit_code:?}: {message}");
+            }
+        }
+        let restarts = baseline
+            .get(name)
+            .map(|job| job.restart_count)
+            .unwrap_or_default();
+        if info.restart_count > restarts {
+            bail!(
+                "upgrade verification failed: job '{name}' restarted {} times",
+                info.restart_count - restarts
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Run plugin hook. Hook is terminated by plugin itself, if it runs longer than `HOOK_TIMEOUT`,
+/// so it is not left running in background.
+async fn run_hook(name: &str, hook: impl std::future::Future<Output = Result<()>>) -> Result<()> {
+    hook.await
+        .with_context(|| format!("plugin '{name}' hook failed"))
+}
+
 async fn check_job_runner(
     connection: &mut impl NodeConnection,
     job_runner_path: &Path,
@@ -1027,6 +1502,11 @@ pub mod tests {
             async fn shutdown(&mut self) -> Result<()>;
             async fn force_shutdown(&mut self) -> Result<()>;
             async fn start(&mut self) -> Result<()>;
+            async fn suspend(&mut self) -> Result<()>;
+            async fn resize(&mut self, node_state: &NodeState) -> Result<()>;
+            async fn resume(&mut self) -> Result<()>;
+            async fn stage_upgrade(&mut self, node_state: &NodeState) -> Result<()>;
+            async fn drop_staged(&mut self) -> Result<()>;
             async fn upgrade(&mut self, node_state: &NodeState) -> Result<()>;
             async fn drop_backup(&mut self) -> Result<()>;
             async fn rollback(&mut self) -> Result<()>;
@@ -1084,6 +1564,11 @@ pub mod tests {
                 config: NodeFirewallConfig,
             ) -> Result<()>;
             async fn cleanup_firewall_config(&self, id: Uuid) -> Result<()>;
+            async fn firewall_status(
+                &self,
+                config: NodeFirewallConfig,
+            ) -> Result<Option<firewall::Status>>;
+            async fn probe_ip(&self, iface: &str, ip: IpAddr) -> Result<bool>;
         }
     }
 
@@ -1193,6 +1678,7 @@ pub mod tests {
         NodeFirewallConfig {
             id: state.id,
             ip: state.ip,
+            ipv6: state.ipv6,
             bridge: Some("bvbr7".to_string()),
             config: state.firewall,
         }
@@ -1204,6 +1690,7 @@ pub mod tests {
             name: "host_name".to_string(),
             url: "api.url".to_string(),
             bridge: Some("bvbr7".to_string()),
+            upgrade_verification: None,
         }
     }
 
@@ -1241,6 +1728,7 @@ pub mod tests {
             },
             ip: IpAddr::from_str("172.16.0.10").unwrap(),
             gateway: IpAddr::from_str("172.16.0.1").unwrap(),
+            ipv6: None,
             assigned_cpus: vec![3],
             vm_config: VmConfig {
                 vcpu_count: 1,
@@ -1250,6 +1738,7 @@ pub mod tests {
                     ram_disk_mount_point: "/mnt/ramdisk".to_string(),
                     ram_disk_size_mb: 512,
                 }],
+                bandwidth: Default::default(),
             },
             firewall: firewall::Config {
                 default_in: firewall::Action::Deny,
@@ -1262,6 +1751,9 @@ pub mod tests {
                         protocol: Some(firewall::Protocol::Tcp),
                         ips: vec!["192.167.0.1/24".to_string()],
                         ports: vec![24567],
+                        ip_sets: vec![],
+                        port_ranges: vec![],
+                        log: false,
                     },
                     firewall::Rule {
                         name: "Allowed incoming udp traffic on ip and port".to_string(),
@@ -1270,8 +1762,12 @@ pub mod tests {
                         protocol: Some(firewall::Protocol::Udp),
                         ips: vec!["192.168.0.1".to_string()],
                         ports: vec![24567],
+                        ip_sets: vec![],
+                        port_ranges: vec![],
+                        log: false,
                     },
                 ],
+                ip_sets: Default::default(),
             },
             properties: HashMap::from_iter([(
                 "arbitrary-text-property".to_string(),
@@ -2085,6 +2581,267 @@ pub mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_suspend_resume_node() -> Result<()> {
+        let test_env = TestEnv::new().await?;
+        let mut pal = test_env.default_pal();
+        let config = default_config(test_env.tmp_root.clone());
+        let mut node_state = default_node_state();
+        node_state.initialized = true;
+
+        let test_tmp_root = test_env.tmp_root.to_path_buf();
+        pal.expect_create_node_connection().return_once(move |_| {
+            let mut mock = MockTestNodeConnection::new();
+            mock.expect_is_closed().returning(|| false);
+            mock.expect_is_broken().returning(|| false);
+            mock.expect_close().times(2).returning(|| ());
+            mock.expect_attach().once().returning(|| Ok(()));
+            let tmp_root = test_tmp_root.clone();
+            mock.expect_babel_client()
+                .returning(move || Ok(test_babel_client(&tmp_root)));
+            mock.expect_engine_socket_path()
+                .return_const(Default::default());
+            mock
+        });
+        add_firewall_expectation(&mut pal, node_state.clone());
+        let plugin_path = test_env.default_plugin_path.clone();
+        pal.expect_create_vm().return_once(move |_, _| {
+            let mut mock = MockTestVM::new();
+            let plugin_path = plugin_path.clone();
+            let mut seq = Sequence::new();
+            mock.expect_plugin_path()
+                .once()
+                .in_sequence(&mut seq)
+                .returning(move || plugin_path.clone());
+            mock.expect_node_env()
+                .once()
+                .in_sequence(&mut seq)
+                .returning(Default::default);
+            mock.expect_state()
+                .once()
+                .in_sequence(&mut seq)
+                .return_const(VmState::RUNNING);
+            mock.expect_suspend()
+                .once()
+                .in_sequence(&mut seq)
+                .returning(|| Ok(()));
+            mock.expect_state()
+                .times(3)
+                .in_sequence(&mut seq)
+                .return_const(VmState::SUSPENDED);
+            mock.expect_resume()
+                .once()
+                .in_sequence(&mut seq)
+                .returning(|| Ok(()));
+            mock.expect_state()
+                .once()
+                .in_sequence(&mut seq)
+                .return_const(VmState::RUNNING);
+            mock.expect_suspend()
+                .once()
+                .in_sequence(&mut seq)
+                .returning(|| Ok(()));
+            // container is gone, e.g. after host reboot
+            mock.expect_state()
+                .once()
+                .in_sequence(&mut seq)
+                .return_const(VmState::SHUTOFF);
+            Ok(mock)
+        });
+
+        let mut node = Node::create(
+            Arc::new(pal),
+            config,
+            node_state,
+            test_env.tx.clone(),
+            default_cpu_registry(),
+        )
+        .await?;
+
+        let mut babel_mock = MockTestBabelService::new();
+        babel_mock
+            .expect_check_job_runner()
+            .once()
+            .returning(|_| Ok(Response::new(BinaryStatus::Ok)));
+        let server = test_env.start_server(babel_mock).await;
+        fs::write(&test_env.tmp_root.join("job_runner"), "dummy job_runner").await?;
+
+        node.suspend().await?;
+        assert_eq!(VmStatus::Suspended, node.expected_status());
+        assert_eq!(VmStatus::Suspended, node.status().await);
+        // already suspended
+        node.suspend().await?;
+        test_env.assert_node_state_saved(&node.state).await;
+
+        node.resume().await?;
+        assert_eq!(VmStatus::Running, node.expected_status());
+        assert_eq!(
+            "node is not suspended",
+            node.resume().await.unwrap_err().to_string()
+        );
+        test_env.assert_node_state_saved(&node.state).await;
+
+        // nothing is frozen anymore, so resume is no-op and stale suspension is reset
+        node.suspend().await?;
+        node.resume().await?;
+        assert_eq!(VmStatus::Stopped, node.expected_status());
+        test_env.assert_node_state_saved(&node.state).await;
+
+        server.assert().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_firewall() -> Result<()> {
+        let test_env = TestEnv::new().await?;
+        let mut pal = test_env.default_pal();
+        let config = default_config(test_env.tmp_root.clone());
+        let node_state = default_node_state();
+
+        pal.expect_create_node_connection().return_once(move |_| {
+            let mut mock = MockTestNodeConnection::new();
+            mock.expect_engine_socket_path()
+                .return_const(Default::default());
+            mock
+        });
+        // applied on create and then once more on drift
+        pal.expect_apply_firewall_config()
+            .with(predicate::eq(build_firewall_config(node_state.clone())))
+            .times(2)
+            .returning(|_| Ok(()));
+        let mut seq = Sequence::new();
+        for drift in [false, true] {
+            pal.expect_firewall_status()
+                .with(predicate::eq(build_firewall_config(node_state.clone())))
+                .once()
+                .in_sequence(&mut seq)
+                .returning(move |_| {
+                    Ok(Some(firewall::Status {
+                        backend: firewall::Backend::Ufw,
+                        expected: vec!["rule".to_string()],
+                        actual: if drift {
+                            vec![]
+                        } else {
+                            vec!["rule".to_string()]
+                        },
+                        drift,
+                    }))
+                });
+        }
+        let plugin_path = test_env.default_plugin_path.clone();
+        pal.expect_create_vm().return_once(move |_, _| {
+            let mut mock = MockTestVM::new();
+            mock.expect_plugin_path()
+                .once()
+                .returning(move || plugin_path.clone());
+            mock.expect_node_env().once().returning(Default::default);
+            Ok(mock)
+        });
+
+        let mut node = Node::create(
+            Arc::new(pal),
+            config,
+            node_state,
+            test_env.tx.clone(),
+            default_cpu_registry(),
+        )
+        .await?;
+
+        assert!(!node.reconcile_firewall().await?);
+        assert!(node.reconcile_firewall().await?);
+        // firewall is not touched while upgrade is in progress
+        node.state.upgrade_state.active = true;
+        assert!(!node.reconcile_firewall().await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resize_node() -> Result<()> {
+        let test_env = TestEnv::new().await?;
+        let mut pal = test_env.default_pal();
+        let config = default_config(test_env.tmp_root.clone());
+        let node_state = default_node_state();
+
+        pal.expect_create_node_connection()
+            .return_once(dummy_connection_mock);
+        add_firewall_expectation(&mut pal, node_state.clone());
+        let plugin_path = test_env.default_plugin_path.clone();
+        pal.expect_create_vm().return_once(move |_, _| {
+            let mut mock = MockTestVM::new();
+            let plugin_path = plugin_path.clone();
+            mock.expect_plugin_path()
+                .once()
+                .returning(move || plugin_path.clone());
+            mock.expect_node_env().once().returning(Default::default);
+            mock.expect_state().times(4).return_const(VmState::SHUTOFF);
+            let mut seq = Sequence::new();
+            mock.expect_resize()
+                .withf(|state| {
+                    state.vm_config.vcpu_count == 3
+                        && state.assigned_cpus.len() == 3
+                        && state.vm_config.mem_size_mb == 4096
+                })
+                .once()
+                .in_sequence(&mut seq)
+                .returning(|_| Ok(()));
+            mock.expect_resize()
+                .once()
+                .in_sequence(&mut seq)
+                .returning(|_| bail!("cgroup update failed"));
+            // restore previous allocation
+            mock.expect_resize()
+                .withf(|state| state.assigned_cpus.len() == 3)
+                .once()
+                .in_sequence(&mut seq)
+                .returning(|_| Ok(()));
+            mock.expect_resize()
+                .withf(|state| state.assigned_cpus == vec![3])
+                .once()
+                .in_sequence(&mut seq)
+                .returning(|_| Ok(()));
+            Ok(mock)
+        });
+
+        let cpu_registry = default_cpu_registry();
+        let mut node = Node::create(
+            Arc::new(pal),
+            config,
+            node_state,
+            test_env.tx.clone(),
+            cpu_registry.clone(),
+        )
+        .await?;
+        node.state.expected_status = VmStatus::Stopped;
+        assert_eq!(3, cpu_registry.len().await);
+
+        node.resize(Some(3), Some(4096)).await?;
+        assert_eq!(vec![3, 2, 1], node.state.assigned_cpus);
+        assert_eq!(1, cpu_registry.len().await);
+        test_env.assert_node_state_saved(&node.state).await;
+
+        assert_eq!(
+            "not enough cpu cores",
+            node.resize(Some(5), None).await.unwrap_err().to_string()
+        );
+        assert_eq!(3, node.state.vm_config.vcpu_count);
+
+        assert_eq!(
+            "cgroup update failed",
+            node.resize(Some(1), None).await.unwrap_err().to_string()
+        );
+        assert_eq!(vec![3, 2, 1], node.state.assigned_cpus);
+        assert_eq!(3, node.state.vm_config.vcpu_count);
+        assert_eq!(1, cpu_registry.len().await);
+
+        node.resize(Some(1), None).await?;
+        assert_eq!(vec![3], node.state.assigned_cpus);
+        assert_eq!(1, node.state.vm_config.vcpu_count);
+        assert_eq!(4096, node.state.vm_config.mem_size_mb);
+        assert_eq!(3, cpu_registry.len().await);
+        test_env.assert_node_state_saved(&node.state).await;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_update_node() -> Result<()> {
         let test_env = TestEnv::new().await?;
@@ -2113,7 +2870,7 @@ pub mod tests {
             mock.expect_state().once().returning(|| VmState::SHUTOFF);
             mock.expect_state().times(2).returning(|| VmState::RUNNING);
             mock.expect_plugin_path()
-                .once()
+                .times(2)
                 .returning(move || plugin_path.clone());
             mock.expect_node_env().returning(Default::default);
             mock.expect_update_node_env().returning(|_| ());
@@ -2128,6 +2885,9 @@ pub mod tests {
             protocol: None,
             ips: vec![],
             ports: vec![],
+            ip_sets: vec![],
+            port_ranges: vec![],
+            log: false,
         });
         let updated_firewall = updated_config.config.clone();
         pal.expect_apply_firewall_config()
@@ -2452,6 +3212,12 @@ pub mod tests {
                 mock
             });
 
+        // new VM is staged before each stop
+        vm_mock
+            .expect_stage_upgrade()
+            .times(3)
+            .returning(|_| Ok(()));
+
         // failed to stop before upgrade
         vm_mock
             .expect_state()
@@ -2467,6 +3233,7 @@ pub mod tests {
         vm_mock.expect_state().once().returning(|| VmState::RUNNING);
         vm_mock.expect_state().once().returning(|| VmState::RUNNING);
         vm_mock.expect_rollback().once().returning(|| Ok(()));
+        vm_mock.expect_drop_staged().once().returning(|| Ok(()));
         test_env.add_plugin_update_expectations(&mut vm_mock);
         pal.expect_apply_firewall_config()
             .once()
@@ -2627,4 +3394,63 @@ pub mod tests {
         server.assert().await;
         Ok(())
     }
+
+    #[test]
+    fn test_check_upgraded_jobs() {
+        let job = |status, restart_count| JobInfo {
+            status,
+            timestamp: SystemTime::UNIX_EPOCH,
+            progress: None,
+            restart_count,
+            logs: vec![],
+            upgrade_blocking: false,
+        };
+        let baseline = HashMap::from_iter([("node".to_string(), job(JobStatus::Running, 1))]);
+
+        check_upgraded_jobs(&baseline, &baseline).unwrap();
+        check_upgraded_jobs(
+            &baseline,
+            &HashMap::from_iter([
+                ("node".to_string(), job(JobStatus::Running, 1)),
+                (
+                    "init".to_string(),
+                    job(
+                        JobStatus::Finished {
+                            exit_code: Some(0),
+                            message: Default::default(),
+                        },
+                        0,
+                    ),
+                ),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            "upgrade verification failed: job 'node' restarted 2 times",
+            check_upgraded_jobs(
+                &baseline,
+                &HashMap::from_iter([("node".to_string(), job(JobStatus::Running, 3))]),
+            )
+            .unwrap_err()
+            .to_string()
+        );
+        assert_eq!(
+            "upgrade verification failed: job 'init' failed with Some(1): init failed",
+            check_upgraded_jobs(
+                &baseline,
+                &HashMap::from_iter([(
+                    "init".to_string(),
+                    job(
+                        JobStatus::Finished {
+                            exit_code: Some(1),
+                            message: "init failed".to_string(),
+                        },
+                        0,
+                    ),
+                )]),
+            )
+            .unwrap_err()
+            .to_string()
+        );
+    }
 }
@@ -0,0 +1,386 @@
+//! Node bundle is a transferable, self-contained representation of the node, that can be used
+//! to migrate node between hosts (see `bv node export` and `bv node import`).
+//! Bundle is a directory with `manifest.json` file and optional protocol data, chunked and
+//! compressed in the same way as archives (see `DownloadManifest`).
+
+use crate::{apptainer_machine::DATA_DIR, node_context::NodeContext, node_state::NodeState};
+use babel_api::engine::{Checksum, Chunk, Compression, DownloadManifest, FileLocation, JobsInfo};
+use eyre::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+};
+use tracing::warn;
+use walkdir::WalkDir;
+
+pub const MANIFEST_FILENAME: &str = "manifest.json";
+const CHUNKS_DIR: &str = "chunks";
+const CHUNK_SIZE: u64 = 256 * 1024 * 1024;
+const COMPRESSION_LEVEL: i32 = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NodeBundle {
+    pub state: NodeState,
+    /// Plugin data saved with `save_data`.
+    pub plugin_data: Option<String>,
+    pub plugin_config: Option<String>,
+    pub plugin_kv: Option<String>,
+    /// Jobs info at the moment of export. Jobs are recreated by plugin on the target host.
+    pub jobs: JobsInfo,
+    /// Protocol data chunks mapping, if exported with data.
+    pub data: Option<DownloadManifest>,
+}
+
+/// Export node into `bundle_dir`. Node must be stopped if `with_data` is set,
+/// so protocol data are consistent.
+pub fn export(
+    state: NodeState,
+    jobs: JobsInfo,
+    context: &NodeContext,
+    with_data: bool,
+    bundle_dir: &Path,
+) -> Result<NodeBundle> {
+    if bundle_dir.exists() && fs::read_dir(bundle_dir)?.next().is_some() {
+        bail!("bundle dir '{}' is not empty", bundle_dir.display());
+    }
+    fs::create_dir_all(bundle_dir.join(CHUNKS_DIR))?;
+    let data = if with_data {
+        Some(write_data_chunks(
+            &context.node_dir.join(DATA_DIR),
+            &bundle_dir.join(CHUNKS_DIR),
+            CHUNK_SIZE,
+        )?)
+    } else {
+        None
+    };
+    let bundle = NodeBundle {
+        state,
+        plugin_data: read_optional(&context.plugin_data)?,
+        plugin_config: read_optional(&context.plugin_config)?,
+        plugin_kv: read_optional(&context.plugin_kv)?,
+        jobs,
+        data,
+    };
+    fs::write(
+        bundle_dir.join(MANIFEST_FILENAME),
+        serde_json::to_string_pretty(&bundle)?,
+    )?;
+    Ok(bundle)
+}
+
+/// Load bundle manifest and verify all data chunks checksums.
+pub fn verify(bundle_dir: &Path) -> Result<NodeBundle> {
+    let manifest_path = bundle_dir.join(MANIFEST_FILENAME);
+    let bundle: NodeBundle = serde_json::from_str(
+        &fs::read_to_string(&manifest_path)
+            .with_context(|| format!("failed to read '{}'", manifest_path.display()))?,
+    )
+    .with_context(|| format!("invalid bundle manifest '{}'", manifest_path.display()))?;
+    if let Some(data) = &bundle.data {
+        for chunk in &data.chunks {
+            let path = bundle_dir.join(CHUNKS_DIR).join(&chunk.key);
+            if file_checksum(&path)? != chunk.checksum {
+                bail!("chunk '{}' checksum mismatch", path.display());
+            }
+        }
+    }
+    Ok(bundle)
+}
+
+/// Restore plugin files and protocol data from bundle into node directory.
+pub fn restore(bundle: &NodeBundle, bundle_dir: &Path, context: &NodeContext) -> Result<()> {
+    fs::create_dir_all(&context.node_dir)?;
+    for (value, path) in [
+        (&bundle.plugin_data, &context.plugin_data),
+        (&bundle.plugin_config, &context.plugin_config),
+        (&bundle.plugin_kv, &context.plugin_kv),
+    ] {
+        if let Some(value) = value {
+            fs::write(path, value)?;
+        }
+    }
+    if let Some(data) = &bundle.data {
+        restore_data_chunks(
+            data,
+            &bundle_dir.join(CHUNKS_DIR),
+            &context.node_dir.join(DATA_DIR),
+        )?;
+    }
+    Ok(())
+}
+
+fn read_optional(path: &Path) -> Result<Option<String>> {
+    Ok(if path.exists() {
+        Some(fs::read_to_string(path)?)
+    } else {
+        None
+    })
+}
+
+fn file_checksum(path: &Path) -> Result<Checksum> {
+    let mut file =
+        fs::File::open(path).with_context(|| format!("failed to open '{}'", path.display()))?;
+    let mut hasher = sha2::Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(Checksum::Sha256(hasher.finalize().into()))
+}
+
+struct ChunkWriter {
+    index: u32,
+    path: PathBuf,
+    encoder: zstd::stream::write::Encoder<'static, fs::File>,
+    destinations: Vec<FileLocation>,
+    size: u64,
+}
+
+impl ChunkWriter {
+    fn new(chunks_dir: &Path, index: u32) -> Result<Self> {
+        let path = chunks_dir.join(chunk_key(index));
+        Ok(Self {
+            index,
+            encoder: zstd::stream::write::Encoder::new(
+                fs::File::create(&path)?,
+                COMPRESSION_LEVEL,
+            )?,
+            path,
+            destinations: vec![],
+            size: 0,
+        })
+    }
+
+    fn finish(self) -> Result<Chunk> {
+        self.encoder.finish()?.sync_all()?;
+        Ok(Chunk {
+            index: self.index,
+            key: chunk_key(self.index),
+            url: None,
+            checksum: file_checksum(&self.path)?,
+            size: fs::metadata(&self.path)?.len(),
+            destinations: self.destinations,
+        })
+    }
+}
+
+fn chunk_key(index: u32) -> String {
+    format!("data.{index}")
+}
+
+fn write_data_chunks(
+    data_dir: &Path,
+    chunks_dir: &Path,
+    chunk_size: u64,
+) -> Result<DownloadManifest> {
+    let mut chunks = vec![];
+    let mut total_size = 0;
+    let mut writer: Option<ChunkWriter> = None;
+    for entry in WalkDir::new(data_dir).sort_by_file_name() {
+        let entry = entry?;
+        if entry.file_type().is_symlink() {
+            warn!("skipping symlink '{}'", entry.path().display());
+            continue;
+        } else if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path().strip_prefix(data_dir)?.to_path_buf();
+        let size = entry.metadata()?.len();
+        let mut file = fs::File::open(entry.path())?;
+        let mut pos = 0;
+        loop {
+            let mut chunk = match writer.take() {
+                Some(chunk) => chunk,
+                None => ChunkWriter::new(chunks_dir, chunks.len() as u32)?,
+            };
+            let len = (size - pos).min(chunk_size - chunk.size);
+            let copied = io::copy(&mut (&mut file).take(len), &mut chunk.encoder)?;
+            if copied != len {
+                bail!("file '{}' changed while exporting", path.display());
+            }
+            chunk.destinations.push(FileLocation {
+                path: path.clone(),
+                pos,
+                size: len,
+            });
+            chunk.size += len;
+            pos += len;
+            if chunk.size == chunk_size {
+                chunks.push(chunk.finish()?);
+            } else {
+                writer = Some(chunk);
+            }
+            if pos >= size {
+                break;
+            }
+        }
+        total_size += size;
+    }
+    if let Some(chunk) = writer.take() {
+        chunks.push(chunk.finish()?);
+    }
+    Ok(DownloadManifest {
+        total_size,
+        compression: Some(Compression::ZSTD(COMPRESSION_LEVEL)),
+        chunks,
+    })
+}
+
+fn restore_data_chunks(
+    manifest: &DownloadManifest,
+    chunks_dir: &Path,
+    data_dir: &Path,
+) -> Result<()> {
+    for chunk in &manifest.chunks {
+        let mut decoder =
+            zstd::stream::read::Decoder::new(fs::File::open(chunks_dir.join(&chunk.key))?)?;
+        for destination in &chunk.destinations {
+            if destination
+                .path
+                .components()
+                .any(|component| !matches!(component, Component::Normal(_)))
+            {
+                bail!("invalid file path '{}'", destination.path.display());
+            }
+            let path = data_dir.join(&destination.path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?;
+            file.seek(SeekFrom::Start(destination.pos))?;
+            let copied = io::copy(&mut (&mut decoder).take(destination.size), &mut file)?;
+            if copied != destination.size {
+                bail!("chunk '{}' is truncated", chunk.key);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::tests::default_node_state;
+    use assert_fs::TempDir;
+
+    fn build_context(node_dir: &Path) -> NodeContext {
+        NodeContext {
+            plugin_data: node_dir.join("plugin.data"),
+            plugin_config: node_dir.join("plugin_config.json"),
+            plugin_kv: node_dir.join("plugin_kv.json"),
+            snapshots_dir: node_dir.join("snapshots"),
+            nodes_dir: Default::default(),
+            node_dir: node_dir.to_path_buf(),
+        }
+    }
+
+    #[test]
+    fn test_export_and_import() -> Result<()> {
+        let tmp_root = TempDir::new()?.to_path_buf();
+        let source = build_context(&tmp_root.join("source"));
+        let data_dir = source.node_dir.join(DATA_DIR);
+        fs::create_dir_all(data_dir.join("sub/dir"))?;
+        fs::write(data_dir.join("a"), "first file content")?;
+        fs::write(data_dir.join("empty"), "")?;
+        fs::write(data_dir.join("sub/dir/b"), "second")?;
+        fs::write(&source.plugin_data, "plugin data")?;
+        fs::write(&source.plugin_kv, r#"{"key":"value"}"#)?;
+
+        let bundle_dir = tmp_root.join("bundle");
+        let mut bundle = export(
+            default_node_state(),
+            Default::default(),
+            &source,
+            false,
+            &bundle_dir,
+        )?;
+        assert!(bundle.data.is_none());
+        assert!(export(
+            default_node_state(),
+            Default::default(),
+            &source,
+            false,
+            &bundle_dir
+        )
+        .is_err());
+
+        // use small chunks, so single chunk maps into multiple files and vice versa
+        let manifest = write_data_chunks(&data_dir, &bundle_dir.join(CHUNKS_DIR), 7)?;
+        assert_eq!(24, manifest.total_size);
+        assert_eq!(4, manifest.chunks.len());
+        assert_eq!(
+            vec![
+                FileLocation {
+                    path: PathBuf::from("a"),
+                    pos: 14,
+                    size: 4,
+                },
+                FileLocation {
+                    path: PathBuf::from("empty"),
+                    pos: 0,
+                    size: 0,
+                },
+                FileLocation {
+                    path: PathBuf::from("sub/dir/b"),
+                    pos: 0,
+                    size: 3,
+                },
+            ],
+            manifest.chunks[2].destinations
+        );
+        bundle.data = Some(manifest);
+        fs::write(
+            bundle_dir.join(MANIFEST_FILENAME),
+            serde_json::to_string(&bundle)?,
+        )?;
+
+        let bundle = verify(&bundle_dir)?;
+        let target = build_context(&tmp_root.join("target"));
+        restore(&bundle, &bundle_dir, &target)?;
+        let restored_dir = target.node_dir.join(DATA_DIR);
+        assert_eq!(
+            "first file content",
+            fs::read_to_string(restored_dir.join("a"))?
+        );
+        assert_eq!("", fs::read_to_string(restored_dir.join("empty"))?);
+        assert_eq!(
+            "second",
+            fs::read_to_string(restored_dir.join("sub/dir/b"))?
+        );
+        assert_eq!("plugin data", fs::read_to_string(&target.plugin_data)?);
+        assert_eq!(r#"{"key":"value"}"#, fs::read_to_string(&target.plugin_kv)?);
+        assert!(!target.plugin_config.exists());
+
+        // export with data and import into another node, the same way as `bv node export/import`
+        let full_bundle_dir = tmp_root.join("full_bundle");
+        export(
+            default_node_state(),
+            Default::default(),
+            &source,
+            true,
+            &full_bundle_dir,
+        )?;
+        let full_bundle = verify(&full_bundle_dir)?;
+        assert_eq!(24, full_bundle.data.as_ref().unwrap().total_size);
+        let imported = build_context(&tmp_root.join("imported"));
+        restore(&full_bundle, &full_bundle_dir, &imported)?;
+        for file in ["a", "empty", "sub/dir/b"] {
+            assert_eq!(
+                fs::read_to_string(data_dir.join(file))?,
+                fs::read_to_string(imported.node_dir.join(DATA_DIR).join(file))?
+            );
+        }
+        assert_eq!("plugin data", fs::read_to_string(&imported.plugin_data)?);
+
+        fs::write(bundle_dir.join(CHUNKS_DIR).join(chunk_key(1)), "corrupted")?;
+        assert!(verify(&bundle_dir)
+            .unwrap_err()
+            .to_string()
+            .contains("checksum mismatch"));
+        Ok(())
+    }
+}
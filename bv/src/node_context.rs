@@ -21,6 +21,8 @@ pub fn build_node_dir(bv_root: &Path, id: Uuid) -> PathBuf {
 pub struct NodeContext {
     pub plugin_data: PathBuf,
     pub plugin_config: PathBuf,
+    pub plugin_kv: PathBuf,
+    pub snapshots_dir: PathBuf,
     pub nodes_dir: PathBuf,
     pub node_dir: PathBuf,
 }
@@ -32,6 +34,8 @@ impl NodeContext {
         Self {
             plugin_data: node_dir.join("plugin.data"),
             plugin_config: node_dir.join("plugin_config.json"),
+            plugin_kv: node_dir.join("plugin_kv.json"),
+            snapshots_dir: node_dir.join("snapshots"),
             nodes_dir,
             node_dir,
         }
@@ -11,6 +11,7 @@ pub fn new(
     node_state: &NodeState,
     data_mount_point: PathBuf,
     protocol_data_path: PathBuf,
+    data_quota_bytes: Option<u64>,
 ) -> NodeEnv {
     NodeEnv {
         node_id: node_state.id.to_string(),
@@ -27,6 +28,7 @@ pub fn new(
         bv_api_url: bv_context.url.clone(),
         data_mount_point,
         protocol_data_path,
+        data_quota_bytes,
     }
 }
 
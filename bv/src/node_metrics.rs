@@ -1,5 +1,7 @@
 //! Here we have the code related to the metrics for nodes. We
 
+use crate::disk_quota::DiskUsage;
+use crate::net_shaping::TrafficUsage;
 use crate::node::BabelEngine;
 use crate::node_state::VmStatus;
 use crate::nodes_manager::{MaybeNode, NodesManager};
@@ -8,9 +10,10 @@ use crate::services::api::{common, pb};
 use babel_api::plugin::NodeHealth;
 use babel_api::{
     engine::{JobStatus, JobsInfo},
-    plugin::ProtocolStatus,
+    plugin::{CustomMetric, CustomMetrics, ProtocolStatus},
 };
 use eyre::Result;
+use metrics::{counter, gauge};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::Debug;
@@ -38,6 +41,14 @@ pub struct Metric {
     pub consensus: Option<bool>,
     pub protocol_status: Option<ProtocolStatus>,
     pub jobs: JobsInfo,
+    /// Custom, protocol specific metrics returned by plugin `metrics()` function.
+    pub custom: CustomMetrics,
+    /// Node data usage versus its quota, if quota is enforced.
+    #[serde(default)]
+    pub disk_usage: Option<DiskUsage>,
+    /// Node network traffic counters, if node runs in its own network namespace.
+    #[serde(default)]
+    pub traffic: Option<TrafficUsage>,
 }
 
 impl Metrics {
@@ -48,8 +59,61 @@ impl Metrics {
                 || m.consensus.is_some()
                 || m.protocol_status.is_some()
                 || !m.jobs.is_empty()
+                || !m.custom.is_empty()
+                || m.disk_usage.is_some()
+                || m.traffic.is_some()
         })
     }
+
+    /// Publish nodes metrics via `metrics` facade, labeled with node id.
+    /// Gauges of nodes that are not reported anymore (e.g. deleted) are dropped by exporter
+    /// once idle, see `METRICS_IDLE_TIMEOUT` in `blockvisord`.
+    pub fn set_all_gauges(&self) {
+        for (id, metric) in self.0.iter() {
+            let node_id = id.to_string();
+            let labels = [("node_id", node_id.clone())];
+            if let Some(height) = metric.height {
+                gauge!("node.height", &labels).set(height as f64);
+            }
+            if let Some(block_age) = metric.block_age {
+                gauge!("node.block_age", &labels).set(block_age as f64);
+            }
+            if let Some(consensus) = metric.consensus {
+                gauge!("node.consensus", &labels).set(if consensus { 1.0 } else { 0.0 });
+            }
+            if let Some(status) = &metric.protocol_status {
+                gauge!("node.health", &labels).set(match status.health {
+                    NodeHealth::Healthy => 1.0,
+                    NodeHealth::Neutral => 0.0,
+                    NodeHealth::Unhealthy => -1.0,
+                });
+            }
+            if let Some(disk_usage) = &metric.disk_usage {
+                gauge!("node.disk.used_bytes", &labels).set(disk_usage.used_bytes as f64);
+                gauge!("node.disk.quota_bytes", &labels).set(disk_usage.quota_bytes as f64);
+            }
+            if let Some(traffic) = &metric.traffic {
+                counter!("node.network.received_bytes", &labels).absolute(traffic.received_bytes);
+                counter!("node.network.sent_bytes", &labels).absolute(traffic.sent_bytes);
+            }
+            for (name, info) in &metric.jobs {
+                let labels = [("node_id", node_id.clone()), ("job", name.clone())];
+                gauge!("node.job.running", &labels).set(if info.status == JobStatus::Running {
+                    1.0
+                } else {
+                    0.0
+                });
+                gauge!("node.job.restarts", &labels).set(info.restart_count as f64);
+            }
+            for (name, value) in &metric.custom {
+                let name = format!("node.custom.{name}");
+                match value {
+                    CustomMetric::Gauge(value) => gauge!(name, &labels).set(*value),
+                    CustomMetric::Counter(value) => counter!(name, &labels).absolute(*value),
+                }
+            }
+        }
+    }
 }
 
 impl Deref for Metrics {
@@ -87,12 +151,21 @@ where
                 match n.try_write() {
                     Err(_) => None,
                     Ok(mut node) => {
-                        if node.status().await == VmStatus::Running && !node.state.dev_mode {
-                            collect_metric(&mut node.babel_engine)
-                                .await
-                                .map(|metric| (node.id(), metric))
+                        let status = node.status().await;
+                        gauge!("node.suspended", "node_id" => node.id().to_string()).set(
+                            if status == VmStatus::Suspended {
+                                1.0
+                            } else {
+                                0.0
+                            },
+                        );
+                        if status == VmStatus::Running && !node.state.dev_mode {
+                            let mut metric = collect_metric(&mut node.babel_engine).await?;
+                            metric.disk_usage = node.disk_usage().await;
+                            metric.traffic = node.traffic_usage().await;
+                            Some((node.id(), metric))
                         } else {
-                            // don't collect metrics for not running or dev nodes
+                            // don't collect metrics for not running (including suspended) or dev nodes
                             None
                         }
                     }
@@ -124,6 +197,9 @@ pub async fn collect_metric<N: NodeConnection>(
                 consensus: None,
                 protocol_status,
                 jobs,
+                custom: Default::default(),
+                disk_usage: None,
+                traffic: None,
             })
         }
         Some(ProtocolStatus { state, .. })
@@ -142,6 +218,9 @@ pub async fn collect_metric<N: NodeConnection>(
                 consensus: None,
                 protocol_status,
                 jobs,
+                custom: Default::default(),
+                disk_usage: None,
+                traffic: None,
             })
         }
         _ => {
@@ -161,6 +240,13 @@ pub async fn collect_metric<N: NodeConnection>(
                 .await
                 .ok()
                 .unwrap_or_default();
+            let custom = match babel_engine.has_capability("metrics") {
+                true => timeout(babel_engine.metrics())
+                    .await
+                    .ok()
+                    .unwrap_or_default(),
+                false => Default::default(),
+            };
 
             Some(Metric {
                 // these could be optional
@@ -170,6 +256,9 @@ pub async fn collect_metric<N: NodeConnection>(
                 // these are expected in every chain
                 protocol_status,
                 jobs,
+                custom,
+                disk_usage: None,
+                traffic: None,
             })
         }
     }
@@ -249,6 +338,7 @@ impl From<Metrics> for pb::MetricsServiceNodeRequest {
                         .protocol_status
                         .map(|protocol_status| protocol_status.into()),
                     jobs,
+                    // custom metrics are kept local (metrics facade) until NodeMetrics has field for them
                 }
             })
             .collect();
@@ -0,0 +1,479 @@
+//! Local snapshots of node protocol data, so node can be checkpointed before risky operations
+//! (e.g. hard forks or DB migrations) and quickly rolled back on the same host.
+//!
+//! Native btrfs or ZFS snapshots are used if node data dir is btrfs subvolume or ZFS dataset
+//! mount point. Otherwise, data are copied with `cp --reflink=auto`, so on filesystems
+//! that support reflinks (e.g. XFS) snapshot is still cheap.
+
+use crate::{node_context::NodeContext, utils};
+use bv_utils::cmd::run_cmd;
+use chrono::{DateTime, Utc};
+use eyre::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::fs;
+use tracing::{info, warn};
+
+const SNAPSHOT_META_FILENAME: &str = "snapshot.json";
+const SNAPSHOT_DATA_DIR: &str = "data";
+const RESTORING_DATA_SUFFIX: &str = "restoring";
+/// Previous data dir, kept aside until restored data are swapped in.
+const REPLACED_DATA_SUFFIX: &str = "replaced";
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum SnapshotMethod {
+    Btrfs,
+    Zfs { dataset: String },
+    Copy,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub method: SnapshotMethod,
+    /// `.protocol_data.lock` stamp at the moment of snapshot, `None` if protocol data was not
+    /// initialized yet.
+    pub data_stamp: Option<SystemTime>,
+    /// Image version node was running when snapshot was created.
+    pub image_version: String,
+}
+
+/// Node snapshots stored in `<node_dir>/snapshots/<name>`.
+#[derive(Debug, Clone)]
+pub struct Snapshots {
+    snapshots_dir: PathBuf,
+    data_dir: PathBuf,
+    /// Plugin persistent files, that are snapshotted together with protocol data.
+    plugin_files: Vec<PathBuf>,
+}
+
+impl Snapshots {
+    pub fn new(context: &NodeContext, data_dir: PathBuf) -> Self {
+        Self {
+            snapshots_dir: context.snapshots_dir.clone(),
+            data_dir,
+            plugin_files: vec![context.plugin_data.clone(), context.plugin_kv.clone()],
+        }
+    }
+
+    /// List all snapshots, ordered by creation time.
+    pub async fn list(&self) -> Result<Vec<Snapshot>> {
+        let mut snapshots = vec![];
+        if !self.snapshots_dir.exists() {
+            return Ok(snapshots);
+        }
+        let mut entries = fs::read_dir(&self.snapshots_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let meta_path = entry.path().join(SNAPSHOT_META_FILENAME);
+            if !meta_path.exists() {
+                warn!("skipping incomplete snapshot '{}'", entry.path().display());
+                continue;
+            }
+            snapshots.push(load_meta(&meta_path).await?);
+        }
+        snapshots.sort_by_key(|snapshot| snapshot.created_at);
+        Ok(snapshots)
+    }
+
+    /// Create snapshot of protocol data and plugin persistent data.
+    /// Node must be stopped, so data are consistent.
+    pub async fn create(&self, name: &str, image_version: &str) -> Result<Snapshot> {
+        check_name(name)?;
+        let snapshot_dir = self.snapshots_dir.join(name);
+        if snapshot_dir.exists() {
+            bail!("snapshot '{name}' already exists");
+        }
+        fs::create_dir_all(&snapshot_dir).await?;
+        let result = self.create_in(&snapshot_dir, name, image_version).await;
+        if result.is_err() {
+            if let Err(err) = remove_snapshot_dir(&snapshot_dir).await {
+                warn!("failed to cleanup snapshot '{name}' after failure: {err:#}");
+            }
+        }
+        result
+    }
+
+    async fn create_in(
+        &self,
+        snapshot_dir: &Path,
+        name: &str,
+        image_version: &str,
+    ) -> Result<Snapshot> {
+        let method = detect_method(&self.data_dir).await;
+        info!("creating '{name}' snapshot using {method:?}");
+        let snapshot_data_dir = snapshot_dir.join(SNAPSHOT_DATA_DIR);
+        match &method {
+            SnapshotMethod::Btrfs => {
+                run_cmd(
+                    "btrfs",
+                    [
+                        "subvolume".as_ref(),
+                        "snapshot".as_ref(),
+                        "-r".as_ref(),
+                        self.data_dir.as_os_str(),
+                        snapshot_data_dir.as_os_str(),
+                    ],
+                )
+                .await?;
+            }
+            SnapshotMethod::Zfs { dataset } => {
+                run_cmd("zfs", ["snapshot", &format!("{dataset}@{name}")]).await?;
+            }
+            SnapshotMethod::Copy => copy_dir(&self.data_dir, &snapshot_data_dir).await?,
+        }
+        for path in &self.plugin_files {
+            if path.exists() {
+                fs::copy(path, snapshot_dir.join(file_name(path)?)).await?;
+            }
+        }
+        let snapshot = Snapshot {
+            name: name.to_owned(),
+            created_at: Utc::now(),
+            method,
+            data_stamp: babel_api::utils::protocol_data_stamp(&self.data_dir)?,
+            image_version: image_version.to_owned(),
+        };
+        // metadata file is written last, so snapshot without it is considered incomplete
+        utils::careful_save(
+            &snapshot_dir.join(SNAPSHOT_META_FILENAME),
+            serde_json::to_string(&snapshot)?.as_bytes(),
+        )
+        .await?;
+        Ok(snapshot)
+    }
+
+    /// Replace current protocol data and plugin persistent data with the ones from snapshot.
+    /// Node must be stopped.
+    pub async fn restore(&self, name: &str) -> Result<Snapshot> {
+        check_name(name)?;
+        let snapshot_dir = self.snapshots_dir.join(name);
+        let snapshot = load_meta(&snapshot_dir.join(SNAPSHOT_META_FILENAME))
+            .await
+            .with_context(|| format!("snapshot '{name}' not found"))?;
+        let snapshot_data_dir = snapshot_dir.join(SNAPSHOT_DATA_DIR);
+        match &snapshot.method {
+            SnapshotMethod::Btrfs => {
+                let restoring_dir = self.data_dir.with_extension(RESTORING_DATA_SUFFIX);
+                run_cmd(
+                    "btrfs",
+                    [
+                        "subvolume".as_ref(),
+                        "snapshot".as_ref(),
+                        snapshot_data_dir.as_os_str(),
+                        restoring_dir.as_os_str(),
+                    ],
+                )
+                .await?;
+                run_cmd(
+                    "btrfs",
+                    [
+                        "subvolume".as_ref(),
+                        "delete".as_ref(),
+                        self.data_dir.as_os_str(),
+                    ],
+                )
+                .await?;
+                fs::rename(&restoring_dir, &self.data_dir).await?;
+            }
+            SnapshotMethod::Zfs { dataset } => {
+                // rollback destroys all newer snapshots of the dataset
+                run_cmd("zfs", ["rollback", "-r", &format!("{dataset}@{name}")]).await?;
+                for newer in self
+                    .list()
+                    .await?
+                    .into_iter()
+                    .filter(|other| other.created_at > snapshot.created_at)
+                    .filter(|other| other.method == snapshot.method)
+                {
+                    warn!("snapshot '{}' destroyed by ZFS rollback", newer.name);
+                    remove_snapshot_dir(&self.snapshots_dir.join(&newer.name)).await?;
+                }
+            }
+            SnapshotMethod::Copy => restore_copy(&snapshot_data_dir, &self.data_dir).await?,
+        }
+        for path in &self.plugin_files {
+            let snapshot_path = snapshot_dir.join(file_name(path)?);
+            if snapshot_path.exists() {
+                fs::copy(&snapshot_path, path).await?;
+            } else if path.exists() {
+                fs::remove_file(path).await?;
+            }
+        }
+        Ok(snapshot)
+    }
+
+    /// Delete snapshot with given name.
+    pub async fn delete(&self, name: &str) -> Result<()> {
+        check_name(name)?;
+        let snapshot_dir = self.snapshots_dir.join(name);
+        if !snapshot_dir.exists() {
+            bail!("snapshot '{name}' not found");
+        }
+        let meta_path = snapshot_dir.join(SNAPSHOT_META_FILENAME);
+        if meta_path.exists() {
+            if let SnapshotMethod::Zfs { dataset } = load_meta(&meta_path).await?.method {
+                run_cmd("zfs", ["destroy", &format!("{dataset}@{name}")]).await?;
+            }
+        }
+        remove_snapshot_dir(&snapshot_dir).await
+    }
+
+    /// Delete all node snapshots, including native ones that are not stored inside node dir.
+    pub async fn delete_all(&self) -> Result<()> {
+        for snapshot in self.list().await? {
+            self.delete(&snapshot.name).await?;
+        }
+        Ok(())
+    }
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        || name.starts_with('.')
+    {
+        bail!("invalid snapshot name '{name}', only alphanumeric characters, '-', '_' and '.' are allowed");
+    }
+    Ok(())
+}
+
+fn file_name(path: &Path) -> Result<&std::ffi::OsStr> {
+    path.file_name()
+        .ok_or_else(|| anyhow!("invalid plugin file path '{}'", path.display()))
+}
+
+async fn load_meta(path: &Path) -> Result<Snapshot> {
+    Ok(serde_json::from_str(
+        &fs::read_to_string(path)
+            .await
+            .with_context(|| format!("failed to read '{}'", path.display()))?,
+    )?)
+}
+
+async fn detect_method(data_dir: &Path) -> SnapshotMethod {
+    let fs_type = run_cmd(
+        "stat",
+        [
+            "-f".as_ref(),
+            "-c".as_ref(),
+            "%T".as_ref(),
+            data_dir.as_os_str(),
+        ],
+    )
+    .await
+    .unwrap_or_default();
+    match fs_type.trim() {
+        "btrfs"
+            if run_cmd(
+                "btrfs",
+                ["subvolume".as_ref(), "show".as_ref(), data_dir.as_os_str()],
+            )
+            .await
+            .is_ok() =>
+        {
+            SnapshotMethod::Btrfs
+        }
+        "zfs" => match zfs_dataset(data_dir).await {
+            Some(dataset) => SnapshotMethod::Zfs { dataset },
+            None => SnapshotMethod::Copy,
+        },
+        _ => SnapshotMethod::Copy,
+    }
+}
+
+/// Returns ZFS dataset name, but only if `data_dir` is its mount point.
+async fn zfs_dataset(data_dir: &Path) -> Option<String> {
+    let out = run_cmd("zfs", ["list", "-H", "-o", "name,mountpoint"])
+        .await
+        .ok()?;
+    parse_zfs_list(&out, data_dir)
+}
+
+fn parse_zfs_list(out: &str, data_dir: &Path) -> Option<String> {
+    out.lines().find_map(|line| {
+        let (name, mountpoint) = line.split_once('\t')?;
+        (Path::new(mountpoint.trim()) == data_dir).then(|| name.to_owned())
+    })
+}
+
+async fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to).await?;
+    if from.exists() {
+        run_cmd(
+            "cp",
+            [
+                "-a".as_ref(),
+                "--reflink=auto".as_ref(),
+                from.join(".").as_os_str(),
+                to.as_os_str(),
+            ],
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Restore data from copy snapshot. Data are staged next to `data_dir`, so it doesn't count
+/// into data dir quota, and then swapped with current data. Interrupted swap is recovered
+/// on next restore.
+///
+/// If `data_dir` is a mount point (e.g. loopback disk quota), it can't be renamed, so its content
+/// is replaced with staged data instead. Current data are removed only after snapshot is
+/// successfully staged.
+async fn restore_copy(snapshot_data_dir: &Path, data_dir: &Path) -> Result<()> {
+    let restoring_dir = data_dir.with_extension(RESTORING_DATA_SUFFIX);
+    let replaced_dir = data_dir.with_extension(REPLACED_DATA_SUFFIX);
+    if replaced_dir.exists() {
+        if data_dir.exists() {
+            fs::remove_dir_all(&replaced_dir).await?;
+        } else {
+            warn!(
+                "recovering '{}' after interrupted restore",
+                data_dir.display()
+            );
+            fs::rename(&replaced_dir, data_dir).await?;
+        }
+    }
+    if restoring_dir.exists() {
+        fs::remove_dir_all(&restoring_dir).await?;
+    }
+    copy_dir(snapshot_data_dir, &restoring_dir).await?;
+    if !data_dir.exists() {
+        fs::rename(&restoring_dir, data_dir).await?;
+    } else if is_mount_point(data_dir).await? {
+        clear_dir(data_dir).await?;
+        copy_dir(&restoring_dir, data_dir).await?;
+        fs::remove_dir_all(&restoring_dir).await?;
+    } else {
+        fs::rename(data_dir, &replaced_dir).await?;
+        fs::rename(&restoring_dir, data_dir).await?;
+        fs::remove_dir_all(&replaced_dir).await?;
+    }
+    Ok(())
+}
+
+async fn is_mount_point(dir: &Path) -> Result<bool> {
+    let parent = dir
+        .parent()
+        .ok_or_else(|| anyhow!("invalid data dir '{}'", dir.display()))?;
+    Ok(fs::metadata(dir).await?.dev() != fs::metadata(parent).await?.dev())
+}
+
+async fn clear_dir(dir: &Path) -> Result<()> {
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_dir() {
+            fs::remove_dir_all(entry.path()).await?;
+        } else {
+            fs::remove_file(entry.path()).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn remove_snapshot_dir(snapshot_dir: &Path) -> Result<()> {
+    let data_dir = snapshot_dir.join(SNAPSHOT_DATA_DIR);
+    if data_dir.exists()
+        && run_cmd(
+            "btrfs",
+            [
+                "subvolume".as_ref(),
+                "delete".as_ref(),
+                data_dir.as_os_str(),
+            ],
+        )
+        .await
+        .is_err()
+    {
+        // not a btrfs subvolume, so read-only flag can't be a problem
+        fs::remove_dir_all(&data_dir).await?;
+    }
+    fs::remove_dir_all(snapshot_dir)
+        .await
+        .with_context(|| format!("failed to delete '{}'", snapshot_dir.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::TempDir;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_parse_zfs_list() {
+        let out = "tank\t/tank\ntank/nodes\t/var/lib/blockvisor/nodes\ntank/data\t/var/lib/blockvisor/nodes/abc/data\n";
+        assert_eq!(
+            Some("tank/data".to_string()),
+            parse_zfs_list(out, Path::new("/var/lib/blockvisor/nodes/abc/data"))
+        );
+        assert_eq!(
+            None,
+            parse_zfs_list(out, Path::new("/var/lib/blockvisor/nodes/xyz/data"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_copy() -> Result<()> {
+        let tmp_root = TempDir::new()?.to_path_buf();
+        let context = NodeContext::build(&tmp_root, Uuid::new_v4());
+        let node_dir = context.node_dir.clone();
+        let data_dir = node_dir.join("data");
+        let snapshots = Snapshots::new(&context, data_dir.clone());
+        fs::create_dir_all(data_dir.join("sub")).await?;
+        fs::write(data_dir.join("sub/file"), "before").await?;
+        fs::write(node_dir.join("plugin.data"), "plugin before").await?;
+        babel_api::utils::touch_protocol_data(&data_dir)?;
+        let stamp = babel_api::utils::protocol_data_stamp(&data_dir)?;
+
+        assert!(snapshots.create("../escape", "1.0.0").await.is_err());
+        let snapshot = snapshots.create("pre-fork", "1.0.0").await?;
+        assert_eq!(SnapshotMethod::Copy, snapshot.method);
+        assert_eq!(stamp, snapshot.data_stamp);
+        assert!(snapshots.create("pre-fork", "1.0.0").await.is_err());
+        assert_eq!(vec![snapshot.clone()], snapshots.list().await?);
+
+        fs::write(data_dir.join("sub/file"), "after").await?;
+        fs::write(data_dir.join("new_file"), "new").await?;
+        fs::remove_file(node_dir.join("plugin.data")).await?;
+        fs::write(node_dir.join("plugin_kv.json"), "{}").await?;
+
+        assert_eq!(snapshot, snapshots.restore("pre-fork").await?);
+        assert_eq!(
+            "before",
+            fs::read_to_string(data_dir.join("sub/file")).await?
+        );
+        assert!(!data_dir.join("new_file").exists());
+        assert_eq!(
+            "plugin before",
+            fs::read_to_string(node_dir.join("plugin.data")).await?
+        );
+        assert!(!node_dir.join("plugin_kv.json").exists());
+        assert_eq!(stamp, babel_api::utils::protocol_data_stamp(&data_dir)?);
+        assert!(!data_dir.with_extension(RESTORING_DATA_SUFFIX).exists());
+        assert!(!data_dir.with_extension(REPLACED_DATA_SUFFIX).exists());
+
+        // interrupted swap, current data already moved aside
+        fs::write(data_dir.join("new_file"), "new").await?;
+        fs::rename(&data_dir, data_dir.with_extension(REPLACED_DATA_SUFFIX)).await?;
+        snapshots.restore("pre-fork").await?;
+        assert_eq!(
+            "before",
+            fs::read_to_string(data_dir.join("sub/file")).await?
+        );
+        assert!(!data_dir.join("new_file").exists());
+        assert!(!data_dir.with_extension(REPLACED_DATA_SUFFIX).exists());
+
+        assert!(snapshots.restore("unknown").await.is_err());
+        snapshots.delete("pre-fork").await?;
+        assert!(snapshots.list().await?.is_empty());
+        assert!(snapshots.delete("pre-fork").await.is_err());
+        Ok(())
+    }
+}
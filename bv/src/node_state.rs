@@ -1,10 +1,18 @@
-use crate::{bv_config::ApptainerConfig, firewall, utils};
+use crate::{bv_config::ApptainerConfig, firewall, net_shaping::BandwidthLimit, utils};
 use babel_api::utils::RamdiskConfiguration;
 use chrono::serde::ts_seconds_option;
 use chrono::{DateTime, Utc};
 use eyre::{Context, Result};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt, fmt::Debug, mem, net::IpAddr, path::Path, time::SystemTime};
+use std::{
+    collections::HashMap,
+    fmt,
+    fmt::Debug,
+    mem,
+    net::{IpAddr, Ipv6Addr},
+    path::Path,
+    time::SystemTime,
+};
 use tokio::fs;
 use tracing::{error, info};
 use uuid::Uuid;
@@ -18,6 +26,7 @@ pub enum VmStatus {
     Stopped,
     Busy,
     Failed,
+    Suspended,
 }
 
 impl fmt::Display for VmStatus {
@@ -62,6 +71,9 @@ pub struct NodeState {
     // potentially configurable
     pub ip: IpAddr,
     pub gateway: IpAddr,
+    /// Second node address, assigned from host IPv6 pool if host network is dual-stack.
+    #[serde(default)]
+    pub ipv6: Option<Ipv6Addr>,
 
     // dynamic
     pub properties: NodeProperties,
@@ -119,12 +131,15 @@ pub struct StateBackup {
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum UpgradeStep {
+    Staged,
     Stop,
     CpuAssignment(CpuAssignmentUpdate),
     Vm,
     Plugin,
     Firewall,
     Restart,
+    /// Upgraded node is watched for verification window, before upgrade is completed.
+    Verification,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
@@ -153,6 +168,9 @@ pub struct VmConfig {
     pub disk_size_gb: u64,
     /// RAM disks configuration.
     pub ramdisks: Vec<RamdiskConfiguration>,
+    /// Network bandwidth limits, host defaults are used for limits not set here.
+    #[serde(default)]
+    pub bandwidth: BandwidthLimit,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
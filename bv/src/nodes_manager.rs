@@ -1,37 +1,53 @@
 use crate::{
     bv_config::SharedConfig,
+    bv_context::BvContext,
     command_failed,
     commands::{self, into_internal, Error},
-    cpu_registry::CpuRegistry,
-    firewall,
-    node::Node,
-    node_context::{build_nodes_dir, NODES_DIR},
+    cpu_registry::{CpuAllocationInfo, CpuRegistry},
+    firewall, image_verification,
+    ip_leases::{IpInfo, IpLeases, IpStatus},
+    node::{self, Node},
+    node_context::{build_nodes_dir, NodeContext, NODES_DIR},
     node_metrics,
-    node_state::{ConfigUpdate, NodeState, VmConfig, VmStatus, NODE_STATE_FILENAME},
-    pal::Pal,
-    scheduler,
+    node_snapshot::Snapshot,
+    node_state::{ConfigUpdate, NodeImage, NodeState, VmConfig, VmStatus, NODE_STATE_FILENAME},
+    pal::{Pal, VmState},
+    rpc_proxy, scheduler,
     scheduler::{Action, Scheduled, Scheduler},
     utils, BV_VAR_PATH,
 };
-use babel_api::{engine::JobInfo, engine::JobsInfo};
+use babel_api::{
+    engine::JobInfo,
+    engine::JobsInfo,
+    plugin::{NodeHealth, ProtocolStatus},
+};
 use eyre::{anyhow, bail, Context, Result};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
-    fmt::Debug,
+    fmt::{self, Debug},
+    future::Future,
+    net::{IpAddr, Ipv6Addr},
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 use thiserror::Error;
 use tokio::{
     fs::{self, read_dir},
-    sync::{mpsc, RwLock, RwLockReadGuard},
+    sync::{mpsc, Mutex, RwLock, RwLockReadGuard},
+    time::Instant,
 };
 use tracing::{debug, error, info, instrument, warn};
 use uuid::Uuid;
 
 pub const STATE_FILENAME: &str = "state.json";
 const MAX_SUPPORTED_RULES: usize = 128;
+/// Max time for single node to be upgraded and become healthy during rolling upgrade.
+const ROLLOUT_NODE_TIMEOUT: Duration = Duration::from_secs(3600);
+const ROLLOUT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Max time to wait for node lock, before script is evaluated.
+const EVALUATE_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub fn build_state_filename(bv_root: &Path) -> PathBuf {
     bv_root
@@ -43,7 +59,7 @@ pub fn build_state_filename(bv_root: &Path) -> PathBuf {
 #[derive(Debug)]
 #[allow(clippy::large_enum_variant)]
 pub enum MaybeNode<P: Pal> {
-    Node(RwLock<Node<P>>),
+    Node(Arc<RwLock<Node<P>>>),
     BrokenNode(NodeState),
 }
 
@@ -58,6 +74,10 @@ pub struct NodesManager<P: Pal + Debug> {
     state: RwLock<State>,
     state_path: PathBuf,
     pal: Arc<P>,
+    rollout: RwLock<HashMap<Uuid, RolloutStatus>>,
+    rpc_routes: rpc_proxy::Routes,
+    /// Serializes host resources check with allocation, so concurrent commands don't overcommit.
+    resources_lock: Mutex<()>,
 }
 
 pub type NodesDataCache = Vec<(Uuid, NodeState)>;
@@ -72,10 +92,59 @@ pub enum BabelError {
     Internal { err: eyre::Error },
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RolloutOptions {
+    /// Max number of nodes upgraded at the same time.
+    pub max_parallel: usize,
+    /// Stop rollout on first failed node, instead of continuing with remaining nodes.
+    pub pause_on_failure: bool,
+    /// Upgrade first node alone and stop rollout if it fails.
+    pub canary: bool,
+}
+
+/// Rolling upgrade progress of single node.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum RolloutStatus {
+    /// Waiting for its batch.
+    Pending,
+    /// Upgrade triggered, but not finished yet.
+    Upgrading,
+    /// Node upgraded, waiting for it to become healthy.
+    Verifying,
+    Done,
+    Failed(String),
+    /// Not upgraded, since rollout was paused after failure.
+    Paused,
+}
+
+impl RolloutStatus {
+    pub fn is_in_progress(&self) -> bool {
+        matches!(
+            self,
+            RolloutStatus::Pending | RolloutStatus::Upgrading | RolloutStatus::Verifying
+        )
+    }
+}
+
+impl fmt::Display for RolloutStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RolloutStatus::Pending => write!(f, "pending"),
+            RolloutStatus::Upgrading => write!(f, "upgrading"),
+            RolloutStatus::Verifying => write!(f, "verifying"),
+            RolloutStatus::Done => write!(f, "done"),
+            RolloutStatus::Failed(_) => write!(f, "failed"),
+            RolloutStatus::Paused => write!(f, "paused"),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 struct State {
     #[serde(default)]
     pub scheduled_tasks: Vec<Scheduled>,
+    #[serde(default)]
+    pub ip_leases: IpLeases,
 }
 
 impl State {
@@ -117,10 +186,12 @@ where
         let state_path = build_state_filename(bv_root);
         let pal = Arc::new(pal);
         let nodes = Arc::new(RwLock::new(HashMap::new()));
-        let available_cpus = pal.available_cpus().await;
-        let cpu_registry = CpuRegistry::new(available_cpus);
-        Ok(if state_path.exists() {
-            let state = State::load(&state_path).await?;
+        let cpu_registry = CpuRegistry::with_topology(
+            pal.cpu_topology().await,
+            api_config.read().await.cpu_allocation_policy,
+        );
+        let nodes = if state_path.exists() {
+            let mut state = State::load(&state_path).await?;
             let scheduler = Scheduler::start(
                 &state.scheduled_tasks,
                 scheduler::NodeTaskHandler(nodes.clone()),
@@ -134,6 +205,13 @@ where
             )
             .await?;
             *nodes.write().await = loaded_nodes;
+            state.ip_leases.sync(
+                &node_state_cache
+                    .values()
+                    .map(|node| (node.id, node_ips(node)))
+                    .collect::<Vec<_>>(),
+            );
+            state.save(&state_path).await?;
             Self {
                 api_config,
                 state: RwLock::new(state),
@@ -144,6 +222,9 @@ where
                 node_state_cache: RwLock::new(node_state_cache),
                 state_path,
                 pal,
+                rollout: Default::default(),
+                rpc_routes: Default::default(),
+                resources_lock: Default::default(),
             }
         } else {
             let scheduler = Scheduler::start(&[], scheduler::NodeTaskHandler(nodes.clone()));
@@ -151,6 +232,7 @@ where
                 api_config,
                 state: RwLock::new(State {
                     scheduled_tasks: vec![],
+                    ip_leases: Default::default(),
                 }),
                 nodes,
                 scheduler,
@@ -159,10 +241,15 @@ where
                 node_state_cache: Default::default(),
                 state_path,
                 pal,
+                rollout: Default::default(),
+                rpc_routes: Default::default(),
+                resources_lock: Default::default(),
             };
             nodes.state.read().await.save(&nodes.state_path).await?;
             nodes
-        })
+        };
+        nodes.update_rpc_routes().await;
+        Ok(nodes)
     }
 
     pub async fn detach(self) {
@@ -205,6 +292,7 @@ where
     #[instrument(skip(self))]
     pub async fn create(&self, mut desired_state: NodeState) -> commands::Result<NodeState> {
         check_babel_version(&desired_state.image.min_babel_version)?;
+        self.verify_image(&desired_state.image).await?;
         let id = desired_state.id;
         let mut node_ids = self.node_ids.write().await;
         if let Some(cache) = self.node_state_cache.read().await.get(&id) {
@@ -219,7 +307,7 @@ where
             )));
         }
 
-        check_firewall_rules(&desired_state.firewall.rules)?;
+        check_firewall_rules(&desired_state.firewall)?;
         if !desired_state.dev_mode {
             self.check_node_requirements(&desired_state, None).await?;
         } else {
@@ -238,44 +326,278 @@ where
                 )));
             }
         }
+        if desired_state.ipv6.is_none() {
+            desired_state.ipv6 = self.assign_ipv6().await?;
+        }
+        self.check_ip_pool(&desired_state).await?;
+        self.check_ip_conflicts(&desired_state).await?;
+        self.state
+            .write()
+            .await
+            .ip_leases
+            .lease(id, &node_ips(&desired_state))
+            .map_err(into_internal)?;
 
-        let node = Node::create(
+        let node = match Node::create(
             self.pal.clone(),
             self.api_config.clone(),
             desired_state,
             self.scheduler.tx(),
             self.cpu_registry.clone(),
         )
-        .await;
-        let node = node?;
+        .await
+        {
+            Ok(node) => node,
+            Err(err) => {
+                self.state.write().await.ip_leases.release(id);
+                return Err(err);
+            }
+        };
+        self.save_state().await;
         let node_state = node.state.clone();
         self.nodes
             .write()
             .await
-            .insert(id, MaybeNode::Node(RwLock::new(node)));
+            .insert(id, MaybeNode::Node(Arc::new(RwLock::new(node))));
         node_ids.insert(node_state.name.clone(), id);
         self.node_state_cache
             .write()
             .await
             .insert(id, node_state.clone());
+        self.update_rpc_routes().await;
         debug!("Node with id `{}` created", id);
 
         Ok(node_state)
     }
 
+    /// Fail if node IP is out of host pool (e.g. wrongly assigned by API), since host network
+    /// is not prepared for it. Dev nodes may use any address, so only warn for them.
+    async fn check_ip_pool(&self, state: &NodeState) -> commands::Result<()> {
+        let net_conf = self.api_config.read().await.net_conf;
+        if net_conf.available_ips.is_empty() || net_conf.available_ips.contains(&state.ip) {
+            return Ok(());
+        }
+        if state.dev_mode {
+            warn!("node ip address `{}` is out of host IP pool", state.ip);
+            Ok(())
+        } else {
+            command_failed!(Error::Internal(anyhow!(
+                "node ip address `{}` is out of host IP pool",
+                state.ip
+            )))
+        }
+    }
+
+    /// Verify image digest and signature, if enabled in config.
+    async fn verify_image(&self, image: &NodeImage) -> commands::Result<()> {
+        let Some(config) = self.api_config.read().await.image_verification.clone() else {
+            return Ok(());
+        };
+        image_verification::verify(&config, image)
+            .await
+            .map_err(Error::ImageVerificationFailed)
+    }
+
+    /// Probe node addresses on node network, if enabled in config.
+    async fn check_ip_conflicts(&self, state: &NodeState) -> commands::Result<()> {
+        let config = self.api_config.read().await;
+        if !config.ip_conflict_detection {
+            return Ok(());
+        }
+        let Some(bridge) = BvContext::from_config(config, state.apptainer_config.clone()).bridge
+        else {
+            // node use host network directly, so there is nothing to probe
+            return Ok(());
+        };
+        let mut conflicts = vec![];
+        for ip in node_ips(state) {
+            let conflict = self
+                .pal
+                .probe_ip(&bridge, ip)
+                .await
+                .map_err(into_internal)?;
+            self.state
+                .write()
+                .await
+                .ip_leases
+                .set_conflict(ip, conflict);
+            if conflict {
+                conflicts.push(ip.to_string());
+            }
+        }
+        if !conflicts.is_empty() {
+            self.save_state().await;
+            command_failed!(Error::Internal(anyhow!(
+                "ip address conflict detected - `{}` already used by other host on node network",
+                conflicts.join(", ")
+            )));
+        }
+        Ok(())
+    }
+
+    /// Status of host IP pool. If `probe` is set, free addresses are probed for conflicts first.
+    pub async fn ip_leases(&self, probe: bool) -> Result<Vec<IpInfo>> {
+        let config = self.api_config.read().await;
+        let mut pool = config.net_conf.available_ips.clone();
+        if let Some(ipv6) = &config.net_conf.ipv6 {
+            // IPv6 pool may be huge, so only addresses in use are listed
+            pool.extend(
+                self.state
+                    .read()
+                    .await
+                    .ip_leases
+                    .leases()
+                    .iter()
+                    .filter_map(|lease| match lease.ip {
+                        IpAddr::V6(ip) if ipv6.available_ips.contains(&ip) => Some(lease.ip),
+                        _ => None,
+                    }),
+            );
+        }
+        if probe {
+            let Some(bridge) = BvContext::from_config(config, None).bridge else {
+                bail!("can't probe IPs, since host network is used by nodes");
+            };
+            let free_ips = self
+                .state
+                .read()
+                .await
+                .ip_leases
+                .status(&pool)
+                .into_iter()
+                .filter(|info| info.status == IpStatus::Free)
+                .map(|info| info.ip)
+                .collect::<Vec<_>>();
+            for ip in free_ips {
+                let conflict = self.pal.probe_ip(&bridge, ip).await?;
+                self.state
+                    .write()
+                    .await
+                    .ip_leases
+                    .set_conflict(ip, conflict);
+            }
+            self.save_state().await;
+        }
+        Ok(self.state.read().await.ip_leases.status(&pool))
+    }
+
+    /// Shared RPC proxy routes, kept up to date with nodes.
+    pub fn rpc_routes(&self) -> rpc_proxy::Routes {
+        self.rpc_routes.clone()
+    }
+
+    async fn update_rpc_routes(&self) {
+        let ids: Vec<_> = self.node_state_cache.read().await.keys().copied().collect();
+        let mut ports = HashMap::new();
+        for id in ids {
+            let plugin_config = NodeContext::build(self.pal.bv_root(), id).plugin_config;
+            if let Some(port) = rpc_proxy::declared_rpc_port(&plugin_config).await {
+                ports.insert(id, port);
+            }
+        }
+        self.rpc_routes.set(
+            self.node_state_cache
+                .read()
+                .await
+                .values()
+                .filter_map(|state| {
+                    ports
+                        .get(&state.id)
+                        .and_then(|port| rpc_proxy::node_route(state, *port))
+                })
+                .collect(),
+        );
+    }
+
+    async fn save_state(&self) {
+        if let Err(err) = self.state.read().await.save(&self.state_path).await {
+            error!("error saving nodes state: {err:#}");
+        }
+    }
+
+    /// Pick free address from host IPv6 pool, `None` if host network is not dual-stack.
+    async fn assign_ipv6(&self) -> commands::Result<Option<Ipv6Addr>> {
+        let Some(net_conf) = self.api_config.read().await.net_conf.ipv6 else {
+            return Ok(None);
+        };
+        let ip = self
+            .state
+            .read()
+            .await
+            .ip_leases
+            .pick_free(net_conf.available_ips)
+            .ok_or_else(|| Error::Internal(anyhow!("no free IPv6 address available")))?;
+        Ok(Some(ip))
+    }
+
+    /// Recreate node exported on other host. Node keeps its identity, but gets new IP
+    /// assigned from host available IPs. Imported node is not started.
+    #[instrument(skip(self))]
+    pub async fn import(&self, mut state: NodeState) -> commands::Result<NodeState> {
+        if self.node_state_cache.read().await.contains_key(&state.id) {
+            command_failed!(Error::Internal(anyhow!(
+                "node with id `{}` already exists",
+                state.id
+            )));
+        }
+        let net_conf = self.api_config.read().await.net_conf;
+        state.ip = self
+            .state
+            .read()
+            .await
+            .ip_leases
+            .pick_free(net_conf.available_ips)
+            .ok_or_else(|| Error::Internal(anyhow!("no free IP available for imported node")))?;
+        state.gateway = net_conf.gateway_ip;
+        // new one is assigned from this host IPv6 pool on create
+        state.ipv6 = None;
+        state.expected_status = VmStatus::Stopped;
+        state.started_at = None;
+        state.restarting = false;
+        state.assigned_cpus.clear();
+        state.upgrade_state = Default::default();
+        self.create(state).await
+    }
+
     #[instrument(skip(self))]
     pub async fn upgrade(
+        &self,
+        desired_state: NodeState,
+    ) -> commands::Result<(NodeState, VmStatus)> {
+        let id = desired_state.id;
+        let unchanged = self
+            .node_state_cache(id)
+            .await
+            .is_ok_and(|node| node.image.id == desired_state.image.id);
+        let res = self.upgrade_node(desired_state).await;
+        let failure = match &res {
+            Err(err) => Some(format!("{err:#}")),
+            // nothing to verify, so don't wait for rollout timeout
+            Ok(_) if unchanged => Some("node image didn't change".to_string()),
+            Ok(_) => None,
+        };
+        self.report_rollout(id, failure).await;
+        self.update_rpc_routes().await;
+        res
+    }
+
+    async fn upgrade_node(
         &self,
         mut desired_state: NodeState,
     ) -> commands::Result<(NodeState, VmStatus)> {
         check_babel_version(&desired_state.image.min_babel_version)?;
         let id = desired_state.id;
-        let nodes_lock = self.nodes.read().await;
-        let MaybeNode::Node(node_lock) = nodes_lock.get(&id).ok_or_else(|| Error::NodeNotFound)?
-        else {
-            command_failed!(Error::Internal(anyhow!(
-                "cannot upgrade broken node `{id}`"
-            )));
+        let node_lock = {
+            let nodes_lock = self.nodes.read().await;
+            let MaybeNode::Node(node_lock) =
+                nodes_lock.get(&id).ok_or_else(|| Error::NodeNotFound)?
+            else {
+                command_failed!(Error::Internal(anyhow!(
+                    "cannot upgrade broken node `{id}`"
+                )));
+            };
+            // don't block other node commands for the whole verification window
+            node_lock.clone()
         };
 
         let read_node = node_lock.read().await;
@@ -285,6 +607,8 @@ where
             let node = read_node.state.clone();
             drop(read_node);
 
+            self.verify_image(&desired_state.image).await?;
+            check_firewall_rules(&desired_state.firewall)?;
             if desired_state.image.store_key != node.image.store_key {
                 command_failed!(Error::Internal(anyhow!(
                     "cannot upgrade node to version that uses different data set: `{}`",
@@ -300,6 +624,11 @@ where
 
             let mut node = node_lock.write().await;
             node.upgrade(desired_state).await?;
+            if let Some(window) = node.pending_upgrade_verification() {
+                drop(node);
+                node::verify_upgrade(&node_lock, window).await?;
+                node = node_lock.write().await;
+            }
             self.node_state_cache
                 .write()
                 .await
@@ -323,6 +652,9 @@ where
         self.nodes.write().await.remove(&id);
         self.node_ids.write().await.remove(&name);
         self.node_state_cache.write().await.remove(&id);
+        self.state.write().await.ip_leases.release(id);
+        self.save_state().await;
+        self.update_rpc_routes().await;
         if let Err(err) = self.scheduler.tx().send(Action::DeleteNode(id)).await {
             error!("Failed to delete node associated tasks form scheduler: {err:#}");
         }
@@ -337,6 +669,18 @@ where
         let MaybeNode::Node(node_lock) = maybe_node else {
             command_failed!(Error::Internal(anyhow!("cannot start broken node `{id}`")));
         };
+        // probing takes a while, so it is done before node is locked for start
+        let probed_state = {
+            let node = node_lock.read().await;
+            // node IP may be still held by its own machine (e.g. not fully stopped yet),
+            // so it is probed only if machine is down
+            (VmStatus::Running != node.expected_status()
+                && node.machine_state().await == VmState::SHUTOFF)
+                .then(|| node.state.clone())
+        };
+        if let Some(state) = probed_state {
+            self.check_ip_conflicts(&state).await?;
+        }
         let mut node = node_lock.write().await;
         if reload_plugin {
             node.reload_plugin()
@@ -351,6 +695,10 @@ where
                 node.babel_engine.reload_plugin_config().await?;
             }
         }
+        drop(node);
+        drop(nodes_lock);
+        // RPC port is declared in plugin config, which is evaluated on first start
+        self.update_rpc_routes().await;
         Ok(())
     }
 
@@ -368,6 +716,67 @@ where
         Ok(())
     }
 
+    #[instrument(skip(self))]
+    pub async fn suspend(&self, id: Uuid) -> commands::Result<()> {
+        let nodes_lock = self.nodes.read().await;
+        let maybe_node = nodes_lock.get(&id).ok_or_else(|| Error::NodeNotFound)?;
+        let MaybeNode::Node(node_lock) = maybe_node else {
+            command_failed!(Error::Internal(anyhow!(
+                "cannot suspend broken node `{id}`"
+            )));
+        };
+        let mut node = node_lock.write().await;
+        node.suspend().await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn resume(&self, id: Uuid) -> commands::Result<()> {
+        let nodes_lock = self.nodes.read().await;
+        let maybe_node = nodes_lock.get(&id).ok_or_else(|| Error::NodeNotFound)?;
+        let MaybeNode::Node(node_lock) = maybe_node else {
+            command_failed!(Error::Internal(anyhow!("cannot resume broken node `{id}`")));
+        };
+        let mut node = node_lock.write().await;
+        node.resume().await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn resize(
+        &self,
+        id: Uuid,
+        vcpu_count: Option<usize>,
+        mem_size_mb: Option<u64>,
+    ) -> commands::Result<()> {
+        let nodes_lock = self.nodes.read().await;
+        let maybe_node = nodes_lock.get(&id).ok_or_else(|| Error::NodeNotFound)?;
+        let MaybeNode::Node(node_lock) = maybe_node else {
+            command_failed!(Error::Internal(anyhow!("cannot resize broken node `{id}`")));
+        };
+        let _resources_lock = self.resources_lock.lock().await;
+        let mut node = node_lock.write().await;
+        let state = node.state.clone();
+        if !state.dev_mode {
+            let mut desired_state = state.clone();
+            if let Some(vcpu_count) = vcpu_count {
+                desired_state.vm_config.vcpu_count = vcpu_count;
+            }
+            if let Some(mem_size_mb) = mem_size_mb {
+                desired_state.vm_config.mem_size_mb = mem_size_mb;
+            }
+            // resources currently allocated by the node are available for it
+            self.check_node_requirements(&desired_state, Some(&state.vm_config))
+                .await?;
+        }
+        node.resize(vcpu_count, mem_size_mb).await?;
+        self.node_state_cache
+            .write()
+            .await
+            .insert(id, node.state.clone());
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     pub async fn restart(&self, id: Uuid, force: bool) -> commands::Result<()> {
         let nodes_lock = self.nodes.read().await;
@@ -386,7 +795,7 @@ where
     #[instrument(skip(self))]
     pub async fn update(&self, id: Uuid, config_update: ConfigUpdate) -> commands::Result<()> {
         if let Some(config) = config_update.new_firewall.as_ref() {
-            check_firewall_rules(&config.rules)?;
+            check_firewall_rules(config)?;
         }
         let nodes_lock = self.nodes.read().await;
         let maybe_node = nodes_lock.get(&id).ok_or_else(|| Error::NodeNotFound)?;
@@ -399,6 +808,7 @@ where
             .write()
             .await
             .insert(id, node.state.clone());
+        self.update_rpc_routes().await;
         Ok(())
     }
 
@@ -433,6 +843,8 @@ where
             }
         }) {
             if let Ok(mut node) = node_lock.try_write() {
+                // properly suspended node is not in Failed state, so only stale suspension
+                // (e.g. after host reboot) is passed to recovery
                 if node.status().await == VmStatus::Failed
                     && node.expected_status() != VmStatus::Failed
                 {
@@ -444,6 +856,72 @@ where
         }
     }
 
+    /// Resume upgrade verifications interrupted by bv restart. Otherwise upgraded node would stay
+    /// with active upgrade forever, since nobody completes (or rolls back) it.
+    pub async fn resume_upgrade_verifications(&self) {
+        let mut pending = vec![];
+        for (id, maybe_node) in self.nodes.read().await.iter() {
+            if let MaybeNode::Node(node_lock) = maybe_node {
+                if let Some(window) = node_lock.read().await.pending_upgrade_verification() {
+                    pending.push((*id, node_lock.clone(), window));
+                }
+            }
+        }
+        futures_util::future::join_all(pending.into_iter().map(
+            |(id, node_lock, window)| async move {
+                info!("Resuming node `{id}` upgrade verification");
+                if let Err(err) = node::verify_upgrade(&node_lock, window).await {
+                    error!("node `{id}` upgrade verification failed with: {err:#}");
+                }
+                let state = node_lock.read().await.state.clone();
+                self.update_node_state_cache(state).await;
+            },
+        ))
+        .await;
+    }
+
+    /// Make sure firewall rules applied on the host match nodes firewall config.
+    pub async fn reconcile_firewall(&self) {
+        // don't block nodes map during whole pass, since firewall tools may be slow
+        let nodes = self
+            .nodes
+            .read()
+            .await
+            .iter()
+            .filter_map(|(id, maybe_node)| {
+                if let MaybeNode::Node(node) = maybe_node {
+                    Some((*id, node.clone()))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        for (id, node_lock) in nodes {
+            if let Ok(mut node) = node_lock.try_write() {
+                match node.reconcile_firewall().await {
+                    Ok(true) => {
+                        warn!("node `{id}` firewall rules drifted from config - re-applied")
+                    }
+                    Ok(false) => {}
+                    Err(e) => error!("node `{id}` firewall reconciliation failed with: {e:#}"),
+                }
+            }
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn firewall_status(&self, id: Uuid) -> Result<firewall::Status> {
+        let nodes_lock = self.nodes.read().await;
+        let maybe_node = nodes_lock.get(&id).ok_or_else(|| Error::NodeNotFound)?;
+        let MaybeNode::Node(node_lock) = maybe_node else {
+            bail!("Cannot get firewall status of broken node `{id}`");
+        };
+        let node = node_lock.read().await;
+        node.firewall_status()
+            .await?
+            .ok_or(anyhow!("firewall status check is not supported"))
+    }
+
     #[instrument(skip(self))]
     pub async fn jobs(&self, id: Uuid) -> Result<JobsInfo> {
         let nodes_lock = self.nodes.read().await;
@@ -519,9 +997,12 @@ where
         };
         let mut node = node_lock.write().await;
 
-        node_metrics::collect_metric(&mut node.babel_engine)
+        let mut metric = node_metrics::collect_metric(&mut node.babel_engine)
             .await
-            .ok_or(anyhow!("metrics not available"))
+            .ok_or(anyhow!("metrics not available"))?;
+        metric.disk_usage = node.disk_usage().await;
+        metric.traffic = node.traffic_usage().await;
+        Ok(metric)
     }
 
     #[instrument(skip(self))]
@@ -577,6 +1058,79 @@ where
         }
     }
 
+    #[instrument(skip(self))]
+    pub async fn evaluate(
+        &self,
+        id: Uuid,
+        script: &str,
+        read_only: bool,
+    ) -> eyre::Result<String, BabelError> {
+        let nodes_lock = self.nodes.read().await;
+        let maybe_node = nodes_lock
+            .get(&id)
+            .ok_or_else(|| Error::NodeNotFound)
+            .map_err(|err| BabelError::Internal { err: err.into() })?;
+        let MaybeNode::Node(node_lock) = maybe_node else {
+            return Err(BabelError::Internal {
+                err: anyhow!("Cannot evaluate script on broken node {id}"),
+            });
+        };
+        // node may be locked for long by other command (e.g. upgrade), don't hang debug session
+        let mut node = tokio::time::timeout(EVALUATE_LOCK_TIMEOUT, node_lock.write())
+            .await
+            .map_err(|_| BabelError::Internal {
+                err: anyhow!("node {id} is busy with other command, try again later"),
+            })?;
+        node.babel_engine
+            .evaluate(script, read_only)
+            .await
+            .map_err(|err| BabelError::Plugin { err })
+    }
+
+    #[instrument(skip(self))]
+    pub async fn snapshots(&self, id: Uuid) -> Result<Vec<Snapshot>> {
+        let nodes_lock = self.nodes.read().await;
+        let maybe_node = nodes_lock.get(&id).ok_or_else(|| Error::NodeNotFound)?;
+        let MaybeNode::Node(node_lock) = maybe_node else {
+            bail!("Cannot list snapshots of broken node `{id}`");
+        };
+        let node = node_lock.read().await;
+        node.snapshots().list().await
+    }
+
+    #[instrument(skip(self))]
+    pub async fn create_snapshot(&self, id: Uuid, name: &str) -> Result<Snapshot> {
+        let nodes_lock = self.nodes.read().await;
+        let maybe_node = nodes_lock.get(&id).ok_or_else(|| Error::NodeNotFound)?;
+        let MaybeNode::Node(node_lock) = maybe_node else {
+            bail!("Cannot create snapshot of broken node `{id}`");
+        };
+        let mut node = node_lock.write().await;
+        node.create_snapshot(name).await
+    }
+
+    #[instrument(skip(self))]
+    pub async fn restore_snapshot(&self, id: Uuid, name: &str) -> Result<Snapshot> {
+        let nodes_lock = self.nodes.read().await;
+        let maybe_node = nodes_lock.get(&id).ok_or_else(|| Error::NodeNotFound)?;
+        let MaybeNode::Node(node_lock) = maybe_node else {
+            bail!("Cannot restore snapshot of broken node `{id}`");
+        };
+        let mut node = node_lock.write().await;
+        node.restore_snapshot(name).await
+    }
+
+    #[instrument(skip(self))]
+    pub async fn delete_snapshot(&self, id: Uuid, name: &str) -> Result<()> {
+        let nodes_lock = self.nodes.read().await;
+        let maybe_node = nodes_lock.get(&id).ok_or_else(|| Error::NodeNotFound)?;
+        let MaybeNode::Node(node_lock) = maybe_node else {
+            bail!("Cannot delete snapshot of broken node `{id}`");
+        };
+        let node = node_lock.read().await;
+        node.snapshots().delete(name).await
+    }
+
     #[instrument(skip(self))]
     pub async fn reload_plugin(&self, id: Uuid) -> eyre::Result<(), BabelError> {
         let nodes_lock = self.nodes.read().await;
@@ -601,6 +1155,9 @@ where
                 .await
                 .map_err(|err| BabelError::Internal { err })?;
         }
+        drop(node);
+        drop(nodes_lock);
+        self.update_rpc_routes().await;
         Ok(())
     }
 
@@ -678,10 +1235,156 @@ where
             .collect()
     }
 
+    pub async fn cpu_allocation_info(&self) -> CpuAllocationInfo {
+        let nodes: Vec<_> = self
+            .node_state_cache
+            .read()
+            .await
+            .values()
+            .map(|node| (node.name.clone(), node.assigned_cpus.clone()))
+            .collect();
+        self.cpu_registry.allocation_info(&nodes).await
+    }
+
     pub fn pal(&self) -> &P {
         &self.pal
     }
 
+    /// Register rolling upgrade of given nodes, so it is rejected right away (not in background)
+    /// if another one is already in progress.
+    pub async fn begin_rollout(&self, ids: &[Uuid]) -> Result<()> {
+        let mut rollout = self.rollout.write().await;
+        if rollout.values().any(|status| status.is_in_progress()) {
+            bail!("another rolling upgrade is already in progress");
+        }
+        *rollout = ids.iter().map(|id| (*id, RolloutStatus::Pending)).collect();
+        Ok(())
+    }
+
+    /// Upgrade given nodes in batches of at most `options.max_parallel` nodes. Each node upgrade is
+    /// requested with `trigger` and next batch is started only when all nodes from previous batch
+    /// are upgraded and healthy (or failed). Rollout must be registered with `begin_rollout` first.
+    /// Every failure is also reported in nodes rollout status.
+    pub async fn rolling_upgrade<F, Fut>(
+        &self,
+        ids: Vec<Uuid>,
+        options: RolloutOptions,
+        trigger: F,
+    ) -> Result<()>
+    where
+        F: Fn(Uuid) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let mut remaining = ids.as_slice();
+        let mut canary = options.canary;
+        let mut failed = 0;
+        while !remaining.is_empty() {
+            let batch_size = if canary {
+                1
+            } else {
+                options.max_parallel.max(1)
+            };
+            let (batch, rest) = remaining.split_at(batch_size.min(remaining.len()));
+            remaining = rest;
+            info!("Rolling upgrade of nodes: {batch:?}");
+            for id in batch {
+                self.set_rollout(*id, RolloutStatus::Upgrading).await;
+                if let Err(err) = trigger(*id).await {
+                    self.set_rollout(*id, RolloutStatus::Failed(format!("{err:#}")))
+                        .await;
+                }
+            }
+            let results =
+                futures_util::future::join_all(batch.iter().map(|id| self.wait_for_rollout(*id)))
+                    .await;
+            let batch_failed = results.iter().filter(|ok| !**ok).count();
+            failed += batch_failed;
+            if batch_failed > 0 && (options.pause_on_failure || canary) {
+                for id in remaining {
+                    self.set_rollout(*id, RolloutStatus::Paused).await;
+                }
+                bail!("rolling upgrade paused after {batch_failed} node(s) failed");
+            }
+            canary = false;
+        }
+        if failed > 0 {
+            bail!("rolling upgrade finished, but {failed} node(s) failed");
+        }
+        Ok(())
+    }
+
+    pub async fn rollout_status(&self) -> HashMap<Uuid, RolloutStatus> {
+        self.rollout.read().await.clone()
+    }
+
+    async fn set_rollout(&self, id: Uuid, status: RolloutStatus) {
+        self.rollout.write().await.insert(id, status);
+    }
+
+    /// Update rolling upgrade status (if any) with node upgrade result.
+    async fn report_rollout(&self, id: Uuid, failure: Option<String>) {
+        if let Some(status) = self.rollout.write().await.get_mut(&id) {
+            if *status == RolloutStatus::Upgrading {
+                *status = match failure {
+                    None => RolloutStatus::Verifying,
+                    Some(message) => RolloutStatus::Failed(message),
+                };
+            }
+        }
+    }
+
+    /// Wait until node is upgraded and healthy. Returns `false` if it failed or timed out.
+    async fn wait_for_rollout(&self, id: Uuid) -> bool {
+        let deadline = Instant::now() + ROLLOUT_NODE_TIMEOUT;
+        loop {
+            let status = self.rollout.read().await.get(&id).cloned();
+            match status {
+                Some(RolloutStatus::Upgrading) => {}
+                Some(RolloutStatus::Verifying) => {
+                    if self.is_node_healthy(id).await {
+                        self.set_rollout(id, RolloutStatus::Done).await;
+                        return true;
+                    }
+                }
+                Some(RolloutStatus::Done) => return true,
+                _ => return false,
+            }
+            if Instant::now() >= deadline {
+                self.set_rollout(
+                    id,
+                    RolloutStatus::Failed("node upgrade timed out".to_string()),
+                )
+                .await;
+                return false;
+            }
+            tokio::time::sleep(ROLLOUT_CHECK_INTERVAL).await;
+        }
+    }
+
+    async fn is_node_healthy(&self, id: Uuid) -> bool {
+        let nodes_lock = self.nodes.read().await;
+        let Some(MaybeNode::Node(node_lock)) = nodes_lock.get(&id) else {
+            return false;
+        };
+        let mut node = node_lock.write().await;
+        if node.expected_status() != VmStatus::Running {
+            // nothing more to verify for stopped node
+            return true;
+        }
+        if node.status().await != VmStatus::Running {
+            return false;
+        }
+        // dead canary can't report its status, so missing (failed or timed out) status
+        // is as bad as unhealthy one
+        match node_metrics::collect_metric(&mut node.babel_engine)
+            .await
+            .and_then(|metric| metric.protocol_status)
+        {
+            Some(ProtocolStatus { health, .. }) => health != NodeHealth::Unhealthy,
+            None => false,
+        }
+    }
+
     async fn load_nodes(
         pal: Arc<P>,
         api_config: SharedConfig,
@@ -738,7 +1441,7 @@ where
                         )
                         .await
                         {
-                            Ok(node) => MaybeNode::Node(RwLock::new(node)),
+                            Ok(node) => MaybeNode::Node(Arc::new(RwLock::new(node))),
                             Err(err) => {
                                 error!("Failed to attach node {id}: {err:#}");
                                 MaybeNode::BrokenNode(state)
@@ -789,16 +1492,22 @@ fn check_babel_version(min_babel_version: &str) -> commands::Result<()> {
     Ok(())
 }
 
-fn check_firewall_rules(rules: &[firewall::Rule]) -> commands::Result<()> {
-    if rules.len() > MAX_SUPPORTED_RULES {
+fn check_firewall_rules(config: &firewall::Config) -> commands::Result<()> {
+    if config.rules.len() > MAX_SUPPORTED_RULES {
         command_failed!(Error::Internal(anyhow!(
             "can't configure more than {MAX_SUPPORTED_RULES} rules!"
         )));
     }
-    crate::firewall::check_rules(rules)?;
+    crate::firewall::check_rules(config)?;
     Ok(())
 }
 
+fn node_ips(state: &NodeState) -> Vec<IpAddr> {
+    let mut ips = vec![state.ip];
+    ips.extend(state.ipv6.map(IpAddr::V6));
+    ips
+}
+
 fn name_not_found(name: &str) -> eyre::Error {
     anyhow!("Node with name `{}` not found", name)
 }
@@ -967,6 +1676,11 @@ mod tests {
             .once()
             .returning(|| bail!("delete VM failed"));
         vm_mock.expect_delete().once().returning(|| Ok(()));
+        let data_dir = test_env.tmp_root.join("data");
+        vm_mock
+            .expect_data_dir()
+            .once()
+            .returning(move || data_dir.clone());
         add_create_node_expectations(&mut pal, 1, first_node_state.clone(), vm_mock);
 
         let mut second_node_state =
@@ -1057,6 +1771,9 @@ mod tests {
                 protocol: None,
                 ips: vec![],
                 ports: vec![],
+                ip_sets: vec![],
+                port_ranges: vec![],
+                log: false,
             })
             .collect::<Vec<_>>();
         let mut too_many_rules_state = build_node_state("node name", "192.168.0.9", "192.168.0.1");
@@ -1114,7 +1831,140 @@ mod tests {
             .await
             .contains_key(&first_node_state.id));
         assert!(nodes.scheduler.stop().await.unwrap().is_empty());
+        assert_eq!(
+            vec![(second_node_state.ip, second_node_state.id)],
+            nodes
+                .state
+                .read()
+                .await
+                .ip_leases
+                .leases()
+                .iter()
+                .map(|lease| (lease.ip, lease.node_id))
+                .collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ip_conflicts() -> Result<()> {
+        let test_env = TestEnv::new().await?;
+        let mut pal = test_env.default_pal();
+        pal.expect_available_cpus().return_const(3usize);
+        pal.expect_available_resources()
+            .returning(available_test_resources);
+        let mut seq = Sequence::new();
+        pal.expect_probe_ip()
+            .withf(|iface, ip| iface == "bvbr7" && ip.to_string() == "192.168.0.7")
+            .once()
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(true));
+        pal.expect_probe_ip()
+            .withf(|iface, ip| iface == "bvbr7" && ip.to_string() == "192.168.0.8")
+            .once()
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(false));
+        // other host took node ip while node was stopped
+        pal.expect_probe_ip()
+            .withf(|iface, ip| iface == "bvbr7" && ip.to_string() == "192.168.0.8")
+            .once()
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(true));
+        let node_state = build_node_state("node name", "192.168.0.8", "192.168.0.1");
+        let mut vm_mock = MockTestVM::new();
+        let plugin_path = test_env.default_plugin_path.clone();
+        vm_mock
+            .expect_plugin_path()
+            .returning(move || plugin_path.clone());
+        vm_mock.expect_node_env().returning(Default::default);
+        let mut vm_seq = Sequence::new();
+        vm_mock
+            .expect_state()
+            .times(2)
+            .in_sequence(&mut vm_seq)
+            .return_const(VmState::SHUTOFF);
+        // machine still running, so it may still hold node ip
+        vm_mock
+            .expect_state()
+            .times(2)
+            .in_sequence(&mut vm_seq)
+            .return_const(VmState::RUNNING);
+        add_firewall_expectation(&mut pal, node_state.clone());
+        pal.expect_create_vm().return_once(move |_, _| Ok(vm_mock));
+        pal.expect_create_node_connection()
+            .return_once(dummy_connection_mock);
+        let config = default_config(test_env.tmp_root.clone());
+        {
+            let mut config = config.config.write().await;
+            config.ip_conflict_detection = true;
+            config.net_conf.available_ips = vec![
+                IpAddr::from_str("192.168.0.7")?,
+                IpAddr::from_str("192.168.0.8")?,
+            ];
+        }
+        let nodes = NodesManager::load(pal, config).await?;
+
+        assert_eq!(
+            "BV internal error: 'node ip address `192.168.0.9` is out of host IP pool'",
+            nodes
+                .create(build_node_state("node name", "192.168.0.9", "192.168.0.1"))
+                .await
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "BV internal error: 'ip address conflict detected - `192.168.0.7` already used by other host on node network'",
+            nodes
+                .create(build_node_state("node name", "192.168.0.7", "192.168.0.1"))
+                .await
+                .unwrap_err()
+                .to_string()
+        );
+        let ips = nodes.ip_leases(false).await?;
+        assert_eq!(2, ips.len());
+        assert!(matches!(ips[0].status, IpStatus::Conflict { .. }));
+        assert_eq!(IpStatus::Free, ips[1].status);
+        assert!(nodes.state.read().await.ip_leases.leases().is_empty());
+
+        nodes.create(node_state.clone()).await?;
+        assert_eq!(
+            "BV internal error: 'ip address conflict detected - `192.168.0.8` already used by other host on node network'",
+            nodes
+                .start(node_state.id, false)
+                .await
+                .unwrap_err()
+                .to_string()
+        );
+        // ip is not probed while node machine is not shut off
+        assert!(nodes
+            .start(node_state.id, false)
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("can't start node which is not stopped properly"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_image_verification() -> Result<()> {
+        let test_env = TestEnv::new().await?;
+        let mut pal = test_env.default_pal();
+        pal.expect_available_cpus().return_const(1usize);
+        let config = default_config(test_env.tmp_root.clone());
+        config.config.write().await.image_verification = Some(image_verification::Config {
+            trusted_keys: vec![test_env.tmp_root.join("cosign.pub")],
+            ignore_tlog: false,
+        });
+        let nodes = NodesManager::load(pal, config).await?;
 
+        let mut node_state = build_node_state("node name", "192.168.0.7", "192.168.0.1");
+        node_state.image.uri = "docker://registry/image:tag".to_string();
+        assert_eq!(
+            "node image verification failed: 'image `docker://registry/image:tag` is not pinned by digest'",
+            nodes.create(node_state).await.unwrap_err().to_string()
+        );
+        assert!(nodes.nodes_list().await.is_empty());
         Ok(())
     }
 
@@ -1401,6 +2251,86 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_rolling_upgrade() -> Result<()> {
+        let test_env = TestEnv::new().await?;
+        let mut pal = test_env.default_pal();
+        pal.expect_available_cpus().return_const(1usize);
+        let config = default_config(test_env.tmp_root.clone());
+        let nodes = NodesManager::load(pal, config).await?;
+        let ids = vec![Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+
+        // canary failed - remaining nodes are not touched
+        nodes.begin_rollout(&ids).await?;
+        assert_eq!(
+            "another rolling upgrade is already in progress",
+            nodes.begin_rollout(&ids).await.unwrap_err().to_string()
+        );
+        let triggered = std::sync::Mutex::new(vec![]);
+        assert_eq!(
+            "rolling upgrade paused after 1 node(s) failed",
+            nodes
+                .rolling_upgrade(
+                    ids.clone(),
+                    RolloutOptions {
+                        max_parallel: 2,
+                        pause_on_failure: false,
+                        canary: true,
+                    },
+                    |id| {
+                        triggered.lock().unwrap().push(id);
+                        async { bail!("api unreachable") }
+                    },
+                )
+                .await
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(vec![ids[0]], *triggered.lock().unwrap());
+        let status = nodes.rollout_status().await;
+        assert_eq!(
+            RolloutStatus::Failed("api unreachable".to_string()),
+            status[&ids[0]]
+        );
+        assert_eq!(RolloutStatus::Paused, status[&ids[1]]);
+        assert_eq!(RolloutStatus::Paused, status[&ids[2]]);
+
+        // node upgrade failures are reported back, but rollout continues
+        nodes.begin_rollout(&ids).await?;
+        let triggered = std::sync::Mutex::new(vec![]);
+        assert_eq!(
+            "rolling upgrade finished, but 3 node(s) failed",
+            nodes
+                .rolling_upgrade(
+                    ids.clone(),
+                    RolloutOptions {
+                        max_parallel: 2,
+                        pause_on_failure: false,
+                        canary: false,
+                    },
+                    |id| {
+                        triggered.lock().unwrap().push(id);
+                        let mut state = default_node_state();
+                        state.id = id;
+                        let nodes = &nodes;
+                        async move {
+                            // simulate upgrade command sent back by API
+                            let _ = nodes.upgrade(state).await;
+                            Ok(())
+                        }
+                    },
+                )
+                .await
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(ids, *triggered.lock().unwrap());
+        for status in nodes.rollout_status().await.values() {
+            assert!(matches!(status, RolloutStatus::Failed(_)));
+        }
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_recovery() -> Result<()> {
         let test_env = TestEnv::new().await?;
@@ -0,0 +1,534 @@
+//! Lightweight node runtime, that run babel in node rootfs with `systemd-nspawn`, or in plain
+//! chroot (with own user, mount and PID namespaces created by `unshare`, so no root privileges are
+//! needed). Nodes always use host network, so neither apptainer nor bridge is needed on the host,
+//! e.g. to run full node lifecycle in CI containers.
+//! CPU and memory limits, disk quota and suspend are not supported.
+
+use crate::{
+    apptainer_machine::{
+        build_rootfs_dir, BABEL_BIN_NAME, BABEL_BIN_PATH, BABEL_KILL_TIMEOUT, BABEL_VAR_PATH,
+        BACKUP_ROOTFS_DIR, DATA_DIR, DATA_DRIVE_MOUNT_POINT, JOURNAL_DIR, PLUGIN_MAIN_FILENAME,
+        PLUGIN_PATH, PROTOCOL_DATA_PATH, STAGING_ROOTFS_DIR,
+    },
+    bv_config::NspawnConfig,
+    bv_context::BvContext,
+    image_cache, node_context, node_env,
+    node_env::NODE_ENV_FILE_PATH,
+    node_state::{NodeState, VmConfig},
+    pal,
+    upgrade_dirs::UpgradeDirs,
+    utils::{get_process_pid, GetProcessIdError},
+};
+use async_trait::async_trait;
+use babel_api::engine::{NodeEnv, PosixSignal};
+use bv_utils::{
+    cmd::run_cmd,
+    system::{gracefully_terminate_process, is_process_running, kill_all_processes},
+};
+use eyre::{anyhow, bail, Context, Result};
+use std::{
+    ffi::OsStr,
+    mem,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use sysinfo::{Pid, PidExt};
+use tokio::{fs, process::Command};
+use tracing::{debug, warn};
+
+const RUNTIME_PID_FILE: &str = "runtime.pid";
+/// File in rootfs with uri of image it was built from, written once rootfs is fully built.
+const ROOTFS_IMAGE_PATH: &str = "var/lib/babel/rootfs_image";
+const FILE_URI_PREFIX: &str = "file://";
+const NSPAWN_BIN_NAME: &str = "systemd-nspawn";
+const UNSHARE_BIN_NAME: &str = "unshare";
+const RUNTIME_STOP_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_PATH_ENV: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+/// Plain chroot setup, run inside runtime namespaces. Arguments are: node rootfs, babel binary
+/// path inside rootfs, and then pairs of bind mount source and target (absolute path inside rootfs).
+/// All mounts are made in runtime mount namespace, so they are gone together with the runtime.
+const CHROOT_SCRIPT: &str = r#"set -e
+root="$1"
+babel="$2"
+shift 2
+mount --rbind /dev "$root/dev"
+mount -t proc proc "$root/proc"
+while [ "$#" -gt 1 ]; do
+    mount --rbind "$1" "$root$2"
+    shift 2
+done
+exec chroot "$root" "$babel" "$root"
+"#;
+
+#[derive(Debug)]
+pub struct NspawnMachine {
+    node_dir: PathBuf,
+    babel_path: PathBuf,
+    chroot_dir: PathBuf,
+    upgrade_dirs: UpgradeDirs,
+    runtime_pid_path: PathBuf,
+    data_dir: PathBuf,
+
+    runtime_pid: Option<Pid>,
+
+    vm_id: String,
+    vm_name: String,
+
+    nspawn_config: NspawnConfig,
+
+    config: Config,
+    config_backup: Option<Config>,
+}
+
+#[derive(Debug, Clone)]
+struct Config {
+    image_uri: String,
+    vm: VmConfig,
+    node_env: NodeEnv,
+}
+
+pub async fn new(
+    bv_root: &Path,
+    bv_context: &BvContext,
+    node_state: &NodeState,
+    babel_path: PathBuf,
+    config: NspawnConfig,
+) -> Result<NspawnMachine> {
+    let node_dir = node_context::build_node_dir(bv_root, node_state.id);
+    let data_dir = node_dir.join(DATA_DIR);
+    if !data_dir.exists() {
+        fs::create_dir_all(&data_dir).await?;
+    }
+    if node_state.initialized && babel_api::utils::protocol_data_stamp(&data_dir)?.is_none() {
+        babel_api::utils::touch_protocol_data(&data_dir)?;
+    }
+
+    Ok(NspawnMachine {
+        chroot_dir: build_rootfs_dir(&node_dir),
+        upgrade_dirs: UpgradeDirs::new(
+            build_rootfs_dir(&node_dir),
+            node_dir.join(BACKUP_ROOTFS_DIR),
+            node_dir.join(STAGING_ROOTFS_DIR),
+            node_dir.join(image_cache::STAGED_IMAGE_FILE),
+        ),
+        runtime_pid_path: node_dir.join(RUNTIME_PID_FILE),
+        node_dir,
+        babel_path,
+        data_dir,
+        runtime_pid: None,
+
+        vm_id: node_state.id.to_string(),
+        vm_name: node_state.name.clone(),
+
+        nspawn_config: config,
+
+        config: Config {
+            image_uri: node_state.image.uri.clone(),
+            vm: node_state.vm_config.clone(),
+            node_env: node_env::new(
+                bv_context,
+                node_state,
+                PathBuf::from_str(DATA_DRIVE_MOUNT_POINT)?,
+                PathBuf::from_str(PROTOCOL_DATA_PATH)?,
+                None,
+            ),
+        },
+        config_backup: None,
+    })
+}
+
+impl NspawnMachine {
+    pub async fn build(&self) -> Result<()> {
+        if !is_built(&self.chroot_dir) {
+            build_rootfs(
+                &self.chroot_dir,
+                &rootfs_tarball(&self.nspawn_config, &self.config.image_uri)?,
+                &self.config.image_uri,
+                &self.vm_id,
+            )
+            .await?;
+        }
+        node_env::save(&self.config.node_env, &self.chroot_dir).await?;
+        Ok(())
+    }
+
+    /// Attach to already running node. Running babel is not restarted, since it is the main
+    /// process of the node container, so node is switched to new babel on next start.
+    pub async fn attach(&mut self) -> Result<()> {
+        self.build().await?;
+        if self.runtime_pid_path.exists() {
+            let pid = Pid::from_str(fs::read_to_string(&self.runtime_pid_path).await?.trim())?;
+            if is_process_running(pid) {
+                self.runtime_pid = Some(pid);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_runtime_running(&self) -> bool {
+        self.runtime_pid.map(is_process_running).unwrap_or(false)
+    }
+
+    fn is_babel_running(&self) -> bool {
+        get_process_pid(BABEL_BIN_NAME, &self.chroot_dir.to_string_lossy()).is_ok()
+    }
+
+    /// Host paths bind mounted into node rootfs.
+    fn binds(&self) -> Vec<(PathBuf, &'static str)> {
+        let mut binds = vec![(self.data_dir.clone(), DATA_DRIVE_MOUNT_POINT)];
+        if Path::new(JOURNAL_DIR).exists() {
+            binds.push((PathBuf::from(JOURNAL_DIR), JOURNAL_DIR));
+        }
+        binds
+    }
+
+    fn nspawn_args(&self, env: &[(String, String)]) -> Vec<String> {
+        let mut args = vec![
+            "--quiet".to_string(),
+            "--console=pipe".to_string(),
+            "--register=no".to_string(),
+            "--keep-unit".to_string(),
+            "--as-pid2".to_string(),
+            format!("--directory={}", self.chroot_dir.display()),
+            format!("--machine={}", self.vm_id),
+            format!("--hostname={}", self.vm_name.replace('_', "-")),
+        ];
+        for (source, target) in self.binds() {
+            args.push(format!("--bind={}:{target}", source.display()));
+        }
+        for (key, value) in env {
+            args.push(format!("--setenv={key}={value}"));
+        }
+        args.push(BABEL_BIN_PATH.to_string());
+        args.push(self.chroot_dir.to_string_lossy().to_string());
+        args
+    }
+
+    /// Babel runs as root of own user namespace (mapped to the user running BV),
+    /// so no privileges are needed on the host, e.g. in CI containers.
+    fn chroot_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "--user".to_string(),
+            "--map-root-user".to_string(),
+            "--mount".to_string(),
+            "--pid".to_string(),
+            "--fork".to_string(),
+            "--kill-child".to_string(),
+            "sh".to_string(),
+            "-c".to_string(),
+            CHROOT_SCRIPT.to_string(),
+            "sh".to_string(),
+            self.chroot_dir.to_string_lossy().to_string(),
+            BABEL_BIN_PATH.to_string(),
+        ];
+        for (source, target) in self.binds() {
+            args.push(source.to_string_lossy().to_string());
+            args.push(target.to_string());
+        }
+        args
+    }
+
+    async fn start_runtime(&mut self) -> Result<()> {
+        fs::copy(
+            &self.babel_path,
+            self.chroot_dir.join(BABEL_BIN_PATH.trim_start_matches('/')),
+        )
+        .await
+        .with_context(|| format!("babel binary not found: {}", self.babel_path.display()))?;
+        let env = load_env_file(&self.chroot_dir.join(NODE_ENV_FILE_PATH)).await?;
+        let mut cmd = if self.nspawn_config.chroot {
+            let mut cmd = Command::new(UNSHARE_BIN_NAME);
+            cmd.args(self.chroot_args())
+                .env_clear()
+                .env("PATH", DEFAULT_PATH_ENV)
+                .envs(env);
+            cmd
+        } else {
+            let mut cmd = Command::new(NSPAWN_BIN_NAME);
+            cmd.args(self.nspawn_args(&env));
+            cmd
+        };
+        debug!("start runtime for {}: '{:?}'", self.vm_id, cmd);
+        let pid = cmd
+            .spawn()?
+            .id()
+            .map(Pid::from_u32)
+            .ok_or_else(|| anyhow!("failed to start runtime for {}", self.vm_id))?;
+        if let Err(err) = fs::write(&self.runtime_pid_path, pid.to_string()).await {
+            warn!(
+                "failed to save runtime pid to file '{}': {:#}",
+                self.runtime_pid_path.display(),
+                err
+            );
+        }
+        self.runtime_pid = Some(pid);
+        Ok(())
+    }
+
+    async fn stop_runtime(&mut self, force: bool) -> Result<()> {
+        debug!("stop runtime for {}", self.vm_id);
+        match get_process_pid(BABEL_BIN_NAME, &self.chroot_dir.to_string_lossy()) {
+            Ok(pid) if !force => {
+                gracefully_terminate_process(pid, BABEL_KILL_TIMEOUT).await;
+            }
+            Err(GetProcessIdError::NotFound) => {}
+            _ => {
+                kill_all_processes(
+                    BABEL_BIN_NAME,
+                    &[&self.chroot_dir.to_string_lossy()],
+                    BABEL_KILL_TIMEOUT,
+                    PosixSignal::SIGTERM,
+                );
+            }
+        }
+        // runtime exits on its own once babel is gone
+        if let Some(pid) = self.runtime_pid {
+            if is_process_running(pid) {
+                gracefully_terminate_process(pid, RUNTIME_STOP_TIMEOUT).await;
+            }
+            if is_process_running(pid) {
+                bail!("failed to stop runtime for vm {}", self.vm_id);
+            }
+        }
+        self.runtime_pid = None;
+        if self.runtime_pid_path.exists() {
+            fs::remove_file(&self.runtime_pid_path).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Local rootfs tarball for given image. Tarball set in config takes precedence over image uri,
+/// so remote images can be replaced e.g. in CI.
+fn rootfs_tarball(config: &NspawnConfig, image_uri: &str) -> Result<PathBuf> {
+    if let Some(path) = &config.rootfs_tarball {
+        return Ok(path.clone());
+    }
+    image_uri
+        .strip_prefix(FILE_URI_PREFIX)
+        .map(PathBuf::from)
+        .ok_or_else(|| {
+            anyhow!("image `{image_uri}` is not supported by nspawn runtime, only local rootfs tarball (`file://` uri) can be used")
+        })
+}
+
+async fn build_rootfs(
+    rootfs_dir: &Path,
+    tarball: &Path,
+    image_uri: &str,
+    vm_id: &str,
+) -> Result<()> {
+    fs::create_dir_all(rootfs_dir).await?;
+    run_cmd(
+        "tar",
+        [
+            OsStr::new("--extract"),
+            OsStr::new("--numeric-owner"),
+            OsStr::new("--file"),
+            tarball.as_os_str(),
+            OsStr::new("--directory"),
+            rootfs_dir.as_os_str(),
+        ],
+    )
+    .await
+    .map_err(|err| {
+        anyhow!(
+            "failed to build '{vm_id}' from '{}': {err:#}",
+            tarball.display()
+        )
+    })?;
+    fs::create_dir_all(rootfs_dir.join(DATA_DRIVE_MOUNT_POINT.trim_start_matches('/'))).await?;
+    fs::create_dir_all(rootfs_dir.join(JOURNAL_DIR.trim_start_matches('/'))).await?;
+    fs::create_dir_all(rootfs_dir.join(BABEL_VAR_PATH)).await?;
+    fs::create_dir_all(rootfs_dir.join("proc")).await?;
+    fs::create_dir_all(rootfs_dir.join("dev")).await?;
+    fs::write(rootfs_dir.join(ROOTFS_IMAGE_PATH), image_uri).await?;
+    Ok(())
+}
+
+fn is_built(rootfs_dir: &Path) -> bool {
+    rootfs_dir.join(ROOTFS_IMAGE_PATH).exists()
+}
+
+/// Load `KEY="value"` lines of node env file, so they can be passed to the runtime.
+async fn load_env_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let content = fs::read_to_string(path)
+        .await
+        .with_context(|| format!("failed to read node env file '{}'", path.display()))?;
+    Ok(parse_env(&content))
+}
+
+fn parse_env(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            (key.trim().to_string(), value.to_string())
+        })
+        .collect()
+}
+
+#[async_trait]
+impl pal::VirtualMachine for NspawnMachine {
+    async fn state(&self) -> pal::VmState {
+        if self.is_runtime_running() {
+            if self.is_babel_running() {
+                pal::VmState::RUNNING
+            } else {
+                pal::VmState::INVALID
+            }
+        } else {
+            pal::VmState::SHUTOFF
+        }
+    }
+
+    async fn delete(&mut self) -> Result<()> {
+        if self.shutdown().await.is_err() {
+            self.force_shutdown().await?;
+        }
+        if self.node_dir.exists() {
+            fs::remove_dir_all(&self.node_dir).await?;
+        }
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        self.stop_runtime(false).await
+    }
+
+    async fn force_shutdown(&mut self) -> Result<()> {
+        self.stop_runtime(true).await
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        if !self.is_runtime_running() {
+            self.start_runtime().await?;
+        }
+        Ok(())
+    }
+
+    async fn suspend(&mut self) -> Result<()> {
+        bail!("suspend is not supported by nspawn runtime")
+    }
+
+    async fn resume(&mut self) -> Result<()> {
+        // nothing can be suspended
+        Ok(())
+    }
+
+    async fn resize(&mut self, node_state: &NodeState) -> Result<()> {
+        // resources limits are not enforced, so only remember new values
+        self.config.vm = node_state.vm_config.clone();
+        Ok(())
+    }
+
+    async fn stage_upgrade(&mut self, node_state: &NodeState) -> Result<()> {
+        let uri = &node_state.image.uri;
+        let tarball = rootfs_tarball(&self.nspawn_config, uri)?;
+        self.upgrade_dirs
+            .stage(
+                uri,
+                build_rootfs(self.upgrade_dirs.staging_dir(), &tarball, uri, &self.vm_id),
+            )
+            .await
+    }
+
+    async fn drop_staged(&mut self) -> Result<()> {
+        self.upgrade_dirs.drop_staged().await
+    }
+
+    async fn upgrade(&mut self, node_state: &NodeState) -> Result<()> {
+        if self.is_runtime_running() {
+            bail!("can't upgrade running vm")
+        }
+        self.config_backup = Some(self.config.clone());
+        self.config.image_uri = node_state.image.uri.clone();
+        self.config.vm = node_state.vm_config.clone();
+        self.update_node_env(node_state);
+
+        // new rootfs may be already built in background, otherwise it is built from scratch
+        self.upgrade_dirs
+            .swap_in_staged(&self.config.image_uri)
+            .await?;
+        self.build().await
+    }
+
+    async fn drop_backup(&mut self) -> Result<()> {
+        self.upgrade_dirs.drop_backup().await?;
+        self.config_backup = None;
+        Ok(())
+    }
+
+    async fn rollback(&mut self) -> Result<()> {
+        self.upgrade_dirs.restore_backup().await?;
+        if let Some(mut backup) = self.config_backup.take() {
+            mem::swap(&mut backup, &mut self.config);
+            self.config_backup = Some(backup);
+        }
+        self.build().await
+    }
+
+    async fn recover(&mut self) -> Result<()> {
+        if self.is_runtime_running() && self.is_babel_running() {
+            return Ok(());
+        }
+        // babel is the main container process, so whole runtime is restarted
+        self.stop_runtime(true).await?;
+        self.start_runtime().await
+    }
+
+    fn node_env(&self) -> NodeEnv {
+        self.config.node_env.clone()
+    }
+
+    fn update_node_env(&mut self, node_state: &NodeState) {
+        node_env::update_state(&mut self.config.node_env, node_state);
+    }
+
+    fn plugin_path(&self) -> PathBuf {
+        self.chroot_dir.join(PLUGIN_PATH).join(PLUGIN_MAIN_FILENAME)
+    }
+
+    fn data_dir(&self) -> PathBuf {
+        self.data_dir.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rootfs_tarball() -> Result<()> {
+        let mut config = NspawnConfig::default();
+        assert_eq!(
+            PathBuf::from("/tmp/rootfs.tar.gz"),
+            rootfs_tarball(&config, "file:///tmp/rootfs.tar.gz")?
+        );
+        assert!(rootfs_tarball(&config, "docker://ubuntu:22.04").is_err());
+        config.rootfs_tarball = Some(PathBuf::from("/ci/rootfs.tar"));
+        assert_eq!(
+            PathBuf::from("/ci/rootfs.tar"),
+            rootfs_tarball(&config, "docker://ubuntu:22.04")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_env() {
+        assert_eq!(
+            vec![
+                ("NODE_ID".to_string(), "some-id".to_string()),
+                ("NODE_NAME".to_string(), "name=with=eq".to_string()),
+                ("RUST_LOG".to_string(), "info".to_string()),
+            ],
+            parse_env("NODE_ID=\"some-id\"\n\nNODE_NAME=\"name=with=eq\"\nRUST_LOG=info\n")
+        );
+    }
+}
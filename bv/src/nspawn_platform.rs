@@ -0,0 +1,167 @@
+use crate::{
+    apptainer_platform::BareNodeConnection,
+    bv_config,
+    bv_config::{NspawnConfig, SharedConfig},
+    bv_context::BvContext,
+    cpu_registry::CpuTopology,
+    linux_platform, node_context,
+    node_state::NodeState,
+    nodes_manager::NodesDataCache,
+    nspawn_machine,
+    pal::{AvailableResources, NodeFirewallConfig, Pal, VirtualMachine},
+    services,
+};
+use async_trait::async_trait;
+use eyre::Result;
+use std::{
+    ops::{Deref, DerefMut},
+    path::Path,
+};
+use uuid::Uuid;
+
+/// Platform running nodes with lightweight `nspawn_machine` runtime, on host network.
+#[derive(Debug)]
+pub struct NspawnPlatform {
+    base: linux_platform::LinuxPlatform,
+    config: NspawnConfig,
+}
+
+impl Deref for NspawnPlatform {
+    type Target = linux_platform::LinuxPlatform;
+
+    fn deref(&self) -> &Self::Target {
+        &self.base
+    }
+}
+
+impl DerefMut for NspawnPlatform {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.base
+    }
+}
+
+impl NspawnPlatform {
+    pub async fn new(config: &bv_config::Config) -> Result<Self> {
+        Ok(Self {
+            base: linux_platform::LinuxPlatform::new().await?,
+            config: config.nspawn.clone(),
+        })
+    }
+
+    async fn new_vm(
+        &self,
+        bv_context: &BvContext,
+        node_state: &NodeState,
+    ) -> Result<nspawn_machine::NspawnMachine> {
+        nspawn_machine::new(
+            &self.bv_root,
+            bv_context,
+            node_state,
+            self.babel_path.clone(),
+            self.config.clone(),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl Pal for NspawnPlatform {
+    fn bv_root(&self) -> &Path {
+        self.base.bv_root.as_path()
+    }
+
+    fn babel_path(&self) -> &Path {
+        self.base.babel_path.as_path()
+    }
+
+    fn job_runner_path(&self) -> &Path {
+        self.base.job_runner_path.as_path()
+    }
+
+    type CommandsStream = services::mqtt::MqttStream;
+    type CommandsStreamConnector = services::mqtt::MqttConnector;
+    fn create_commands_stream_connector(
+        &self,
+        config: &SharedConfig,
+    ) -> Self::CommandsStreamConnector {
+        services::mqtt::MqttConnector {
+            config: config.clone(),
+        }
+    }
+
+    type ApiServiceConnector = services::DefaultConnector;
+    fn create_api_service_connector(&self, config: &SharedConfig) -> Self::ApiServiceConnector {
+        services::DefaultConnector {
+            config: config.clone(),
+        }
+    }
+
+    type NodeConnection = BareNodeConnection;
+    fn create_node_connection(&self, node_id: Uuid) -> Self::NodeConnection {
+        BareNodeConnection::new(node_context::build_node_dir(self.bv_root(), node_id))
+    }
+
+    type VirtualMachine = nspawn_machine::NspawnMachine;
+
+    async fn create_vm(
+        &self,
+        bv_context: &BvContext,
+        node_state: &NodeState,
+    ) -> Result<Self::VirtualMachine> {
+        let mut vm = self.new_vm(bv_context, node_state).await?;
+        if let Err(err) = vm.build().await {
+            vm.delete().await?;
+            Err(err)
+        } else {
+            Ok(vm)
+        }
+    }
+
+    async fn attach_vm(
+        &self,
+        bv_context: &BvContext,
+        node_state: &NodeState,
+    ) -> Result<Self::VirtualMachine> {
+        let mut vm = self.new_vm(bv_context, node_state).await?;
+        vm.attach().await?;
+        Ok(vm)
+    }
+
+    async fn available_cpus(&self) -> usize {
+        linux_platform::available_cpus()
+    }
+
+    async fn cpu_topology(&self) -> CpuTopology {
+        linux_platform::cpu_topology()
+    }
+
+    async fn available_resources(
+        &self,
+        nodes_data_cache: NodesDataCache,
+    ) -> Result<AvailableResources> {
+        self.base
+            .available_resources(
+                nodes_data_cache.clone(),
+                self.used_disk_space_correction(nodes_data_cache).await?,
+            )
+            .await
+    }
+
+    async fn used_disk_space_correction(&self, nodes_data_cache: NodesDataCache) -> Result<u64> {
+        linux_platform::used_disk_space_correction(self.bv_root(), nodes_data_cache).await
+    }
+
+    type RecoveryBackoff = linux_platform::RecoveryBackoff;
+    fn create_recovery_backoff(&self) -> Self::RecoveryBackoff {
+        Default::default()
+    }
+
+    // all nodes share host network, so there are no node specific rules to apply
+    async fn apply_firewall_config(&self, _config: NodeFirewallConfig) -> Result<()> {
+        Ok(())
+    }
+
+    async fn cleanup_firewall_config(&self, _id: Uuid) -> Result<()> {
+        Ok(())
+    }
+}
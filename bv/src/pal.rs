@@ -4,14 +4,19 @@
 /// It defines `Pal` trait which is top level abstraction that contains definitions of sub layers.
 ///
 use crate::{
-    bv_config::SharedConfig, bv_context::BvContext, firewall, node_state::NodeState,
+    bv_config::SharedConfig, bv_context::BvContext, cpu_registry::CpuTopology,
+    disk_quota::DiskUsage, firewall, net_shaping::TrafficUsage, node_state::NodeState,
     nodes_manager::NodesDataCache, services,
 };
 use async_trait::async_trait;
 use babel_api::engine::NodeEnv;
 use eyre::Result;
 use std::path::PathBuf;
-use std::{fmt::Debug, net::IpAddr, path::Path};
+use std::{
+    fmt::Debug,
+    net::{IpAddr, Ipv6Addr},
+    path::Path,
+};
 use tonic::{codegen::InterceptedService, transport::Channel};
 use uuid::Uuid;
 
@@ -66,6 +71,11 @@ pub trait Pal {
 
     /// Get available cpus.
     async fn available_cpus(&self) -> usize;
+    /// Get host CPU topology, used for NUMA and SMT aware CPU allocation.
+    /// Defaults to flat topology of `available_cpus`.
+    async fn cpu_topology(&self) -> CpuTopology {
+        CpuTopology::flat(self.available_cpus().await)
+    }
     /// Get available resources, but take into account requirements declared by nodes.
     async fn available_resources(
         &self,
@@ -84,16 +94,40 @@ pub trait Pal {
     async fn apply_firewall_config(&self, config: NodeFirewallConfig) -> Result<()>;
     /// Cleanup node specific firewall rules.
     async fn cleanup_firewall_config(&self, id: Uuid) -> Result<()>;
+    /// Compare node specific firewall rules actually applied on the host with expected config.
+    /// Returns `None` if platform doesn't support such check.
+    async fn firewall_status(
+        &self,
+        _config: NodeFirewallConfig,
+    ) -> Result<Option<firewall::Status>> {
+        Ok(None)
+    }
+    /// Check if given IP address is already used by other host on network attached to `iface`.
+    /// Defaults to no detection.
+    async fn probe_ip(&self, _iface: &str, _ip: IpAddr) -> Result<bool> {
+        Ok(false)
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct NodeFirewallConfig {
     pub id: Uuid,
     pub ip: IpAddr,
+    /// Second node address, if host network is dual-stack.
+    pub ipv6: Option<Ipv6Addr>,
     pub bridge: Option<String>,
     pub config: firewall::Config,
 }
 
+impl NodeFirewallConfig {
+    /// All node addresses, rules are applied to each of them.
+    pub fn node_ips(&self) -> Vec<IpAddr> {
+        let mut ips = vec![self.ip];
+        ips.extend(self.ipv6.map(IpAddr::V6));
+        ips
+    }
+}
+
 #[derive(Debug)]
 pub struct AvailableResources {
     /// Virtual cores to share with VM.
@@ -151,6 +185,8 @@ pub enum VmState {
     RUNNING,
     /// Machine is in invalid state - not stopped, but not fully functioning.
     INVALID,
+    /// Machine processes are frozen, but not stopped.
+    SUSPENDED,
 }
 
 #[async_trait]
@@ -165,6 +201,17 @@ pub trait VirtualMachine {
     async fn force_shutdown(&mut self) -> Result<()>;
     /// Start the VM.
     async fn start(&mut self) -> Result<()>;
+    /// Freeze all VM processes, without stopping them.
+    async fn suspend(&mut self) -> Result<()>;
+    /// Thaw VM processes frozen by `suspend`.
+    async fn resume(&mut self) -> Result<()>;
+    /// Apply new resources (CPU and memory) limits to the VM, in place if it is running.
+    async fn resize(&mut self, node_state: &NodeState) -> Result<()>;
+    /// Prepare VM upgrade according to expected node_state (e.g. build new rootfs),
+    /// while VM is still running. Staged data are used by following `upgrade` call.
+    async fn stage_upgrade(&mut self, node_state: &NodeState) -> Result<()>;
+    /// Drop data staged for upgrade, but not used.
+    async fn drop_staged(&mut self) -> Result<()>;
     /// Upgrade VM according to expected node_state.
     async fn upgrade(&mut self, node_state: &NodeState) -> Result<()>;
     /// Drop VM backup saved during last upgrade.
@@ -181,6 +228,19 @@ pub trait VirtualMachine {
     fn plugin_path(&self) -> PathBuf;
     /// Get path to data directory.
     fn data_dir(&self) -> PathBuf;
+    /// Check if data directory content is accessible on the host, e.g. data drive of VM may be
+    /// attached to the guest while VM is running, so data directory is just empty mount point.
+    fn check_data_dir_access(&self) -> Result<()> {
+        Ok(())
+    }
+    /// Get data directory usage versus its quota, `None` if quota is not enforced.
+    async fn disk_usage(&self) -> Result<Option<DiskUsage>> {
+        Ok(None)
+    }
+    /// Get node network traffic counters, `None` if not available (e.g. on host network).
+    async fn traffic_usage(&self) -> Result<Option<TrafficUsage>> {
+        Ok(None)
+    }
 }
 
 pub trait RecoverBackoff {
@@ -14,6 +14,7 @@ fn style_node_status(cell: CellStruct, value: &VmStatus) -> CellStruct {
         VmStatus::Running => cell.foreground_color(Some(Green)),
         VmStatus::Stopped => cell.foreground_color(Some(Yellow)),
         VmStatus::Failed => cell.foreground_color(Some(Red)),
+        VmStatus::Suspended => cell.foreground_color(Some(Blue)),
     }
 }
 
@@ -31,6 +32,8 @@ pub struct PrettyTableRow {
     pub ip: String,
     #[table(title = "Uptime  [h:m:s]")]
     pub uptime: String,
+    #[table(title = "Rollout")]
+    pub rollout: String,
 }
 
 /// Converts into a [`cli_table::TableStruct`] table that could be displayed on command line
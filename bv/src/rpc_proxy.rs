@@ -0,0 +1,853 @@
+//! Optional TLS reverse proxy for node RPC, so it can be securely exposed as `https://<dns_name>`
+//! without running separate proxy (e.g. nginx) on the host.
+//!
+//! Requests are routed by TLS SNI (or `Host` header) to node IP and port declared by the image
+//! with `rpc_port` in plugin config. Routes are updated by `NodesManager` whenever node is created,
+//! started, upgraded, updated or deleted. Certificates are loaded from configured directory, and missing ones
+//! may be obtained with local ACME client (`certbot`), which HTTP-01 challenges are served on
+//! plain HTTP port.
+//!
+//! Only HTTP/1.1 (including WebSocket upgrades) is proxied, both to clients and nodes,
+//! so HTTP/2 only protocols (e.g. gRPC) can't be exposed this way.
+
+use crate::{node_state::NodeState, BV_VAR_PATH};
+use babel_api::plugin_config::PluginConfig;
+use bv_utils::{cmd::run_cmd, run_flag::RunFlag};
+use bytes::Bytes;
+use eyre::{anyhow, Context, Result};
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::{
+    body::Incoming,
+    header::{self, HeaderValue},
+    server::conn::http1,
+    service::service_fn,
+    Request, Response, StatusCode,
+};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::{TokioExecutor, TokioIo},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::{fs, net::TcpListener, time::sleep};
+use tokio_rustls::{
+    rustls::{
+        client::verify_server_name,
+        crypto::ring,
+        pki_types::ServerName,
+        server::{ClientHello, ParsedCertificate, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tracing::{debug, info, warn};
+
+const API_KEY_HEADER: &str = "x-api-key";
+const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+const ACME_DIR: &str = "acme";
+const WEBROOT_DIR: &str = "webroot";
+const CERTBOT_DIR: &str = "certbot";
+const CERT_FILE: &str = "fullchain.pem";
+const KEY_FILE: &str = "privkey.pem";
+const CERTS_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+/// Minimal time between attempts to obtain certificate for the same domain,
+/// so ACME server rate limits are not hit.
+const ACME_RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+const ACME_RENEW_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+type ProxyBody = BoxBody<Bytes, hyper::Error>;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Port on which node RPC is served over HTTPS.
+    #[serde(default = "default_https_port")]
+    pub https_port: u16,
+    /// Port on which plain HTTP requests are redirected to HTTPS (except ACME HTTP-01 challenges).
+    #[serde(default = "default_http_port")]
+    pub http_port: u16,
+    /// Directory with certificates, in `<certs_dir>/<domain>/{fullchain,privkey}.pem` layout
+    /// (e.g. certbot `live` directory). Defaults to directory where `acme` certificates are stored.
+    #[serde(default)]
+    pub certs_dir: Option<PathBuf>,
+    /// Obtain missing certificates with local ACME client (`certbot`). Disabled if not set.
+    #[serde(default)]
+    pub acme: Option<AcmeConfig>,
+    /// API keys allowed to access node RPC. Requests are not authenticated if empty.
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct AcmeConfig {
+    /// Contact email used for ACME account registration.
+    pub email: String,
+    /// Use staging ACME server, e.g. for testing.
+    #[serde(default)]
+    pub staging: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ApiKey {
+    /// Key expected in `x-api-key` request header.
+    pub key: String,
+    /// Max number of requests per second (across all nodes), not limited if not set.
+    #[serde(default)]
+    pub requests_per_sec: Option<u32>,
+}
+
+fn default_https_port() -> u16 {
+    443
+}
+
+fn default_http_port() -> u16 {
+    80
+}
+
+/// Shared table of routes from node `dns_name` to node RPC address.
+#[derive(Debug, Clone, Default)]
+pub struct Routes(Arc<RwLock<HashMap<String, SocketAddr>>>);
+
+impl Routes {
+    pub fn set(&self, routes: HashMap<String, SocketAddr>) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = routes;
+    }
+
+    pub fn get(&self, dns_name: &str) -> Option<SocketAddr> {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(dns_name)
+            .copied()
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .keys()
+            .cloned()
+            .collect()
+    }
+}
+
+/// RPC port declared by node image in plugin config. It is known only once plugin config
+/// is evaluated (and saved in `plugin_config_path`) on first node start.
+pub async fn declared_rpc_port(plugin_config_path: &Path) -> Option<u16> {
+    let content = fs::read_to_string(plugin_config_path).await.ok()?;
+    serde_json::from_str::<PluginConfig>(&content)
+        .ok()?
+        .rpc_port
+}
+
+/// Route to node RPC on `port` declared by its image, if node has `dns_name`.
+pub fn node_route(state: &NodeState, port: u16) -> Option<(String, SocketAddr)> {
+    if state.dns_name.is_empty() {
+        return None;
+    }
+    Some((
+        state.dns_name.to_lowercase(),
+        SocketAddr::new(state.ip, port),
+    ))
+}
+
+/// Run proxy until `run` flag is stopped.
+pub async fn run(bv_root: &Path, config: Config, routes: Routes, run: RunFlag) -> Result<()> {
+    let acme_dir = bv_root.join(BV_VAR_PATH).join(ACME_DIR);
+    let certs_dir = config
+        .certs_dir
+        .clone()
+        .unwrap_or_else(|| acme_dir.join(CERTBOT_DIR).join("live"));
+    let resolver = Arc::new(CertResolver::default());
+    let mut tls_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    // HTTP/2 is not supported (see module docs), so it is not advertised to clients
+    tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let https_listener = TcpListener::bind(("0.0.0.0", config.https_port))
+        .await
+        .with_context(|| format!("failed to bind RPC proxy to port {}", config.https_port))?;
+    let http_listener = TcpListener::bind(("0.0.0.0", config.http_port))
+        .await
+        .with_context(|| format!("failed to bind RPC proxy to port {}", config.http_port))?;
+    info!(
+        "RPC proxy listening on ports {} (HTTPS) and {} (HTTP)",
+        config.https_port, config.http_port
+    );
+    let acme = config
+        .acme
+        .clone()
+        .map(|acme_config| Acme::new(acme_config, &acme_dir));
+    let redirect_port = config.https_port;
+    let proxy = Arc::new(Proxy {
+        config,
+        routes: routes.clone(),
+        client: Client::builder(TokioExecutor::new()).build_http(),
+        limiter: Default::default(),
+    });
+    tokio::join!(
+        proxy.serve_https(
+            https_listener,
+            TlsAcceptor::from(Arc::new(tls_config)),
+            run.clone()
+        ),
+        serve_http(
+            http_listener,
+            Arc::new(acme_dir.join(WEBROOT_DIR)),
+            redirect_port,
+            run.clone()
+        ),
+        maintain_certs(certs_dir, routes, resolver, acme, run),
+    );
+    Ok(())
+}
+
+struct Proxy {
+    config: Config,
+    routes: Routes,
+    client: Client<HttpConnector, Incoming>,
+    limiter: RateLimiter,
+}
+
+impl Proxy {
+    async fn serve_https(
+        self: Arc<Self>,
+        listener: TcpListener,
+        acceptor: TlsAcceptor,
+        mut run: RunFlag,
+    ) {
+        while run.load() {
+            let Some(accepted) = run.select(listener.accept()).await else {
+                break;
+            };
+            let (stream, remote) = match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!("RPC proxy failed to accept connection: {err:#}");
+                    continue;
+                }
+            };
+            let proxy = self.clone();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        debug!("TLS handshake with {remote} failed: {err:#}");
+                        return;
+                    }
+                };
+                let sni = stream.get_ref().1.server_name().map(str::to_lowercase);
+                let service = service_fn(move |req| proxy.clone().handle(sni.clone(), remote, req));
+                if let Err(err) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .with_upgrades()
+                    .await
+                {
+                    debug!("RPC proxy connection with {remote} failed: {err:#}");
+                }
+            });
+        }
+    }
+
+    async fn handle(
+        self: Arc<Self>,
+        sni: Option<String>,
+        remote: SocketAddr,
+        mut req: Request<Incoming>,
+    ) -> Result<Response<ProxyBody>, Infallible> {
+        let host = sni.or_else(|| request_host(&req));
+        let Some(target) = host.as_deref().and_then(|host| self.routes.get(host)) else {
+            return Ok(text_response(StatusCode::NOT_FOUND, "unknown node"));
+        };
+        if let Err(response) = self.authorize(&req, Instant::now()) {
+            return Ok(response);
+        }
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/");
+        let Ok(uri) = format!("http://{target}{path}").parse() else {
+            return Ok(text_response(
+                StatusCode::BAD_REQUEST,
+                "invalid request uri",
+            ));
+        };
+        *req.uri_mut() = uri;
+        let headers = req.headers_mut();
+        headers.remove(API_KEY_HEADER);
+        if let Ok(value) = HeaderValue::from_str(&remote.ip().to_string()) {
+            headers.insert("x-forwarded-for", value);
+        }
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+
+        let client_upgrade = hyper::upgrade::on(&mut req);
+        match self.client.request(req).await {
+            Ok(mut response) => {
+                if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+                    // e.g. websocket connection, just pipe data in both directions from now on
+                    let node_upgrade = hyper::upgrade::on(&mut response);
+                    tokio::spawn(async move {
+                        match tokio::try_join!(client_upgrade, node_upgrade) {
+                            Ok((client, node)) => {
+                                let _ = tokio::io::copy_bidirectional(
+                                    &mut TokioIo::new(client),
+                                    &mut TokioIo::new(node),
+                                )
+                                .await;
+                            }
+                            Err(err) => debug!("failed to upgrade RPC proxy connection: {err:#}"),
+                        }
+                    });
+                }
+                Ok(response.map(|body| body.boxed()))
+            }
+            Err(err) => {
+                warn!("RPC proxy failed to forward request to {target}: {err:#}");
+                Ok(text_response(
+                    StatusCode::BAD_GATEWAY,
+                    "node RPC is not available",
+                ))
+            }
+        }
+    }
+
+    /// Check API key and its rate limit, returns error response if request is not allowed.
+    #[allow(clippy::result_large_err)]
+    fn authorize<B>(&self, req: &Request<B>, now: Instant) -> Result<(), Response<ProxyBody>> {
+        if self.config.api_keys.is_empty() {
+            return Ok(());
+        }
+        let Some(key) = req
+            .headers()
+            .get(API_KEY_HEADER)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return Err(text_response(StatusCode::UNAUTHORIZED, "missing API key"));
+        };
+        let Some(api_key) = self
+            .config
+            .api_keys
+            .iter()
+            .find(|api_key| constant_time_eq(api_key.key.as_bytes(), key.as_bytes()))
+        else {
+            return Err(text_response(StatusCode::UNAUTHORIZED, "invalid API key"));
+        };
+        if let Some(limit) = api_key.requests_per_sec {
+            if !self.limiter.allow(&api_key.key, limit, now) {
+                return Err(text_response(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "rate limit exceeded",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compare secrets in time that doesn't depend on their content, so key can't be guessed
+/// byte by byte with timing attack.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+async fn serve_http(
+    listener: TcpListener,
+    webroot: Arc<PathBuf>,
+    https_port: u16,
+    mut run: RunFlag,
+) {
+    while run.load() {
+        let Some(accepted) = run.select(listener.accept()).await else {
+            break;
+        };
+        let (stream, remote) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("RPC proxy failed to accept connection: {err:#}");
+                continue;
+            }
+        };
+        let webroot = webroot.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| handle_http(webroot.clone(), https_port, req));
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!("RPC proxy connection with {remote} failed: {err:#}");
+            }
+        });
+    }
+}
+
+/// Serve ACME HTTP-01 challenges from webroot and redirect anything else to HTTPS.
+async fn handle_http(
+    webroot: Arc<PathBuf>,
+    https_port: u16,
+    req: Request<Incoming>,
+) -> Result<Response<ProxyBody>, Infallible> {
+    if let Some(token) = req.uri().path().strip_prefix(ACME_CHALLENGE_PATH) {
+        // tokens are base64url encoded, so anything else can't be valid challenge
+        if token.is_empty()
+            || !token
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Ok(text_response(StatusCode::NOT_FOUND, "not found"));
+        }
+        let path = webroot.join(&ACME_CHALLENGE_PATH[1..]).join(token);
+        return Ok(match fs::read(path).await {
+            Ok(content) => Response::new(full_body(content)),
+            Err(_) => text_response(StatusCode::NOT_FOUND, "not found"),
+        });
+    }
+    let Some(host) = request_host(&req) else {
+        return Ok(text_response(StatusCode::BAD_REQUEST, "missing host"));
+    };
+    let port = if https_port == default_https_port() {
+        String::new()
+    } else {
+        format!(":{https_port}")
+    };
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let mut response = text_response(StatusCode::PERMANENT_REDIRECT, "");
+    if let Ok(location) = HeaderValue::from_str(&format!("https://{host}{port}{path}")) {
+        response.headers_mut().insert(header::LOCATION, location);
+    }
+    Ok(response)
+}
+
+fn request_host<B>(req: &Request<B>) -> Option<String> {
+    let host = req.headers().get(header::HOST)?.to_str().ok()?;
+    host.split(':')
+        .next()
+        .filter(|host| !host.is_empty())
+        .map(str::to_lowercase)
+}
+
+fn full_body(content: impl Into<Bytes>) -> ProxyBody {
+    Full::new(content.into())
+        .map_err(|never| match never {})
+        .boxed()
+}
+
+fn text_response(status: StatusCode, message: &'static str) -> Response<ProxyBody> {
+    let mut response = Response::new(full_body(message));
+    *response.status_mut() = status;
+    response
+}
+
+/// Token bucket per API key, allowing bursts of up to one second worth of requests.
+#[derive(Debug, Default)]
+struct RateLimiter(Mutex<HashMap<String, (f64, Instant)>>);
+
+impl RateLimiter {
+    fn allow(&self, key: &str, requests_per_sec: u32, now: Instant) -> bool {
+        let rate = f64::from(requests_per_sec);
+        let mut buckets = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let (tokens, last) = buckets.entry(key.to_string()).or_insert((rate, now));
+        *tokens = (*tokens + now.saturating_duration_since(*last).as_secs_f64() * rate).min(rate);
+        *last = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Select certificate by SNI, from periodically reloaded set.
+#[derive(Debug, Default)]
+struct CertResolver(RwLock<HashMap<String, Arc<CertifiedKey>>>);
+
+impl CertResolver {
+    fn set(&self, certs: HashMap<String, Arc<CertifiedKey>>) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = certs;
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let name = client_hello.server_name()?.to_lowercase();
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&name)
+            .cloned()
+    }
+}
+
+async fn maintain_certs(
+    certs_dir: PathBuf,
+    routes: Routes,
+    resolver: Arc<CertResolver>,
+    mut acme: Option<Acme>,
+    mut run: RunFlag,
+) {
+    while run.load() {
+        let names = routes.names();
+        if let Some(acme) = &mut acme {
+            acme.obtain_missing(&certs_dir, &names).await;
+        }
+        resolver.set(load_certs(&certs_dir, &names).await);
+        run.select(sleep(CERTS_RELOAD_INTERVAL)).await;
+    }
+}
+
+/// Load certificates for given domains.
+async fn load_certs(certs_dir: &Path, names: &[String]) -> HashMap<String, Arc<CertifiedKey>> {
+    let mut certs = HashMap::new();
+    for name in names {
+        if let Some(cert) = find_cert(certs_dir, name).await {
+            certs.insert(name.clone(), Arc::new(cert));
+        } else {
+            debug!("No certificate found for `{name}`");
+        }
+    }
+    certs
+}
+
+/// Find certificate valid for given domain. Certificate is looked up in `<certs_dir>/<domain>/`
+/// first, and then in `<certs_dir>/<parent_domain>/`. Certificate from parent domain dir is used
+/// only if it is valid for the domain (i.e. it is a wildcard certificate), otherwise TLS handshake
+/// would fail anyway.
+async fn find_cert(certs_dir: &Path, name: &str) -> Option<CertifiedKey> {
+    for dir_name in cert_dir_names(name) {
+        let dir = certs_dir.join(dir_name);
+        if !dir.join(CERT_FILE).exists() {
+            continue;
+        }
+        match load_cert(&dir).await {
+            Ok(cert) => match check_cert_name(&cert, name) {
+                Ok(()) => return Some(cert),
+                Err(err) => debug!(
+                    "certificate in '{}' is not valid for `{name}`: {err:#}",
+                    dir.display()
+                ),
+            },
+            Err(err) => warn!(
+                "failed to load certificate for `{name}` from '{}': {err:#}",
+                dir.display()
+            ),
+        }
+    }
+    None
+}
+
+async fn load_cert(dir: &Path) -> Result<CertifiedKey> {
+    let certs_pem = fs::read(dir.join(CERT_FILE)).await?;
+    let key_pem = fs::read(dir.join(KEY_FILE)).await?;
+    let certs = rustls_pemfile::certs(&mut certs_pem.as_slice()).collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut key_pem.as_slice())?
+        .ok_or_else(|| anyhow!("no private key found"))?;
+    Ok(CertifiedKey::new(
+        certs,
+        ring::sign::any_supported_type(&key)?,
+    ))
+}
+
+fn check_cert_name(cert: &CertifiedKey, name: &str) -> Result<()> {
+    let end_entity = cert.end_entity_cert()?;
+    verify_server_name(
+        &ParsedCertificate::try_from(end_entity)?,
+        &ServerName::try_from(name)?,
+    )?;
+    Ok(())
+}
+
+fn cert_dir_names(name: &str) -> Vec<&str> {
+    let mut names = vec![name];
+    if let Some((_, parent)) = name.split_once('.') {
+        // don't look for certificates of top level domains
+        if parent.contains('.') {
+            names.push(parent);
+        }
+    }
+    names
+}
+
+/// Local ACME client, obtaining and renewing certificates with `certbot`.
+struct Acme {
+    config: AcmeConfig,
+    webroot: PathBuf,
+    certbot_dir: PathBuf,
+    attempts: HashMap<String, Instant>,
+    last_renew: Option<Instant>,
+}
+
+impl Acme {
+    fn new(config: AcmeConfig, acme_dir: &Path) -> Self {
+        Self {
+            config,
+            webroot: acme_dir.join(WEBROOT_DIR),
+            certbot_dir: acme_dir.join(CERTBOT_DIR),
+            attempts: Default::default(),
+            last_renew: None,
+        }
+    }
+
+    async fn obtain_missing(&mut self, certs_dir: &Path, names: &[String]) {
+        for name in names {
+            if find_cert(certs_dir, name).await.is_some()
+                || self
+                    .attempts
+                    .get(name)
+                    .is_some_and(|at| at.elapsed() < ACME_RETRY_INTERVAL)
+            {
+                continue;
+            }
+            self.attempts.insert(name.clone(), Instant::now());
+            info!("Obtaining certificate for `{name}`");
+            if let Err(err) = run_cmd("certbot", self.certonly_args(name)).await {
+                warn!("failed to obtain certificate for `{name}`: {err:#}");
+            }
+        }
+        if !matches!(self.last_renew, Some(at) if at.elapsed() < ACME_RENEW_INTERVAL) {
+            self.last_renew = Some(Instant::now());
+            if let Err(err) = run_cmd("certbot", self.certbot_args("renew")).await {
+                warn!("failed to renew certificates: {err:#}");
+            }
+        }
+    }
+
+    fn certbot_args(&self, cmd: &str) -> Vec<String> {
+        let dir = self.certbot_dir.display();
+        let mut args = vec![
+            cmd.to_string(),
+            "--non-interactive".to_string(),
+            format!("--config-dir={dir}"),
+            format!("--work-dir={dir}/work"),
+            format!("--logs-dir={dir}/logs"),
+        ];
+        if self.config.staging {
+            args.push("--staging".to_string());
+        }
+        args
+    }
+
+    fn certonly_args(&self, name: &str) -> Vec<String> {
+        let mut args = self.certbot_args("certonly");
+        args.extend([
+            "--agree-tos".to_string(),
+            format!("--email={}", self.config.email),
+            "--webroot".to_string(),
+            format!("--webroot-path={}", self.webroot.display()),
+            format!("--cert-name={name}"),
+            format!("--domains={name}"),
+        ]);
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::tests::default_node_state;
+    use assert_fs::TempDir;
+
+    fn test_proxy(api_keys: Vec<ApiKey>) -> Proxy {
+        Proxy {
+            config: Config {
+                https_port: default_https_port(),
+                http_port: default_http_port(),
+                certs_dir: None,
+                acme: None,
+                api_keys,
+            },
+            routes: Default::default(),
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            limiter: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_node_route() {
+        let mut state = default_node_state();
+        assert_eq!(
+            Some(("dns.name".to_string(), SocketAddr::new(state.ip, 8545))),
+            node_route(&state, 8545)
+        );
+        state.dns_name = "Node.Example.com".to_string();
+        assert_eq!(
+            Some((
+                "node.example.com".to_string(),
+                SocketAddr::new(state.ip, 8545)
+            )),
+            node_route(&state, 8545)
+        );
+        state.dns_name = String::new();
+        assert_eq!(None, node_route(&state, 8545));
+    }
+
+    #[tokio::test]
+    async fn test_declared_rpc_port() -> Result<()> {
+        let tmp_root = TempDir::new()?.to_path_buf();
+        let path = tmp_root.join("plugin_config.json");
+        // plugin config not evaluated yet
+        assert_eq!(None, declared_rpc_port(&path).await);
+        let mut plugin_config = PluginConfig::default();
+        fs::write(&path, serde_json::to_string(&plugin_config)?).await?;
+        assert_eq!(None, declared_rpc_port(&path).await);
+        plugin_config.rpc_port = Some(8545);
+        fs::write(&path, serde_json::to_string(&plugin_config)?).await?;
+        assert_eq!(Some(8545), declared_rpc_port(&path).await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_cert() -> Result<()> {
+        let test_certs = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/certs");
+        let certs_dir = TempDir::new()?.to_path_buf();
+        let install = |cert: &'static str, dir_name: &'static str| {
+            let dir = certs_dir.join(dir_name);
+            let test_certs = test_certs.join(cert);
+            async move {
+                fs::create_dir_all(&dir).await?;
+                fs::copy(test_certs.join(CERT_FILE), dir.join(CERT_FILE)).await?;
+                fs::copy(test_certs.join(KEY_FILE), dir.join(KEY_FILE)).await?;
+                Ok::<_, eyre::Report>(())
+            }
+        };
+        assert!(find_cert(&certs_dir, "node.example.com").await.is_none());
+
+        // certificate of parent domain, that is not a wildcard one, can't be used
+        install("example.com", "example.com").await?;
+        assert!(find_cert(&certs_dir, "example.com").await.is_some());
+        assert!(find_cert(&certs_dir, "node.example.com").await.is_none());
+
+        install("wildcard.example.com", "example.com").await?;
+        assert!(find_cert(&certs_dir, "node.example.com").await.is_some());
+        assert!(find_cert(&certs_dir, "example.com").await.is_none());
+        assert!(find_cert(&certs_dir, "other.domain.com").await.is_none());
+
+        // invalid certificate in exact domain dir is skipped
+        install("example.com", "node.example.com").await?;
+        assert!(find_cert(&certs_dir, "node.example.com").await.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_authorize() {
+        let request = |key: Option<&str>| {
+            let mut builder = Request::builder().uri("/");
+            if let Some(key) = key {
+                builder = builder.header(API_KEY_HEADER, key);
+            }
+            builder.body(()).unwrap()
+        };
+        let now = Instant::now();
+        assert!(test_proxy(vec![]).authorize(&request(None), now).is_ok());
+
+        let proxy = test_proxy(vec![
+            ApiKey {
+                key: "limited".to_string(),
+                requests_per_sec: Some(2),
+            },
+            ApiKey {
+                key: "unlimited".to_string(),
+                requests_per_sec: None,
+            },
+        ]);
+        let status = |key, now| {
+            proxy
+                .authorize(&request(key), now)
+                .err()
+                .map(|response| response.status())
+        };
+        assert_eq!(Some(StatusCode::UNAUTHORIZED), status(None, now));
+        assert_eq!(Some(StatusCode::UNAUTHORIZED), status(Some("other"), now));
+        assert_eq!(Some(StatusCode::UNAUTHORIZED), status(Some("limite"), now));
+        assert_eq!(
+            Some(StatusCode::UNAUTHORIZED),
+            status(Some("limitedx"), now)
+        );
+        assert_eq!(None, status(Some("limited"), now));
+        assert_eq!(None, status(Some("limited"), now));
+        assert_eq!(
+            Some(StatusCode::TOO_MANY_REQUESTS),
+            status(Some("limited"), now)
+        );
+        for _ in 0..10 {
+            assert_eq!(None, status(Some("unlimited"), now));
+        }
+        // bucket is refilled with time
+        let later = now + Duration::from_millis(500);
+        assert_eq!(None, status(Some("limited"), later));
+        assert_eq!(
+            Some(StatusCode::TOO_MANY_REQUESTS),
+            status(Some("limited"), later)
+        );
+    }
+
+    #[test]
+    fn test_request_host() {
+        let request = |host: &str| {
+            Request::builder()
+                .uri("/")
+                .header(header::HOST, host)
+                .body(())
+                .unwrap()
+        };
+        assert_eq!(
+            Some("node.example.com".to_string()),
+            request_host(&request("Node.Example.com:443"))
+        );
+        assert_eq!(
+            Some("node.example.com".to_string()),
+            request_host(&request("node.example.com"))
+        );
+        assert_eq!(None, request_host(&Request::builder().body(()).unwrap()));
+    }
+
+    #[test]
+    fn test_cert_dir_names() {
+        assert_eq!(
+            vec!["node.n0des.example.com", "n0des.example.com"],
+            cert_dir_names("node.n0des.example.com")
+        );
+        assert_eq!(vec!["example.com"], cert_dir_names("example.com"));
+    }
+
+    #[test]
+    fn test_certbot_args() {
+        let acme = Acme::new(
+            AcmeConfig {
+                email: "admin@example.com".to_string(),
+                staging: true,
+            },
+            Path::new("/acme"),
+        );
+        assert_eq!(
+            vec![
+                "certonly",
+                "--non-interactive",
+                "--config-dir=/acme/certbot",
+                "--work-dir=/acme/certbot/work",
+                "--logs-dir=/acme/certbot/logs",
+                "--staging",
+                "--agree-tos",
+                "--email=admin@example.com",
+                "--webroot",
+                "--webroot-path=/acme/webroot",
+                "--cert-name=node.example.com",
+                "--domains=node.example.com",
+            ],
+            acme.certonly_args("node.example.com")
+        );
+    }
+}
@@ -12,7 +12,7 @@ use crate::{
 };
 use babel_api::utils::RamdiskConfiguration;
 use eyre::{anyhow, bail, Context, Result};
-use metrics::{counter, Counter};
+use metrics::{counter, histogram, Counter};
 use pb::{
     archive_service_client, command_service_client, discovery_service_client, image_service_client,
     metrics_service_client, node_command::Command, node_service_client, protocol_service_client,
@@ -62,6 +62,9 @@ lazy_static::lazy_static! {
     pub static ref API_UPDATE_TIME_MS_COUNTER: Counter = counter!("api.commands.update.ms");
 }
 
+/// Histogram of API commands processing time, labeled with command type and result.
+pub const COMMAND_DURATION_HISTOGRAM: &str = "api.commands.duration_seconds";
+
 pub type ProtocolServiceClient =
     protocol_service_client::ProtocolServiceClient<AuthenticatedService>;
 pub type ArchiveServiceClient = archive_service_client::ArchiveServiceClient<AuthenticatedService>;
@@ -207,14 +210,21 @@ impl CommandsService<'_> {
                 match command.command {
                     Some(pb::command::Command::Node(node_command)) => {
                         let node_id = node_command.node_id.clone();
-                        self.handle_command_result(
-                            command_id,
-                            process_node_command(nodes_manager.clone(), node_command).await,
+                        let command_type = node_command_type(&node_command);
+                        let started = Instant::now();
+                        let result =
+                            process_node_command(nodes_manager.clone(), node_command).await;
+                        histogram!(
+                            COMMAND_DURATION_HISTOGRAM,
+                            "command" => command_type,
+                            "result" => if result.is_ok() { "ok" } else { "error" }
                         )
-                        .await
-                        .with_context(|| {
-                            format!("node '{node_id}' command '{command_id}' failed")
-                        })?;
+                        .record(started.elapsed().as_secs_f64());
+                        self.handle_command_result(command_id, result)
+                            .await
+                            .with_context(|| {
+                                format!("node '{node_id}' command '{command_id}' failed")
+                            })?;
                     }
                     Some(pb::command::Command::Host(host_command)) => {
                         self.handle_command_result(command_id, process_host_command(host_command))
@@ -268,6 +278,9 @@ impl CommandsService<'_> {
                         Error::NodeUpgradeFailure(_, _) => {
                             pb::CommandExitCode::NodeUpgradeFailure.into()
                         }
+                        Error::ImageVerificationFailed(_) => {
+                            pb::CommandExitCode::InternalError.into()
+                        }
                     }),
                     exit_message: Some(format!("{err:#}")),
                     retry_hint_seconds: None,
@@ -309,6 +322,19 @@ impl CommandsService<'_> {
     }
 }
 
+fn node_command_type(node_command: &pb::NodeCommand) -> &'static str {
+    match node_command.command {
+        Some(Command::Create(_)) => "create",
+        Some(Command::Delete(_)) => "delete",
+        Some(Command::Start(_)) => "start",
+        Some(Command::Stop(_)) => "stop",
+        Some(Command::Restart(_)) => "restart",
+        Some(Command::Upgrade(_)) => "upgrade",
+        Some(Command::Update(_)) => "update",
+        None => "none",
+    }
+}
+
 async fn process_node_command<P>(
     nodes_manager: Arc<NodesManager<P>>,
     node_command: pb::NodeCommand,
@@ -403,6 +429,7 @@ impl TryFrom<common::FirewallConfig> for firewall::Config {
             default_in,
             default_out,
             rules,
+            ip_sets: Default::default(),
         })
     }
 }
@@ -449,16 +476,26 @@ impl TryFrom<common::FirewallProtocol> for firewall::Protocol {
 impl TryFrom<common::FirewallRule> for firewall::Rule {
     type Error = eyre::Error;
     fn try_from(rule: common::FirewallRule) -> Result<Self, Self::Error> {
-        let action = rule.action().try_into()?;
+        let mut action = rule.action().try_into()?;
         let direction = rule.direction().try_into()?;
         let protocol = Some(rule.protocol().try_into()?);
+        // API has no notion of ip sets (expanded into `ips` on push), while `limit` action,
+        // port ranges and logging are carried in rule description
+        let extensions = firewall::ApiRuleExtensions::decode(rule.description.as_deref())
+            .with_context(|| format!("invalid firewall rule `{}`", rule.key))?;
+        if extensions.limit && action == firewall::Action::Allow {
+            action = firewall::Action::Limit;
+        }
         Ok(Self {
             name: rule.key,
             action,
             direction,
             protocol,
             ips: rule.ips.into_iter().map(|ip| ip.ip).collect(),
+            ip_sets: vec![],
             ports: rule.ports.into_iter().map(|p| p.port as u16).collect(),
+            port_ranges: extensions.port_ranges,
+            log: extensions.log,
         })
     }
 }
@@ -477,6 +514,7 @@ impl From<common::VmConfig> for node_state::VmConfig {
                     ram_disk_size_mb: ramdisk.size_bytes / 1_000_000,
                 })
                 .collect(),
+            bandwidth: Default::default(),
         }
     }
 }
@@ -553,6 +591,8 @@ impl TryFrom<pb::Node> for NodeState {
                 .ip_gateway
                 .parse()
                 .with_context(|| format!("invalid gateway `{}`", node.ip_gateway))?,
+            // API doesn't manage IPv6 addresses, it is assigned from host pool
+            ipv6: None,
             firewall,
             properties,
             protocol_id: node.protocol_id,
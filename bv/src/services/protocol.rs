@@ -1,6 +1,6 @@
 use crate::nib_meta::StorePointer;
 use crate::{
-    api_with_retry, nib,
+    api_with_retry, firewall, nib,
     nib_meta::{self, UiType},
     node_state::ProtocolImageKey,
     services::{
@@ -12,11 +12,11 @@ use crate::{
     },
 };
 use core::fmt;
-use eyre::{anyhow, Context, Result};
+use eyre::{anyhow, bail, Context, Result};
 use serde::{Deserialize, Serialize};
 use std::fmt::Formatter;
 use tonic::transport::Channel;
-use tracing::instrument;
+use tracing::{instrument, warn};
 
 #[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
 pub enum NodeType {
@@ -264,7 +264,13 @@ impl<C: ApiServiceConnector + Clone> ProtocolService<C> {
         min_babel_version: String,
     ) -> Result<PushResult<pb::Image>> {
         let mut client = self.connect_image_service().await?;
-        let mut firewall: common::FirewallConfig = image.firewall_config.into();
+        if image.bandwidth.is_some() {
+            warn!(
+                "bandwidth limits of `{}` are not supported by API, nodes created by API use host defaults",
+                image.variant_key
+            );
+        }
+        let mut firewall: common::FirewallConfig = image.firewall_config.try_into()?;
         firewall.rules.sort_by(|a, b| a.key.cmp(&b.key));
         let req = pb::ImageServiceAddImageRequest {
             protocol_version_id,
@@ -389,13 +395,17 @@ impl From<ProtocolImageKey> for common::ProtocolVersionKey {
     }
 }
 
-impl From<nib_meta::Action> for common::FirewallAction {
-    fn from(value: nib_meta::Action) -> Self {
-        match value {
+impl TryFrom<nib_meta::Action> for common::FirewallAction {
+    type Error = eyre::Error;
+    fn try_from(value: nib_meta::Action) -> Result<Self, Self::Error> {
+        Ok(match value {
             nib_meta::Action::Allow => common::FirewallAction::Allow,
             nib_meta::Action::Deny => common::FirewallAction::Drop,
             nib_meta::Action::Reject => common::FirewallAction::Reject,
-        }
+            nib_meta::Action::Limit => {
+                bail!("`limit` firewall action is supported by API only in rules")
+            }
+        })
     }
 }
 
@@ -418,13 +428,34 @@ impl From<nib_meta::Direction> for common::FirewallDirection {
     }
 }
 
-impl From<nib_meta::FirewallRule> for common::FirewallRule {
-    fn from(value: nib_meta::FirewallRule) -> Self {
-        let action: common::FirewallAction = value.action.into();
+impl TryFrom<nib_meta::FirewallRule> for common::FirewallRule {
+    type Error = eyre::Error;
+    fn try_from(value: nib_meta::FirewallRule) -> Result<Self, Self::Error> {
+        if !value.ip_sets.is_empty() {
+            bail!("unresolved ip sets in firewall rule `{}`", value.key);
+        }
+        // API has no fields for these, so they are carried in description
+        let extensions = firewall::ApiRuleExtensions {
+            limit: value.action == nib_meta::Action::Limit,
+            log: value.log,
+            port_ranges: value
+                .port_ranges
+                .iter()
+                .map(|range| firewall::PortRange {
+                    from: range.from,
+                    to: range.to,
+                })
+                .collect(),
+        };
+        let action: common::FirewallAction = match value.action {
+            // limit allows packets, so it is the closest API action
+            nib_meta::Action::Limit => common::FirewallAction::Allow,
+            action => action.try_into()?,
+        };
         let direction: common::FirewallDirection = value.direction.into();
         let protocol: common::FirewallProtocol = value.protocol.into();
 
-        Self {
+        Ok(Self {
             key: value.key,
             action: action.into(),
             direction: direction.into(),
@@ -445,20 +476,34 @@ impl From<nib_meta::FirewallRule> for common::FirewallRule {
                     name: port.name,
                 })
                 .collect(),
-            description: value.description,
-        }
+            description: extensions.encode(value.description),
+        })
     }
 }
 
-impl From<nib_meta::FirewallConfig> for common::FirewallConfig {
-    fn from(value: nib_meta::FirewallConfig) -> Self {
-        let default_in: common::FirewallAction = value.default_in.into();
-        let default_out: common::FirewallAction = value.default_out.into();
-        Self {
+impl TryFrom<nib_meta::FirewallConfig> for common::FirewallConfig {
+    type Error = eyre::Error;
+    fn try_from(value: nib_meta::FirewallConfig) -> Result<Self, Self::Error> {
+        let default_in: common::FirewallAction = value.default_in.try_into()?;
+        let default_out: common::FirewallAction = value.default_out.try_into()?;
+        // API has no notion of ip sets, so these are expanded into rule ips
+        let mut rules = value.rules;
+        for rule in &mut rules {
+            for ip_set in std::mem::take(&mut rule.ip_sets) {
+                let Some(set) = value.ip_sets.iter().find(|set| set.key == ip_set) else {
+                    bail!("unknown ip set `{ip_set}` in firewall rule `{}`", rule.key);
+                };
+                rule.ips.extend(set.ips.iter().cloned());
+            }
+        }
+        Ok(Self {
             default_in: default_in.into(),
             default_out: default_out.into(),
-            rules: value.rules.into_iter().map(|rule| rule.into()).collect(),
-        }
+            rules: rules
+                .into_iter()
+                .map(|rule| rule.try_into())
+                .collect::<Result<Vec<_>>>()?,
+        })
     }
 }
 
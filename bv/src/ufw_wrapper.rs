@@ -1,4 +1,4 @@
-use crate::firewall::{Direction, Protocol, Rule};
+use crate::firewall::{self, Direction, Protocol, Rule};
 use crate::pal::NodeFirewallConfig;
 use async_trait::async_trait;
 use eyre::{bail, Result};
@@ -13,6 +13,10 @@ pub async fn cleanup_node_rules(node_id: Uuid) -> Result<()> {
     cleanup_node_rules_with(node_id, &SysRunner).await
 }
 
+pub async fn node_rules_status(config: NodeFirewallConfig) -> Result<firewall::Status> {
+    node_rules_status_with(config, &SysRunner).await
+}
+
 #[async_trait]
 trait UfwRunner {
     async fn run<'a>(&self, args: &[&'a str]) -> Result<String>;
@@ -80,6 +84,36 @@ async fn cleanup_node_rules_with(node_id: Uuid, runner: &impl UfwRunner) -> Resu
     Ok(())
 }
 
+async fn node_rules_status_with(
+    config: NodeFirewallConfig,
+    runner: &impl UfwRunner,
+) -> Result<firewall::Status> {
+    let id = config.id.to_string();
+    let iface = config.bridge.clone();
+    let rule_args = RuleArgs::from_rules(config);
+    let expected = rule_args
+        .iter()
+        .map(|args| args.as_vec(&iface).join(" "))
+        .collect();
+    let expected_keys = rule_args.iter().map(|args| args.status_key()).collect();
+    let stdout = runner.run(&["status", "numbered"]).await?;
+    let actual = stdout
+        .lines()
+        .filter(|line| line.contains(&id))
+        .map(|line| line.trim().to_string())
+        .collect::<Vec<_>>();
+    let actual_keys = actual
+        .iter()
+        .filter_map(|line| status_line_key(line))
+        .collect();
+    Ok(firewall::Status {
+        backend: firewall::Backend::Ufw,
+        drift: firewall::is_drift(expected_keys, actual_keys),
+        expected,
+        actual,
+    })
+}
+
 struct RuleArgs {
     policy: String,
     direction: Direction,
@@ -87,12 +121,21 @@ struct RuleArgs {
     ip_from: String,
     ip_to: String,
     port: Option<String>, // no port means - no port argument passed at all i.e. rule apply for all ports
+    log: bool,
     name: String,
 }
 
 impl RuleArgs {
     fn from_rules(config: NodeFirewallConfig) -> Vec<Self> {
         let mut rule_args = Vec::default();
+        for node_ip in config.node_ips() {
+            Self::from_node_ip(&config, node_ip.to_string(), &mut rule_args);
+        }
+        rule_args
+    }
+
+    /// Rules for one of node addresses, only remote ips of the same family are used.
+    fn from_node_ip(config: &NodeFirewallConfig, node_ip: String, rule_args: &mut Vec<RuleArgs>) {
         for rule in config.config.rules.iter().rev() {
             let proto = rule.protocol.as_ref().and_then(|proto| {
                 if &Protocol::Both != proto {
@@ -102,22 +145,27 @@ impl RuleArgs {
                 }
             });
 
-            if rule.ips.is_empty() {
+            let all_ips = config.config.rule_ips(rule);
+            let ips = firewall::family_ips(&all_ips, &node_ip);
+            if all_ips.is_empty() {
                 Self::from_rule(
-                    &config,
+                    config,
+                    &node_ip,
                     rule.clone(),
                     proto,
                     "any".to_string(),
-                    &mut rule_args,
+                    rule_args,
                 );
             } else {
-                for ip in &rule.ips {
+                // rule doesn't apply to this address if it has no ips of the same family
+                for ip in &ips {
                     Self::from_rule(
-                        &config,
+                        config,
+                        &node_ip,
                         rule.clone(),
                         proto.clone(),
                         ip.clone(),
-                        &mut rule_args,
+                        rule_args,
                     );
                 }
             }
@@ -127,24 +175,26 @@ impl RuleArgs {
             direction: Direction::In,
             protocol: None,
             ip_from: "any".to_string(),
-            ip_to: config.ip.to_string(),
+            ip_to: node_ip.clone(),
             port: None,
+            log: false,
             name: format!("{} default in", config.id),
         });
         rule_args.push(Self {
             policy: variant_to_string(&config.config.default_out),
             direction: Direction::Out,
             protocol: None,
-            ip_from: config.ip.to_string(),
+            ip_from: node_ip,
             ip_to: "any".to_string(),
             port: None,
+            log: false,
             name: format!("{} default out", config.id),
         });
-        rule_args
     }
 
     fn from_rule(
         config: &NodeFirewallConfig,
+        node_ip: &str,
         rule: Rule,
         proto: Option<String>,
         ip: String,
@@ -152,11 +202,11 @@ impl RuleArgs {
     ) {
         let name = format!("{} {}", config.id, rule.name);
         let (ip_from, ip_to) = match rule.direction {
-            Direction::Out => (config.ip.to_string(), ip),
-            Direction::In => (ip, config.ip.to_string()),
+            Direction::Out => (node_ip.to_string(), ip),
+            Direction::In => (ip, node_ip.to_string()),
         };
 
-        if rule.ports.is_empty() {
+        if !rule.has_ports() {
             rule_args.push(Self {
                 policy: variant_to_string(&rule.action),
                 direction: rule.direction,
@@ -164,23 +214,65 @@ impl RuleArgs {
                 ip_from,
                 ip_to,
                 port: None,
+                log: rule.log,
                 name,
             });
         } else {
-            for port in &rule.ports {
+            let mut ports = rule
+                .ports
+                .iter()
+                .map(|port| (port.to_string(), proto.clone()))
+                .collect::<Vec<_>>();
+            for range in &rule.port_ranges {
+                let port = format!("{}:{}", range.from, range.to);
+                // ufw requires protocol to be explicitly set for port ranges
+                if let Some(proto) = &proto {
+                    ports.push((port, Some(proto.clone())));
+                } else {
+                    ports.push((port.clone(), Some("tcp".to_string())));
+                    ports.push((port, Some("udp".to_string())));
+                }
+            }
+            for (port, protocol) in ports {
                 rule_args.push(Self {
                     policy: variant_to_string(&rule.action),
                     direction: rule.direction.clone(),
-                    protocol: proto.clone(),
+                    protocol,
                     ip_from: ip_from.clone(),
                     ip_to: ip_to.clone(),
-                    port: Some(port.to_string()),
+                    port: Some(port),
+                    log: rule.log,
                     name: name.clone(),
                 });
             }
         }
     }
 
+    /// Rule in the form shown by `ufw status`, see `status_line_key`.
+    fn status_key(&self) -> String {
+        let address = |ip: &str| {
+            if ip == "any" {
+                "Anywhere".to_string()
+            } else {
+                ip.to_string()
+            }
+        };
+        let mut to = address(&self.ip_to);
+        if let Some(port) = &self.port {
+            to.push_str(&format!(" {port}"));
+            if let Some(proto) = &self.protocol {
+                to.push_str(&format!("/{proto}"));
+            }
+        }
+        status_key(
+            &self.policy.to_uppercase(),
+            &to,
+            &address(&self.ip_from),
+            self.log,
+            &self.name,
+        )
+    }
+
     fn as_vec<'a>(&'a self, iface: &'a Option<String>) -> Vec<&'a str> {
         let mut args = if let Some(iface) = iface {
             vec![
@@ -189,21 +281,17 @@ impl RuleArgs {
                 to_variant_name(&self.direction).unwrap(),
                 "on",
                 iface,
-                "from",
-                &self.ip_from,
-                "to",
-                &self.ip_to,
             ]
         } else {
             vec![
                 self.policy.as_str(),
                 to_variant_name(&self.direction).unwrap(),
-                "from",
-                &self.ip_from,
-                "to",
-                &self.ip_to,
             ]
         };
+        if self.log {
+            args.push("log");
+        }
+        args.extend(["from", self.ip_from.as_str(), "to", self.ip_to.as_str()]);
         if let Some(port) = &self.port {
             args.push("port");
             args.push(port.as_str());
@@ -218,6 +306,43 @@ impl RuleArgs {
     }
 }
 
+/// Normalized `ufw status numbered` line, comparable with `RuleArgs::status_key`.
+/// Interface and direction columns are skipped, since these are the same for all node rules
+/// of given direction, while addresses are already directional (`To` and `From` columns).
+fn status_line_key(line: &str) -> Option<String> {
+    let (_, line) = line.split_once(']')?;
+    // ufw shows rule comment at the end of line
+    let (rule, comment) = line.split_once("# ")?;
+    let mut log = false;
+    let mut tokens = vec![];
+    let mut iter = rule.split_whitespace();
+    while let Some(token) = iter.next() {
+        match token {
+            "on" => {
+                iter.next();
+            }
+            "IN" | "OUT" | "FWD" | "(v6)" => {}
+            "(log)" | "(log-all)" => log = true,
+            _ => tokens.push(token),
+        }
+    }
+    let action = tokens
+        .iter()
+        .position(|token| ["ALLOW", "DENY", "REJECT", "LIMIT"].contains(token))?;
+    Some(status_key(
+        tokens[action],
+        &tokens[..action].join(" "),
+        &tokens[action + 1..].join(" "),
+        log,
+        comment.trim(),
+    ))
+}
+
+fn status_key(action: &str, to: &str, from: &str, log: bool, name: &str) -> String {
+    let log = if log { " log" } else { "" };
+    format!("{action} {to} from {from}{log} # {name}")
+}
+
 fn variant_to_string<T: serde::ser::Serialize>(variant: &T) -> String {
     // `to_variant_name()` may fail only with `UnsupportedType` which shall not happen,
     // so it is safe to unwrap here.
@@ -234,7 +359,7 @@ mod tests {
     use crate::firewall;
     use crate::firewall::{Action, Rule};
     use mockall::*;
-    use std::net::IpAddr;
+    use std::net::{IpAddr, Ipv6Addr};
     use std::str::FromStr;
 
     mock! {
@@ -258,11 +383,13 @@ mod tests {
         NodeFirewallConfig {
             id: Uuid::parse_str("4931bafa-92d9-4521-9fc6-a77eee047530").unwrap(),
             ip: IpAddr::from_str("192.168.0.7").unwrap(),
+            ipv6: None,
             bridge: Some("bvbr0".to_string()),
             config: firewall::Config {
                 default_in: Action::Deny,
                 default_out: Action::Allow,
                 rules: vec![],
+                ip_sets: Default::default(),
             },
         }
     }
@@ -398,6 +525,9 @@ mod tests {
                 protocol: None,
                 ips: vec!["ip.is.validated.before".to_string()],
                 ports: vec![7],
+                ip_sets: vec![],
+                port_ranges: vec![],
+                log: false,
             },
             Rule {
                 name: "rule B".to_string(),
@@ -406,6 +536,9 @@ mod tests {
                 protocol: Some(Protocol::Tcp),
                 ips: vec!["ip.is.validated.before".to_string()],
                 ports: vec![144, 77],
+                ip_sets: vec![],
+                port_ranges: vec![],
+                log: false,
             },
             Rule {
                 name: "no ports".to_string(),
@@ -414,6 +547,9 @@ mod tests {
                 protocol: None,
                 ips: vec![],
                 ports: vec![],
+                ip_sets: vec![],
+                port_ranges: vec![],
+                log: false,
             },
             Rule {
                 name: "".to_string(),
@@ -422,6 +558,9 @@ mod tests {
                 protocol: None,
                 ips: vec![],
                 ports: vec![7],
+                ip_sets: vec![],
+                port_ranges: vec![],
+                log: false,
             },
         ];
         let mut mock_runner = MockTestRunner::new();
@@ -699,6 +838,9 @@ mod tests {
             protocol: None,
             ips: vec!["ip.is.validated.before".to_string()],
             ports: vec![7],
+            ip_sets: vec![],
+            port_ranges: vec![],
+            log: false,
         }];
         let mut mock_runner = MockTestRunner::new();
         expect_with_args(
@@ -793,4 +935,269 @@ mod tests {
         apply_firewall_config_with(config, mock_runner).await?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_extended_rules() -> Result<()> {
+        let mut config = default_config();
+        config.bridge = None;
+        config.config.ip_sets = std::collections::BTreeMap::from([(
+            "clients".to_string(),
+            vec!["10.0.0.0/8".to_string()],
+        )]);
+        config.config.rules = vec![Rule {
+            name: "rpc".to_string(),
+            action: Action::Limit,
+            direction: Direction::In,
+            protocol: None,
+            ips: vec![],
+            ports: vec![],
+            ip_sets: vec!["clients".to_string()],
+            port_ranges: vec![firewall::PortRange {
+                from: 8545,
+                to: 8546,
+            }],
+            log: true,
+        }];
+        let mut mock_runner = MockTestRunner::new();
+        expect_with_args(
+            &mut mock_runner,
+            &[
+                "--dry-run",
+                "limit",
+                "in",
+                "log",
+                "from",
+                "10.0.0.0/8",
+                "to",
+                "192.168.0.7",
+                "port",
+                "8545:8546",
+                "proto",
+                "tcp",
+                "comment",
+                "4931bafa-92d9-4521-9fc6-a77eee047530 rpc",
+            ],
+        );
+        expect_with_args(
+            &mut mock_runner,
+            &[
+                "--dry-run",
+                "limit",
+                "in",
+                "log",
+                "from",
+                "10.0.0.0/8",
+                "to",
+                "192.168.0.7",
+                "port",
+                "8545:8546",
+                "proto",
+                "udp",
+                "comment",
+                "4931bafa-92d9-4521-9fc6-a77eee047530 rpc",
+            ],
+        );
+        expect_with_args(
+            &mut mock_runner,
+            &[
+                "--dry-run",
+                "deny",
+                "in",
+                "from",
+                "any",
+                "to",
+                "192.168.0.7",
+                "comment",
+                "4931bafa-92d9-4521-9fc6-a77eee047530 default in",
+            ],
+        );
+        expect_with_args(
+            &mut mock_runner,
+            &[
+                "--dry-run",
+                "allow",
+                "out",
+                "from",
+                "192.168.0.7",
+                "to",
+                "any",
+                "comment",
+                "4931bafa-92d9-4521-9fc6-a77eee047530 default out",
+            ],
+        );
+
+        expect_with_args(&mut mock_runner, &["status", "numbered"]);
+
+        expect_with_args(
+            &mut mock_runner,
+            &[
+                "limit",
+                "in",
+                "log",
+                "from",
+                "10.0.0.0/8",
+                "to",
+                "192.168.0.7",
+                "port",
+                "8545:8546",
+                "proto",
+                "tcp",
+                "comment",
+                "4931bafa-92d9-4521-9fc6-a77eee047530 rpc",
+            ],
+        );
+        expect_with_args(
+            &mut mock_runner,
+            &[
+                "limit",
+                "in",
+                "log",
+                "from",
+                "10.0.0.0/8",
+                "to",
+                "192.168.0.7",
+                "port",
+                "8545:8546",
+                "proto",
+                "udp",
+                "comment",
+                "4931bafa-92d9-4521-9fc6-a77eee047530 rpc",
+            ],
+        );
+        expect_with_args(
+            &mut mock_runner,
+            &[
+                "deny",
+                "in",
+                "from",
+                "any",
+                "to",
+                "192.168.0.7",
+                "comment",
+                "4931bafa-92d9-4521-9fc6-a77eee047530 default in",
+            ],
+        );
+        expect_with_args(
+            &mut mock_runner,
+            &[
+                "allow",
+                "out",
+                "from",
+                "192.168.0.7",
+                "to",
+                "any",
+                "comment",
+                "4931bafa-92d9-4521-9fc6-a77eee047530 default out",
+            ],
+        );
+
+        apply_firewall_config_with(config, mock_runner).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_node_rules_status() -> Result<()> {
+        let config = default_config();
+        let mut mock_runner = MockTestRunner::new();
+        mock_runner
+            .expect_run()
+            .times(2)
+            .withf(|args| args == ["status", "numbered"])
+            .returning(|_| Ok("Status: active\n\
+            [ 1] 192.168.0.7 on bvbr0           DENY FWD    Anywhere                   # 4931bafa-92d9-4521-9fc6-a77eee047530 default in\n\
+            [ 2] Anywhere on bvbr0              ALLOW FWD   192.168.0.7                # 4931bafa-92d9-4521-9fc6-a77eee047530 default out\n\
+            [ 3] 192.168.0.13 on bvbr0          DENY FWD    Anywhere                   # 5931bafa-92d9-4521-9fc6-a77eee047530 default in".to_string()));
+
+        let status = node_rules_status_with(config.clone(), &mock_runner).await?;
+        assert!(!status.drift);
+        assert_eq!(
+            vec![
+                "route deny in on bvbr0 from any to 192.168.0.7 comment 4931bafa-92d9-4521-9fc6-a77eee047530 default in",
+                "route allow out on bvbr0 from 192.168.0.7 to any comment 4931bafa-92d9-4521-9fc6-a77eee047530 default out",
+            ],
+            status.expected
+        );
+        assert_eq!(2, status.actual.len());
+
+        // rule edited in place, but comment left untouched
+        let mut edited_runner = MockTestRunner::new();
+        edited_runner
+            .expect_run()
+            .once()
+            .withf(|args| args == ["status", "numbered"])
+            .returning(|_| Ok("Status: active\n\
+            [ 1] 192.168.0.7 on bvbr0           ALLOW FWD   Anywhere                   # 4931bafa-92d9-4521-9fc6-a77eee047530 default in\n\
+            [ 2] Anywhere on bvbr0              ALLOW FWD   192.168.0.7                # 4931bafa-92d9-4521-9fc6-a77eee047530 default out".to_string()));
+        let status = node_rules_status_with(config.clone(), &edited_runner).await?;
+        assert!(status.drift);
+
+        // rule missing on the host
+        let mut config = config;
+        config.config.rules.push(Rule {
+            name: "rule".to_string(),
+            action: Action::Allow,
+            direction: Direction::In,
+            protocol: None,
+            ips: vec![],
+            ip_sets: vec![],
+            ports: vec![],
+            port_ranges: vec![],
+            log: false,
+        });
+        let status = node_rules_status_with(config, &mock_runner).await?;
+        assert!(status.drift);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack() -> Result<()> {
+        let mut config = default_config();
+        config.ipv6 = Some(Ipv6Addr::from_str("fd00::7").unwrap());
+        config.config.rules = vec![
+            Rule {
+                name: "rpc".to_string(),
+                action: Action::Allow,
+                direction: Direction::In,
+                protocol: Some(Protocol::Tcp),
+                ips: vec!["1.2.3.4".to_string(), "2001:db8::/32".to_string()],
+                ip_sets: vec![],
+                ports: vec![8545],
+                port_ranges: vec![],
+                log: false,
+            },
+            Rule {
+                name: "v4 only".to_string(),
+                action: Action::Deny,
+                direction: Direction::Out,
+                protocol: None,
+                ips: vec!["10.0.0.0/8".to_string()],
+                ip_sets: vec![],
+                ports: vec![],
+                port_ranges: vec![],
+                log: false,
+            },
+        ];
+        let mut mock_runner = MockTestRunner::new();
+        mock_runner
+            .expect_run()
+            .once()
+            .withf(|args| args == ["status", "numbered"])
+            .returning(|_| Ok("Status: active\n".to_string()));
+
+        let status = node_rules_status_with(config, &mock_runner).await?;
+        assert_eq!(
+            vec![
+                "route deny out on bvbr0 from 192.168.0.7 to 10.0.0.0/8 comment 4931bafa-92d9-4521-9fc6-a77eee047530 v4 only",
+                "route allow in on bvbr0 from 1.2.3.4 to 192.168.0.7 port 8545 proto tcp comment 4931bafa-92d9-4521-9fc6-a77eee047530 rpc",
+                "route deny in on bvbr0 from any to 192.168.0.7 comment 4931bafa-92d9-4521-9fc6-a77eee047530 default in",
+                "route allow out on bvbr0 from 192.168.0.7 to any comment 4931bafa-92d9-4521-9fc6-a77eee047530 default out",
+                "route allow in on bvbr0 from 2001:db8::/32 to fd00::7 port 8545 proto tcp comment 4931bafa-92d9-4521-9fc6-a77eee047530 rpc",
+                "route deny in on bvbr0 from any to fd00::7 comment 4931bafa-92d9-4521-9fc6-a77eee047530 default in",
+                "route allow out on bvbr0 from fd00::7 to any comment 4931bafa-92d9-4521-9fc6-a77eee047530 default out",
+            ],
+            status.expected
+        );
+        assert!(status.drift);
+        Ok(())
+    }
 }
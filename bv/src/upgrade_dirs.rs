@@ -0,0 +1,209 @@
+//! Node runtime directory swapping on upgrade, shared by all machine implementations.
+//!
+//! Runtime dir (rootfs or VM dir) of upgraded image may be built in background into staging dir
+//! (see `pal::VirtualMachine::stage_upgrade`), while node is still running. On upgrade, current
+//! runtime dir is moved to backup dir and staged one (if any) takes its place.
+//! Backup is restored on rollback, or dropped once upgrade is finished.
+
+use eyre::Result;
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+use tracing::warn;
+
+#[derive(Debug, Clone)]
+pub struct UpgradeDirs {
+    dir: PathBuf,
+    backup_dir: PathBuf,
+    staging_dir: PathBuf,
+    staged_image_path: PathBuf,
+}
+
+impl UpgradeDirs {
+    pub fn new(
+        dir: PathBuf,
+        backup_dir: PathBuf,
+        staging_dir: PathBuf,
+        staged_image_path: PathBuf,
+    ) -> Self {
+        Self {
+            dir,
+            backup_dir,
+            staging_dir,
+            staged_image_path,
+        }
+    }
+
+    pub fn staging_dir(&self) -> &Path {
+        &self.staging_dir
+    }
+
+    /// File with uri of staged image.
+    pub fn staged_image_path(&self) -> &Path {
+        &self.staged_image_path
+    }
+
+    /// Returns image uri, if runtime dir for it is already built in staging dir.
+    pub async fn staged_image(&self) -> Option<String> {
+        if !self.staging_dir.exists() {
+            return None;
+        }
+        fs::read_to_string(&self.staged_image_path).await.ok()
+    }
+
+    /// Stage image `uri` by awaiting `build`, that is expected to build it into `staging_dir()`.
+    /// Nothing is built if the same image is already staged.
+    pub async fn stage(&self, uri: &str, build: impl Future<Output = Result<()>>) -> Result<()> {
+        if self.staged_image().await.as_deref() == Some(uri) {
+            // already staged by previous, not finished upgrade attempt
+            return Ok(());
+        }
+        self.drop_staged().await?;
+        if let Err(err) = build.await {
+            if let Err(cleanup_err) = self.drop_staged().await {
+                warn!(
+                    "failed to cleanup staging dir '{}': {cleanup_err:#}",
+                    self.staging_dir.display()
+                );
+            }
+            return Err(err);
+        }
+        fs::write(&self.staged_image_path, uri).await?;
+        Ok(())
+    }
+
+    pub async fn drop_staged(&self) -> Result<()> {
+        if self.staging_dir.exists() {
+            fs::remove_dir_all(&self.staging_dir).await?;
+        }
+        if self.staged_image_path.exists() {
+            fs::remove_file(&self.staged_image_path).await?;
+        }
+        Ok(())
+    }
+
+    /// Move runtime dir to backup and swap in staged one, if it was built for `uri`.
+    /// Returns `false` if there was nothing to swap in, so runtime dir must be built from scratch.
+    pub async fn swap_in_staged(&self, uri: &str) -> Result<bool> {
+        fs::rename(&self.dir, &self.backup_dir).await?;
+        if self.staged_image().await.as_deref() == Some(uri) {
+            fs::rename(&self.staging_dir, &self.dir).await?;
+            fs::remove_file(&self.staged_image_path).await?;
+            Ok(true)
+        } else {
+            self.drop_staged().await?;
+            Ok(false)
+        }
+    }
+
+    pub fn has_backup(&self) -> bool {
+        self.backup_dir.exists()
+    }
+
+    pub async fn drop_backup(&self) -> Result<()> {
+        if self.backup_dir.exists() {
+            fs::remove_dir_all(&self.backup_dir).await?
+        }
+        Ok(())
+    }
+
+    /// Replace runtime dir with its backup, if there is any.
+    pub async fn restore_backup(&self) -> Result<()> {
+        if self.backup_dir.exists() {
+            if self.dir.exists() {
+                fs::remove_dir_all(&self.dir).await?;
+            }
+            fs::rename(&self.backup_dir, &self.dir).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::TempDir;
+    use eyre::bail;
+
+    fn upgrade_dirs(root: &Path) -> UpgradeDirs {
+        UpgradeDirs::new(
+            root.join("rootfs"),
+            root.join("rootfs_backup"),
+            root.join("rootfs_staging"),
+            root.join("staged_image"),
+        )
+    }
+
+    async fn build(dir: &Path, content: &str) -> Result<()> {
+        fs::create_dir_all(dir).await?;
+        fs::write(dir.join("image"), content).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stage_upgrade_rollback() -> Result<()> {
+        let tmp_root = TempDir::new()?.to_path_buf();
+        let dirs = upgrade_dirs(&tmp_root);
+        build(&tmp_root.join("rootfs"), "v1").await?;
+
+        assert_eq!(None, dirs.staged_image().await);
+        dirs.stage("v2", build(dirs.staging_dir(), "v2")).await?;
+        assert_eq!(Some("v2".to_string()), dirs.staged_image().await);
+        // already staged, so not built again
+        dirs.stage("v2", async { bail!("unexpected build") })
+            .await?;
+
+        assert!(dirs.swap_in_staged("v2").await?);
+        assert_eq!(
+            "v2",
+            fs::read_to_string(tmp_root.join("rootfs/image")).await?
+        );
+        assert!(dirs.has_backup());
+        assert!(!dirs.staging_dir().exists());
+        assert!(!dirs.staged_image_path().exists());
+
+        dirs.restore_backup().await?;
+        assert_eq!(
+            "v1",
+            fs::read_to_string(tmp_root.join("rootfs/image")).await?
+        );
+        assert!(!dirs.has_backup());
+        // nothing to restore anymore
+        dirs.restore_backup().await?;
+        assert_eq!(
+            "v1",
+            fs::read_to_string(tmp_root.join("rootfs/image")).await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stage_failure_and_mismatch() -> Result<()> {
+        let tmp_root = TempDir::new()?.to_path_buf();
+        let dirs = upgrade_dirs(&tmp_root);
+        build(&tmp_root.join("rootfs"), "v1").await?;
+
+        let res = dirs
+            .stage("v2", async {
+                build(dirs.staging_dir(), "partial").await?;
+                bail!("build failed")
+            })
+            .await;
+        assert!(res.is_err());
+        assert!(!dirs.staging_dir().exists());
+        assert!(!dirs.staged_image_path().exists());
+
+        dirs.stage("v2", build(dirs.staging_dir(), "v2")).await?;
+        // staged other image, so runtime dir must be built from scratch
+        assert!(!dirs.swap_in_staged("v3").await?);
+        assert!(!tmp_root.join("rootfs").exists());
+        assert!(!dirs.staging_dir().exists());
+        assert!(!dirs.staged_image_path().exists());
+
+        dirs.drop_backup().await?;
+        assert!(!dirs.has_backup());
+        Ok(())
+    }
+}
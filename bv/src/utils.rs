@@ -133,6 +133,13 @@ pub fn render_template(template: &str, destination: &Path, params: &[(&str, &str
     Ok(())
 }
 
+/// Check if given path is a mount point.
+pub async fn is_mount_point(path: &Path) -> bool {
+    run_cmd("mountpoint", [OsStr::new("-q"), path.as_os_str()])
+        .await
+        .is_ok()
+}
+
 pub async fn careful_save(path: &Path, content: &[u8]) -> Result<()> {
     let backup_path = path.with_extension(format!(
         "{}_bak",
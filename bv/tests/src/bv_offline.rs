@@ -5,17 +5,21 @@ use assert_cmd::Command;
 use assert_fs::TempDir;
 use blockvisord::api_config::ApiConfig;
 use blockvisord::{
-    apptainer_machine::build_rootfs_dir,
-    bv_config::{Config, SharedConfig},
-    node_context::build_node_dir,
+    apptainer_machine::{build_rootfs_dir, BACKUP_ROOTFS_DIR, STAGING_ROOTFS_DIR},
+    bv_config::{Config, NspawnConfig, SharedConfig},
+    node_context::{build_node_dir, NodeContext},
+    node_snapshot::Snapshots,
     services,
     services::api::pb,
     utils,
 };
 use bv_utils::{cmd::run_cmd, run_flag::RunFlag, system::is_process_running};
-use eyre::{bail, Result};
+use eyre::{anyhow, bail, Result};
 use std::net::ToSocketAddrs;
-use std::path::PathBuf;
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
 use tokio::{
     fs,
     time::{sleep, Duration},
@@ -276,6 +280,184 @@ async fn test_bv_cmd_node_lifecycle() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 3)]
+async fn test_bv_cmd_nspawn_node_lifecycle() -> Result<()> {
+    let mut test_env = TestEnv::new().await?;
+    let images_dir = test_env.bv_root.join("nspawn_images");
+    let image_v1 = build_nspawn_image(&images_dir, "image_v1").await?;
+    let image_v2 = build_nspawn_image(&images_dir, "image_v2").await?;
+    let pal = test_env.build_nspawn_platform(NspawnConfig {
+        chroot: true,
+        rootfs_tarball: None,
+    });
+    test_env
+        .run_blockvisord_with_pal(RunFlag::default(), pal)
+        .await?;
+
+    println!("create a node");
+    let (vm_id, _) = &test_env.create_node_from(&image_v1, "216.18.214.195");
+    println!("create vm_id: {vm_id}");
+    let node_dir = build_node_dir(&test_env.bv_root, Uuid::parse_str(vm_id)?);
+    let rootfs_image = build_rootfs_dir(&node_dir).join("var/lib/babel/rootfs_image");
+
+    println!("start stopped node");
+    test_env.bv_run(&["node", "start", vm_id], "Started node");
+    test_env
+        .wait_for_job_status(vm_id, "echo", "Running", Duration::from_secs(5))
+        .await;
+
+    println!("upgrade running node");
+    test_env.nib_run(
+        &[
+            "image",
+            "upgrade",
+            "--path",
+            &image_v2.to_string_lossy(),
+            vm_id,
+        ],
+        "Upgraded dev_node",
+    );
+    test_env.bv_run(&["node", "status", vm_id], "Running");
+    test_env
+        .wait_for_job_status(vm_id, "echo2", "Running", Duration::from_secs(5))
+        .await;
+    assert!(fs::read_to_string(&rootfs_image)
+        .await?
+        .ends_with("image_v2/rootfs.tar"));
+    assert!(!node_dir.join(STAGING_ROOTFS_DIR).exists());
+    assert!(!node_dir.join(BACKUP_ROOTFS_DIR).exists());
+
+    println!("rollback failed upgrade");
+    test_env.bv_run(&["node", "stop", vm_id], "Stopped node");
+    let broken_image = images_dir.join("image_v3.yaml");
+    fs::write(
+        &broken_image,
+        fs::read_to_string(&image_v2)
+            .await?
+            .replace("version: 0.0.2", "version: 0.0.3")
+            .replace("image_v2/rootfs.tar", "image_v3/rootfs.tar"),
+    )
+    .await?;
+    Command::cargo_bin("nib")
+        .unwrap()
+        .args([
+            "image",
+            "upgrade",
+            "--path",
+            &broken_image.to_string_lossy(),
+            vm_id,
+        ])
+        .env("BV_ROOT", &test_env.bv_root)
+        .assert()
+        .failure();
+    assert!(fs::read_to_string(&rootfs_image)
+        .await?
+        .ends_with("image_v2/rootfs.tar"));
+    assert!(!node_dir.join(BACKUP_ROOTFS_DIR).exists());
+    test_env.bv_run(&["node", "start", vm_id], "Started node");
+    test_env
+        .wait_for_job_status(vm_id, "echo2", "Running", Duration::from_secs(5))
+        .await;
+
+    println!("delete started node");
+    test_env.bv_run(&["node", "delete", "--yes", vm_id], "Deleted node");
+    assert!(!node_dir.exists());
+    Ok(())
+}
+
+/// Host tools (with their shared libraries) copied into generated test rootfs.
+const NSPAWN_ROOTFS_TOOLS: [&str; 6] = ["sh", "echo", "sleep", "touch", "cat", "mkdir"];
+
+/// Copy of test image definition, with rootfs tarball generated from host tools and image files
+/// (the same as added by image Dockerfile), since nspawn runtime can run only local (`file://`)
+/// images. No docker nor root privileges are needed.
+async fn build_nspawn_image(dir: &Path, image: &str) -> Result<PathBuf> {
+    let image_src_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join(image);
+    let image_dir = dir.join(image);
+    let rootfs_dir = image_dir.join("rootfs");
+    let bin_dir = rootfs_dir.join("usr/bin");
+    fs::create_dir_all(&bin_dir).await?;
+    fs::symlink("usr/bin", rootfs_dir.join("bin")).await?;
+    for tool in NSPAWN_ROOTFS_TOOLS {
+        let path = find_host_bin(tool)?;
+        fs::copy(&path, bin_dir.join(tool)).await?;
+        for lib in shared_libs(&path).await? {
+            let target = rootfs_dir.join(lib.strip_prefix("/")?);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::copy(&lib, target).await?;
+        }
+    }
+    let plugin_dir = rootfs_dir.join("var/lib/babel/plugin");
+    fs::create_dir_all(&plugin_dir).await?;
+    fs::copy(
+        image_src_dir.join("main.rhai"),
+        plugin_dir.join("main.rhai"),
+    )
+    .await?;
+    for dir in ["root", "tmp", "blockjoy"] {
+        fs::create_dir_all(rootfs_dir.join(dir)).await?;
+        fs::write(rootfs_dir.join(dir).join("test"), "ok\n").await?;
+    }
+    let tarball = image_dir.join("rootfs.tar");
+    run_cmd(
+        "tar",
+        [
+            OsStr::new("--create"),
+            OsStr::new("--file"),
+            tarball.as_os_str(),
+            OsStr::new("--directory"),
+            rootfs_dir.as_os_str(),
+            OsStr::new("."),
+        ],
+    )
+    .await?;
+    fs::remove_dir_all(&rootfs_dir).await?;
+
+    let babel_yaml = fs::read_to_string(image_src_dir.join("babel.yaml"))
+        .await?
+        .lines()
+        .map(|line| {
+            if line.starts_with("container_uri:") {
+                format!("container_uri: file://{}", tarball.display())
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let path = image_dir.join("babel.yaml");
+    fs::write(&path, babel_yaml).await?;
+    Ok(path)
+}
+
+fn find_host_bin(name: &str) -> Result<PathBuf> {
+    std::env::var_os("PATH")
+        .and_then(|paths| {
+            std::env::split_paths(&paths)
+                .map(|dir| dir.join(name))
+                .find(|path| path.is_file())
+        })
+        .ok_or_else(|| anyhow!("`{name}` not found on the host"))
+}
+
+/// Shared libraries (including dynamic loader) needed by given binary, as listed by `ldd`.
+/// Nothing is listed for static binaries.
+async fn shared_libs(bin: &Path) -> Result<Vec<PathBuf>> {
+    let output = tokio::process::Command::new("ldd")
+        .arg(bin)
+        .output()
+        .await?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .filter(|token| token.starts_with('/'))
+        .map(PathBuf::from)
+        .collect())
+}
+
 #[tokio::test(flavor = "multi_thread", worker_threads = 3)]
 async fn test_bv_cmd_node_recovery() -> Result<()> {
     let mut test_env = TestEnv::new().await?;
@@ -423,3 +605,42 @@ async fn test_discovery_on_connection_error() -> Result<()> {
     assert_eq!("notification_url", &final_cfg.blockjoy_mqtt_url.unwrap());
     Ok(())
 }
+
+#[tokio::test]
+async fn test_snapshot_copy_restore_into_mount_point() -> Result<()> {
+    let tmp_root = TempDir::new()?.to_path_buf();
+    let context = NodeContext::build(&tmp_root, Uuid::new_v4());
+    let data_dir = context.node_dir.join("data");
+    fs::create_dir_all(&data_dir).await?;
+    // data dir is loopback mount if disk quota is enabled, which can't be renamed
+    run_cmd(
+        "mount",
+        [
+            "-t".as_ref(),
+            "tmpfs".as_ref(),
+            "tmpfs".as_ref(),
+            data_dir.as_os_str(),
+        ],
+    )
+    .await?;
+    let snapshots = Snapshots::new(&context, data_dir.clone());
+    let result = async {
+        fs::create_dir_all(data_dir.join("sub")).await?;
+        fs::write(data_dir.join("sub/file"), "before").await?;
+        snapshots.create("pre-fork", "1.0.0").await?;
+        fs::write(data_dir.join("sub/file"), "after").await?;
+        fs::write(data_dir.join("new_file"), "new").await?;
+
+        snapshots.restore("pre-fork").await?;
+        assert_eq!(
+            "before",
+            fs::read_to_string(data_dir.join("sub/file")).await?
+        );
+        assert!(!data_dir.join("new_file").exists());
+        assert!(!data_dir.with_extension("restoring").exists());
+        Ok(())
+    }
+    .await;
+    run_cmd("umount", [data_dir.as_os_str()]).await?;
+    result
+}
@@ -7,14 +7,16 @@ use blockvisord::{
     apptainer_machine,
     apptainer_platform::BareNodeConnection,
     blockvisord::BlockvisorD,
-    bv_config::{ApptainerConfig, Config, SharedConfig},
+    bv_config::{ApptainerConfig, Config, NspawnConfig, SharedConfig},
     bv_context::BvContext,
+    disk_quota::DiskQuotaMethod,
     node_context,
     node_context::NODES_DIR,
     node_state::{NodeState, VmStatus},
     nodes_manager::NodesDataCache,
+    nspawn_machine,
     pal::{AvailableResources, NodeFirewallConfig, RecoverBackoff},
-    pal::{CommandsStream, Pal, ServiceConnector},
+    pal::{CommandsStream, Pal, ServiceConnector, VirtualMachine},
     services::{self, ApiInterceptor, AuthToken},
     BV_VAR_PATH,
 };
@@ -22,6 +24,7 @@ use bv_utils::{logging::setup_logging, rpc::DefaultTimeout, run_flag::RunFlag};
 use eyre::Result;
 use predicates::prelude::predicate;
 use std::{
+    fmt::Debug,
     fs,
     net::IpAddr,
     path::{Path, PathBuf},
@@ -95,6 +98,26 @@ impl TestEnv {
     }
 
     pub fn build_dummy_platform(&self) -> DummyPlatform {
+        self.build_platform(ApptainerConfig {
+            extra_args: None,
+            host_network: true,
+            cpu_limit: true,
+            memory_limit: true,
+            disk_quota: DiskQuotaMethod::Disabled,
+            image_cache: false,
+            bandwidth_limit: Default::default(),
+        })
+    }
+
+    /// Dummy platform running nodes with `nspawn` runtime, instead of apptainer.
+    pub fn build_nspawn_platform(
+        &self,
+        config: NspawnConfig,
+    ) -> DummyPlatform<nspawn_machine::NspawnMachine> {
+        self.build_platform(config)
+    }
+
+    fn build_platform<V: DummyRuntime>(&self, config: V::Config) -> DummyPlatform<V> {
         let babel_dir = Path::new(env!("CARGO_MANIFEST_DIR"))
             .join("../target")
             .join("x86_64-unknown-linux-musl")
@@ -103,6 +126,7 @@ impl TestEnv {
             bv_root: self.bv_root.clone(),
             babel_path: babel_dir.join("babel"),
             job_runner_path: babel_dir.join("babel_job_runner"),
+            config,
         }
     }
 
@@ -111,10 +135,10 @@ impl TestEnv {
             .await
     }
 
-    pub async fn run_blockvisord_with_pal(
+    pub async fn run_blockvisord_with_pal<V: DummyRuntime>(
         &mut self,
         run: RunFlag,
-        pal: DummyPlatform,
+        pal: DummyPlatform<V>,
     ) -> Result<JoinHandle<Result<()>>> {
         let blockvisord = BlockvisorD::new(pal, Config::load(&self.bv_root).await?).await?;
         self.api_config.blockvisor_port = blockvisord.local_addr()?.port();
@@ -164,11 +188,16 @@ impl TestEnv {
     }
 
     pub fn create_node(&self, image: &str, ip: &str) -> (String, String) {
-        let mut cmd = Command::cargo_bin("nib").unwrap();
         let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
             .join("tests")
             .join(image)
             .join("babel.yaml");
+        self.create_node_from(&path, ip)
+    }
+
+    /// Create dev node from image definition (`babel.yaml`) at given `path`.
+    pub fn create_node_from(&self, path: &Path, ip: &str) -> (String, String) {
+        let mut cmd = Command::cargo_bin("nib").unwrap();
         let cmd = cmd
             .args([
                 "image",
@@ -188,14 +217,7 @@ impl TestEnv {
         let stderr = str::from_utf8(&output.stderr).unwrap();
         println!("create stdout: {stdout}");
         println!("create stderr: {stderr}");
-        let vm_id = stdout
-            .trim_start_matches(&format!(
-                "Created new dev_node from `{image}` image with ID "
-            ))
-            .split('`')
-            .nth(1)
-            .unwrap()
-            .to_string();
+        let vm_id = stdout.split('`').nth(1).unwrap().to_string();
         let vm_name = stdout.split('`').rev().nth(1).unwrap().to_string();
         (vm_id, vm_name)
     }
@@ -312,15 +334,125 @@ pub async fn wait_for_expected(call: impl Fn() -> AssertResult, timeout: Duratio
     }
 }
 
+/// Node runtime used by `DummyPlatform`.
+#[async_trait]
+pub trait DummyRuntime: VirtualMachine + Debug + Send + Sync + Sized + 'static {
+    type Config: Debug + Send + Sync;
+
+    async fn create(
+        pal: &DummyPlatform<Self>,
+        bv_context: &BvContext,
+        node_state: &NodeState,
+    ) -> Result<Self>;
+
+    async fn attach(
+        pal: &DummyPlatform<Self>,
+        bv_context: &BvContext,
+        node_state: &NodeState,
+    ) -> Result<Self>;
+}
+
+async fn new_apptainer_vm(
+    pal: &DummyPlatform,
+    bv_context: &BvContext,
+    node_state: &NodeState,
+    create: bool,
+) -> Result<apptainer_machine::ApptainerMachine> {
+    apptainer_machine::new(
+        &pal.bv_root,
+        NetConf {
+            gateway: IpAddr::from_str("216.18.214.89")?,
+            bridge: IpAddr::from_str("216.18.214.90")?,
+            mask_bits: 24,
+            ipv6: None,
+        },
+        bv_context,
+        node_state,
+        pal.babel_path.clone(),
+        pal.config.clone(),
+        create,
+    )
+    .await
+}
+
+#[async_trait]
+impl DummyRuntime for apptainer_machine::ApptainerMachine {
+    type Config = ApptainerConfig;
+
+    async fn create(
+        pal: &DummyPlatform<Self>,
+        bv_context: &BvContext,
+        node_state: &NodeState,
+    ) -> Result<Self> {
+        let vm = new_apptainer_vm(pal, bv_context, node_state, true).await?;
+        vm.build().await?;
+        Ok(vm)
+    }
+
+    async fn attach(
+        pal: &DummyPlatform<Self>,
+        bv_context: &BvContext,
+        node_state: &NodeState,
+    ) -> Result<Self> {
+        let mut vm = new_apptainer_vm(pal, bv_context, node_state, false).await?;
+        vm.attach().await?;
+        Ok(vm)
+    }
+}
+
+#[async_trait]
+impl DummyRuntime for nspawn_machine::NspawnMachine {
+    type Config = NspawnConfig;
+
+    async fn create(
+        pal: &DummyPlatform<Self>,
+        bv_context: &BvContext,
+        node_state: &NodeState,
+    ) -> Result<Self> {
+        let mut vm = nspawn_machine::new(
+            &pal.bv_root,
+            bv_context,
+            node_state,
+            pal.babel_path.clone(),
+            pal.config.clone(),
+        )
+        .await?;
+        if let Err(err) = vm.build().await {
+            vm.delete().await?;
+            Err(err)
+        } else {
+            Ok(vm)
+        }
+    }
+
+    async fn attach(
+        pal: &DummyPlatform<Self>,
+        bv_context: &BvContext,
+        node_state: &NodeState,
+    ) -> Result<Self> {
+        let mut vm = nspawn_machine::new(
+            &pal.bv_root,
+            bv_context,
+            node_state,
+            pal.babel_path.clone(),
+            pal.config.clone(),
+        )
+        .await?;
+        vm.attach().await?;
+        Ok(vm)
+    }
+}
+
 #[derive(Debug)]
-pub struct DummyPlatform {
+pub struct DummyPlatform<V: DummyRuntime = apptainer_machine::ApptainerMachine> {
     pub(crate) bv_root: PathBuf,
     pub(crate) babel_path: PathBuf,
     pub(crate) job_runner_path: PathBuf,
+    pub(crate) config: V::Config,
 }
 
 #[async_trait]
-impl Pal for DummyPlatform {
+impl<V: DummyRuntime> Pal for DummyPlatform<V> {
     fn bv_root(&self) -> &Path {
         &self.bv_root
     }
@@ -353,33 +485,14 @@ impl Pal for DummyPlatform {
         BareNodeConnection::new(node_context::build_node_dir(&self.bv_root, node_id))
     }
 
-    type VirtualMachine = apptainer_machine::ApptainerMachine;
+    type VirtualMachine = V;
 
     async fn create_vm(
         &self,
         bv_context: &BvContext,
         node_state: &NodeState,
     ) -> Result<Self::VirtualMachine> {
-        let vm = apptainer_machine::new(
-            &self.bv_root,
-            NetConf {
-                gateway: IpAddr::from_str("216.18.214.89")?,
-                bridge: IpAddr::from_str("216.18.214.90")?,
-                mask_bits: 24,
-            },
-            bv_context,
-            node_state,
-            self.babel_path.clone(),
-            ApptainerConfig {
-                extra_args: None,
-                host_network: true,
-                cpu_limit: true,
-                memory_limit: true,
-            },
-        )
-        .await?;
-        vm.build().await?;
-        Ok(vm)
+        V::create(self, bv_context, node_state).await
     }
 
     async fn attach_vm(
@@ -387,26 +500,7 @@ impl Pal for DummyPlatform {
         bv_context: &BvContext,
         node_state: &NodeState,
     ) -> Result<Self::VirtualMachine> {
-        let mut vm = apptainer_machine::new(
-            &self.bv_root,
-            NetConf {
-                gateway: IpAddr::from_str("216.18.214.89")?,
-                bridge: IpAddr::from_str("216.18.214.90")?,
-                mask_bits: 24,
-            },
-            bv_context,
-            node_state,
-            self.babel_path.clone(),
-            ApptainerConfig {
-                extra_args: None,
-                host_network: true,
-                cpu_limit: true,
-                memory_limit: true,
-            },
-        )
-        .await?;
-        vm.attach().await?;
-        Ok(vm)
+        V::attach(self, bv_context, node_state).await
     }
 
     async fn available_cpus(&self) -> usize {
@@ -8,7 +8,7 @@ use babel_api::{
 use eyre::Result;
 use mockall::*;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     path::Path,
     time::{Duration, SystemTime},
 };
@@ -38,6 +38,11 @@ mock! {
         fn node_env(&self) -> NodeEnv;
         fn save_data(&self, value: &str) -> Result<()>;
         fn load_data(&self) -> Result<String>;
+        fn kv_get(&self, key: &str) -> Result<Option<String>>;
+        fn kv_set(&self, key: &str, value: &str) -> Result<()>;
+        fn kv_delete(&self, key: &str) -> Result<bool>;
+        fn kv_list(&self, prefix: &str) -> Result<BTreeMap<String, String>>;
+        fn kv_compare_and_swap(&self, key: &str, expected: Option<String>, new: &str) -> Result<bool>;
         fn save_config(&self, value: &PluginConfig) -> Result<()>;
         fn load_config(&self) -> Result<PluginConfig>;
         fn log(&self, level: tracing::Level, message: &str);
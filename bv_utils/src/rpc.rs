@@ -1,6 +1,12 @@
 use eyre::{bail, Context, ContextCompat};
-use std::{path::Path, time::Duration};
-use tokio::net::UnixStream;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+};
 use tonic::{
     service::Interceptor,
     transport::{Channel, Endpoint, Uri},
@@ -64,6 +70,44 @@ pub fn build_socket_channel(socket_path: impl AsRef<Path>) -> Channel {
         }))
 }
 
+/// Build channel to guest vsock `port`, via Firecracker host side vsock UDS.
+pub fn build_vsock_channel(uds_path: impl AsRef<Path>, port: u32) -> Channel {
+    let uds_path = uds_path.as_ref().to_path_buf();
+    Endpoint::from_static("http://[::]:50052")
+        .connect_timeout(RPC_CONNECT_TIMEOUT)
+        .connect_with_connector_lazy(tower::service_fn(move |_: Uri| {
+            connect_vsock(uds_path.clone(), port)
+        }))
+}
+
+/// Connect to guest vsock port with Firecracker `CONNECT <port>` handshake.
+async fn connect_vsock(uds_path: PathBuf, port: u32) -> std::io::Result<UnixStream> {
+    let mut stream = UnixStream::connect(uds_path).await?;
+    stream
+        .write_all(format!("CONNECT {port}\n").as_bytes())
+        .await?;
+    // read response byte by byte, so nothing sent by guest is consumed
+    let mut response = Vec::new();
+    loop {
+        let byte = stream.read_u8().await?;
+        if byte == b'\n' {
+            break;
+        }
+        response.push(byte);
+    }
+    if response.starts_with(b"OK ") {
+        Ok(stream)
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            format!(
+                "vsock connection to port {port} refused: {}",
+                String::from_utf8_lossy(&response)
+            ),
+        ))
+    }
+}
+
 pub fn estimate_put_download_manifest_request_timeout(chunks_len: usize) -> Duration {
     // download manifest can be pretty big, so make timeout proportional to number of chunks
     // min 10s and then 10s for each 1000 of chunks